@@ -0,0 +1,445 @@
+//! Message and byte counters for the traffic passing through a host server.
+//!
+//! Every game instance talks to the host server over its own connection, so traffic is counted
+//! per connection: the players that a game instance creates share its connection. On top of that,
+//! the game messages each player sends are counted by the player's GUID, so players that share a
+//! connection can be told apart.
+//!
+//! Counters of closed connections are added up, and the counters of their players are dropped, so
+//! the collector does not grow for the life of the host server.
+
+use crate::runtime;
+use crate::GUID;
+use futures::stream::Stream;
+use std::collections::HashMap;
+use std::ops::{AddAssign, Sub};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Identifies a connection between a game instance and the host server.
+pub type ConnectionId = u64;
+
+/// The host server messages that traffic is split by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Session enumeration requests (`enum`).
+    EnumSessions,
+    /// Replies to other messages (`repl`).
+    Reply,
+    /// Regular sends (`send`).
+    Send,
+    /// Anything else, like player creation.
+    Other,
+}
+
+impl Method {
+    pub(crate) fn from_name(method: &[u8]) -> Self {
+        match method {
+            b"enum" => Method::EnumSessions,
+            b"repl" => Method::Reply,
+            b"send" => Method::Send,
+            _ => Method::Other,
+        }
+    }
+}
+
+/// A message and byte count.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counter {
+    /// Number of messages.
+    pub messages: u64,
+    /// Number of payload bytes.
+    pub bytes: u64,
+}
+
+impl AddAssign for Counter {
+    fn add_assign(&mut self, other: Self) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Sub for Counter {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            messages: self.messages - other.messages,
+            bytes: self.bytes - other.bytes,
+        }
+    }
+}
+
+/// Counters for one direction of traffic, split by method.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MethodCounters {
+    pub enum_sessions: Counter,
+    pub reply: Counter,
+    pub send: Counter,
+    pub other: Counter,
+}
+
+impl MethodCounters {
+    /// Get the counter for a method.
+    pub fn get(&self, method: Method) -> Counter {
+        match method {
+            Method::EnumSessions => self.enum_sessions,
+            Method::Reply => self.reply,
+            Method::Send => self.send,
+            Method::Other => self.other,
+        }
+    }
+
+    fn get_mut(&mut self, method: Method) -> &mut Counter {
+        match method {
+            Method::EnumSessions => &mut self.enum_sessions,
+            Method::Reply => &mut self.reply,
+            Method::Send => &mut self.send,
+            Method::Other => &mut self.other,
+        }
+    }
+
+    /// Sum the counters of all methods.
+    pub fn total(&self) -> Counter {
+        let mut total = self.enum_sessions;
+        total += self.reply;
+        total += self.send;
+        total += self.other;
+        total
+    }
+}
+
+impl AddAssign for MethodCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.enum_sessions += other.enum_sessions;
+        self.reply += other.reply;
+        self.send += other.send;
+        self.other += other.other;
+    }
+}
+
+impl Sub for MethodCounters {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            enum_sessions: self.enum_sessions - other.enum_sessions,
+            reply: self.reply - other.reply,
+            send: self.send - other.send,
+            other: self.other - other.other,
+        }
+    }
+}
+
+/// Traffic in both directions, from the point of view of the game instance.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// Messages the game sent out to the network.
+    pub sent: MethodCounters,
+    /// Messages the network delivered to the game.
+    pub received: MethodCounters,
+}
+
+impl AddAssign for Traffic {
+    fn add_assign(&mut self, other: Self) {
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+}
+
+impl Sub for Traffic {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            sent: self.sent - other.sent,
+            received: self.received - other.received,
+        }
+    }
+}
+
+/// Traffic counters for a single open connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionTraffic {
+    /// The players created by the game instance on this connection.
+    pub players: Vec<GUID>,
+    pub traffic: Traffic,
+    /// The game messages sent by each player on this connection, by the player's GUID.
+    pub sent_by_player: HashMap<GUID, Counter>,
+}
+
+/// Point-in-time copy of all traffic counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BandwidthSnapshot {
+    /// The connections that are open.
+    pub connections: HashMap<ConnectionId, ConnectionTraffic>,
+    /// The traffic of all connections that have closed.
+    pub closed: Traffic,
+}
+
+impl BandwidthSnapshot {
+    /// Get the traffic of the connection that a player was created on. This includes the traffic
+    /// of every other player on the same connection.
+    pub fn connection_of(&self, player: GUID) -> Option<Traffic> {
+        self.connections
+            .values()
+            .find(|connection| connection.players.contains(&player))
+            .map(|connection| connection.traffic)
+    }
+
+    /// Get the game messages sent by a player of an open connection.
+    pub fn sent_by(&self, player: GUID) -> Option<Counter> {
+        self.connections
+            .values()
+            .find_map(|connection| connection.sent_by_player.get(&player).copied())
+    }
+
+    /// Sum the traffic of all connections, open and closed.
+    pub fn total(&self) -> Traffic {
+        let mut total = self.closed;
+        for connection in self.connections.values() {
+            total += connection.traffic;
+        }
+        total
+    }
+}
+
+/// Periodic bandwidth report, see [`BandwidthStats::reports`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandwidthReport {
+    /// Time since the previous report.
+    pub elapsed: Duration,
+    /// Counters since the host server started.
+    pub total: BandwidthSnapshot,
+    /// Per-connection traffic since the previous report, for the connections that are open.
+    pub delta: HashMap<ConnectionId, Traffic>,
+    /// Traffic since the previous report of the connections that closed since then.
+    pub closed_delta: Traffic,
+}
+
+/// Collects traffic counters from a host server.
+///
+/// This is a cheap handle: clones share the same counters.
+#[derive(Debug, Clone, Default)]
+pub struct BandwidthStats {
+    inner: Arc<Mutex<BandwidthSnapshot>>,
+}
+
+impl BandwidthStats {
+    /// Create an empty statistics collector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn open_connection(&self, connection: ConnectionId) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections.insert(
+            connection,
+            ConnectionTraffic {
+                players: vec![],
+                traffic: Traffic::default(),
+                sent_by_player: HashMap::new(),
+            },
+        );
+    }
+
+    pub(crate) fn close_connection(&self, connection: ConnectionId) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.remove(&connection) {
+            inner.closed += connection.traffic;
+        }
+    }
+
+    pub(crate) fn add_player(&self, connection: ConnectionId, player: GUID) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.get_mut(&connection) {
+            connection.players.push(player);
+        }
+    }
+
+    /// Count a message sent by the game on a connection.
+    pub(crate) fn record_sent(&self, connection: ConnectionId, method: Method, bytes: usize) {
+        self.record(connection, bytes, |traffic| traffic.sent.get_mut(method));
+    }
+
+    /// Count a game message sent by a player on a connection, on top of the connection's own
+    /// counters.
+    pub(crate) fn record_sent_by(&self, connection: ConnectionId, player: GUID, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.get_mut(&connection) {
+            *connection.sent_by_player.entry(player).or_default() += Counter {
+                messages: 1,
+                bytes: bytes as u64,
+            };
+        }
+    }
+
+    /// Count a message delivered to the game on a connection.
+    pub(crate) fn record_received(&self, connection: ConnectionId, method: Method, bytes: usize) {
+        self.record(connection, bytes, |traffic| {
+            traffic.received.get_mut(method)
+        });
+    }
+
+    fn record(
+        &self,
+        connection: ConnectionId,
+        bytes: usize,
+        counter: impl FnOnce(&mut Traffic) -> &mut Counter,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(connection) = inner.connections.get_mut(&connection) {
+            *counter(&mut connection.traffic) += Counter {
+                messages: 1,
+                bytes: bytes as u64,
+            };
+        }
+    }
+
+    /// Get a copy of all counters.
+    pub fn snapshot(&self) -> BandwidthSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// Get a stream that yields a report every `interval`.
+    ///
+    /// The stream never ends; drop it to stop reporting.
+    pub fn reports(&self, interval: Duration) -> impl Stream<Item = BandwidthReport> {
+        let stats = self.clone();
+        let initial = (stats.snapshot(), Instant::now());
+        futures::stream::unfold(initial, move |(previous, previous_at)| {
+            let stats = stats.clone();
+            async move {
+                runtime::sleep(interval).await;
+                let now = Instant::now();
+                let total = stats.snapshot();
+                let delta = total
+                    .connections
+                    .iter()
+                    .map(|(&id, connection)| {
+                        let before = previous
+                            .connections
+                            .get(&id)
+                            .map(|connection| connection.traffic)
+                            .unwrap_or_default();
+                        (id, connection.traffic - before)
+                    })
+                    .collect();
+                // The closed counters grew by everything the connections that closed ever sent,
+                // and part of that was reported before.
+                let mut closed_delta = total.closed - previous.closed;
+                for (id, connection) in &previous.connections {
+                    if !total.connections.contains_key(id) {
+                        closed_delta = closed_delta - connection.traffic;
+                    }
+                }
+                let report = BandwidthReport {
+                    elapsed: now.duration_since(previous_at),
+                    total: total.clone(),
+                    delta,
+                    closed_delta,
+                };
+                Some((report, (total, now)))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_traffic_per_connection() {
+        let stats = BandwidthStats::new();
+        let host = GUID::from_u128(1);
+        let joiner = GUID::from_u128(2);
+
+        stats.open_connection(0);
+        stats.open_connection(1);
+        stats.add_player(0, host);
+        stats.add_player(1, joiner);
+        stats.record_sent(1, Method::EnumSessions, 40);
+        stats.record_received(0, Method::Send, 40);
+        stats.record_sent(0, Method::Reply, 100);
+        stats.record_received(1, Method::Send, 100);
+        stats.record_sent(1, Method::Send, 10);
+        stats.record_sent(1, Method::Send, 20);
+        stats.close_connection(1);
+        // Unknown connections are ignored.
+        stats.record_sent(7, Method::Send, 10);
+
+        let snapshot = stats.snapshot();
+        let joiner_traffic = snapshot.closed;
+        assert_eq!(snapshot.connection_of(joiner), None);
+        assert_eq!(
+            joiner_traffic.sent.send,
+            Counter {
+                messages: 2,
+                bytes: 30
+            }
+        );
+        assert_eq!(joiner_traffic.sent.enum_sessions.bytes, 40);
+        assert_eq!(joiner_traffic.received.total().bytes, 100);
+        assert_eq!(snapshot.connection_of(host).unwrap().sent.reply.messages, 1);
+        assert_eq!(snapshot.connections.len(), 1);
+        assert_eq!(snapshot.total().sent.total().bytes, 170);
+        assert_eq!(snapshot.total().received.total().messages, 2);
+    }
+
+    #[test]
+    fn counts_sent_messages_per_player() {
+        let stats = BandwidthStats::new();
+        let first = GUID::from_u128(1);
+        let second = GUID::from_u128(2);
+
+        // Both players are created by the same game instance.
+        stats.open_connection(0);
+        stats.add_player(0, first);
+        stats.add_player(0, second);
+        stats.record_sent(0, Method::Send, 10);
+        stats.record_sent_by(0, first, 10);
+        stats.record_sent(0, Method::Send, 20);
+        stats.record_sent_by(0, second, 20);
+        stats.record_sent(0, Method::Send, 30);
+        stats.record_sent_by(0, second, 30);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.sent_by(first),
+            Some(Counter {
+                messages: 1,
+                bytes: 10
+            })
+        );
+        assert_eq!(
+            snapshot.sent_by(second),
+            Some(Counter {
+                messages: 2,
+                bytes: 50
+            })
+        );
+        assert_eq!(snapshot.connection_of(first).unwrap().sent.send.bytes, 60);
+
+        stats.close_connection(0);
+        assert_eq!(stats.snapshot().sent_by(second), None);
+    }
+
+    #[test]
+    fn reports_traffic_of_closed_connections_once() {
+        use futures::stream::StreamExt;
+
+        let stats = BandwidthStats::new();
+        stats.open_connection(0);
+        let reports = stats.reports(Duration::from_millis(10));
+        futures::pin_mut!(reports);
+        stats.record_sent(0, Method::Send, 10);
+        crate::runtime::block_on(async {
+            let first = reports.next().await.unwrap();
+            assert_eq!(first.delta[&0].sent.send.bytes, 10);
+            stats.record_sent(0, Method::Send, 5);
+            stats.close_connection(0);
+            let second = reports.next().await.unwrap();
+            assert!(second.delta.is_empty());
+            assert_eq!(second.closed_delta.sent.send.bytes, 5);
+            assert_eq!(second.total.total().sent.send.bytes, 15);
+        });
+    }
+}
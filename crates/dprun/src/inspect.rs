@@ -1,3 +1,8 @@
+//! Decoding of DirectPlay protocol messages, for logging and statistics.
+//!
+//! Messages come straight from the network, so every length in them is checked against the
+//! message itself before it is used.
+
 use byteorder::{ReadBytesExt, LE};
 use std::io::{self, Cursor, Read};
 use uuid::Uuid;
@@ -9,22 +14,42 @@ impl std::fmt::Debug for CmdId {
     }
 }
 
+/// A DirectPlay protocol message.
 #[derive(Debug)]
-struct ProtocolMessage {
+pub struct ProtocolMessage {
     signature: String,
     version: u16,
     cmd: CmdId,
-    body: Command,
+    pub body: Command,
+}
+
+impl ProtocolMessage {
+    /// The numeric DirectPlay command of this message.
+    pub fn command_id(&self) -> u16 {
+        self.cmd.0
+    }
+
+    /// The DirectPlay protocol version that the sender speaks.
+    pub fn version(&self) -> u16 {
+        self.version
+    }
 }
 
+/// The DirectPlay commands that we know how to read. The others are kept as raw bytes.
 #[derive(Debug)]
-enum Command {
+pub enum Command {
     EnumSessionsReply(String, Uuid),
     EnumSessions(Uuid, u32),
     RequestPlayerId(u32),
     RequestPlayerReply(u32),
     CreatePlayer(u32, String),
     DeletePlayer(u32),
+    CreateGroup(u32, String),
+    DeleteGroup(u32),
+    /// A player joined a group: the player ID and the group ID.
+    AddPlayerToGroup(u32, u32),
+    /// A player left a group: the player ID and the group ID.
+    DeletePlayerFromGroup(u32, u32),
     AddForwardRequest(u32, u32),
     Ping(u32, u32),
     PingReply(u32, u32),
@@ -32,13 +57,32 @@ enum Command {
     PacketizedData(u32, u32),
     PacketizedMessage(Box<ProtocolMessage>),
     PacketizedAck,
+    /// A player took over as the name server after the host left: the new host's ID.
+    IAmNameServer(u32),
     Other(Vec<u8>),
 }
 
-fn parse_cmd(cmd: u16, mut message: impl Read) -> io::Result<Command> {
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read a field of `size` bytes, failing if the message is shorter than that.
+fn read_sized(message: impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    message.take(size as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn parse_cmd(cmd: u16, mut message: impl Read, depth: usize) -> io::Result<Command> {
     match cmd {
         0x01 => {
             let size = message.read_u32::<LE>()? as usize;
+            if size < 24 {
+                return Err(invalid("session description too small"));
+            }
             let _ = message.read_u32::<LE>()?;
             let guid = {
                 let mut bytes = [0; 16];
@@ -74,24 +118,34 @@ fn parse_cmd(cmd: u16, mut message: impl Read) -> io::Result<Command> {
             let new_id = message.read_u32::<LE>()?;
             Ok(Command::RequestPlayerReply(new_id))
         }
-        0x08 => {
+        0x08 | 0x09 => {
             std::io::copy(&mut message.by_ref().take(20), &mut std::io::sink())?;
             std::io::copy(&mut message.by_ref().take(8), &mut std::io::sink())?;
             let id = message.read_u32::<LE>()?;
             let name_len = message.read_u32::<LE>()? as usize;
             std::io::copy(&mut message.by_ref().take(8 * 4), &mut std::io::sink())?;
-            let name = {
-                let mut name_bytes = vec![0; name_len];
-                message.read_exact(&mut name_bytes)?;
-                String::from_utf8_lossy(&name_bytes).to_string()
-            };
-            Ok(Command::CreatePlayer(id, name))
+            let name = String::from_utf8_lossy(&read_sized(&mut message, name_len)?).to_string();
+            if cmd == 0x08 {
+                Ok(Command::CreatePlayer(id, name))
+            } else {
+                Ok(Command::CreateGroup(id, name))
+            }
         }
         0x0b => {
             let _ = message.read_u32::<LE>()?;
             let id = message.read_u32::<LE>()?;
             Ok(Command::DeletePlayer(id))
         }
+        0x0c..=0x0e => {
+            let _to = message.read_u32::<LE>()?;
+            let player = message.read_u32::<LE>()?;
+            let group = message.read_u32::<LE>()?;
+            Ok(match cmd {
+                0x0c => Command::DeleteGroup(group),
+                0x0d => Command::AddPlayerToGroup(player, group),
+                _ => Command::DeletePlayerFromGroup(player, group),
+            })
+        }
         0x13 => {
             let to = message.read_u32::<LE>()?;
             let new_player = message.read_u32::<LE>()?;
@@ -114,15 +168,25 @@ fn parse_cmd(cmd: u16, mut message: impl Read) -> io::Result<Command> {
             std::io::copy(&mut message.by_ref().take(8), &mut std::io::sink())?;
             let total = message.read_u32::<LE>()?;
             if total == 1 {
+                // A packet carries a single message, which is never split up again.
+                if depth > 0 {
+                    return Err(invalid("nested packetized message"));
+                }
                 std::io::copy(&mut message.by_ref().take(8), &mut std::io::sink())?;
-                Ok(Command::PacketizedMessage(Box::new(parse_message(
+                Ok(Command::PacketizedMessage(Box::new(parse_message_at(
                     message,
+                    depth + 1,
                 )?)))
             } else {
                 Ok(Command::PacketizedData(index, total))
             }
         }
         0x31 => Ok(Command::PacketizedAck),
+        0x35 => {
+            let _to = message.read_u32::<LE>()?;
+            let host = message.read_u32::<LE>()?;
+            Ok(Command::IAmNameServer(host))
+        }
         _ => {
             let mut bytes = vec![];
             message.read_to_end(&mut bytes)?;
@@ -131,12 +195,18 @@ fn parse_cmd(cmd: u16, mut message: impl Read) -> io::Result<Command> {
     }
 }
 
-fn parse_message(mut message: impl Read) -> io::Result<ProtocolMessage> {
+/// Parse a DirectPlay protocol message, starting at its "play" signature.
+pub fn parse_message(message: impl Read) -> io::Result<ProtocolMessage> {
+    parse_message_at(message, 0)
+}
+
+/// Parse a message, `depth` levels deep in packetized messages.
+fn parse_message_at(mut message: impl Read, depth: usize) -> io::Result<ProtocolMessage> {
     let mut signature = [0; 4];
     message.read_exact(&mut signature)?;
     let cmd = message.read_u16::<LE>()?;
     let version = message.read_u16::<LE>()?;
-    let sub = parse_cmd(cmd, message)?;
+    let sub = parse_cmd(cmd, message, depth)?;
     Ok(ProtocolMessage {
         signature: String::from_utf8_lossy(&signature).to_string(),
         version,
@@ -145,13 +215,126 @@ fn parse_message(mut message: impl Read) -> io::Result<ProtocolMessage> {
     })
 }
 
-pub fn print_network_message(message: &[u8]) {
+/// Parse a DirectPlay network message, prefixed by the GUID of the player it comes from.
+pub fn parse_network_message(message: &[u8]) -> io::Result<(Uuid, ProtocolMessage)> {
     let mut message = Cursor::new(message);
     let guid = {
         let mut bytes = [0; 16];
-        message.read_exact(&mut bytes).unwrap();
+        message.read_exact(&mut bytes)?;
         Uuid::from_bytes(bytes)
     };
-    log::debug!("[print_network_message] message from: {:?}", guid);
-    log::debug!("{:#?}", parse_message(message).unwrap());
+    Ok((guid, parse_message(message)?))
+}
+
+/// Check whether a network message announces a new name server, after the host of a session
+/// with `DPSESSION_MIGRATEHOST` left. The sender is the new host.
+pub fn is_name_server_announcement(message: &[u8]) -> bool {
+    matches!(
+        parse_network_message(message),
+        Ok((
+            _,
+            ProtocolMessage {
+                body: Command::IAmNameServer(_),
+                ..
+            }
+        ))
+    )
+}
+
+pub fn print_network_message(message: &[u8]) {
+    match parse_network_message(message) {
+        Ok((guid, message)) => {
+            log::debug!("[print_network_message] message from: {:?}", guid);
+            log::debug!("{:#?}", message);
+        }
+        Err(err) => log::debug!("[print_network_message] unreadable message: {:?}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(cmd: u16, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Uuid::from_u128(1).as_bytes().to_vec();
+        bytes.extend_from_slice(b"play");
+        bytes.extend_from_slice(&cmd.to_le_bytes());
+        bytes.extend_from_slice(&14u16.to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    fn is_invalid(message: &[u8]) -> bool {
+        parse_network_message(message).is_err()
+    }
+
+    #[test]
+    fn parses_known_commands() {
+        let (sender, ping) = parse_network_message(&message(0x16, &u32s(&[3, 1000]))).unwrap();
+        assert_eq!(sender, Uuid::from_u128(1));
+        assert!(matches!(ping.body, Command::Ping(3, 1000)));
+        assert_eq!(ping.command_id(), 0x16);
+        assert_eq!(ping.version(), 14);
+
+        let mut create = u32s(&[0, 5, 0, 28, 0, 48, 0, 5, 4, 0, 0, 0, 0, 4, 48, 14, 0]);
+        create.extend_from_slice(b"abcd");
+        let (_, create) = parse_network_message(&message(0x08, &create)).unwrap();
+        assert!(matches!(create.body, Command::CreatePlayer(5, ref name) if name == "abcd"));
+    }
+
+    #[test]
+    fn parses_group_commands() {
+        let mut group = u32s(&[0, 0, 7, 28, 0, 48, 0, 7, 4, 0, 0, 0, 0, 4, 48, 14, 0]);
+        group.extend_from_slice(b"team");
+        let (_, group) = parse_network_message(&message(0x09, &group)).unwrap();
+        assert!(matches!(group.body, Command::CreateGroup(7, ref name) if name == "team"));
+        let (_, join) = parse_network_message(&message(0x0d, &u32s(&[0, 5, 7, 0, 0]))).unwrap();
+        assert!(matches!(join.body, Command::AddPlayerToGroup(5, 7)));
+        let (_, leave) = parse_network_message(&message(0x0e, &u32s(&[0, 5, 7, 0, 0]))).unwrap();
+        assert!(matches!(leave.body, Command::DeletePlayerFromGroup(5, 7)));
+        let (_, delete) = parse_network_message(&message(0x0c, &u32s(&[0, 0, 7, 0, 0]))).unwrap();
+        assert!(matches!(delete.body, Command::DeleteGroup(7)));
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let ping = message(0x16, &u32s(&[3, 1000]));
+        for length in 0..ping.len() {
+            assert!(is_invalid(&ping[..length]), "length {}", length);
+        }
+        let mut create = u32s(&[0, 5, 0, 28, 0, 48, 0, 5, 4, 0, 0, 0, 0, 4, 48, 14, 0]);
+        create.extend_from_slice(b"ab");
+        assert!(is_invalid(&message(0x08, &create)));
+    }
+
+    #[test]
+    fn rejects_oversized_fields() {
+        // A name length far beyond the message must not be allocated.
+        let create = u32s(&[0, 5, 0, 28, 0, 48, 0, 5, u32::MAX, 0, 0, 0, 0, 4, 48, 14, 0]);
+        assert!(is_invalid(&message(0x08, &create)));
+        // Session descriptions are at least 24 bytes.
+        for size in 0..24 {
+            let mut reply = u32s(&[size, 0]);
+            reply.extend_from_slice(&[0; 40]);
+            assert!(is_invalid(&message(0x01, &reply)), "size {}", size);
+        }
+        // Packetized messages do not nest.
+        let mut inner = b"play".to_vec();
+        inner.extend_from_slice(&0x30u16.to_le_bytes());
+        inner.extend_from_slice(&14u16.to_le_bytes());
+        let mut packet = vec![0; 16];
+        packet.extend_from_slice(&u32s(&[0, 0, 0, 1, 0, 0]));
+        inner.extend_from_slice(&packet);
+        inner.extend_from_slice(b"play");
+        let mut outer = packet;
+        outer.extend_from_slice(&inner);
+        assert!(is_invalid(&message(0x30, &outer)));
+    }
 }
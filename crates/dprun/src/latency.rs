@@ -0,0 +1,291 @@
+//! Round-trip time statistics, measured from DirectPlay's own keep-alive pings.
+//!
+//! DirectPlay periodically sends `Ping` messages carrying the sender's tick count, and the other
+//! side answers with a `PingReply` that echoes the same tick count. The host server sees both
+//! halves go by: pings leave the local application, replies arrive for it. Pairing them up gives
+//! us the round-trip time to every player without adding any traffic of our own.
+//!
+//! Tick counts alone do not identify a ping: players ping each other on their own clocks, and may
+//! well use the same tick count. Pings are paired with replies by tick count and by the game
+//! instance they were sent to, which is the one that replies.
+
+use crate::inspect::{parse_network_message, Command};
+use crate::structs::DPID;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid as GUID;
+
+/// How many round-trip samples to keep per player.
+const WINDOW_SIZE: usize = 16;
+/// How long to wait for replies to a ping before counting it as unanswered.
+const PING_EXPIRY: Duration = Duration::from_secs(30);
+
+/// Connection quality to a single player.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerLatency {
+    /// Number of ping replies received from this player.
+    pub replies: u64,
+    /// The most recent round-trip time.
+    pub last_rtt: Duration,
+    /// Average round-trip time over the last few replies.
+    pub average_rtt: Duration,
+    /// Lowest round-trip time over the last few replies.
+    pub min_rtt: Duration,
+    /// Highest round-trip time over the last few replies.
+    pub max_rtt: Duration,
+    /// Smoothed variation between consecutive round-trip times, as in RFC 3550.
+    pub jitter: Duration,
+}
+
+#[derive(Debug)]
+struct PeerState {
+    replies: u64,
+    samples: VecDeque<Duration>,
+    jitter: Duration,
+}
+
+impl PeerState {
+    fn new() -> Self {
+        Self {
+            replies: 0,
+            samples: VecDeque::with_capacity(WINDOW_SIZE),
+            jitter: Duration::default(),
+        }
+    }
+
+    fn add_sample(&mut self, rtt: Duration) {
+        if let Some(&last) = self.samples.back() {
+            let difference = rtt.abs_diff(last);
+            // J = J + (|D| - J) / 16
+            self.jitter = if difference > self.jitter {
+                self.jitter + (difference - self.jitter) / 16
+            } else {
+                self.jitter - (self.jitter - difference) / 16
+            };
+        }
+        if self.samples.len() == WINDOW_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+        self.replies += 1;
+    }
+
+    fn summary(&self) -> PeerLatency {
+        let total: Duration = self.samples.iter().sum();
+        PeerLatency {
+            replies: self.replies,
+            last_rtt: self.samples.back().copied().unwrap_or_default(),
+            average_rtt: total / self.samples.len().max(1) as u32,
+            min_rtt: self.samples.iter().min().copied().unwrap_or_default(),
+            max_rtt: self.samples.iter().max().copied().unwrap_or_default(),
+            jitter: self.jitter,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct OutstandingPing {
+    sent_at: Instant,
+    answered: bool,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Pings sent by the local application, keyed by the player they were sent to and their tick
+    /// count. Pings to everyone have no receiver, and can be answered by every player, so entries
+    /// stay around until they expire.
+    outstanding: HashMap<(Option<GUID>, u32), OutstandingPing>,
+    peers: HashMap<DPID, PeerState>,
+    pings_sent: u64,
+    unanswered: u64,
+}
+
+impl Inner {
+    fn expire(&mut self, now: Instant) {
+        let unanswered = &mut self.unanswered;
+        self.outstanding.retain(|_, ping| {
+            let keep = now.duration_since(ping.sent_at) < PING_EXPIRY;
+            if !keep && !ping.answered {
+                *unanswered += 1;
+            }
+            keep
+        });
+    }
+}
+
+/// Collects round-trip times from the DirectPlay messages passing through a host server.
+///
+/// This is a cheap handle: clones share the same statistics, so one can be handed to a UI while
+/// the host server keeps feeding it.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyStats {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl LatencyStats {
+    /// Create an empty statistics collector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a network message sent by the local application to `receiver`, or to everyone.
+    pub(crate) fn observe_outgoing(&self, receiver: Option<GUID>, message: &[u8]) {
+        self.observe_outgoing_at(receiver, message, Instant::now());
+    }
+
+    /// Record a network message received for the local application.
+    pub(crate) fn observe_incoming(&self, message: &[u8]) {
+        self.observe_incoming_at(message, Instant::now());
+    }
+
+    fn observe_outgoing_at(&self, receiver: Option<GUID>, message: &[u8], now: Instant) {
+        if let Ok((_, message)) = parse_network_message(message) {
+            if let Command::Ping(_from, ticks) = message.body {
+                let mut inner = self.inner.lock().unwrap();
+                inner.expire(now);
+                inner.pings_sent += 1;
+                inner.outstanding.insert(
+                    (receiver, ticks),
+                    OutstandingPing {
+                        sent_at: now,
+                        answered: false,
+                    },
+                );
+            }
+        }
+    }
+
+    fn observe_incoming_at(&self, message: &[u8], now: Instant) {
+        if let Ok((sender, message)) = parse_network_message(message) {
+            if let Command::PingReply(from, ticks) = message.body {
+                let mut inner = self.inner.lock().unwrap();
+                let key = if inner.outstanding.contains_key(&(Some(sender), ticks)) {
+                    (Some(sender), ticks)
+                } else {
+                    (None, ticks)
+                };
+                let rtt = match inner.outstanding.get_mut(&key) {
+                    Some(ping) => {
+                        ping.answered = true;
+                        now.duration_since(ping.sent_at)
+                    }
+                    None => return,
+                };
+                inner
+                    .peers
+                    .entry(from as DPID)
+                    .or_insert_with(PeerState::new)
+                    .add_sample(rtt);
+            }
+        }
+    }
+
+    /// Get the connection quality to a player, if they have answered any pings yet.
+    pub fn get(&self, player: DPID) -> Option<PeerLatency> {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.get(&player).map(PeerState::summary)
+    }
+
+    /// Get the connection quality to every player that has answered pings.
+    pub fn snapshot(&self) -> HashMap<DPID, PeerLatency> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .peers
+            .iter()
+            .map(|(&id, state)| (id, state.summary()))
+            .collect()
+    }
+
+    /// Number of pings sent by the local application.
+    pub fn pings_sent(&self) -> u64 {
+        self.inner.lock().unwrap().pings_sent
+    }
+
+    /// Number of pings that nobody replied to in time.
+    pub fn unanswered(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire(Instant::now());
+        inner.unanswered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(cmd: u16, from: u32, ticks: u32) -> Vec<u8> {
+        let mut bytes = GUID::from_u128(from as u128).as_bytes().to_vec();
+        bytes.extend_from_slice(b"play");
+        bytes.extend_from_slice(&cmd.to_le_bytes());
+        bytes.extend_from_slice(&14u16.to_le_bytes());
+        bytes.extend_from_slice(&from.to_le_bytes());
+        bytes.extend_from_slice(&ticks.to_le_bytes());
+        bytes
+    }
+
+    fn ping(from: u32, ticks: u32) -> Vec<u8> {
+        message(0x16, from, ticks)
+    }
+
+    fn ping_reply(from: u32, ticks: u32) -> Vec<u8> {
+        message(0x17, from, ticks)
+    }
+
+    #[test]
+    fn pairs_pings_with_replies_per_player() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+
+        stats.observe_outgoing_at(None, &ping(1, 1000), start);
+        stats.observe_incoming_at(&ping_reply(2, 1000), start + Duration::from_millis(40));
+        stats.observe_incoming_at(&ping_reply(3, 1000), start + Duration::from_millis(90));
+        stats.observe_outgoing_at(None, &ping(1, 2000), start + Duration::from_secs(1));
+        stats.observe_incoming_at(
+            &ping_reply(2, 2000),
+            start + Duration::from_secs(1) + Duration::from_millis(60),
+        );
+        // Replies to pings we never saw are ignored.
+        stats.observe_incoming_at(&ping_reply(2, 3000), start + Duration::from_secs(2));
+
+        assert_eq!(stats.pings_sent(), 2);
+        let player2 = stats.get(2).unwrap();
+        assert_eq!(player2.replies, 2);
+        assert_eq!(player2.last_rtt, Duration::from_millis(60));
+        assert_eq!(player2.average_rtt, Duration::from_millis(50));
+        assert_eq!(player2.min_rtt, Duration::from_millis(40));
+        assert_eq!(player2.max_rtt, Duration::from_millis(60));
+        assert_eq!(player2.jitter, Duration::from_millis(20) / 16);
+        assert_eq!(stats.get(3).unwrap().last_rtt, Duration::from_millis(90));
+        assert_eq!(stats.get(4), None);
+    }
+
+    #[test]
+    fn counts_expired_pings_as_unanswered() {
+        let stats = LatencyStats::new();
+        let start = Instant::now() - PING_EXPIRY * 2;
+
+        stats.observe_outgoing_at(None, &ping(1, 1000), start);
+        stats.observe_outgoing_at(None, &ping(1, 2000), start);
+        stats.observe_incoming_at(&ping_reply(2, 2000), start + Duration::from_millis(10));
+
+        assert_eq!(stats.unanswered(), 1);
+    }
+
+    #[test]
+    fn keeps_pings_to_different_players_apart() {
+        let stats = LatencyStats::new();
+        let start = Instant::now();
+        let player2 = Some(GUID::from_u128(2));
+        let player3 = Some(GUID::from_u128(3));
+
+        stats.observe_outgoing_at(player2, &ping(1, 1000), start);
+        stats.observe_outgoing_at(player3, &ping(1, 1000), start + Duration::from_millis(30));
+        stats.observe_incoming_at(&ping_reply(3, 1000), start + Duration::from_millis(40));
+        stats.observe_incoming_at(&ping_reply(2, 1000), start + Duration::from_millis(50));
+
+        assert_eq!(stats.pings_sent(), 2);
+        assert_eq!(stats.get(2).unwrap().last_rtt, Duration::from_millis(50));
+        assert_eq!(stats.get(3).unwrap().last_rtt, Duration::from_millis(10));
+    }
+}
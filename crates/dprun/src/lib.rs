@@ -4,19 +4,36 @@
 //! On Linux, this crate uses Wine to start dprun.
 //!
 //! The DPRun executable must be available separately.
-
-mod inspect;
+//!
+//! dprun runs on async-std by default. Disable default features and enable the `tokio` feature to
+//! run it on a tokio runtime instead.
+
+mod bandwidth;
+pub mod inspect;
+mod latency;
+pub mod name_server;
+mod roster;
+mod runtime;
 mod server;
+pub mod session;
 pub mod structs;
 
+use crate::runtime::Child;
 use crate::server::HostServer;
-use async_process::{Command, Stdio};
-use async_std::io::{self, BufReader};
-use async_std::prelude::*;
+use futures::io::{AsyncBufReadExt, BufReader};
+use futures::stream::StreamExt;
+use std::io;
 use std::path::PathBuf;
-
-pub use crate::server::{AppController, ServiceProvider};
-pub use crate::structs::DPID;
+use std::process::Command;
+
+pub use crate::bandwidth::{
+    BandwidthReport, BandwidthSnapshot, BandwidthStats, ConnectionId, ConnectionTraffic, Counter,
+    Method, MethodCounters, Traffic,
+};
+pub use crate::latency::{LatencyStats, PeerLatency};
+pub use crate::roster::{PlayerIdentity, Roster};
+pub use crate::server::{AppController, AppMessage, ServiceProvider};
+pub use crate::structs::{Delivery, DPID};
 pub use uuid::Uuid as GUID;
 
 // TODO move these to consts again when parse_str is const fn
@@ -314,6 +331,8 @@ pub struct DPRun {
     command: Command,
     host_server_port: Option<u16>,
     service_provider: Option<Box<dyn ServiceProvider>>,
+    latency_stats: LatencyStats,
+    bandwidth_stats: BandwidthStats,
 }
 
 impl DPRun {
@@ -322,9 +341,24 @@ impl DPRun {
         format!("{:?}", self.command)
     }
 
+    /// Get a handle to the round-trip time statistics for this session.
+    ///
+    /// The statistics are measured from DirectPlay's keep-alive pings, so they are only collected
+    /// when a service provider handler is used.
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.latency_stats.clone()
+    }
+
+    /// Get a handle to the traffic counters for this session.
+    ///
+    /// Traffic is only counted when a service provider handler is used.
+    pub fn bandwidth_stats(&self) -> BandwidthStats {
+        self.bandwidth_stats.clone()
+    }
+
     /// Start a game without the host server for the DPRun Service Provider.
-    async fn start_without_server(mut self) -> Result<(), io::Error> {
-        let status = self.command.status().await?;
+    async fn start_without_server(self) -> Result<(), io::Error> {
+        let status = runtime::status(self.command).await?;
         if status.success() {
             Ok(())
         } else {
@@ -340,20 +374,18 @@ impl DPRun {
         let server = HostServer::new(
             self.host_server_port.unwrap_or(2197),
             self.service_provider.unwrap(),
-        );
+        )
+        .with_latency_stats(self.latency_stats)
+        .with_bandwidth_stats(self.bandwidth_stats);
 
         let (server, mut controller) = server.start().await?;
-        let mut command = self.command;
-        let mut child = command
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+        let mut child = Child::spawn_piped(self.command)?;
 
         let command_future = async move {
             let mut result = Ok(());
 
-            let mut stdout = BufReader::new(child.stdout.as_mut().unwrap()).lines();
-            let mut stderr = BufReader::new(child.stderr.as_mut().unwrap()).lines();
+            let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+            let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
 
             futures::join!(
                 async move {
@@ -376,7 +408,9 @@ impl DPRun {
                 ));
             }
 
-            controller.stop().await;
+            if let Err(err) = controller.stop().await {
+                log::warn!("[DPRun::start] could not stop host server: {:?}", err);
+            }
 
             result
         };
@@ -477,6 +511,8 @@ pub fn run(options: DPRunOptions) -> DPRun {
         command,
         host_server_port,
         service_provider,
+        latency_stats: LatencyStats::new(),
+        bandwidth_stats: BandwidthStats::new(),
     }
 }
 
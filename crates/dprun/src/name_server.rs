@@ -0,0 +1,965 @@
+//! A DirectPlay name server that runs without a game.
+//!
+//! In a DirectPlay session, the host's game is the name server: it describes the session to
+//! players looking for one, hands out player IDs, and tells everyone who is in the session.
+//! `NameServer` does that part of the protocol itself, on top of any service provider, so players
+//! can join a session that no game process hosts. That is useful for dedicated lobbies, and for
+//! tests that should not need Wine.
+//!
+//! The name server speaks the DirectPlay 6 protocol as described in [MC-DPL4CS]. It does not
+//! support session passwords, groups or host migration, and player IDs are not obfuscated.
+//!
+//! [MC-DPL4CS]: https://docs.microsoft.com/en-us/openspecs/windows_protocols/mc-dpl4cs/
+
+use crate::inspect::{parse_network_message, Command};
+use crate::server::{AppController, AppMessage, ServiceProvider};
+use crate::structs::{CreatePlayerData, OpenData, ReplyData, SendData, DPID, DPSEND_GUARANTEED};
+use async_channel::Receiver;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::io::{self, Cursor, Read, Write};
+use uuid::Uuid as GUID;
+
+/// The protocol version that we speak, as in DirectX 6.
+const PROTOCOL_VERSION: u16 = 0x0e;
+
+const DPSP_MSG_ENUMSESSIONSREPLY: u16 = 0x01;
+const DPSP_MSG_REQUESTPLAYERREPLY: u16 = 0x07;
+const DPSP_MSG_CREATEPLAYER: u16 = 0x08;
+const DPSP_MSG_DELETEPLAYER: u16 = 0x0b;
+const DPSP_MSG_PINGREPLY: u16 = 0x17;
+const DPSP_MSG_SUPERENUMPLAYERSREPLY: u16 = 0x29;
+
+/// Player flag: the player represents a machine in the session, rather than someone playing.
+pub const DPLAYI_PLAYER_SYSPLAYER: u32 = 0x1;
+/// Player flag: the player is the name server.
+pub const DPLAYI_PLAYER_NAMESRVR: u32 = 0x2;
+/// Player flag: the player was created on this machine.
+const DPLAYI_PLAYER_LOCAL: u32 = 0x8;
+
+/// Only list sessions that can be joined.
+const DPENUMSESSIONS_AVAILABLE: u32 = 0x1;
+/// Session flag: the session does not accept new players.
+pub const DPSESSION_NEWPLAYERSDISABLED: u32 = 0x1;
+/// Session flag: no new machines may join the session.
+pub const DPSESSION_JOINDISABLED: u32 = 0x20;
+
+/// Result that refuses a player ID, because the session does not take new players.
+const DPERR_NONEWPLAYERS: u32 = 0x8877_014A;
+
+/// Size of a `DPSESSIONDESC2`.
+const SESSION_DESC_SIZE: u32 = 80;
+/// Size of the fixed part of a `DPLAYI_PACKEDPLAYER`.
+const PACKED_PLAYER_SIZE: u32 = 48;
+/// Size of the fixed part of a `DPLAYI_SUPERPACKEDPLAYER`.
+const SUPER_PACKED_PLAYER_SIZE: u32 = 16;
+/// Where the packed player starts in `CreatePlayer` and `AddForwardRequest` messages.
+const CREATE_OFFSET: u32 = 28;
+
+/// The session that a `NameServer` hosts.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The GUID of this session.
+    pub instance: GUID,
+    /// The GUID of the game. Players looking for sessions of other games don't see this one.
+    pub application: GUID,
+    /// The name that players see when looking for sessions.
+    pub name: String,
+    /// The largest number of players, or 0 for no limit.
+    pub max_players: u32,
+    /// DPSESSION_* flags.
+    pub flags: u32,
+    /// Values that the game stores in the session description.
+    pub user: [u32; 4],
+}
+
+/// A player in the session, as the name server knows it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    pub id: DPID,
+    /// DPLAYI_PLAYER_* flags.
+    pub flags: u32,
+    pub short_name: String,
+    pub long_name: String,
+    /// The system player of the machine that this player is on. For system players, this is
+    /// their own ID.
+    pub system_player: DPID,
+    /// Where the service provider can reach the player.
+    pub sp_data: Vec<u8>,
+    /// Data that the game attached to the player.
+    pub player_data: Vec<u8>,
+    /// The GUID that the player's machine sends messages with.
+    pub address: GUID,
+}
+
+impl Player {
+    fn is_system_player(&self) -> bool {
+        self.flags & DPLAYI_PLAYER_SYSPLAYER != 0
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Start a DirectPlay message.
+pub(crate) fn message_header(command: u16) -> Vec<u8> {
+    let mut bytes = b"play".to_vec();
+    bytes.write_u16::<LE>(command).unwrap();
+    bytes.write_u16::<LE>(PROTOCOL_VERSION).unwrap();
+    bytes
+}
+
+/// Encode a string the way DirectPlay sends it: UTF-16 with a terminating 0.
+fn encode_string(string: &str) -> Vec<u8> {
+    if string.is_empty() {
+        return vec![];
+    }
+    let mut bytes = vec![];
+    for unit in string.encode_utf16().chain(std::iter::once(0)) {
+        bytes.write_u16::<LE>(unit).unwrap();
+    }
+    bytes
+}
+
+fn decode_string(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Read `size` bytes, checking the size against the rest of the message before allocating, so
+/// a bad size from the network can not make us allocate gigabytes.
+fn read_bytes(cursor: &mut Cursor<&[u8]>, size: u32) -> io::Result<Vec<u8>> {
+    let remaining = (cursor.get_ref().len() as u64).saturating_sub(cursor.position());
+    if u64::from(size) > remaining {
+        return Err(invalid("size past the end of the message"));
+    }
+    let mut bytes = vec![0; size as usize];
+    cursor.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Build the `CreatePlayer` message that tells a game about a new player.
+pub fn create_player_message(player: &Player) -> Vec<u8> {
+    let mut message = message_header(DPSP_MSG_CREATEPLAYER);
+    // To, player ID, group ID, create offset, password offset.
+    message.write_i32::<LE>(0).unwrap();
+    message.write_i32::<LE>(player.id).unwrap();
+    message.write_i32::<LE>(0).unwrap();
+    message.write_u32::<LE>(CREATE_OFFSET).unwrap();
+    message.write_u32::<LE>(0).unwrap();
+    encode_packed_player(&mut message, player);
+    message
+}
+
+/// Build the `DeletePlayer` message that tells a game a player has left the session.
+///
+/// Games send this message themselves when one of their players leaves. Service providers can
+/// use it to remove players whose game can no longer say so, such as players that were kicked.
+pub fn delete_player_message(id: DPID) -> Vec<u8> {
+    let mut message = message_header(DPSP_MSG_DELETEPLAYER);
+    // IDTo, which is unused.
+    message.write_u32::<LE>(0).unwrap();
+    message.write_i32::<LE>(id).unwrap();
+    // Group ID, create offset and password offset.
+    message.extend_from_slice(&[0; 12]);
+    message
+}
+
+/// Read the `DPLAYI_PACKEDPLAYER` in a `CreatePlayer` or `AddForwardRequest` message.
+pub(crate) fn parse_packed_player(message: &[u8], address: GUID) -> io::Result<Player> {
+    let mut cursor = Cursor::new(message);
+    cursor.set_position(8 + 12);
+    let create_offset = cursor.read_u32::<LE>()?;
+    cursor.set_position(create_offset as u64);
+    let _size = cursor.read_u32::<LE>()?;
+    let flags = cursor.read_u32::<LE>()?;
+    let id = cursor.read_i32::<LE>()?;
+    let short_name_length = cursor.read_u32::<LE>()?;
+    let long_name_length = cursor.read_u32::<LE>()?;
+    let sp_data_size = cursor.read_u32::<LE>()?;
+    let player_data_size = cursor.read_u32::<LE>()?;
+    let _number_of_players = cursor.read_u32::<LE>()?;
+    let system_player = cursor.read_i32::<LE>()?;
+    let fixed_size = cursor.read_u32::<LE>()?;
+    if fixed_size < PACKED_PLAYER_SIZE {
+        return Err(invalid("packed player too small"));
+    }
+    cursor.set_position(create_offset as u64 + fixed_size as u64);
+    let short_name = decode_string(&read_bytes(&mut cursor, short_name_length)?);
+    let long_name = decode_string(&read_bytes(&mut cursor, long_name_length)?);
+    let sp_data = read_bytes(&mut cursor, sp_data_size)?;
+    let player_data = read_bytes(&mut cursor, player_data_size)?;
+    Ok(Player {
+        id,
+        flags: flags & !DPLAYI_PLAYER_LOCAL,
+        short_name,
+        long_name,
+        system_player,
+        sp_data,
+        player_data,
+        address,
+    })
+}
+
+/// Encode a `DPSESSIONDESC2`.
+fn encode_session_desc(bytes: &mut Vec<u8>, session: &Session, current_players: u32) {
+    bytes.write_u32::<LE>(SESSION_DESC_SIZE).unwrap();
+    bytes.write_u32::<LE>(session.flags).unwrap();
+    bytes.write_all(session.instance.as_bytes()).unwrap();
+    bytes.write_all(session.application.as_bytes()).unwrap();
+    bytes.write_u32::<LE>(session.max_players).unwrap();
+    bytes.write_u32::<LE>(current_players).unwrap();
+    // Pointers to the name and password, which are meaningless on the wire.
+    bytes.write_u32::<LE>(0).unwrap();
+    bytes.write_u32::<LE>(0).unwrap();
+    // The key that player IDs are obfuscated with, and a reserved field.
+    bytes.write_u32::<LE>(0).unwrap();
+    bytes.write_u32::<LE>(0).unwrap();
+    for &user in &session.user {
+        bytes.write_u32::<LE>(user).unwrap();
+    }
+}
+
+/// Encode a `DPLAYI_PACKEDPLAYER`.
+fn encode_packed_player(bytes: &mut Vec<u8>, player: &Player) {
+    let short_name = encode_string(&player.short_name);
+    let long_name = encode_string(&player.long_name);
+    let size = PACKED_PLAYER_SIZE as usize
+        + short_name.len()
+        + long_name.len()
+        + player.sp_data.len()
+        + player.player_data.len();
+    bytes.write_u32::<LE>(size as u32).unwrap();
+    bytes.write_u32::<LE>(player.flags).unwrap();
+    bytes.write_i32::<LE>(player.id).unwrap();
+    bytes.write_u32::<LE>(short_name.len() as u32).unwrap();
+    bytes.write_u32::<LE>(long_name.len() as u32).unwrap();
+    bytes.write_u32::<LE>(player.sp_data.len() as u32).unwrap();
+    bytes
+        .write_u32::<LE>(player.player_data.len() as u32)
+        .unwrap();
+    // Number of players, for groups.
+    bytes.write_u32::<LE>(0).unwrap();
+    bytes.write_i32::<LE>(player.system_player).unwrap();
+    bytes.write_u32::<LE>(PACKED_PLAYER_SIZE).unwrap();
+    bytes.write_u32::<LE>(PROTOCOL_VERSION as u32).unwrap();
+    // Parent ID, for groups.
+    bytes.write_u32::<LE>(0).unwrap();
+    bytes.extend_from_slice(&short_name);
+    bytes.extend_from_slice(&long_name);
+    bytes.extend_from_slice(&player.sp_data);
+    bytes.extend_from_slice(&player.player_data);
+}
+
+/// The value of a size field in the player info mask of a super packed player: how many bytes
+/// the length takes.
+fn size_code(length: usize) -> u32 {
+    match length {
+        0 => 0,
+        1..=0xFF => 1,
+        0x100..=0xFFFF => 2,
+        _ => 3,
+    }
+}
+
+fn write_sized(bytes: &mut Vec<u8>, code: u32, length: usize) {
+    match code {
+        1 => bytes.write_u8(length as u8).unwrap(),
+        2 => bytes.write_u16::<LE>(length as u16).unwrap(),
+        3 => bytes.write_u32::<LE>(length as u32).unwrap(),
+        _ => (),
+    }
+}
+
+/// Encode a `DPLAYI_SUPERPACKEDPLAYER`: a packed player that leaves out empty fields.
+fn encode_super_packed_player(bytes: &mut Vec<u8>, player: &Player) {
+    let short_name = encode_string(&player.short_name);
+    let long_name = encode_string(&player.long_name);
+    let player_data_code = size_code(player.player_data.len());
+    let sp_data_code = size_code(player.sp_data.len());
+    let mask = (!short_name.is_empty() as u32)
+        | (!long_name.is_empty() as u32) << 1
+        | player_data_code << 2
+        | sp_data_code << 4;
+
+    bytes.write_u32::<LE>(SUPER_PACKED_PLAYER_SIZE).unwrap();
+    bytes.write_u32::<LE>(player.flags).unwrap();
+    bytes.write_i32::<LE>(player.id).unwrap();
+    bytes.write_u32::<LE>(mask).unwrap();
+    if player.is_system_player() {
+        bytes.write_u32::<LE>(PROTOCOL_VERSION as u32).unwrap();
+    } else {
+        bytes.write_i32::<LE>(player.system_player).unwrap();
+    }
+    bytes.extend_from_slice(&short_name);
+    bytes.extend_from_slice(&long_name);
+    write_sized(bytes, player_data_code, player.player_data.len());
+    bytes.extend_from_slice(&player.player_data);
+    write_sized(bytes, sp_data_code, player.sp_data.len());
+    bytes.extend_from_slice(&player.sp_data);
+}
+
+/// Hosts a DirectPlay session without a game.
+pub struct NameServer {
+    sp: Box<dyn ServiceProvider>,
+    controller: AppController,
+    messages: Receiver<AppMessage>,
+    /// The GUID that our messages start with.
+    guid: GUID,
+    session: Session,
+    /// Our own system player comes first.
+    players: Vec<Player>,
+    next_id: DPID,
+}
+
+impl NameServer {
+    /// Create a name server for a session, reachable through a service provider. Players know
+    /// it by `guid`, like a game's `SelfID`.
+    pub fn new(sp: Box<dyn ServiceProvider>, guid: GUID, session: Session) -> Self {
+        let (controller, messages) = AppController::create();
+        let system_player = Player {
+            id: 1,
+            flags: DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR,
+            short_name: String::new(),
+            long_name: String::new(),
+            system_player: 1,
+            sp_data: guid.as_bytes().to_vec(),
+            player_data: vec![],
+            address: guid,
+        };
+        Self {
+            sp,
+            controller,
+            messages,
+            guid,
+            session,
+            players: vec![system_player],
+            next_id: 2,
+        }
+    }
+
+    /// The session being hosted.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Everyone in the session, including the name server's own system player.
+    pub fn players(&self) -> &[Player] {
+        &self.players
+    }
+
+    /// Number of players in the session, not counting system players.
+    fn current_players(&self) -> u32 {
+        self.players
+            .iter()
+            .filter(|player| !player.is_system_player())
+            .count() as u32
+    }
+
+    fn is_full(&self) -> bool {
+        self.session.max_players != 0 && self.current_players() >= self.session.max_players
+    }
+
+    /// Whether a player with the given flags may not join. New machines may be kept out while
+    /// those already in the session can still add players.
+    fn refuses_player(&self, flags: u32) -> bool {
+        let closed_to = if flags & DPLAYI_PLAYER_SYSPLAYER != 0 {
+            DPSESSION_JOINDISABLED | DPSESSION_NEWPLAYERSDISABLED
+        } else {
+            DPSESSION_NEWPLAYERSDISABLED
+        };
+        self.is_full() || self.session.flags & closed_to != 0
+    }
+
+    /// Open the session and answer players until the service provider closes it.
+    pub async fn run(&mut self) -> io::Result<()> {
+        let open = OpenData {
+            create: true,
+            return_status: false,
+            open_flags: 0,
+            session_flags: self.session.flags as i32,
+        };
+        self.sp.open(self.controller.clone(), 0, open).await?;
+        let system_player = CreatePlayerData {
+            player_guid: self.guid,
+            flags: (DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR | DPLAYI_PLAYER_LOCAL) as i32,
+        };
+        self.sp
+            .create_player(self.controller.clone(), 0, system_player)
+            .await?;
+
+        while let Ok(message) = self.messages.recv().await {
+            match message {
+                AppMessage::Send(_, _, data) => {
+                    if let Err(err) = self.handle_message(&data).await {
+                        log::warn!("[NameServer] could not handle message: {:?}", err);
+                    }
+                }
+                AppMessage::Close => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop hosting.
+    pub async fn shutdown(mut self) -> io::Result<()> {
+        self.sp.shutdown().await
+    }
+
+    async fn reply(&mut self, reply_to: GUID, message: Vec<u8>) -> io::Result<()> {
+        let mut data = self.guid.as_bytes().to_vec();
+        data.extend_from_slice(&message);
+        let reply = ReplyData {
+            reply_to,
+            name_server_id: self.players[0].id,
+            message: data,
+        };
+        self.sp.reply(self.controller.clone(), 0, reply).await
+    }
+
+    async fn send(&mut self, receiver: GUID, message: &[u8]) -> io::Result<()> {
+        let mut data = self.guid.as_bytes().to_vec();
+        data.extend_from_slice(message);
+        let send = SendData {
+            flags: DPSEND_GUARANTEED,
+            receiver_id: Some(receiver),
+            sender_id: self.guid,
+            system_message: true,
+            message: data,
+        };
+        self.sp.send(self.controller.clone(), 0, send).await
+    }
+
+    async fn handle_message(&mut self, data: &[u8]) -> io::Result<()> {
+        if data.get(16..20) != Some(b"play") {
+            // Game messages to the name server's system player; there is no game to read them.
+            return Ok(());
+        }
+        let (sender, message) = parse_network_message(data)?;
+        let body = &data[16..];
+        match message.body {
+            Command::EnumSessions(application, flags) => {
+                self.enum_sessions(sender, application, flags).await
+            }
+            Command::RequestPlayerId(flags) => {
+                let (id, result) = if self.refuses_player(flags) {
+                    log::debug!("[NameServer] refused a player ID to {}", sender);
+                    (0, DPERR_NONEWPLAYERS)
+                } else {
+                    self.next_id += 1;
+                    (self.next_id - 1, 0)
+                };
+                let mut reply = message_header(DPSP_MSG_REQUESTPLAYERREPLY);
+                reply.write_i32::<LE>(id).unwrap();
+                // Security description, SSPI and CAPI provider offsets.
+                reply.extend_from_slice(&[0; 24 + 4 + 4]);
+                reply.write_u32::<LE>(result).unwrap();
+                self.reply(sender, reply).await
+            }
+            Command::AddForwardRequest(..) => {
+                let player = parse_packed_player(body, sender)?;
+                if player.id == self.players[0].id {
+                    return Err(invalid("player has the name server's ID"));
+                }
+                self.add_system_player(player).await
+            }
+            Command::CreatePlayer(..) => {
+                let player = parse_packed_player(body, sender)?;
+                if player.id == self.players[0].id {
+                    return Err(invalid("player has the name server's ID"));
+                }
+                log::debug!("[NameServer] player {} joined", player.short_name);
+                self.players.retain(|existing| existing.id != player.id);
+                self.players.push(player);
+                Ok(())
+            }
+            Command::DeletePlayer(id) => {
+                let id = id as DPID;
+                // Only the machine that a player is on may delete it, and nobody may delete ours.
+                match self.players.iter().position(|player| player.id == id) {
+                    Some(0) | None => return Ok(()),
+                    Some(index) if self.players[index].address != sender => {
+                        log::warn!("[NameServer] {} may not delete player {}", sender, id);
+                        return Ok(());
+                    }
+                    Some(_) => (),
+                }
+                self.players
+                    .retain(|player| player.id != id && player.system_player != id);
+                Ok(())
+            }
+            Command::Ping(_from, ticks) => {
+                let mut reply = message_header(DPSP_MSG_PINGREPLY);
+                reply.write_i32::<LE>(self.players[0].id).unwrap();
+                reply.write_u32::<LE>(ticks).unwrap();
+                self.send(sender, &reply).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn enum_sessions(
+        &mut self,
+        sender: GUID,
+        application: GUID,
+        flags: u32,
+    ) -> io::Result<()> {
+        if !application.is_nil() && application != self.session.application {
+            return Ok(());
+        }
+        if flags & DPENUMSESSIONS_AVAILABLE != 0 && self.refuses_player(DPLAYI_PLAYER_SYSPLAYER) {
+            return Ok(());
+        }
+
+        let mut reply = message_header(DPSP_MSG_ENUMSESSIONSREPLY);
+        encode_session_desc(&mut reply, &self.session, self.current_players());
+        let name_offset = reply.len() as u32 + 4;
+        reply.write_u32::<LE>(name_offset).unwrap();
+        reply.extend_from_slice(&encode_string(&self.session.name));
+        self.reply(sender, reply).await
+    }
+
+    /// A new machine joins the session: tell it about everyone, and everyone about it.
+    async fn add_system_player(&mut self, player: Player) -> io::Result<()> {
+        log::debug!(
+            "[NameServer] machine {} joined as {}",
+            player.address,
+            player.id
+        );
+        let address = player.address;
+        self.players.retain(|existing| existing.id != player.id);
+        self.players.push(player.clone());
+
+        let create = create_player_message(&player);
+        let others: Vec<GUID> = self
+            .players
+            .iter()
+            .filter(|other| other.is_system_player() && other.address != address)
+            .map(|other| other.address)
+            .filter(|&other| other != self.guid)
+            .collect();
+        for other in others {
+            self.send(other, &create).await?;
+        }
+
+        let reply = self.super_enum_players_reply();
+        self.reply(address, reply).await
+    }
+
+    /// Describe the session and everyone in it.
+    fn super_enum_players_reply(&self) -> Vec<u8> {
+        let mut reply = message_header(DPSP_MSG_SUPERENUMPLAYERSREPLY);
+        let name = encode_string(&self.session.name);
+        let description_offset = reply.len() as u32 + 28;
+        let name_offset = description_offset + SESSION_DESC_SIZE;
+        let packed_offset = name_offset + name.len() as u32;
+        reply.write_u32::<LE>(self.players.len() as u32).unwrap();
+        // Group count.
+        reply.write_u32::<LE>(0).unwrap();
+        reply.write_u32::<LE>(packed_offset).unwrap();
+        // Shortcut count.
+        reply.write_u32::<LE>(0).unwrap();
+        reply.write_u32::<LE>(description_offset).unwrap();
+        reply
+            .write_u32::<LE>(if name.is_empty() { 0 } else { name_offset })
+            .unwrap();
+        // Password offset.
+        reply.write_u32::<LE>(0).unwrap();
+        encode_session_desc(&mut reply, &self.session, self.current_players());
+        reply.extend_from_slice(&name);
+        for player in &self.players {
+            encode_super_packed_player(&mut reply, player);
+        }
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::convert::TryInto;
+    use std::sync::{Arc, Mutex};
+
+    const SERVER: GUID = GUID::from_u128(1);
+    const ALICE: GUID = GUID::from_u128(2);
+    const BOB: GUID = GUID::from_u128(3);
+    const GAME: GUID = GUID::from_u128(4);
+
+    /// What the name server asked the service provider to do: reply to or send to a GUID.
+    #[derive(Debug, PartialEq)]
+    enum Sent {
+        Reply(GUID, Vec<u8>),
+        Send(GUID, Vec<u8>),
+    }
+
+    #[derive(Default)]
+    struct RecordingSP(Arc<Mutex<Vec<Sent>>>);
+
+    #[async_trait]
+    impl ServiceProvider for RecordingSP {
+        async fn enum_sessions(
+            &mut self,
+            _: AppController,
+            _: u32,
+            _: crate::structs::EnumSessionsData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn open(&mut self, _: AppController, _: u32, _: OpenData) -> io::Result<()> {
+            Ok(())
+        }
+        async fn create_player(
+            &mut self,
+            _: AppController,
+            _: u32,
+            _: CreatePlayerData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn reply(&mut self, _: AppController, _: u32, data: ReplyData) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Sent::Reply(data.reply_to, data.message));
+            Ok(())
+        }
+        async fn send(&mut self, _: AppController, _: u32, data: SendData) -> io::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Sent::Send(data.receiver_id.unwrap(), data.message));
+            Ok(())
+        }
+    }
+
+    fn from(sender: GUID, message: &[u8]) -> Vec<u8> {
+        let mut data = sender.as_bytes().to_vec();
+        data.extend_from_slice(message);
+        data
+    }
+
+    fn player(id: DPID, flags: u32, name: &str, address: GUID) -> Player {
+        Player {
+            id,
+            flags,
+            short_name: name.to_string(),
+            long_name: String::new(),
+            system_player: id,
+            sp_data: address.as_bytes().to_vec(),
+            player_data: vec![],
+            address,
+        }
+    }
+
+    /// A `CreatePlayer` or `AddForwardRequest` message.
+    fn create_message(command: u16, player: &Player) -> Vec<u8> {
+        let mut message = message_header(command);
+        message.write_i32::<LE>(0).unwrap();
+        message.write_i32::<LE>(player.id).unwrap();
+        message.write_i32::<LE>(0).unwrap();
+        message.write_u32::<LE>(CREATE_OFFSET).unwrap();
+        message.write_u32::<LE>(0).unwrap();
+        encode_packed_player(&mut message, player);
+        from(player.address, &message)
+    }
+
+    #[test]
+    fn hosts_a_session_without_a_game() {
+        crate::runtime::block_on(async {
+            let sent = Arc::new(Mutex::new(vec![]));
+            let session = Session {
+                instance: GUID::from_u128(10),
+                application: GAME,
+                name: "Lobby".to_string(),
+                max_players: 8,
+                flags: 0,
+                user: [0; 4],
+            };
+            let sp = RecordingSP(Arc::clone(&sent));
+            let mut server = NameServer::new(Box::new(sp), SERVER, session);
+            let take = || std::mem::take(&mut *sent.lock().unwrap());
+
+            // Sessions of other games are not listed.
+            let mut enum_request = message_header(0x02);
+            enum_request.extend_from_slice(GUID::from_u128(99).as_bytes());
+            enum_request.extend_from_slice(&0u32.to_le_bytes());
+            server
+                .handle_message(&from(ALICE, &enum_request))
+                .await
+                .unwrap();
+            assert!(take().is_empty());
+
+            let mut enum_request = message_header(0x02);
+            enum_request.extend_from_slice(GAME.as_bytes());
+            enum_request.extend_from_slice(&DPENUMSESSIONS_AVAILABLE.to_le_bytes());
+            server
+                .handle_message(&from(ALICE, &enum_request))
+                .await
+                .unwrap();
+            let reply = match take().pop() {
+                Some(Sent::Reply(ALICE, reply)) => reply,
+                other => panic!("expected a reply, got {:?}", other),
+            };
+            let (sender, message) = parse_network_message(&reply).unwrap();
+            assert_eq!(sender, SERVER);
+            match message.body {
+                Command::EnumSessionsReply(_, instance) => {
+                    assert_eq!(instance, GUID::from_u128(10))
+                }
+                other => panic!("expected a session description, got {:?}", other),
+            }
+            assert_eq!(decode_string(&reply[16 + 92..]), "Lobby");
+
+            let mut request_id = message_header(0x05);
+            request_id.extend_from_slice(&DPLAYI_PLAYER_SYSPLAYER.to_le_bytes());
+            server
+                .handle_message(&from(ALICE, &request_id))
+                .await
+                .unwrap();
+            let reply = match take().pop() {
+                Some(Sent::Reply(ALICE, reply)) => reply,
+                other => panic!("expected a reply, got {:?}", other),
+            };
+            let alice_id = match parse_network_message(&reply).unwrap().1.body {
+                Command::RequestPlayerReply(id) => id as DPID,
+                other => panic!("expected a player ID, got {:?}", other),
+            };
+            assert_eq!(alice_id, 2);
+
+            // Alice's machine joins and learns about the name server.
+            let alice_machine = player(alice_id, DPLAYI_PLAYER_SYSPLAYER, "", ALICE);
+            server
+                .handle_message(&create_message(0x13, &alice_machine))
+                .await
+                .unwrap();
+            let reply = match take().pop() {
+                Some(Sent::Reply(ALICE, reply)) => reply,
+                other => panic!("expected a reply, got {:?}", other),
+            };
+            assert_eq!(&reply[20..24], &[0x29, 0, 0x0e, 0]);
+            assert_eq!(&reply[24..28], &2u32.to_le_bytes());
+            assert_eq!(server.players()[1], alice_machine);
+
+            let alice = Player {
+                system_player: alice_id,
+                ..player(5, 0, "Alice", ALICE)
+            };
+            server
+                .handle_message(&create_message(0x08, &alice))
+                .await
+                .unwrap();
+            assert_eq!(server.current_players(), 1);
+
+            // Bob's machine joins, and Alice's machine hears about it.
+            let bob_machine = player(6, DPLAYI_PLAYER_SYSPLAYER, "", BOB);
+            server
+                .handle_message(&create_message(0x13, &bob_machine))
+                .await
+                .unwrap();
+            let sent = take();
+            assert_eq!(sent.len(), 2);
+            match &sent[0] {
+                Sent::Send(ALICE, message) => {
+                    match parse_network_message(message).unwrap().1.body {
+                        Command::CreatePlayer(id, _) => assert_eq!(id, 6),
+                        other => panic!("expected a new player, got {:?}", other),
+                    }
+                }
+                other => panic!("expected a message to Alice, got {:?}", other),
+            }
+            match &sent[1] {
+                Sent::Reply(BOB, reply) => assert_eq!(&reply[24..28], &4u32.to_le_bytes()),
+                other => panic!("expected a reply to Bob, got {:?}", other),
+            }
+
+            // Alice leaves, and her players go with her machine.
+            let mut delete = message_header(0x0b);
+            delete.write_i32::<LE>(0).unwrap();
+            delete.write_i32::<LE>(alice_id).unwrap();
+            delete.write_i32::<LE>(0).unwrap();
+            server.handle_message(&from(ALICE, &delete)).await.unwrap();
+            let ids: Vec<DPID> = server.players().iter().map(|player| player.id).collect();
+            assert_eq!(ids, vec![1, 6]);
+        });
+    }
+
+    fn lobby(max_players: u32, flags: u32) -> Session {
+        Session {
+            instance: GUID::from_u128(10),
+            application: GAME,
+            name: "Lobby".to_string(),
+            max_players,
+            flags,
+            user: [0; 4],
+        }
+    }
+
+    /// Ask a name server for a player ID, and return the ID and result that it answers with.
+    async fn request_player_id(
+        server: &mut NameServer,
+        sent: &Mutex<Vec<Sent>>,
+        sender: GUID,
+        flags: u32,
+    ) -> (DPID, u32) {
+        let mut request_id = message_header(0x05);
+        request_id.extend_from_slice(&flags.to_le_bytes());
+        server
+            .handle_message(&from(sender, &request_id))
+            .await
+            .unwrap();
+        match sent.lock().unwrap().pop() {
+            Some(Sent::Reply(to, reply)) if to == sender => {
+                let id = i32::from_le_bytes(reply[24..28].try_into().unwrap());
+                let result = u32::from_le_bytes(reply[reply.len() - 4..].try_into().unwrap());
+                (id, result)
+            }
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_players_when_the_session_is_closed_or_full() {
+        crate::runtime::block_on(async {
+            let sent = Arc::new(Mutex::new(vec![]));
+            let sp = RecordingSP(Arc::clone(&sent));
+            let mut server = NameServer::new(Box::new(sp), SERVER, lobby(0, 0));
+            assert_eq!(
+                request_player_id(&mut server, &sent, ALICE, DPLAYI_PLAYER_SYSPLAYER).await,
+                (2, 0)
+            );
+
+            // Machines already in the session may still add players when joining is disabled.
+            server.session.flags = DPSESSION_JOINDISABLED;
+            assert_eq!(
+                request_player_id(&mut server, &sent, BOB, DPLAYI_PLAYER_SYSPLAYER).await,
+                (0, DPERR_NONEWPLAYERS)
+            );
+            assert_eq!(
+                request_player_id(&mut server, &sent, ALICE, 0).await,
+                (3, 0)
+            );
+
+            server.session.flags = DPSESSION_NEWPLAYERSDISABLED;
+            assert_eq!(
+                request_player_id(&mut server, &sent, ALICE, 0).await,
+                (0, DPERR_NONEWPLAYERS)
+            );
+
+            server.session.flags = 0;
+            server.session.max_players = 1;
+            let alice = Player {
+                system_player: 2,
+                ..player(3, 0, "Alice", ALICE)
+            };
+            server
+                .handle_message(&create_message(0x08, &alice))
+                .await
+                .unwrap();
+            assert_eq!(
+                request_player_id(&mut server, &sent, ALICE, 0).await,
+                (0, DPERR_NONEWPLAYERS)
+            );
+            assert_eq!(
+                request_player_id(&mut server, &sent, BOB, DPLAYI_PLAYER_SYSPLAYER).await,
+                (0, DPERR_NONEWPLAYERS)
+            );
+        });
+    }
+
+    #[test]
+    fn only_deletes_players_for_their_machine() {
+        crate::runtime::block_on(async {
+            let sp = RecordingSP::default();
+            let mut server = NameServer::new(Box::new(sp), SERVER, lobby(0, 0));
+            let alice_machine = player(2, DPLAYI_PLAYER_SYSPLAYER, "", ALICE);
+            let bob_machine = player(3, DPLAYI_PLAYER_SYSPLAYER, "", BOB);
+            let alice = Player {
+                system_player: 2,
+                ..player(4, 0, "Alice", ALICE)
+            };
+            for message in &[
+                create_message(0x13, &alice_machine),
+                create_message(0x13, &bob_machine),
+                create_message(0x08, &alice),
+            ] {
+                server.handle_message(message).await.unwrap();
+            }
+            let ids = |server: &NameServer| -> Vec<DPID> {
+                server.players().iter().map(|player| player.id).collect()
+            };
+
+            let delete = |sender: GUID, id: DPID| {
+                let mut delete = message_header(0x0b);
+                delete.write_i32::<LE>(0).unwrap();
+                delete.write_i32::<LE>(id).unwrap();
+                delete.write_i32::<LE>(0).unwrap();
+                from(sender, &delete)
+            };
+            // Nobody may remove the name server, or another machine's players.
+            server.handle_message(&delete(ALICE, 1)).await.unwrap();
+            server.handle_message(&delete(BOB, 4)).await.unwrap();
+            server.handle_message(&delete(BOB, 2)).await.unwrap();
+            assert_eq!(ids(&server), vec![1, 2, 3, 4]);
+
+            // Nor take over its ID.
+            let impostor = player(1, DPLAYI_PLAYER_SYSPLAYER, "", BOB);
+            assert!(server
+                .handle_message(&create_message(0x13, &impostor))
+                .await
+                .is_err());
+            assert!(server
+                .handle_message(&create_message(0x08, &impostor))
+                .await
+                .is_err());
+            assert_eq!(server.players()[0].address, SERVER);
+
+            server.handle_message(&delete(ALICE, 4)).await.unwrap();
+            assert_eq!(ids(&server), vec![1, 2, 3]);
+            server.handle_message(&delete(BOB, 3)).await.unwrap();
+            assert_eq!(ids(&server), vec![1, 2]);
+        });
+    }
+
+    #[test]
+    fn rejects_malformed_packed_players() {
+        crate::runtime::block_on(async {
+            let sp = RecordingSP::default();
+            let mut server = NameServer::new(Box::new(sp), SERVER, lobby(0, 0));
+            let alice = Player {
+                system_player: 2,
+                ..player(4, 0, "Alice", ALICE)
+            };
+            let message = create_message(0x08, &alice);
+
+            // Cut off anywhere in the packed player.
+            for length in 16 + 28..message.len() {
+                assert!(server.handle_message(&message[..length]).await.is_err());
+            }
+            // A create offset past the end.
+            let mut bad_offset = message.clone();
+            bad_offset[16 + 20..16 + 24].copy_from_slice(&1000u32.to_le_bytes());
+            assert!(server.handle_message(&bad_offset).await.is_err());
+            // A fixed part that is too small for the fields.
+            let mut bad_size = message.clone();
+            let fixed_size = 16 + CREATE_OFFSET as usize + 36;
+            bad_size[fixed_size..fixed_size + 4].copy_from_slice(&8u32.to_le_bytes());
+            assert!(server.handle_message(&bad_size).await.is_err());
+            // Names and data that don't fit in the message.
+            for field in 3..7 {
+                let mut too_long = message.clone();
+                let start = 16 + CREATE_OFFSET as usize + 4 * field;
+                too_long[start..start + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+                assert!(server.handle_message(&too_long).await.is_err());
+            }
+            assert_eq!(server.players().len(), 1);
+
+            server.handle_message(&message).await.unwrap();
+            assert_eq!(server.players()[1], alice);
+        });
+    }
+}
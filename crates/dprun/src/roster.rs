@@ -0,0 +1,331 @@
+//! Keep track of who is in a session, for service providers that want to remove players.
+//!
+//! Service providers only see GUIDs: every game instance sends its messages with a GUID in front.
+//! People running a lobby know players by their names, and the games themselves know players by
+//! their DirectPlay IDs. `Roster` reads the `CreatePlayer` messages going through a service
+//! provider to tie the three together, so a player can be kicked or banned by name, and the other
+//! games can be told that the player's IDs are gone.
+//!
+//! `Roster` also follows the `CreateGroup` and `AddPlayerToGroup` messages, so service providers
+//! can send group messages to the game instances with players in the group. The GUIDs of players
+//! and groups are the first 16 bytes of their service provider data.
+
+use crate::inspect::{parse_network_message, Command};
+use crate::name_server::{delete_player_message, parse_packed_player, DPLAYI_PLAYER_SYSPLAYER};
+use crate::structs::DPID;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid as GUID;
+
+/// How a service provider user can refer to a player.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PlayerIdentity {
+    /// The GUID of a player or of its game instance, as seen by the service provider.
+    Guid(GUID),
+    /// The name of a player, as shown in the game.
+    Name(String),
+}
+
+#[derive(Debug)]
+struct RosterPlayer {
+    name: String,
+    system_player: DPID,
+}
+
+/// The players in a session, and the players that may not join it.
+#[derive(Debug, Default)]
+pub struct Roster {
+    players: HashMap<DPID, RosterPlayer>,
+    /// The game instance behind each system player.
+    instances: HashMap<DPID, GUID>,
+    /// The GUIDs of players and groups, as seen by the service provider.
+    guids: HashMap<DPID, GUID>,
+    /// Player groups and the IDs of their members.
+    groups: HashMap<DPID, HashSet<DPID>>,
+    banned_guids: HashSet<GUID>,
+    banned_names: HashSet<String>,
+}
+
+impl Roster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Learn about players from a network message that `instance` sent.
+    ///
+    /// `CreatePlayer` messages add players, and `AddForwardRequest` messages, which joining game
+    /// instances send to the host, tell us which system player belongs to which game instance.
+    /// `DeletePlayer` messages remove players again. Group messages keep track of groups and
+    /// their members. Other messages are ignored.
+    pub fn observe(&mut self, instance: GUID, message: &[u8]) {
+        if message.get(16..20) != Some(b"play") {
+            return;
+        }
+        let body = match parse_network_message(message) {
+            Ok((_, message)) => message.body,
+            Err(_) => return,
+        };
+        match body {
+            Command::CreatePlayer(..) | Command::AddForwardRequest(..) => {
+                let player = match parse_packed_player(&message[16..], instance) {
+                    Ok(player) => player,
+                    Err(err) => {
+                        log::warn!("[Roster] could not read player: {:?}", err);
+                        return;
+                    }
+                };
+                // The name server announces other machines' system players, so only the
+                // forward request or the machine's own players tell us where they are.
+                let is_system_player = player.flags & DPLAYI_PLAYER_SYSPLAYER != 0;
+                if !is_system_player || matches!(body, Command::AddForwardRequest(..)) {
+                    self.instances.insert(player.system_player, instance);
+                }
+                if let Some(guid) = sp_data_guid(&player.sp_data) {
+                    self.guids.insert(player.id, guid);
+                }
+                self.players.insert(
+                    player.id,
+                    RosterPlayer {
+                        name: player.short_name,
+                        system_player: player.system_player,
+                    },
+                );
+            }
+            Command::DeletePlayer(id) => self.remove_player(id as DPID),
+            Command::CreateGroup(..) => {
+                let group = match parse_packed_player(&message[16..], instance) {
+                    Ok(group) => group,
+                    Err(err) => {
+                        log::warn!("[Roster] could not read group: {:?}", err);
+                        return;
+                    }
+                };
+                if let Some(guid) = sp_data_guid(&group.sp_data) {
+                    self.guids.insert(group.id, guid);
+                }
+                self.groups.entry(group.id).or_default();
+            }
+            Command::DeleteGroup(group) => {
+                let group = group as DPID;
+                self.groups.remove(&group);
+                self.guids.remove(&group);
+            }
+            Command::AddPlayerToGroup(player, group) => {
+                if let Some(members) = self.groups.get_mut(&(group as DPID)) {
+                    members.insert(player as DPID);
+                }
+            }
+            Command::DeletePlayerFromGroup(player, group) => {
+                if let Some(members) = self.groups.get_mut(&(group as DPID)) {
+                    members.remove(&(player as DPID));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn remove_player(&mut self, id: DPID) {
+        self.players.remove(&id);
+        self.instances.remove(&id);
+        self.guids.remove(&id);
+        for members in self.groups.values_mut() {
+            members.remove(&id);
+        }
+    }
+
+    /// The GUIDs of the members of a group, or `None` if there is no group with that GUID.
+    pub fn group_members(&self, group: GUID) -> Option<Vec<GUID>> {
+        let (id, _) = self
+            .guids
+            .iter()
+            .find(|(id, &guid)| guid == group && self.groups.contains_key(id))?;
+        Some(
+            self.groups[id]
+                .iter()
+                .filter_map(|member| self.guids.get(member).copied())
+                .collect(),
+        )
+    }
+
+    /// Find the game instance of the player with the given name.
+    pub fn find(&self, name: &str) -> Option<GUID> {
+        self.players
+            .values()
+            .filter(|player| player.name == name)
+            .find_map(|player| self.instances.get(&player.system_player).copied())
+    }
+
+    /// The DirectPlay IDs of every player on a game instance. The system player comes last.
+    pub fn players_of(&self, instance: GUID) -> Vec<DPID> {
+        let mut ids: Vec<DPID> = self
+            .players
+            .iter()
+            .filter(|(_, player)| self.instances.get(&player.system_player) == Some(&instance))
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_by_key(|&id| (self.players[&id].system_player == id, id));
+        ids
+    }
+
+    /// Build the `DeletePlayer` messages that remove every player of a game instance from the
+    /// other games, as if the game instance had sent them itself.
+    pub fn delete_messages(&self, instance: GUID) -> Vec<Vec<u8>> {
+        self.players_of(instance)
+            .into_iter()
+            .map(|id| {
+                let mut message = instance.as_bytes().to_vec();
+                message.extend_from_slice(&delete_player_message(id));
+                message
+            })
+            .collect()
+    }
+
+    /// Forget the players of a game instance that left.
+    pub fn forget(&mut self, instance: GUID) {
+        for id in self.players_of(instance) {
+            self.remove_player(id);
+        }
+        self.instances.retain(|_, other| *other != instance);
+    }
+
+    /// Keep a player from joining again.
+    pub fn ban(&mut self, identity: &PlayerIdentity) {
+        match identity {
+            PlayerIdentity::Guid(guid) => self.banned_guids.insert(*guid),
+            PlayerIdentity::Name(name) => self.banned_names.insert(name.clone()),
+        };
+    }
+
+    /// Check if a game instance is banned, or has a player with a banned name.
+    pub fn is_banned(&self, instance: GUID) -> bool {
+        self.banned_guids.contains(&instance)
+            || self
+                .players_of(instance)
+                .into_iter()
+                .any(|id| self.banned_names.contains(&self.players[&id].name))
+    }
+}
+
+/// Read the GUID that DPRUN service providers put at the start of their player data.
+fn sp_data_guid(sp_data: &[u8]) -> Option<GUID> {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(sp_data.get(..16)?);
+    Some(GUID::from_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::name_server::{create_player_message, message_header, Player};
+
+    fn player(id: DPID, system_player: DPID, name: &str) -> Player {
+        Player {
+            id,
+            flags: if id == system_player {
+                DPLAYI_PLAYER_SYSPLAYER
+            } else {
+                0
+            },
+            short_name: name.to_string(),
+            long_name: String::new(),
+            system_player,
+            sp_data: GUID::from_u128(id as u128 + 100).as_bytes().to_vec(),
+            player_data: vec![],
+            address: GUID::nil(),
+        }
+    }
+
+    fn from(instance: GUID, message: &[u8]) -> Vec<u8> {
+        let mut data = instance.as_bytes().to_vec();
+        data.extend_from_slice(message);
+        data
+    }
+
+    #[test]
+    fn finds_and_bans_players() {
+        let host = GUID::from_u128(1);
+        let guest = GUID::from_u128(2);
+        let mut roster = Roster::new();
+        // The host tells the guest about its own system player, which does not say where it is.
+        roster.observe(host, &from(host, &create_player_message(&player(2, 2, ""))));
+        roster.observe(
+            host,
+            &from(host, &create_player_message(&player(3, 2, "Host"))),
+        );
+        roster.observe(
+            guest,
+            &from(guest, &create_player_message(&player(4, 4, ""))),
+        );
+        roster.observe(
+            guest,
+            &from(guest, &create_player_message(&player(5, 4, "Guest"))),
+        );
+
+        assert_eq!(roster.find("Host"), Some(host));
+        assert_eq!(roster.find("Guest"), Some(guest));
+        assert_eq!(roster.find("Nobody"), None);
+        assert_eq!(roster.players_of(guest), vec![5, 4]);
+
+        let deletes = roster.delete_messages(guest);
+        assert_eq!(deletes.len(), 2);
+        let (sender, message) = parse_network_message(&deletes[0]).unwrap();
+        assert_eq!(sender, guest);
+        assert!(matches!(message.body, Command::DeletePlayer(5)));
+
+        assert!(!roster.is_banned(guest));
+        roster.ban(&PlayerIdentity::Name("Guest".to_string()));
+        assert!(roster.is_banned(guest));
+        assert!(!roster.is_banned(host));
+
+        roster.forget(guest);
+        assert_eq!(roster.find("Guest"), None);
+        assert!(!roster.is_banned(guest));
+        roster.ban(&PlayerIdentity::Guid(guest));
+        assert!(roster.is_banned(guest));
+    }
+
+    fn group_message(command: u16, player: DPID, group: DPID) -> Vec<u8> {
+        let mut message = message_header(command);
+        for value in &[0, player, group, 0, 0] {
+            message.extend_from_slice(&value.to_le_bytes());
+        }
+        message
+    }
+
+    #[test]
+    fn follows_group_membership() {
+        let host = GUID::from_u128(1);
+        let guest = GUID::from_u128(2);
+        let mut roster = Roster::new();
+        roster.observe(
+            host,
+            &from(host, &create_player_message(&player(3, 2, "Host"))),
+        );
+        roster.observe(
+            guest,
+            &from(guest, &create_player_message(&player(5, 4, "Guest"))),
+        );
+        // Groups are sent as packed players with a different command.
+        let mut create_group = create_player_message(&player(7, 0, "Team"));
+        create_group[4] = 0x09;
+        roster.observe(host, &from(host, &create_group));
+        let team = GUID::from_u128(107);
+        assert_eq!(roster.group_members(team), Some(vec![]));
+
+        roster.observe(host, &from(host, &group_message(0x0d, 3, 7)));
+        roster.observe(guest, &from(guest, &group_message(0x0d, 5, 7)));
+        let mut members = roster.group_members(team).unwrap();
+        members.sort();
+        assert_eq!(members, vec![GUID::from_u128(103), GUID::from_u128(105)]);
+
+        roster.observe(host, &from(host, &group_message(0x0e, 3, 7)));
+        assert_eq!(roster.group_members(team), Some(vec![GUID::from_u128(105)]));
+        // Players that leave are no longer part of groups.
+        roster.forget(guest);
+        assert_eq!(roster.group_members(team), Some(vec![]));
+
+        roster.observe(host, &from(host, &group_message(0x0c, 0, 7)));
+        assert_eq!(roster.group_members(team), None);
+        // Players are not groups.
+        assert_eq!(roster.group_members(GUID::from_u128(103)), None);
+    }
+}
@@ -0,0 +1,247 @@
+//! Thin layer over the async runtime that dprun runs on.
+//!
+//! Everything that needs an executor or an OS resource (spawning tasks, listening on TCP sockets,
+//! starting the dprun process) goes through here, so the rest of the crate only deals in
+//! `futures` traits. Pick the runtime with the `async-std` (default) or `tokio` cargo feature.
+
+#[cfg(not(any(feature = "async-std", feature = "tokio")))]
+compile_error!("dprun needs a runtime: enable either the `async-std` or the `tokio` feature");
+
+use futures::io::AsyncRead;
+use std::future::Future;
+use std::io;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+pub(crate) use imp::{sleep, spawn, JoinHandle, TcpListener, TcpStream};
+
+#[cfg(test)]
+pub(crate) use imp::block_on;
+
+/// Error returned by [`timeout`] when the deadline passes first.
+#[derive(Debug)]
+pub(crate) struct TimedOut;
+
+/// Run a future, giving up after `duration`.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, TimedOut> {
+    futures::pin_mut!(future);
+    let deadline = sleep(duration);
+    futures::pin_mut!(deadline);
+    match futures::future::select(future, deadline).await {
+        futures::future::Either::Left((output, _)) => Ok(output),
+        futures::future::Either::Right(_) => Err(TimedOut),
+    }
+}
+
+/// A readable pipe from a child process.
+pub(crate) type ChildPipe = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Run a command to completion without capturing its output.
+pub(crate) async fn status(command: Command) -> io::Result<ExitStatus> {
+    imp::Child::spawn(command)?.status().await
+}
+
+/// A running child process with piped stdout and stderr.
+pub(crate) struct Child {
+    inner: imp::Child,
+    /// The standard output of the process, if it was not taken yet.
+    pub stdout: Option<ChildPipe>,
+    /// The standard error of the process, if it was not taken yet.
+    pub stderr: Option<ChildPipe>,
+}
+
+impl Child {
+    /// Start a command with its stdout and stderr piped back to us.
+    pub fn spawn_piped(mut command: Command) -> io::Result<Self> {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut inner = imp::Child::spawn(command)?;
+        let (stdout, stderr) = inner.take_pipes();
+        Ok(Self {
+            inner,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Wait for the process to exit.
+    pub async fn status(&mut self) -> io::Result<ExitStatus> {
+        self.inner.status().await
+    }
+}
+
+#[cfg(all(feature = "async-std", not(feature = "tokio")))]
+mod imp {
+    use super::ChildPipe;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::process::{Command, ExitStatus};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+
+    pub(crate) type TcpStream = async_std::net::TcpStream;
+
+    /// Handle to a spawned task. Awaiting it yields the task's output.
+    pub(crate) struct JoinHandle<T>(async_std::task::JoinHandle<T>);
+
+    impl<T> Future for JoinHandle<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            Pin::new(&mut self.0).poll(cx)
+        }
+    }
+
+    /// Spawn a task on the async-std executor.
+    pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(async_std::task::spawn(future))
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        async_std::task::sleep(duration).await
+    }
+
+    #[cfg(test)]
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+
+    pub(crate) struct TcpListener(async_std::net::TcpListener);
+
+    impl TcpListener {
+        pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+            Ok(Self(async_std::net::TcpListener::bind(address).await?))
+        }
+
+        /// Accept an incoming connection. Nagle's algorithm is disabled on the returned stream.
+        pub async fn accept(&self) -> io::Result<TcpStream> {
+            let (stream, _) = self.0.accept().await?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        }
+    }
+
+    pub(crate) struct Child(async_process::Child);
+
+    impl Child {
+        pub fn spawn(command: Command) -> io::Result<Self> {
+            Ok(Self(async_process::Command::from(command).spawn()?))
+        }
+
+        pub fn take_pipes(&mut self) -> (Option<ChildPipe>, Option<ChildPipe>) {
+            (
+                self.0.stdout.take().map(|pipe| Box::new(pipe) as ChildPipe),
+                self.0.stderr.take().map(|pipe| Box::new(pipe) as ChildPipe),
+            )
+        }
+
+        pub async fn status(&mut self) -> io::Result<ExitStatus> {
+            self.0.status().await
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod imp {
+    use super::ChildPipe;
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::process::{Command, ExitStatus};
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+    pub(crate) type TcpStream = Compat<tokio::net::TcpStream>;
+
+    /// Handle to a spawned task. Awaiting it yields the task's output.
+    pub(crate) struct JoinHandle<T>(tokio::task::JoinHandle<T>);
+
+    impl<T> Future for JoinHandle<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            match Pin::new(&mut self.0).poll(cx) {
+                Poll::Ready(Ok(output)) => Poll::Ready(output),
+                Poll::Ready(Err(err)) if err.is_panic() => {
+                    std::panic::resume_unwind(err.into_panic())
+                }
+                Poll::Ready(Err(err)) => panic!("task failed: {}", err),
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+
+    /// Spawn a task on the current tokio runtime.
+    ///
+    /// Panics if called outside of a tokio runtime.
+    pub(crate) fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(tokio::task::spawn(future))
+    }
+
+    pub(crate) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    #[cfg(test)]
+    pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    pub(crate) struct TcpListener(tokio::net::TcpListener);
+
+    impl TcpListener {
+        pub async fn bind(address: SocketAddr) -> io::Result<Self> {
+            Ok(Self(tokio::net::TcpListener::bind(address).await?))
+        }
+
+        /// Accept an incoming connection. Nagle's algorithm is disabled on the returned stream.
+        pub async fn accept(&self) -> io::Result<TcpStream> {
+            let (stream, _) = self.0.accept().await?;
+            stream.set_nodelay(true)?;
+            Ok(stream.compat())
+        }
+    }
+
+    pub(crate) struct Child(tokio::process::Child);
+
+    impl Child {
+        pub fn spawn(command: Command) -> io::Result<Self> {
+            Ok(Self(tokio::process::Command::from(command).spawn()?))
+        }
+
+        pub fn take_pipes(&mut self) -> (Option<ChildPipe>, Option<ChildPipe>) {
+            (
+                self.0
+                    .stdout
+                    .take()
+                    .map(|pipe| Box::new(pipe.compat()) as ChildPipe),
+                self.0
+                    .stderr
+                    .take()
+                    .map(|pipe| Box::new(pipe.compat()) as ChildPipe),
+            )
+        }
+
+        pub async fn status(&mut self) -> io::Result<ExitStatus> {
+            self.0.wait().await
+        }
+    }
+}
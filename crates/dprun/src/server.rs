@@ -1,13 +1,18 @@
+use crate::bandwidth::{BandwidthStats, ConnectionId, Method};
+use crate::latency::LatencyStats;
+use crate::runtime::{self, JoinHandle, TcpListener, TcpStream};
 use crate::{inspect::print_network_message, structs::*};
-use async_std::channel::{self, Receiver, Sender};
-use async_std::io;
-use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
-use async_std::sync::{Arc, Mutex};
+use async_channel::{self as channel, Receiver, Sender};
 use async_trait::async_trait;
+use futures::lock::Mutex;
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{FuturesUnordered, StreamExt};
 use futures_codec::{Framed, LengthCodec};
 use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum ControlMessage {
@@ -19,6 +24,8 @@ pub enum ControlMessage {
 pub enum AppMessage {
     /// Send a message to the DirectPlay application.
     Send(u32, u32, Vec<u8>),
+    /// Close the connection to the DirectPlay application, after sending queued messages.
+    Close,
 }
 
 /// Trait for custom Service Provider implementations.
@@ -44,6 +51,19 @@ pub trait ServiceProvider: Sync + Send {
         data: ReplyData,
     ) -> io::Result<()>;
     async fn send(&mut self, controller: AppController, id: u32, data: SendData) -> io::Result<()>;
+
+    /// Called when a connection to the application closes, for example because the game exited.
+    /// `controller` is the same controller that was passed to the other methods for this
+    /// connection, and can no longer be used to send messages.
+    async fn disconnected(&mut self, _controller: AppController) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the host server stops, after every connection to the application has
+    /// been closed. Providers can use this to tear down their own tasks and network resources.
+    async fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 /// Struct containing methods to control the service provider host server.
@@ -67,9 +87,14 @@ impl ServerController {
 
     /// Stop the host server.
     ///
-    /// Returns a Future, so make sure to consume it.
-    pub async fn stop(&mut self) {
-        self.sender.send(ControlMessage::Stop).await;
+    /// Returns a Future, so make sure to consume it. The server future resolves once all
+    /// connections are closed and the service provider has shut down. Fails if the server has
+    /// already stopped.
+    pub async fn stop(&mut self) -> io::Result<()> {
+        self.sender
+            .send(ControlMessage::Stop)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "host server is not running"))
     }
 }
 
@@ -101,6 +126,26 @@ impl AppController {
             .await;
     }
 
+    /// Send a message to the game with the given delivery class.
+    ///
+    /// Guaranteed messages wait for room in the game's queue. Unreliable messages are dropped
+    /// instead when the game is not keeping up, so they never hold up the sender.
+    pub async fn deliver(&mut self, delivery: Delivery, data: Vec<u8>) {
+        match delivery {
+            Delivery::Guaranteed => self.send(data).await,
+            Delivery::Unreliable => {
+                let msg_id = self.next_message_id;
+                match self
+                    .sender
+                    .try_send(AppMessage::Send(msg_id, std::u32::MAX, data))
+                {
+                    Ok(()) => self.next_message_id += 1,
+                    Err(_) => log::debug!("[AppController::deliver] dropped unreliable message"),
+                }
+            }
+        }
+    }
+
     pub async fn reply(&mut self, id: u32, data: Vec<u8>) {
         let msg_id = self.next_message_id;
         self.next_message_id += 1;
@@ -108,10 +153,29 @@ impl AppController {
             .send(AppMessage::Send(msg_id as u32, id, data))
             .await;
     }
+
+    /// Close the connection to the game. DirectPlay sees this as losing the session.
+    pub async fn close(&mut self) {
+        let _ = self.sender.send(AppMessage::Close).await;
+    }
+
+    /// Check whether the connection to the game has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// The statistics collectors that a connection reports into.
+#[derive(Clone)]
+struct ConnectionStats {
+    connection: ConnectionId,
+    latency: LatencyStats,
+    bandwidth: BandwidthStats,
 }
 
 async fn handle_message(
     service_provider: Arc<Mutex<Box<dyn ServiceProvider>>>,
+    stats: &ConnectionStats,
     controller: &mut AppController,
     id: u32,
     method: &[u8],
@@ -130,7 +194,7 @@ async fn handle_message(
                 .await
         }
         b"open" => {
-            let open = OpenData::parse(message);
+            let open = OpenData::try_parse(message)?;
             service_provider
                 .lock()
                 .await
@@ -138,7 +202,10 @@ async fn handle_message(
                 .await
         }
         b"crpl" => {
-            let create_player = CreatePlayerData::parse(message);
+            let create_player = CreatePlayerData::try_parse(message)?;
+            stats
+                .bandwidth
+                .add_player(stats.connection, create_player.player_guid);
             service_provider
                 .lock()
                 .await
@@ -146,7 +213,7 @@ async fn handle_message(
                 .await
         }
         b"repl" => {
-            let reply = ReplyData::parse(message);
+            let reply = ReplyData::try_parse(message)?;
             print_network_message(message);
             service_provider
                 .lock()
@@ -155,8 +222,14 @@ async fn handle_message(
                 .await
         }
         b"send" => {
-            let send = SendData::parse(message);
+            let send = SendData::try_parse(message)?;
             print_network_message(message);
+            stats
+                .latency
+                .observe_outgoing(send.receiver_id, &send.message);
+            stats
+                .bandwidth
+                .record_sent_by(stats.connection, send.sender_id, message.len());
             service_provider
                 .lock()
                 .await
@@ -175,30 +248,57 @@ async fn handle_message(
     }
 }
 
+/// How long a closing connection may spend flushing messages that were queued for the application.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
 fn handle_connection(
     service_provider: Arc<Mutex<Box<dyn ServiceProvider>>>,
+    stats: ConnectionStats,
     sock: TcpStream,
-) -> io::Result<()> {
-    sock.set_nodelay(true)?;
-    let (mut writer, mut reader) = Framed::new(sock, LengthCodec).split();
-    let (mut app_controller, mut app_receiver) = AppController::create();
+    shutdown: Receiver<()>,
+) -> JoinHandle<()> {
+    let (mut writer, reader) = Framed::new(sock, LengthCodec).split();
+    let (mut app_controller, app_receiver) = AppController::create();
+    // Closed by the reader when it stops, so the writer knows to wrap up.
+    let (reader_done, reader_done_receiver) = channel::bounded::<()>(1);
+    // Closed by the writer when the service provider closes the connection, so the reader stops
+    // handing the game's messages to it, for example after the game was kicked.
+    let (writer_done, writer_done_receiver) = channel::bounded::<()>(1);
     log::debug!("[handle_connection] Connection incoming");
 
+    let (connection, bandwidth) = (stats.connection, stats.bandwidth.clone());
+    bandwidth.open_connection(connection);
+
+    let read_stats = stats.clone();
+    let read_service_provider = Arc::clone(&service_provider);
+    let closed_controller = app_controller.clone();
     let read_future = async move {
-        while let Some(message) = reader.next().await {
+        let mut reader = reader.fuse();
+        let mut shutdown = shutdown.fuse();
+        let mut writer_done = writer_done_receiver.fuse();
+        loop {
+            let message = futures::select_biased! {
+                _ = shutdown.next() => {
+                    log::debug!("[handle_connection] Shutting down");
+                    break;
+                }
+                _ = writer_done.next() => break,
+                message = reader.next() => message,
+            };
             let mut message = match message {
-                Ok(message) if message.len() > 12 => message,
-                Ok(message) => {
+                Some(Ok(message)) if message.len() > 12 => message,
+                Some(Ok(message)) => {
                     log::warn!(
                         "[handle_connection] invalid message, too short: {:?}",
                         message
                     );
                     continue;
                 }
-                Err(err) => {
+                Some(Err(err)) => {
                     log::warn!("[handle_connection] Request error: {:?}", err);
                     break;
                 }
+                None => break,
             };
             let id = {
                 let mut bytes = [0; 4];
@@ -211,42 +311,115 @@ fn handle_connection(
                 u32::from_be_bytes(bytes)
             };
             let method = message.split_to(4);
-            handle_message(
-                Arc::clone(&service_provider),
+            read_stats.bandwidth.record_sent(
+                read_stats.connection,
+                Method::from_name(&method),
+                message.len(),
+            );
+            if let Err(err) = handle_message(
+                Arc::clone(&read_service_provider),
+                &read_stats,
                 &mut app_controller,
                 id,
                 &method,
                 &message,
             )
             .await
-            .unwrap();
+            {
+                log::warn!(
+                    "[handle_connection] Service provider could not handle {:?} message: {:?}",
+                    String::from_utf8_lossy(&method),
+                    err
+                );
+            }
         }
+        reader_done.close();
         log::debug!("[handle_connection] Connection finished");
     };
 
     let write_future = async move {
-        while let Some(app_message) = app_receiver.next().await {
+        let encode = |msg_id: u32, reply_to_id: u32, data: Vec<u8>| {
+            let method = if reply_to_id == std::u32::MAX {
+                Method::Send
+            } else {
+                Method::Reply
+            };
+            stats
+                .bandwidth
+                .record_received(stats.connection, method, data.len());
+            stats.latency.observe_incoming(&data);
+            log::debug!(
+                "[handle_connection] Send message {} in reply to {}",
+                msg_id,
+                reply_to_id
+            );
+            let mut message = vec![0; data.len() + 12];
+            (&mut message[0..4]).copy_from_slice(&msg_id.to_be_bytes());
+            (&mut message[4..8]).copy_from_slice(&reply_to_id.to_be_bytes());
+            (&mut message[8..12]).copy_from_slice(&0u32.to_be_bytes());
+            (&mut message[12..]).copy_from_slice(&data);
+            message
+        };
+
+        let mut app_receiver = app_receiver.fuse();
+        let mut reader_done = reader_done_receiver.fuse();
+        loop {
+            let app_message = futures::select! {
+                app_message = app_receiver.next() => app_message,
+                _ = reader_done.next() => break,
+            };
             match app_message {
-                AppMessage::Send(msg_id, reply_to_id, data) => {
-                    log::debug!(
-                        "[handle_connection] Send message {} in reply to {}",
-                        msg_id,
-                        reply_to_id
-                    );
-                    let mut message = vec![0; data.len() + 12];
-                    (&mut message[0..4]).copy_from_slice(&msg_id.to_be_bytes());
-                    (&mut message[4..8]).copy_from_slice(&reply_to_id.to_be_bytes());
-                    (&mut message[8..12]).copy_from_slice(&0u32.to_be_bytes());
-                    (&mut message[12..]).copy_from_slice(&data);
-                    writer.send(message.into()).await.unwrap();
+                Some(AppMessage::Send(msg_id, reply_to_id, data)) => {
+                    let message = encode(msg_id, reply_to_id, data);
+                    if let Err(err) = writer.send(message.into()).await {
+                        log::warn!("[handle_connection] Write error: {:?}", err);
+                        return;
+                    }
+                }
+                Some(AppMessage::Close) => {
+                    log::debug!("[handle_connection] Closing at the service provider's request");
+                    writer_done.close();
+                    break;
+                }
+                None => break,
+            }
+        }
+
+        // Flush whatever the service provider queued up before the connection went away.
+        let drain = async {
+            let app_receiver = app_receiver.get_mut();
+            app_receiver.close();
+            while let Ok(app_message) = app_receiver.try_recv() {
+                if let AppMessage::Send(msg_id, reply_to_id, data) = app_message {
+                    writer
+                        .send(encode(msg_id, reply_to_id, data).into())
+                        .await?;
                 }
             }
+            writer.close().await
+        };
+        match runtime::timeout(DRAIN_TIMEOUT, drain).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => log::warn!("[handle_connection] Write error: {:?}", err),
+            Err(_) => log::warn!("[handle_connection] Timed out flushing outgoing messages"),
         }
     };
 
-    async_std::task::spawn(read_future);
-    async_std::task::spawn(write_future);
-    Ok(())
+    runtime::spawn(async move {
+        futures::join!(read_future, write_future);
+        bandwidth.close_connection(connection);
+        if let Err(err) = service_provider
+            .lock()
+            .await
+            .disconnected(closed_controller)
+            .await
+        {
+            log::warn!(
+                "[handle_connection] Service provider disconnect failed: {:?}",
+                err
+            );
+        }
+    })
 }
 
 #[derive(Debug)]
@@ -260,6 +433,8 @@ pub struct HostServer {
     controller: ServerController,
     receiver: Receiver<ControlMessage>,
     service_provider: Box<dyn ServiceProvider>,
+    latency_stats: LatencyStats,
+    bandwidth_stats: BandwidthStats,
 }
 
 impl HostServer {
@@ -272,6 +447,24 @@ impl HostServer {
             controller,
             receiver,
             service_provider,
+            latency_stats: LatencyStats::new(),
+            bandwidth_stats: BandwidthStats::new(),
+        }
+    }
+
+    /// Collect round-trip times into the given statistics handle.
+    pub fn with_latency_stats(self, latency_stats: LatencyStats) -> Self {
+        Self {
+            latency_stats,
+            ..self
+        }
+    }
+
+    /// Count traffic into the given statistics handle.
+    pub fn with_bandwidth_stats(self, bandwidth_stats: BandwidthStats) -> Self {
+        Self {
+            bandwidth_stats,
+            ..self
         }
     }
 
@@ -280,33 +473,356 @@ impl HostServer {
             "[HostServer::start] Starting HostServer on {:?}",
             self.address
         );
-        let client = TcpListener::bind(&self.address).await?;
+        let client = TcpListener::bind(self.address).await?;
 
         let service_provider = Arc::new(Mutex::new(self.service_provider));
-        let _server_controller = self.controller.clone();
         let receiver = self.receiver;
+        let latency_stats = self.latency_stats;
+        let bandwidth_stats = self.bandwidth_stats;
         let server = async move {
+            // Closed when the server stops, which tells every connection task to wrap up.
+            let (shutdown, shutdown_receiver) = channel::bounded::<()>(1);
+            let mut connections = FuturesUnordered::new();
+            let mut next_connection_id = 0;
+
             let control_messages = receiver.map(EventType::Control).map(io::Result::Ok);
-            let socket_messages = client
-                .incoming()
-                .map(|result| result.map(EventType::Socket));
+            let socket_messages = futures::stream::unfold(client, |client| async move {
+                let result = client.accept().await.map(EventType::Socket);
+                Some((result, client))
+            });
 
-            let mut stream = futures::stream::select(socket_messages, control_messages);
-            while let Some(message) = stream.next().await {
+            let mut stream = Box::pin(futures::stream::select(socket_messages, control_messages));
+            loop {
+                let message = futures::select! {
+                    message = stream.next() => message,
+                    // Reap connection tasks that ended on their own.
+                    _ = connections.select_next_some() => continue,
+                };
                 log::debug!("[HostServer::start] Receiving message: {:?}", message);
                 let message = match message {
-                    Err(_) => break,
-                    Ok(EventType::Control(ControlMessage::Stop)) => break,
-                    Ok(message) => message,
+                    None | Some(Err(_)) => break,
+                    Some(Ok(EventType::Control(ControlMessage::Stop))) => break,
+                    Some(Ok(message)) => message,
                 };
 
                 if let EventType::Socket(socket) = message {
                     log::debug!("[HostServer::start] Spawning socket handler...");
-                    handle_connection(Arc::clone(&service_provider), socket).unwrap();
+                    let stats = ConnectionStats {
+                        connection: next_connection_id,
+                        latency: latency_stats.clone(),
+                        bandwidth: bandwidth_stats.clone(),
+                    };
+                    next_connection_id += 1;
+                    connections.push(handle_connection(
+                        Arc::clone(&service_provider),
+                        stats,
+                        socket,
+                        shutdown_receiver.clone(),
+                    ));
                 }
             }
+
+            log::debug!(
+                "[HostServer::start] Stopping, waiting for {} connections",
+                connections.len()
+            );
+            // Stop accepting new connections.
+            drop(stream);
+            shutdown.close();
+            while connections.next().await.is_some() {}
+
+            if let Err(err) = service_provider.lock().await.shutdown().await {
+                log::warn!(
+                    "[HostServer::start] Service provider shutdown failed: {:?}",
+                    err
+                );
+            }
+            log::debug!("[HostServer::start] Stopped");
         };
 
         Ok((server, self.controller))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Service provider that records when it is shut down and when it is dropped.
+    struct TrackingSP {
+        shut_down: Arc<AtomicBool>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Drop for TrackingSP {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl ServiceProvider for TrackingSP {
+        async fn enum_sessions(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: EnumSessionsData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn open(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: OpenData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn create_player(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: CreatePlayerData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn reply(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: ReplyData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn send(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: SendData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn shutdown(&mut self) -> io::Result<()> {
+            self.shut_down.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    /// Service provider that closes the connection as soon as the game enumerates sessions.
+    struct ClosingSP {
+        enums: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ServiceProvider for ClosingSP {
+        async fn enum_sessions(
+            &mut self,
+            mut controller: AppController,
+            _id: u32,
+            _data: EnumSessionsData,
+        ) -> io::Result<()> {
+            self.enums.fetch_add(1, Ordering::SeqCst);
+            controller.close().await;
+            Ok(())
+        }
+        async fn open(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: OpenData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn create_player(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: CreatePlayerData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn reply(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: ReplyData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+        async fn send(
+            &mut self,
+            _controller: AppController,
+            _id: u32,
+            _data: SendData,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Frame a message from the game with its length prefix, message ID, reply ID and method.
+    fn frame(method: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut message = (12 + data.len() as u64).to_be_bytes().to_vec();
+        message.extend_from_slice(&[0; 8]);
+        message.extend_from_slice(method);
+        message.extend_from_slice(data);
+        message
+    }
+
+    /// Run blocking client code on its own thread, so it does not hold up the host server when
+    /// the runtime only has one thread.
+    async fn on_client_thread<T: Send + 'static>(client: impl FnOnce() -> T + Send + 'static) -> T {
+        let (sender, receiver) = futures::channel::oneshot::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(client());
+        });
+        receiver.await.unwrap()
+    }
+
+    #[test]
+    fn drops_unreliable_messages_when_the_game_falls_behind() {
+        let (mut controller, receiver) = AppController::create();
+        crate::runtime::block_on(async {
+            for i in 0..8u8 {
+                controller.deliver(Delivery::Unreliable, vec![i]).await;
+            }
+            // The queue holds five messages; the rest were dropped without waiting.
+            let queued: Vec<AppMessage> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+            assert_eq!(queued.len(), 5);
+            assert!(matches!(&queued[4], AppMessage::Send(4, _, data) if data == &[4]));
+
+            // Guaranteed messages are queued, and message IDs carry on without gaps.
+            controller.deliver(Delivery::Guaranteed, vec![9]).await;
+            assert!(matches!(receiver.try_recv(), Ok(AppMessage::Send(5, _, _))));
+        });
+    }
+
+    #[test]
+    fn stop_closes_connections_and_leaves_no_tasks() {
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let port = free_port();
+        let server = HostServer::new(
+            port,
+            Box::new(TrackingSP {
+                shut_down: Arc::clone(&shut_down),
+                dropped: Arc::clone(&dropped),
+            }),
+        );
+
+        let clients = runtime::block_on(async move {
+            let (server, mut controller) = server.start().await.unwrap();
+            let server = runtime::spawn(server);
+
+            // Idle connections: their reader tasks are blocked waiting for data.
+            let clients = (0..2)
+                .map(|_| std::net::TcpStream::connect(("127.0.0.1", port)).unwrap())
+                .collect::<Vec<_>>();
+            runtime::sleep(Duration::from_millis(100)).await;
+
+            controller.stop().await.unwrap();
+            runtime::timeout(Duration::from_secs(5), server)
+                .await
+                .expect("host server did not stop");
+            assert!(controller.stop().await.is_err());
+            clients
+        });
+
+        assert!(shut_down.load(Ordering::SeqCst));
+        // Every connection task held a reference to the service provider, so it can only have
+        // been dropped if all of them have exited.
+        assert!(dropped.load(Ordering::SeqCst));
+        for mut client in clients {
+            client
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn stops_reading_when_the_service_provider_closes_the_connection() {
+        let enums = Arc::new(AtomicUsize::new(0));
+        let port = free_port();
+        let server = HostServer::new(
+            port,
+            Box::new(ClosingSP {
+                enums: Arc::clone(&enums),
+            }),
+        );
+
+        runtime::block_on(async move {
+            let (server, mut controller) = server.start().await.unwrap();
+            let server = runtime::spawn(server);
+
+            let closed = on_client_thread(move || {
+                let request = frame(b"enum", &[0; 20]);
+                let mut client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(2)))
+                    .unwrap();
+                client.write_all(&request).unwrap();
+                let closed = client.read(&mut [0; 1]).unwrap() == 0;
+                // Whatever the game still sends is not handed to the service provider.
+                let _ = client.write_all(&request);
+                closed
+            })
+            .await;
+            assert!(closed);
+            runtime::sleep(Duration::from_millis(200)).await;
+            assert_eq!(enums.load(Ordering::SeqCst), 1);
+
+            controller.stop().await.unwrap();
+            runtime::timeout(Duration::from_secs(5), server)
+                .await
+                .expect("host server did not stop");
+        });
+    }
+
+    #[test]
+    fn skips_truncated_messages() {
+        let enums = Arc::new(AtomicUsize::new(0));
+        let port = free_port();
+        let server = HostServer::new(
+            port,
+            Box::new(ClosingSP {
+                enums: Arc::clone(&enums),
+            }),
+        );
+
+        runtime::block_on(async move {
+            let (server, mut controller) = server.start().await.unwrap();
+            let server = runtime::spawn(server);
+
+            let closed = on_client_thread(move || {
+                let mut client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(2)))
+                    .unwrap();
+                for method in [b"open", b"crpl", b"repl", b"send"] {
+                    client.write_all(&frame(method, &[0; 4])).unwrap();
+                }
+                // The connection is still served, so this closes it.
+                client.write_all(&frame(b"enum", &[0; 20])).unwrap();
+                client.read(&mut [0; 1]).unwrap() == 0
+            })
+            .await;
+            assert!(closed);
+            assert_eq!(enums.load(Ordering::SeqCst), 1);
+
+            controller.stop().await.unwrap();
+            runtime::timeout(Duration::from_secs(5), server)
+                .await
+                .expect("host server did not stop");
+        });
+    }
+}
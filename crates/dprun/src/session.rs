@@ -0,0 +1,438 @@
+//! The session bookkeeping that peer-to-peer service providers share.
+//!
+//! A provider's network task gets `Command`s from the service provider, and messages from other
+//! peers. `Session` keeps track of what those mean for the session: whether we host it, which
+//! peer does, which peers remote players live on and who is waiting for an answer to an
+//! enumeration request. It never touches the network itself: it tells the provider what to send
+//! and to whom, and the provider sends it in its own format. A peer is whatever the provider
+//! addresses other machines by, like a socket address, a connection or a node.
+
+use crate::inspect::is_name_server_announcement;
+use crate::{AppController, Delivery, GUID};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+
+/// Network messages start with the GUID of the player or game instance that sent them.
+pub fn message_sender(message: &[u8]) -> Option<GUID> {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(message.get(0..16)?);
+    Some(GUID::from_bytes(bytes))
+}
+
+/// Messages from a service provider to its network task.
+///
+/// Every command carries the controller of the game that caused it, which incoming messages are
+/// delivered to from then on.
+pub enum Command {
+    /// The local game opened a session, either hosting (`create`) or joining it. Hosts answer
+    /// enumeration requests from other peers.
+    Open {
+        controller: AppController,
+        create: bool,
+        /// Whether another peer takes over as the host when the host leaves.
+        migrate_host: bool,
+    },
+    /// Look for sessions.
+    EnumSessions {
+        controller: AppController,
+        message: Vec<u8>,
+    },
+    /// The local game created a player.
+    CreatePlayer {
+        controller: AppController,
+        player: GUID,
+    },
+    /// The local game answered a message from another game.
+    Reply {
+        controller: AppController,
+        reply_to: GUID,
+        message: Vec<u8>,
+    },
+    /// The local game sent a message to a player, or to everyone in the session if there is no
+    /// receiver.
+    Send {
+        controller: AppController,
+        sender: GUID,
+        receiver: Option<GUID>,
+        delivery: Delivery,
+        message: Vec<u8>,
+    },
+}
+
+impl Command {
+    pub fn controller(&self) -> &AppController {
+        match self {
+            Command::Open { controller, .. }
+            | Command::EnumSessions { controller, .. }
+            | Command::CreatePlayer { controller, .. }
+            | Command::Reply { controller, .. }
+            | Command::Send { controller, .. } => controller,
+        }
+    }
+}
+
+/// Where a message from the local game should go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route<P> {
+    /// To the peer that the receiving player lives on.
+    Peer(P),
+    /// To everyone in the session.
+    Everyone,
+    /// To a player that we don't know the peer of.
+    Nowhere,
+}
+
+/// What a provider should send for a command from the local game.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outgoing<P> {
+    /// Look for sessions, wherever the provider finds them.
+    EnumSessions(Vec<u8>),
+    /// Tell the session about a player that the local game created.
+    NewPlayer(GUID),
+    /// Answer the enumeration request of a peer.
+    EnumReply(P, Vec<u8>),
+    /// Send a game message.
+    Game {
+        route: Route<P>,
+        sender: GUID,
+        receiver: Option<GUID>,
+        delivery: Delivery,
+        data: Vec<u8>,
+    },
+}
+
+/// The state of the session that the local game takes part in, as far as the network is
+/// concerned.
+pub struct Session<P> {
+    /// The local game.
+    app: Option<AppController>,
+    /// Whether the local game is hosting the session.
+    hosting: bool,
+    /// Whether the session goes on with a new host when the host leaves.
+    migrate_host: bool,
+    /// The peer hosting the session, once it has answered an enumeration request.
+    host: Option<P>,
+    /// Enumeration requests from other peers that the local game has not answered yet, keyed
+    /// by the GUID that the requesting game sent them with.
+    pending_enums: HashMap<GUID, P>,
+    /// Players created by the local game.
+    local_players: Vec<GUID>,
+    /// The peers that remote players and game instances live on.
+    players: HashMap<GUID, P>,
+}
+
+impl<P> Default for Session<P> {
+    fn default() -> Self {
+        Self {
+            app: None,
+            hosting: false,
+            migrate_host: false,
+            host: None,
+            pending_enums: HashMap::new(),
+            local_players: vec![],
+            players: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Copy + Eq + Hash + Debug> Session<P> {
+    /// Whether the local game is hosting the session.
+    pub fn hosting(&self) -> bool {
+        self.hosting
+    }
+
+    /// The peer hosting the session, if it is not us.
+    pub fn host(&self) -> Option<P> {
+        self.host
+    }
+
+    /// Players created by the local game.
+    pub fn local_players(&self) -> &[GUID] {
+        &self.local_players
+    }
+
+    /// The peer that a remote player lives on.
+    pub fn player_peer(&self, player: GUID) -> Option<P> {
+        self.players.get(&player).copied()
+    }
+
+    /// Remote players, with the peers they live on.
+    pub fn players(&self) -> impl Iterator<Item = (GUID, P)> + '_ {
+        self.players.iter().map(|(&player, &peer)| (player, peer))
+    }
+
+    /// Everyone else in the session: the host and the peers of remote players.
+    pub fn peers(&self) -> Vec<P> {
+        let mut peers: Vec<P> = self.host.iter().copied().collect();
+        for &peer in self.players.values() {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    /// Handle a command from the local game, and say what to send for it.
+    pub fn command(&mut self, command: Command) -> Option<Outgoing<P>> {
+        self.app = Some(command.controller().clone());
+        match command {
+            Command::Open {
+                create,
+                migrate_host,
+                ..
+            } => {
+                self.hosting = create;
+                self.migrate_host = migrate_host;
+                None
+            }
+            Command::EnumSessions { message, .. } => Some(Outgoing::EnumSessions(message)),
+            Command::CreatePlayer { player, .. } => {
+                self.local_players.push(player);
+                Some(Outgoing::NewPlayer(player))
+            }
+            Command::Reply {
+                reply_to, message, ..
+            } => match self.pending_enums.remove(&reply_to) {
+                Some(peer) => Some(Outgoing::EnumReply(peer, message)),
+                None => Some(Outgoing::Game {
+                    route: self.route(Some(reply_to)),
+                    sender: GUID::nil(),
+                    receiver: Some(reply_to),
+                    delivery: Delivery::Guaranteed,
+                    data: message,
+                }),
+            },
+            Command::Send {
+                sender,
+                receiver,
+                delivery,
+                message,
+                ..
+            } => {
+                // After the host left, the local game announces that it took over.
+                if self.host.is_none() && is_name_server_announcement(&message) {
+                    log::debug!("[session] taking over as the host");
+                    self.hosting = true;
+                    self.host = None;
+                }
+                Some(Outgoing::Game {
+                    route: self.route(receiver),
+                    sender,
+                    receiver,
+                    delivery,
+                    data: message,
+                })
+            }
+        }
+    }
+
+    fn route(&self, receiver: Option<GUID>) -> Route<P> {
+        match receiver {
+            Some(receiver) => match self.players.get(&receiver) {
+                Some(&peer) => Route::Peer(peer),
+                None => {
+                    log::debug!("[session] don't know where player {} is", receiver);
+                    Route::Nowhere
+                }
+            },
+            None => Route::Everyone,
+        }
+    }
+
+    /// Remember that a remote player lives on `peer`. Returns whether the player is new to us.
+    pub fn add_player(&mut self, player: GUID, peer: P) -> bool {
+        if player.is_nil() || self.local_players.contains(&player) {
+            return false;
+        }
+        self.players.insert(player, peer) != Some(peer)
+    }
+
+    /// Learn where the sender of a game message lives. Replies to messages go to the GUID that
+    /// the message started with, so that is remembered too.
+    pub fn learn_players(&mut self, peer: P, sender: GUID, data: &[u8]) {
+        for player in std::iter::once(sender).chain(message_sender(data)) {
+            self.add_player(player, peer);
+        }
+    }
+
+    /// Handle an enumeration request from a peer. Only hosts have sessions to describe.
+    pub async fn enum_request(&mut self, peer: P, request: Vec<u8>) {
+        if let (Some(app), true, Some(requester)) =
+            (&mut self.app, self.hosting, message_sender(&request))
+        {
+            self.pending_enums.insert(requester, peer);
+            app.send(request).await;
+        }
+    }
+
+    /// Handle the answer to an enumeration request, from the peer that hosts the session.
+    pub async fn enum_reply(&mut self, peer: P, reply: Vec<u8>) {
+        if let Some(app) = &mut self.app {
+            self.host = Some(peer);
+            app.send(reply).await;
+        }
+    }
+
+    /// Handle a game message from a peer, in order if it was guaranteed.
+    pub async fn game_message(&mut self, peer: P, sender: GUID, delivery: Delivery, data: Vec<u8>) {
+        // Only a session whose host left can get a new one.
+        if self.host.is_none() && !self.hosting && is_name_server_announcement(&data) {
+            log::debug!("[session] {:?} took over as the host", peer);
+            self.hosting = false;
+            self.host = Some(peer);
+        }
+        self.learn_players(peer, sender, &data);
+        if let Some(app) = &mut self.app {
+            app.deliver(delivery, data).await;
+        }
+    }
+
+    /// Forget a peer that went away. If it was the host, the game's connection is closed,
+    /// unless another peer takes over.
+    pub async fn peer_lost(&mut self, peer: P) {
+        self.players.retain(|_, player_peer| *player_peer != peer);
+        self.pending_enums.retain(|_, requester| *requester != peer);
+        if self.host == Some(peer) {
+            log::debug!("[session] lost connection to the host");
+            self.host = None;
+            if self.migrate_host {
+                log::debug!("[session] waiting for another peer to take over");
+            } else if let Some(app) = &mut self.app {
+                app.close().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::block_on;
+    use crate::AppMessage;
+
+    fn open(controller: &AppController, create: bool, migrate_host: bool) -> Command {
+        Command::Open {
+            controller: controller.clone(),
+            create,
+            migrate_host,
+        }
+    }
+
+    fn enum_request(requester: GUID) -> Vec<u8> {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        message
+    }
+
+    #[test]
+    fn routes_replies_to_the_requesting_peer() {
+        let (controller, receiver) = AppController::create();
+        let mut session = Session::<u32>::default();
+        session.command(open(&controller, true, false));
+
+        let requester = GUID::from_u128(1);
+        block_on(session.enum_request(7, enum_request(requester)));
+        assert!(matches!(receiver.try_recv(), Ok(AppMessage::Send(..))));
+        let reply = Command::Reply {
+            controller: controller.clone(),
+            reply_to: requester,
+            message: b"session".to_vec(),
+        };
+        assert_eq!(
+            session.command(reply),
+            Some(Outgoing::EnumReply(7, b"session".to_vec()))
+        );
+
+        // Once the requester joined, messages to its players go to its peer.
+        let player = GUID::from_u128(2);
+        block_on(session.game_message(7, player, Delivery::Guaranteed, enum_request(requester)));
+        let send = |receiver| Command::Send {
+            controller: controller.clone(),
+            sender: GUID::from_u128(3),
+            receiver,
+            delivery: Delivery::Unreliable,
+            message: b"hello".to_vec(),
+        };
+        match session.command(send(Some(player))) {
+            Some(Outgoing::Game { route, .. }) => assert_eq!(route, Route::Peer(7)),
+            outgoing => panic!("unexpected {:?}", outgoing),
+        }
+        match session.command(send(Some(GUID::from_u128(4)))) {
+            Some(Outgoing::Game { route, .. }) => assert_eq!(route, Route::Nowhere),
+            outgoing => panic!("unexpected {:?}", outgoing),
+        }
+        match session.command(send(None)) {
+            Some(Outgoing::Game { route, .. }) => assert_eq!(route, Route::Everyone),
+            outgoing => panic!("unexpected {:?}", outgoing),
+        }
+    }
+
+    #[test]
+    fn ignores_requests_unless_hosting() {
+        let (controller, receiver) = AppController::create();
+        let mut session = Session::<u32>::default();
+        session.command(open(&controller, false, false));
+        block_on(session.enum_request(7, enum_request(GUID::from_u128(1))));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn closes_the_game_when_the_host_leaves() {
+        for &migrate_host in &[false, true] {
+            let (controller, receiver) = AppController::create();
+            let mut session = Session::<u32>::default();
+            session.command(open(&controller, false, migrate_host));
+            block_on(session.enum_reply(1, b"session".to_vec()));
+            assert!(matches!(receiver.try_recv(), Ok(AppMessage::Send(..))));
+            assert!(session.add_player(GUID::from_u128(2), 1));
+            assert!(session.add_player(GUID::from_u128(3), 2));
+
+            block_on(session.peer_lost(1));
+            assert_eq!(session.host(), None);
+            assert_eq!(session.peers(), vec![2]);
+            assert_eq!(
+                matches!(receiver.try_recv(), Ok(AppMessage::Close)),
+                !migrate_host
+            );
+        }
+    }
+
+    #[test]
+    fn takes_one_new_host_after_the_host_leaves() {
+        let (controller, _receiver) = AppController::create();
+        let mut session = Session::<u32>::default();
+        session.command(open(&controller, false, true));
+        block_on(session.enum_reply(1, b"session".to_vec()));
+
+        let mut announcement = GUID::from_u128(3).as_bytes().to_vec();
+        announcement.extend_from_slice(b"play\x35\x00\x0e\x00");
+        announcement.extend_from_slice(&[0; 16]);
+        let announce = |session: &mut Session<u32>, peer| {
+            let message = announcement.clone();
+            block_on(session.game_message(peer, GUID::from_u128(3), Delivery::Guaranteed, message));
+        };
+
+        // Nobody takes over while the host is still there.
+        announce(&mut session, 2);
+        assert_eq!(session.host(), Some(1));
+
+        block_on(session.peer_lost(1));
+        announce(&mut session, 2);
+        assert_eq!(session.host(), Some(2));
+        announce(&mut session, 3);
+        assert_eq!(session.host(), Some(2));
+
+        // The local game can't take over from the new host either.
+        let send = Command::Send {
+            controller: controller.clone(),
+            sender: GUID::from_u128(4),
+            receiver: None,
+            delivery: Delivery::Guaranteed,
+            message: announcement.clone(),
+        };
+        session.command(send);
+        assert!(!session.hosting());
+        assert_eq!(session.host(), Some(2));
+    }
+}
@@ -1,15 +1,55 @@
-use byteorder::{ReadBytesExt, LE};
-use std::io::{self, Cursor, Read};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::io::{self, Cursor, Read, Write};
 use uuid::Uuid;
 
 pub type DPID = i32;
 
+/// Send flag: the message must arrive, in order.
+pub const DPSEND_GUARANTEED: i32 = 0x1;
+/// Send flag: the message should skip ahead of other queued messages.
+pub const DPSEND_HIGHPRIORITY: i32 = 0x2;
+/// Send flag: the message is digitally signed.
+pub const DPSEND_SIGNED: i32 = 0x20;
+/// Send flag: the message is encrypted.
+pub const DPSEND_ENCRYPTED: i32 = 0x40;
+
+/// Session flag: when the host leaves, another player becomes the name server and the session
+/// goes on.
+pub const DPSESSION_MIGRATEHOST: i32 = 0x4;
+
+/// How a message should be delivered to the other side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The message must arrive, in order with other guaranteed messages.
+    Guaranteed,
+    /// The message may be dropped or reordered; it is better late than never, but better never
+    /// than holding up other traffic.
+    Unreliable,
+}
+
 fn read_guid(mut read: impl Read) -> io::Result<Uuid> {
     let mut guid = [0; 16];
     read.read_exact(&mut guid)?;
     Ok(Uuid::from_bytes(guid))
 }
 
+/// Read a length-prefixed message, failing if the length is larger than the remaining input.
+fn read_message(cursor: &mut Cursor<&[u8]>) -> io::Result<Vec<u8>> {
+    let size = cursor.read_i32::<LE>()?;
+    let remaining = cursor.get_ref().len() as u64 - cursor.position();
+    if size < 0 || size as u64 > remaining {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let mut message = vec![0; size as usize];
+    cursor.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn write_message(mut write: impl Write, message: &[u8]) -> io::Result<()> {
+    write.write_i32::<LE>(message.len() as i32)?;
+    write.write_all(message)
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct CreatePlayerData {
@@ -20,18 +60,32 @@ pub struct CreatePlayerData {
 
 impl CreatePlayerData {
     pub fn parse(bytes: &[u8]) -> Self {
+        Self::try_parse(bytes).unwrap()
+    }
+
+    /// Parse player creation data, failing if it is truncated.
+    pub fn try_parse(bytes: &[u8]) -> io::Result<Self> {
         let mut cursor = Cursor::new(bytes);
 
-        let _dpid = cursor.read_u32::<LE>().unwrap();
-        let guid = read_guid(&mut cursor).unwrap();
+        let _dpid = cursor.read_u32::<LE>()?;
+        let guid = read_guid(&mut cursor)?;
 
-        let flags = cursor.read_i32::<LE>().unwrap();
+        let flags = cursor.read_i32::<LE>()?;
 
-        Self {
+        Ok(Self {
             // player_id: dpid,
             player_guid: guid,
             flags,
-        }
+        })
+    }
+
+    /// Encode player creation data the way dprun sends it, the inverse of `parse`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24);
+        bytes.write_u32::<LE>(0).unwrap();
+        bytes.write_all(self.player_guid.as_bytes()).unwrap();
+        bytes.write_i32::<LE>(self.flags).unwrap();
+        bytes
     }
 }
 
@@ -52,18 +106,39 @@ pub struct OpenData {
 
 impl OpenData {
     pub fn parse(bytes: &[u8]) -> Self {
+        Self::try_parse(bytes).unwrap()
+    }
+
+    /// Parse session opening data, failing if it is truncated.
+    pub fn try_parse(bytes: &[u8]) -> io::Result<Self> {
         let mut cursor = Cursor::new(bytes);
-        let create = cursor.read_u8().unwrap() != 0;
-        let return_status = cursor.read_u8().unwrap() != 0;
-        let _padding = cursor.read_u16::<LE>().unwrap();
-        let open_flags = cursor.read_i32::<LE>().unwrap();
-        let session_flags = cursor.read_i32::<LE>().unwrap();
-        Self {
+        let create = cursor.read_u8()? != 0;
+        let return_status = cursor.read_u8()? != 0;
+        let _padding = cursor.read_u16::<LE>()?;
+        let open_flags = cursor.read_i32::<LE>()?;
+        let session_flags = cursor.read_i32::<LE>()?;
+        Ok(Self {
             create,
             return_status,
             open_flags,
             session_flags,
-        }
+        })
+    }
+
+    /// Whether the session goes on with a new host when the host leaves.
+    pub fn migrate_host(&self) -> bool {
+        self.session_flags & DPSESSION_MIGRATEHOST != 0
+    }
+
+    /// Encode session opening data the way dprun sends it, the inverse of `parse`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12);
+        bytes.write_u8(self.create as u8).unwrap();
+        bytes.write_u8(self.return_status as u8).unwrap();
+        bytes.write_u16::<LE>(0).unwrap();
+        bytes.write_i32::<LE>(self.open_flags).unwrap();
+        bytes.write_i32::<LE>(self.session_flags).unwrap();
+        bytes
     }
 }
 
@@ -77,29 +152,63 @@ pub struct SendData {
 }
 
 impl SendData {
+    /// Whether DirectPlay asked for guaranteed delivery of this message.
+    pub fn guaranteed(&self) -> bool {
+        self.flags & DPSEND_GUARANTEED != 0
+    }
+
+    /// Whether DirectPlay asked for this message to skip ahead of others.
+    pub fn high_priority(&self) -> bool {
+        self.flags & DPSEND_HIGHPRIORITY != 0
+    }
+
+    /// How this message should be delivered.
+    pub fn delivery(&self) -> Delivery {
+        if self.guaranteed() {
+            Delivery::Guaranteed
+        } else {
+            Delivery::Unreliable
+        }
+    }
+
     pub fn parse(bytes: &[u8]) -> Self {
+        Self::try_parse(bytes).unwrap()
+    }
+
+    /// Parse send data, failing if it is truncated.
+    pub fn try_parse(bytes: &[u8]) -> io::Result<Self> {
         let mut cursor = Cursor::new(bytes);
 
-        let flags = cursor.read_i32::<LE>().unwrap();
+        let flags = cursor.read_i32::<LE>()?;
 
-        let receiver_id = match read_guid(&mut cursor).unwrap() {
+        let receiver_id = match read_guid(&mut cursor)? {
             guid if guid == Uuid::nil() => None,
             guid => Some(guid),
         };
-        let sender_id = read_guid(&mut cursor).unwrap();
+        let sender_id = read_guid(&mut cursor)?;
 
-        let system_message = cursor.read_i32::<LE>().unwrap() != 0;
-        let message_size = cursor.read_i32::<LE>().unwrap();
-        let mut message = vec![0; message_size as usize];
-        cursor.read_exact(&mut message).unwrap();
+        let system_message = cursor.read_i32::<LE>()? != 0;
+        let message = read_message(&mut cursor)?;
 
-        Self {
+        Ok(Self {
             flags,
             receiver_id,
             sender_id,
             system_message,
             message,
-        }
+        })
+    }
+
+    /// Encode send data the way dprun sends it, the inverse of `parse`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(44 + self.message.len());
+        bytes.write_i32::<LE>(self.flags).unwrap();
+        let receiver_id = self.receiver_id.unwrap_or_else(Uuid::nil);
+        bytes.write_all(receiver_id.as_bytes()).unwrap();
+        bytes.write_all(self.sender_id.as_bytes()).unwrap();
+        bytes.write_i32::<LE>(self.system_message as i32).unwrap();
+        write_message(&mut bytes, &self.message).unwrap();
+        bytes
     }
 }
 
@@ -112,19 +221,62 @@ pub struct ReplyData {
 
 impl ReplyData {
     pub fn parse(bytes: &[u8]) -> Self {
+        Self::try_parse(bytes).unwrap()
+    }
+
+    /// Parse reply data, failing if it is truncated.
+    pub fn try_parse(bytes: &[u8]) -> io::Result<Self> {
         let mut cursor = Cursor::new(bytes);
 
-        let reply_to = read_guid(&mut cursor).unwrap();
+        let reply_to = read_guid(&mut cursor)?;
 
-        let name_server_id = cursor.read_i32::<LE>().unwrap();
-        let message_size = cursor.read_i32::<LE>().unwrap();
-        let mut message = vec![0; message_size as usize];
-        cursor.read_exact(&mut message).unwrap();
+        let name_server_id = cursor.read_i32::<LE>()?;
+        let message = read_message(&mut cursor)?;
 
-        Self {
+        Ok(Self {
             reply_to,
             name_server_id,
             message,
+        })
+    }
+
+    /// Encode reply data the way dprun sends it, the inverse of `parse`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24 + self.message.len());
+        bytes.write_all(self.reply_to.as_bytes()).unwrap();
+        bytes.write_i32::<LE>(self.name_server_id).unwrap();
+        write_message(&mut bytes, &self.message).unwrap();
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_data(flags: i32) -> SendData {
+        SendData {
+            flags,
+            receiver_id: None,
+            sender_id: Uuid::from_u128(1),
+            system_message: false,
+            message: b"data".to_vec(),
         }
     }
+
+    #[test]
+    fn decodes_send_flags() {
+        let guaranteed = SendData::try_parse(&send_data(DPSEND_GUARANTEED).to_bytes()).unwrap();
+        assert!(guaranteed.guaranteed());
+        assert!(!guaranteed.high_priority());
+        assert_eq!(guaranteed.delivery(), Delivery::Guaranteed);
+
+        let urgent = send_data(DPSEND_HIGHPRIORITY | DPSEND_SIGNED);
+        assert!(urgent.high_priority());
+        assert_eq!(urgent.delivery(), Delivery::Unreliable);
+
+        let both = send_data(DPSEND_GUARANTEED | DPSEND_HIGHPRIORITY | DPSEND_ENCRYPTED);
+        assert_eq!(both.delivery(), Delivery::Guaranteed);
+        assert_eq!(send_data(0).delivery(), Delivery::Unreliable);
+    }
 }
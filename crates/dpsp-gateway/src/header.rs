@@ -0,0 +1,96 @@
+//! The header that DirectPlay's TCP/IP service provider puts in front of every message.
+//!
+//! The header is 20 bytes: the size of the whole message including the header in the low 20
+//! bits of a little endian u32 and a token in the high 12 bits, followed by a Windows
+//! `sockaddr_in` with the address that the sender wants replies on. The DirectPlay message
+//! follows, starting with its "play" signature.
+
+use async_std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub(crate) const HEADER_SIZE: usize = 20;
+/// Largest message that fits in the 20 bit size field.
+pub(crate) const MAX_MESSAGE_SIZE: usize = (1 << 20) - 1;
+/// Token for messages from a remote machine.
+const TOKEN_REMOTE: u32 = 0xFAB;
+const AF_INET: u16 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Header {
+    /// Size of the whole message, including this header.
+    pub size: usize,
+    /// Where the sender wants replies to go. An unspecified IP means the address the message
+    /// came from.
+    pub reply_to: SocketAddrV4,
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid DirectPlay message header",
+    )
+}
+
+/// Put a header in front of a DirectPlay message.
+pub(crate) fn frame(reply_to: SocketAddrV4, message: &[u8]) -> io::Result<Vec<u8>> {
+    let size = HEADER_SIZE + message.len();
+    if size > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "DirectPlay message too large",
+        ));
+    }
+    let mut bytes = Vec::with_capacity(size);
+    bytes.extend_from_slice(&(size as u32 | TOKEN_REMOTE << 20).to_le_bytes());
+    bytes.extend_from_slice(&AF_INET.to_le_bytes());
+    // sockaddr_in stores the port and address in network byte order.
+    bytes.extend_from_slice(&reply_to.port().to_be_bytes());
+    bytes.extend_from_slice(&reply_to.ip().octets());
+    bytes.extend_from_slice(&[0; 8]);
+    bytes.extend_from_slice(message);
+    Ok(bytes)
+}
+
+/// Read the size from the first four bytes of a message.
+pub(crate) fn message_size(bytes: [u8; 4]) -> usize {
+    (u32::from_le_bytes(bytes) & MAX_MESSAGE_SIZE as u32) as usize
+}
+
+/// Split a message into its header and the DirectPlay message.
+pub(crate) fn split(bytes: &[u8]) -> io::Result<(Header, &[u8])> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(invalid());
+    }
+    let size = message_size([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    if size < HEADER_SIZE || size > bytes.len() {
+        return Err(invalid());
+    }
+    let port = u16::from_be_bytes([bytes[6], bytes[7]]);
+    let ip = Ipv4Addr::new(bytes[8], bytes[9], bytes[10], bytes[11]);
+    let header = Header {
+        size,
+        reply_to: SocketAddrV4::new(ip, port),
+    };
+    Ok((header, &bytes[HEADER_SIZE..size]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_and_splits_messages() {
+        let reply_to = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 2), 2300);
+        let bytes = frame(reply_to, b"play\x02\x00\x0e\x00").unwrap();
+        assert_eq!(
+            &bytes[..HEADER_SIZE],
+            &[28, 0, 0xB0, 0xFA, 2, 0, 0x08, 0xFC, 192, 168, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        let (header, message) = split(&bytes).unwrap();
+        assert_eq!(header, Header { size: 28, reply_to });
+        assert_eq!(message, b"play\x02\x00\x0e\x00");
+
+        assert!(split(&bytes[..10]).is_err());
+        assert!(split(&bytes[..27]).is_err());
+    }
+}
@@ -0,0 +1,486 @@
+//! Service provider that lets stock DirectPlay clients, using the TCP/IP service provider that
+//! ships with Windows, join sessions hosted through DPRUN.
+//!
+//! The gateway listens where a native TCP/IP host would: UDP and TCP port 47624 for enumeration
+//! requests, and a stream port (2300 by default) for everything else. It passes native messages
+//! on to the local game, and sends the game's messages for native clients back with the header
+//! that the TCP/IP service provider expects.
+//!
+//! Other DPRUN peers can be in the same session: messages for them go to another service
+//! provider, set with `with_provider`.
+//!
+//! Native clients are known to the game by a GUID made of the 16 bytes of their stream
+//! `sockaddr_in`, which is also how the TCP/IP service provider's player data starts. That way
+//! messages that the game addresses to native players can be routed without further
+//! bookkeeping.
+//!
+//! Limitations:
+//!
+//! - Only the DPRUN side can host; the gateway does not look for sessions hosted by native
+//!   games.
+//! - Unreliable messages to native clients are sent over TCP too, because we don't know the
+//!   datagram address that they announced in their player data.
+//! - Service provider data inside DirectPlay messages, like the addresses of players, is passed
+//!   through unchanged. Native clients learn where DPRUN players are from it, so they must send
+//!   to them through the host.
+
+mod header;
+mod network;
+
+use async_std::io;
+use async_std::net::{TcpListener, UdpSocket};
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use dprun::session::Command;
+use dprun::{structs, AppController, ServiceProvider, GUID};
+use futures::channel::mpsc::{self, UnboundedSender};
+use network::{Network, Sockets};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+/// The port that native clients send enumeration requests to.
+pub const DEFAULT_ENUM_PORT: u16 = 47624;
+/// The port that native hosts receive other messages on, from the range that DirectPlay uses.
+pub const DEFAULT_STREAM_PORT: u16 = 2300;
+
+const AF_INET: [u8; 2] = [2, 0];
+
+/// The GUID that the game knows a native client by: the bytes of its `sockaddr_in`.
+fn native_guid(address: SocketAddrV4) -> GUID {
+    let mut bytes = [0; 16];
+    bytes[0..2].copy_from_slice(&AF_INET);
+    bytes[2..4].copy_from_slice(&address.port().to_be_bytes());
+    bytes[4..8].copy_from_slice(&address.ip().octets());
+    GUID::from_bytes(bytes)
+}
+
+/// The address of a native client, if the GUID belongs to one.
+fn native_address(guid: GUID) -> Option<SocketAddrV4> {
+    let bytes = guid.as_bytes();
+    if bytes[0..2] != AF_INET || bytes[8..16] != [0; 8] {
+        return None;
+    }
+    let port = u16::from_be_bytes([bytes[2], bytes[3]]);
+    let ip = Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7]);
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// The socket task and the channel to send it commands.
+struct RunningNetwork {
+    enum_addr: SocketAddr,
+    stream_addr: SocketAddr,
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+pub struct GatewaySP {
+    bind_ip: Ipv4Addr,
+    enum_port: u16,
+    stream_port: u16,
+    advertised_address: Option<SocketAddrV4>,
+    provider: Option<Box<dyn ServiceProvider>>,
+    network: Option<RunningNetwork>,
+}
+
+impl Default for GatewaySP {
+    fn default() -> Self {
+        Self {
+            bind_ip: Ipv4Addr::UNSPECIFIED,
+            enum_port: DEFAULT_ENUM_PORT,
+            stream_port: DEFAULT_STREAM_PORT,
+            advertised_address: None,
+            provider: None,
+            network: None,
+        }
+    }
+}
+
+impl GatewaySP {
+    /// Set the IP address to listen on. Defaults to all interfaces.
+    pub fn with_bind_ip(self, bind_ip: Ipv4Addr) -> Self {
+        Self { bind_ip, ..self }
+    }
+
+    /// Set the port to receive enumeration requests on. Native clients only send them to
+    /// 47624, so this is mostly useful for tests.
+    pub fn with_enum_port(self, enum_port: u16) -> Self {
+        Self { enum_port, ..self }
+    }
+
+    /// Set the port to receive other messages from native clients on.
+    pub fn with_stream_port(self, stream_port: u16) -> Self {
+        Self {
+            stream_port,
+            ..self
+        }
+    }
+
+    /// Set the address that native clients should send messages to, if it is not the one we
+    /// listen on, like behind NAT. By default we leave the IP out of our messages, so clients
+    /// use the address the messages came from.
+    pub fn with_advertised_address(self, advertised_address: SocketAddrV4) -> Self {
+        Self {
+            advertised_address: Some(advertised_address),
+            ..self
+        }
+    }
+
+    /// Set the service provider for DPRUN peers in the same session.
+    pub fn with_provider(self, provider: Box<dyn ServiceProvider>) -> Self {
+        Self {
+            provider: Some(provider),
+            ..self
+        }
+    }
+
+    /// The address that enumeration requests are received on. Binds the sockets if that did
+    /// not happen yet.
+    pub async fn enum_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.network().await?.enum_addr)
+    }
+
+    /// The address that other messages from native clients are received on. Binds the sockets
+    /// if that did not happen yet.
+    pub async fn stream_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.network().await?.stream_addr)
+    }
+
+    async fn network(&mut self) -> io::Result<&RunningNetwork> {
+        if self.network.is_none() {
+            // The UDP and TCP sockets share a port number, so when asked for any free port,
+            // bind the first one and reuse its port for the second.
+            let enum_datagrams = UdpSocket::bind((self.bind_ip, self.enum_port)).await?;
+            let enum_addr = enum_datagrams.local_addr()?;
+            let enum_listener = TcpListener::bind(enum_addr).await?;
+            let stream_listener = TcpListener::bind((self.bind_ip, self.stream_port)).await?;
+            let stream_addr = stream_listener.local_addr()?;
+            let datagrams = UdpSocket::bind(stream_addr).await?;
+            log::debug!(
+                "[gateway] listening on {} for enumeration and {} for messages",
+                enum_addr,
+                stream_addr
+            );
+
+            let advertised_address = self
+                .advertised_address
+                .unwrap_or_else(|| SocketAddrV4::new(self.bind_ip, stream_addr.port()));
+            let sockets = Sockets {
+                enum_datagrams,
+                enum_listener,
+                stream_listener,
+                datagrams,
+            };
+            let (commands, receiver) = mpsc::unbounded();
+            let task = task::spawn(Network::new(advertised_address).run(sockets, receiver));
+            self.network = Some(RunningNetwork {
+                enum_addr,
+                stream_addr,
+                commands,
+                task,
+            });
+        }
+        Ok(self.network.as_ref().unwrap())
+    }
+
+    /// Send a command to the socket task, starting it if necessary.
+    async fn command(&mut self, command: Command) -> io::Result<()> {
+        self.network()
+            .await?
+            .commands
+            .unbounded_send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "gateway sockets stopped"))
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for GatewaySP {
+    async fn enum_sessions(
+        &mut self,
+        controller: AppController,
+        id: u32,
+        data: structs::EnumSessionsData,
+    ) -> io::Result<()> {
+        match &mut self.provider {
+            Some(provider) => provider.enum_sessions(controller, id, data).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn open(
+        &mut self,
+        controller: AppController,
+        id: u32,
+        data: structs::OpenData,
+    ) -> io::Result<()> {
+        let (create, migrate_host) = (data.create, data.migrate_host());
+        if let Some(provider) = &mut self.provider {
+            provider.open(controller.clone(), id, data).await?;
+        }
+        self.command(Command::Open {
+            controller,
+            create,
+            migrate_host,
+        })
+        .await
+    }
+
+    async fn create_player(
+        &mut self,
+        controller: AppController,
+        id: u32,
+        data: structs::CreatePlayerData,
+    ) -> io::Result<()> {
+        // Native clients tell each other about their players in DirectPlay messages.
+        match &mut self.provider {
+            Some(provider) => provider.create_player(controller, id, data).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn reply(
+        &mut self,
+        controller: AppController,
+        id: u32,
+        data: structs::ReplyData,
+    ) -> io::Result<()> {
+        match (native_address(data.reply_to), &mut self.provider) {
+            (None, Some(provider)) => provider.reply(controller, id, data).await,
+            (None, None) => Ok(()),
+            (Some(_), _) => {
+                self.command(Command::Reply {
+                    controller,
+                    reply_to: data.reply_to,
+                    message: data.message,
+                })
+                .await
+            }
+        }
+    }
+
+    async fn send(
+        &mut self,
+        controller: AppController,
+        id: u32,
+        data: structs::SendData,
+    ) -> io::Result<()> {
+        // Messages without a receiver go to everyone, on both sides.
+        let (to_native, to_provider) = match data.receiver_id.map(native_address) {
+            None => (true, true),
+            Some(Some(_)) => (true, false),
+            Some(None) => (false, true),
+        };
+        if to_provider {
+            if let Some(provider) = &mut self.provider {
+                let copy = structs::SendData {
+                    message: data.message.clone(),
+                    ..data
+                };
+                provider.send(controller.clone(), id, copy).await?;
+            }
+        }
+        if to_native {
+            self.command(Command::Send {
+                controller,
+                sender: data.sender_id,
+                receiver: data.receiver_id,
+                delivery: data.delivery(),
+                message: data.message,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn disconnected(&mut self, controller: AppController) -> io::Result<()> {
+        match &mut self.provider {
+            Some(provider) => provider.disconnected(controller).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(RunningNetwork { commands, task, .. }) = self.network.take() {
+            drop(commands);
+            task.await;
+        }
+        match &mut self.provider {
+            Some(provider) => provider.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::io::prelude::*;
+    use async_std::net::TcpStream;
+    use dprun::structs::DPSEND_GUARANTEED;
+    use dprun::AppMessage;
+    use std::time::Duration;
+
+    const HOST: GUID = GUID::from_u128(1);
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> Option<Vec<u8>> {
+        match timeout(Duration::from_secs(2), receiver.recv()).await {
+            Ok(Ok(AppMessage::Send(_, _, data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Read a message with its header from a native client's connection.
+    async fn read_native(stream: &mut TcpStream) -> (header::Header, Vec<u8>) {
+        let mut bytes = vec![0; 4];
+        stream.read_exact(&mut bytes).await.unwrap();
+        let size = header::message_size([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        bytes.resize(size, 0);
+        stream.read_exact(&mut bytes[4..]).await.unwrap();
+        let (header, message) = header::split(&bytes).unwrap();
+        (header, message.to_vec())
+    }
+
+    fn from_host(data: &[u8]) -> Vec<u8> {
+        let mut message = HOST.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn native_clients_find_and_talk_to_the_host() {
+        task::block_on(async {
+            let localhost = Ipv4Addr::new(127, 0, 0, 1);
+            let mut gateway = GatewaySP::default()
+                .with_bind_ip(localhost)
+                .with_enum_port(0)
+                .with_stream_port(0);
+            let enum_addr = gateway.enum_addr().await.unwrap();
+            let stream_addr = gateway.stream_addr().await.unwrap();
+            let (controller, receiver) = AppController::create();
+            let open = structs::OpenData {
+                create: true,
+                return_status: false,
+                open_flags: 0,
+                session_flags: 0,
+            };
+            gateway.open(controller.clone(), 0, open).await.unwrap();
+
+            // The client leaves its IP out, like DirectPlay does.
+            let client = TcpListener::bind((localhost, 0)).await.unwrap();
+            let client_port = client.local_addr().unwrap().port();
+            let client_id = native_guid(SocketAddrV4::new(localhost, client_port));
+            let mut request = b"play\x02\x00\x0e\x00".to_vec();
+            request.extend_from_slice(GUID::from_u128(2).as_bytes());
+            request.extend_from_slice(&0u32.to_le_bytes());
+            let socket = UdpSocket::bind((localhost, 0)).await.unwrap();
+            let packet = header::frame(
+                SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, client_port),
+                &request,
+            )
+            .unwrap();
+            socket.send_to(&packet, enum_addr).await.unwrap();
+
+            let mut expected = client_id.as_bytes().to_vec();
+            expected.extend_from_slice(&request);
+            assert_eq!(next_message(&receiver).await, Some(expected));
+
+            // The answer comes in over TCP, telling the client where to send the rest.
+            let reply = structs::ReplyData {
+                reply_to: client_id,
+                name_server_id: 0,
+                message: from_host(b"play session description"),
+            };
+            gateway.reply(controller.clone(), 0, reply).await.unwrap();
+            let (mut from_gateway, _) = client.accept().await.unwrap();
+            let (header, message) = read_native(&mut from_gateway).await;
+            assert_eq!(header.reply_to.port(), stream_addr.port());
+            assert_eq!(message, b"play session description");
+
+            let mut to_gateway = TcpStream::connect(stream_addr).await.unwrap();
+            let packet = header::frame(
+                SocketAddrV4::new(localhost, client_port),
+                b"play hello host",
+            )
+            .unwrap();
+            to_gateway.write_all(&packet).await.unwrap();
+            let mut expected = client_id.as_bytes().to_vec();
+            expected.extend_from_slice(b"play hello host");
+            assert_eq!(next_message(&receiver).await, Some(expected));
+
+            for receiver_id in &[Some(client_id), None] {
+                let data = structs::SendData {
+                    flags: DPSEND_GUARANTEED,
+                    receiver_id: *receiver_id,
+                    sender_id: HOST,
+                    system_message: false,
+                    message: from_host(b"play hello client"),
+                };
+                gateway.send(controller.clone(), 0, data).await.unwrap();
+                let (_, message) = read_native(&mut from_gateway).await;
+                assert_eq!(message, b"play hello client");
+            }
+
+            gateway.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn knows_clients_by_their_connection() {
+        task::block_on(async {
+            let localhost = Ipv4Addr::new(127, 0, 0, 1);
+            let mut gateway = GatewaySP::default()
+                .with_bind_ip(localhost)
+                .with_enum_port(0)
+                .with_stream_port(0);
+            let stream_addr = gateway.stream_addr().await.unwrap();
+            let (controller, receiver) = AppController::create();
+            let open = structs::OpenData {
+                create: true,
+                return_status: false,
+                open_flags: 0,
+                session_flags: 0,
+            };
+            gateway.open(controller.clone(), 0, open).await.unwrap();
+
+            // A client claiming to be another machine is known by the address it connected
+            // from.
+            let client = TcpListener::bind((localhost, 0)).await.unwrap();
+            let client_port = client.local_addr().unwrap().port();
+            let client_id = native_guid(SocketAddrV4::new(localhost, client_port));
+            let mut to_gateway = TcpStream::connect(stream_addr).await.unwrap();
+            let spoofed = SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), client_port);
+            let packet = header::frame(spoofed, b"play hello host").unwrap();
+            to_gateway.write_all(&packet).await.unwrap();
+            let mut expected = client_id.as_bytes().to_vec();
+            expected.extend_from_slice(b"play hello host");
+            assert_eq!(next_message(&receiver).await, Some(expected));
+
+            let to_everyone = || structs::SendData {
+                flags: DPSEND_GUARANTEED,
+                receiver_id: None,
+                sender_id: HOST,
+                system_message: false,
+                message: from_host(b"play hello everyone"),
+            };
+            gateway
+                .send(controller.clone(), 0, to_everyone())
+                .await
+                .unwrap();
+            let (mut from_gateway, _) = client.accept().await.unwrap();
+            let (_, message) = read_native(&mut from_gateway).await;
+            assert_eq!(message, b"play hello everyone");
+
+            // Once the client's connection closes, it is gone from the session.
+            drop(to_gateway);
+            task::sleep(Duration::from_millis(200)).await;
+            gateway
+                .send(controller.clone(), 0, to_everyone())
+                .await
+                .unwrap();
+            let mut buffer = [0; 1];
+            let read = timeout(Duration::from_secs(2), from_gateway.read(&mut buffer)).await;
+            assert_eq!(read.unwrap().unwrap(), 0);
+
+            gateway.shutdown().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,285 @@
+//! The native side of the gateway: sockets that speak DirectPlay's TCP/IP protocol, running in
+//! their own task.
+//!
+//! Stock DirectPlay clients broadcast enumeration requests to UDP port 47624, and the host
+//! answers over TCP at the address in the request's header. After that, clients send their
+//! messages over TCP, or UDP if they may be dropped, to the address in the header of our
+//! messages. We listen on all of them and pass the DirectPlay messages on to the game.
+
+use crate::header::{self, Header, HEADER_SIZE};
+use crate::{native_address, native_guid};
+use async_std::io::prelude::*;
+use async_std::net::{TcpListener, TcpStream, UdpSocket};
+use async_std::task;
+use dprun::inspect::{parse_message, Command as ProtocolCommand};
+use dprun::session::{Command, Outgoing, Route, Session};
+use dprun::{Delivery, GUID};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::stream::StreamExt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+
+/// Largest datagram we can receive.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// The sockets that native clients talk to.
+pub(crate) struct Sockets {
+    pub enum_datagrams: UdpSocket,
+    pub enum_listener: TcpListener,
+    pub stream_listener: TcpListener,
+    pub datagrams: UdpSocket,
+}
+
+/// Something that a native client did.
+enum Incoming {
+    /// The client sent a message. Guaranteed messages come in over a TCP connection, and `from`
+    /// is the address of its other end.
+    Message {
+        from: SocketAddr,
+        delivery: Delivery,
+        bytes: Vec<u8>,
+    },
+    /// A TCP connection from a client closed.
+    Closed(SocketAddr),
+}
+
+/// Read messages from a TCP connection until it closes.
+async fn read_stream(mut stream: TcpStream, incoming: UnboundedSender<Incoming>) {
+    let from = match stream.peer_addr() {
+        Ok(address) => address,
+        Err(_) => return,
+    };
+    loop {
+        let mut size = [0; 4];
+        if stream.read_exact(&mut size).await.is_err() {
+            break;
+        }
+        let size = header::message_size(size);
+        if size < HEADER_SIZE {
+            log::warn!("[gateway] bad message size from {}", from);
+            break;
+        }
+        let mut bytes = vec![0; size];
+        bytes[..4].copy_from_slice(&(size as u32).to_le_bytes());
+        if stream.read_exact(&mut bytes[4..]).await.is_err() {
+            break;
+        }
+        let message = Incoming::Message {
+            from,
+            delivery: Delivery::Guaranteed,
+            bytes,
+        };
+        if incoming.unbounded_send(message).is_err() {
+            break;
+        }
+    }
+    let _ = incoming.unbounded_send(Incoming::Closed(from));
+}
+
+/// Accept TCP connections until the gateway stops.
+async fn accept(listener: TcpListener, incoming: UnboundedSender<Incoming>) {
+    while !incoming.is_closed() {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                task::spawn(read_stream(stream, incoming.clone()));
+            }
+            Err(err) => log::debug!("[gateway] accept error: {:?}", err),
+        }
+    }
+}
+
+/// Receive datagrams until the gateway stops.
+async fn receive(socket: UdpSocket, incoming: UnboundedSender<Incoming>) {
+    let mut buffer = vec![0; MAX_PACKET_SIZE];
+    while !incoming.is_closed() {
+        if let Ok((size, from)) = socket.recv_from(&mut buffer).await {
+            let message = Incoming::Message {
+                from,
+                delivery: Delivery::Unreliable,
+                bytes: buffer[..size].to_vec(),
+            };
+            let _ = incoming.unbounded_send(message);
+        }
+    }
+}
+
+/// Send messages to a native client over TCP, connecting when necessary.
+async fn write_stream(address: SocketAddrV4, mut messages: UnboundedReceiver<Vec<u8>>) {
+    let mut stream: Option<TcpStream> = None;
+    while let Some(message) = messages.next().await {
+        if stream.is_none() {
+            match TcpStream::connect(address).await {
+                Ok(connected) => {
+                    let _ = connected.set_nodelay(true);
+                    stream = Some(connected);
+                }
+                Err(err) => {
+                    log::warn!("[gateway] could not connect to {}: {:?}", address, err);
+                    continue;
+                }
+            }
+        }
+        if let Some(connected) = &mut stream {
+            if let Err(err) = connected.write_all(&message).await {
+                log::warn!("[gateway] could not send to {}: {:?}", address, err);
+                stream = None;
+            }
+        }
+    }
+}
+
+pub(crate) struct Network {
+    /// The address that native clients should send messages to, put in our headers.
+    advertised_address: SocketAddrV4,
+    /// The session, with the addresses of native clients that we heard from.
+    session: Session<SocketAddrV4>,
+    /// The clients that TCP connections came from, by the address of the connection.
+    connections: HashMap<SocketAddr, SocketAddrV4>,
+    /// Connections to native clients.
+    streams: HashMap<SocketAddrV4, UnboundedSender<Vec<u8>>>,
+}
+
+impl Network {
+    pub fn new(advertised_address: SocketAddrV4) -> Self {
+        Self {
+            advertised_address,
+            session: Session::default(),
+            connections: HashMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Drive the sockets until the service provider goes away.
+    pub async fn run(mut self, sockets: Sockets, commands: UnboundedReceiver<Command>) {
+        let (sender, incoming) = mpsc::unbounded();
+        let tasks = vec![
+            task::spawn(receive(sockets.enum_datagrams, sender.clone())),
+            task::spawn(receive(sockets.datagrams, sender.clone())),
+            task::spawn(accept(sockets.enum_listener, sender.clone())),
+            task::spawn(accept(sockets.stream_listener, sender.clone())),
+        ];
+
+        let mut incoming = incoming.fuse();
+        let mut commands = commands.fuse();
+        loop {
+            futures::select! {
+                command = commands.next() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                message = incoming.next() => {
+                    if let Some(message) = message {
+                        self.handle_incoming(message).await;
+                    }
+                }
+            }
+        }
+
+        log::debug!("[gateway] service provider is gone, closing sockets");
+        // The socket tasks are blocked on their sockets, so they would not notice that the
+        // channel is closed.
+        sender.close_channel();
+        for task in tasks {
+            task.cancel().await;
+        }
+    }
+
+    /// Send a DirectPlay message, without the GUID that the game put in front of it, to a
+    /// native client.
+    fn send(&mut self, address: SocketAddrV4, message: &[u8]) {
+        let message = match message.get(16..) {
+            Some(message) => message,
+            None => return log::warn!("[gateway] message from game is too short"),
+        };
+        let bytes = match header::frame(self.advertised_address, message) {
+            Ok(bytes) => bytes,
+            Err(err) => return log::warn!("[gateway] could not send message: {:?}", err),
+        };
+        let stream = self.streams.entry(address).or_insert_with(|| {
+            let (sender, receiver) = mpsc::unbounded();
+            task::spawn(write_stream(address, receiver));
+            sender
+        });
+        let _ = stream.unbounded_send(bytes);
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match self.session.command(command) {
+            Some(Outgoing::EnumReply(address, message)) => self.send(address, &message),
+            Some(Outgoing::Game {
+                route,
+                receiver,
+                data,
+                ..
+            }) => {
+                let addresses = match route {
+                    Route::Peer(address) => vec![address],
+                    Route::Everyone => self.session.peers(),
+                    // Native clients that we did not hear from yet can still be found by their
+                    // GUID.
+                    Route::Nowhere => receiver.and_then(native_address).into_iter().collect(),
+                };
+                for address in addresses {
+                    self.send(address, &data);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    async fn handle_incoming(&mut self, incoming: Incoming) {
+        match incoming {
+            Incoming::Message {
+                from,
+                delivery,
+                bytes,
+            } => self.handle_message(from, delivery, &bytes).await,
+            Incoming::Closed(from) => {
+                let client = match self.connections.remove(&from) {
+                    Some(client) => client,
+                    None => return,
+                };
+                // Clients may open more than one connection.
+                if !self.connections.values().any(|&other| other == client) {
+                    log::debug!("[gateway] {} disconnected", client);
+                    self.streams.remove(&client);
+                    self.session.peer_lost(client).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, from: SocketAddr, delivery: Delivery, bytes: &[u8]) {
+        let (Header { reply_to, .. }, message) = match header::split(bytes) {
+            Ok(split) => split,
+            Err(err) => return log::debug!("[gateway] bad message from {}: {:?}", from, err),
+        };
+        if !message.starts_with(b"play") {
+            return log::debug!("[gateway] not a DirectPlay message from {}", from);
+        }
+        // Clients know where they listen, but the IP they claim is not to be trusted: take the
+        // one that the message came from, so nobody can pose as another machine.
+        let client_address = match from.ip() {
+            IpAddr::V4(ip) => SocketAddrV4::new(ip, reply_to.port()),
+            IpAddr::V6(_) => return log::debug!("[gateway] message from IPv6 address {}", from),
+        };
+        if delivery == Delivery::Guaranteed {
+            self.connections.insert(from, client_address);
+        }
+        let client = native_guid(client_address);
+
+        let enum_request = match parse_message(message) {
+            Ok(parsed) => matches!(parsed.body, ProtocolCommand::EnumSessions(..)),
+            Err(_) => false,
+        };
+        let mut data = client.as_bytes().to_vec();
+        data.extend_from_slice(message);
+        if enum_request {
+            self.session.enum_request(client_address, data).await;
+        } else {
+            self.session
+                .game_message(client_address, GUID::nil(), delivery, data)
+                .await;
+        }
+    }
+}
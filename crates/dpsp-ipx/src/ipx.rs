@@ -0,0 +1,140 @@
+//! IPX packets, as they are tunneled through UDP in RFC 1234.
+//!
+//! Every UDP datagram carries one IPX packet: a 30 byte header with the destination and source
+//! addresses, followed by the data. Addresses are a network number, the node address of a
+//! machine, and a socket number on that machine.
+
+use async_std::io;
+use std::convert::TryInto;
+use std::fmt;
+
+const HEADER_SIZE: usize = 30;
+/// IPX does not use checksums; the field is always all ones.
+const NO_CHECKSUM: u16 = 0xFFFF;
+/// Packet type for plain IPX packets.
+const PACKET_TYPE_IPX: u8 = 4;
+
+/// The address of a machine on an IPX network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node(pub [u8; 6]);
+
+impl Node {
+    /// Packets for the broadcast node go to every machine on the network.
+    pub const BROADCAST: Node = Node([0xFF; 6]);
+
+    /// A random node address. The locally administered bit is set, as it is for MAC addresses
+    /// that were not assigned by a manufacturer.
+    pub fn random() -> Self {
+        let mut node: [u8; 6] = rand::random();
+        node[0] = (node[0] | 0x02) & !0x01;
+        Node(node)
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// A full IPX address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Address {
+    pub network: [u8; 4],
+    pub node: Node,
+    pub socket: u16,
+}
+
+impl Address {
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.network);
+        bytes.extend_from_slice(&self.node.0);
+        bytes.extend_from_slice(&self.socket.to_be_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        Self {
+            network: bytes[0..4].try_into().unwrap(),
+            node: Node(bytes[4..10].try_into().unwrap()),
+            socket: u16::from_be_bytes(bytes[10..12].try_into().unwrap()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Packet {
+    pub destination: Address,
+    pub source: Address,
+    pub data: Vec<u8>,
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid IPX packet")
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.data.len());
+        bytes.extend_from_slice(&NO_CHECKSUM.to_be_bytes());
+        bytes.extend_from_slice(&((HEADER_SIZE + self.data.len()) as u16).to_be_bytes());
+        // Transport control: the number of routers the packet went through.
+        bytes.push(0);
+        bytes.push(PACKET_TYPE_IPX);
+        self.destination.encode_into(&mut bytes);
+        self.source.encode_into(&mut bytes);
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid());
+        }
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if length < HEADER_SIZE || length > bytes.len() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            destination: Address::decode(&bytes[6..18]),
+            source: Address::decode(&bytes[18..30]),
+            data: bytes[HEADER_SIZE..length].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_packets() {
+        let packet = Packet {
+            destination: Address {
+                network: [0; 4],
+                node: Node::BROADCAST,
+                socket: 0x5000,
+            },
+            source: Address {
+                network: [0; 4],
+                node: Node([2, 0, 0, 0, 0, 1]),
+                socket: 0x5000,
+            },
+            data: b"hello".to_vec(),
+        };
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), 35);
+        assert_eq!(&bytes[0..6], &[0xFF, 0xFF, 0, 35, 0, 4]);
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+
+        assert!(Packet::decode(&bytes[..20]).is_err());
+        assert!(Packet::decode(&bytes[..34]).is_err());
+        let node = Node::random();
+        assert_eq!(node.0[0] & 0x03, 0x02);
+        assert_eq!(Node([2, 0, 0, 0, 0, 0x1f]).to_string(), "02:00:00:00:00:1f");
+    }
+}
@@ -0,0 +1,473 @@
+//! Service provider for LAN play that finds sessions and addresses peers the way DirectPlay's
+//! IPX service provider does, tunneling IPX packets through UDP.
+//!
+//! Games on an IPX network need no addresses: joining players broadcast to find sessions, and
+//! every machine is known by its node address. This provider does the same on a LAN, with IPX
+//! packets in UDP datagrams as in RFC 1234. Enumeration requests go to the broadcast address, and
+//! everything else to the node it is addressed to.
+//!
+//! Like IPX itself, this provider does not make sure that packets arrive. LANs rarely lose
+//! packets, but games that need more should use the UDP or QUIC service providers. Peers in a
+//! session send each other keep-alives, so a peer that goes away is noticed even when the game has
+//! nothing to say.
+//!
+//! This is a DPRUN service provider, not an emulation of the IPX one: the game is started with
+//! the DPRUN service provider as usual, and only talks to other DPRUN peers that use this
+//! provider. It does not interoperate with games using DirectPlay's own IPX service provider,
+//! and broadcasts don't leave the local network, so it is for LAN play only.
+
+mod ipx;
+mod message;
+mod network;
+
+use async_std::io;
+use async_std::net::UdpSocket;
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use dprun::session::Command;
+use dprun::{structs, AppController, ServiceProvider};
+use futures::channel::mpsc::{self, UnboundedSender};
+pub use ipx::Node;
+use network::{Config, Network};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The UDP port that peers send IPX packets to. RFC 1234 uses port 213, which needs privileges
+/// on most systems.
+pub const DEFAULT_PORT: u16 = 2213;
+
+/// Player flag: the player was created by the local game.
+const DPLAYI_PLAYER_LOCAL: i32 = 8;
+
+/// The socket task and the channel to send it commands.
+struct RunningNetwork {
+    local_addr: SocketAddr,
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+pub struct IpxSP {
+    port: u16,
+    node: Node,
+    broadcast_addresses: Option<Vec<SocketAddr>>,
+    peer_timeout: Duration,
+    network: Option<RunningNetwork>,
+}
+
+impl Default for IpxSP {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            node: Node::random(),
+            broadcast_addresses: None,
+            peer_timeout: Duration::from_secs(10),
+            network: None,
+        }
+    }
+}
+
+impl IpxSP {
+    /// Set the UDP port to receive packets on. Peers on the network should all use the same
+    /// one, because it is also where broadcasts go.
+    pub fn with_port(self, port: u16) -> Self {
+        Self { port, ..self }
+    }
+
+    /// Set our node address, instead of a random one.
+    pub fn with_node(self, node: Node) -> Self {
+        Self { node, ..self }
+    }
+
+    /// Set where to send packets for every node on the network. Defaults to the broadcast
+    /// address of the local network, at the same port we receive packets on.
+    pub fn with_broadcast_addresses(self, broadcast_addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            broadcast_addresses: Some(broadcast_addresses),
+            ..self
+        }
+    }
+
+    /// Set how long a peer may stay silent before it counts as gone. If the host goes silent,
+    /// the game's connection is closed, unless the session lets another peer take over.
+    pub fn with_peer_timeout(self, peer_timeout: Duration) -> Self {
+        Self {
+            peer_timeout,
+            ..self
+        }
+    }
+
+    /// Our node address, which other peers know us by.
+    pub fn node(&self) -> Node {
+        self.node
+    }
+
+    /// The address that packets are received on. Binds the socket if that did not happen yet.
+    pub async fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.network().await?.local_addr)
+    }
+
+    async fn network(&mut self) -> io::Result<&RunningNetwork> {
+        if self.network.is_none() {
+            let bind_address: SocketAddr = ([0, 0, 0, 0], self.port).into();
+            let socket = UdpSocket::bind(bind_address).await?;
+            socket.set_broadcast(true)?;
+            let local_addr = socket.local_addr()?;
+            log::debug!("[ipx] node {} listening on {}", self.node, local_addr);
+            let config = Config {
+                node: self.node,
+                broadcast_addresses: self
+                    .broadcast_addresses
+                    .clone()
+                    .unwrap_or_else(|| vec![([255, 255, 255, 255], local_addr.port()).into()]),
+                peer_timeout: self.peer_timeout,
+            };
+            let (commands, receiver) = mpsc::unbounded();
+            let task = task::spawn(Network::new(socket, config).run(receiver));
+            self.network = Some(RunningNetwork {
+                local_addr,
+                commands,
+                task,
+            });
+        }
+        Ok(self.network.as_ref().unwrap())
+    }
+
+    /// Send a command to the socket task, starting it if necessary.
+    async fn command(&mut self, command: Command) -> io::Result<()> {
+        self.network()
+            .await?
+            .commands
+            .unbounded_send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "IPX socket stopped"))
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for IpxSP {
+    async fn enum_sessions(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::EnumSessionsData,
+    ) -> io::Result<()> {
+        self.command(Command::EnumSessions {
+            controller,
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn open(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::OpenData,
+    ) -> io::Result<()> {
+        self.command(Command::Open {
+            controller,
+            create: data.create,
+            migrate_host: data.migrate_host(),
+        })
+        .await
+    }
+
+    async fn create_player(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::CreatePlayerData,
+    ) -> io::Result<()> {
+        // DirectPlay also tells us about players on other peers; those are announced by the
+        // peer that created them.
+        if data.flags & DPLAYI_PLAYER_LOCAL == 0 {
+            return Ok(());
+        }
+        self.command(Command::CreatePlayer {
+            controller,
+            player: data.player_guid,
+        })
+        .await
+    }
+
+    async fn reply(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::ReplyData,
+    ) -> io::Result<()> {
+        self.command(Command::Reply {
+            controller,
+            reply_to: data.reply_to,
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn send(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::SendData,
+    ) -> io::Result<()> {
+        self.command(Command::Send {
+            controller,
+            sender: data.sender_id,
+            receiver: data.receiver_id,
+            delivery: data.delivery(),
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(RunningNetwork { commands, task, .. }) = self.network.take() {
+            drop(commands);
+            task.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use dprun::structs::{DPSEND_GUARANTEED, DPSESSION_MIGRATEHOST};
+    use dprun::AppMessage;
+    use dprun::GUID;
+
+    fn open(create: bool) -> structs::OpenData {
+        structs::OpenData {
+            create,
+            return_status: false,
+            open_flags: 0,
+            session_flags: 0,
+        }
+    }
+
+    fn enum_request(requester: GUID) -> structs::EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        structs::EnumSessionsData { message }
+    }
+
+    fn local_player(player: GUID) -> structs::CreatePlayerData {
+        structs::CreatePlayerData {
+            player_guid: player,
+            flags: DPLAYI_PLAYER_LOCAL,
+        }
+    }
+
+    fn message(sender: GUID, receiver: Option<GUID>, data: &[u8]) -> structs::SendData {
+        let mut message = sender.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        structs::SendData {
+            flags: DPSEND_GUARANTEED,
+            receiver_id: receiver,
+            sender_id: sender,
+            system_message: false,
+            message,
+        }
+    }
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> Option<Vec<u8>> {
+        match timeout(Duration::from_secs(2), receiver.recv()).await {
+            Ok(Ok(AppMessage::Send(_, _, data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    struct Peer {
+        sp: IpxSP,
+        id: GUID,
+        controller: AppController,
+        receiver: async_channel::Receiver<AppMessage>,
+    }
+
+    impl Peer {
+        async fn send(&mut self, receiver: Option<GUID>, data: &[u8]) -> Vec<u8> {
+            let message = message(self.id, receiver, data);
+            let expected = message.message.clone();
+            self.sp
+                .send(self.controller.clone(), 0, message)
+                .await
+                .unwrap();
+            expected
+        }
+
+        async fn enum_sessions(&mut self) {
+            let request = enum_request(self.id);
+            self.sp
+                .enum_sessions(self.controller.clone(), 0, request)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Peers on loopback, which has no broadcast address, so every peer sends broadcasts to
+    /// all the others.
+    fn network(ports: &[u16]) -> Vec<Peer> {
+        let addresses: Vec<SocketAddr> = ports
+            .iter()
+            .map(|&port| ([127, 0, 0, 1], port).into())
+            .collect();
+        ports
+            .iter()
+            .enumerate()
+            .map(|(i, &port)| {
+                let (controller, receiver) = AppController::create();
+                Peer {
+                    sp: IpxSP::default()
+                        .with_port(port)
+                        .with_broadcast_addresses(addresses.clone())
+                        .with_peer_timeout(Duration::from_secs(3)),
+                    id: GUID::from_u128(i as u128 + 1),
+                    controller,
+                    receiver,
+                }
+            })
+            .collect()
+    }
+
+    /// Host a session on the first peer, and have the others join it.
+    async fn start_session(peers: &mut [Peer], session_flags: i32) {
+        let open = |create| structs::OpenData {
+            session_flags,
+            ..open(create)
+        };
+        {
+            let host = &mut peers[0];
+            host.sp
+                .open(host.controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+            host.sp
+                .create_player(host.controller.clone(), 0, local_player(host.id))
+                .await
+                .unwrap();
+        }
+
+        for joiner in 1..peers.len() {
+            let joiner_id = peers[joiner].id;
+            let request = enum_request(joiner_id);
+            let expected = request.message.clone();
+            let controller = peers[joiner].controller.clone();
+            peers[joiner]
+                .sp
+                .enum_sessions(controller, 0, request)
+                .await
+                .unwrap();
+            assert_eq!(next_message(&peers[0].receiver).await, Some(expected));
+            let reply = structs::ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session description".to_vec(),
+            };
+            let controller = peers[0].controller.clone();
+            peers[0].sp.reply(controller, 0, reply).await.unwrap();
+            assert_eq!(
+                next_message(&peers[joiner].receiver).await,
+                Some(b"session description".to_vec())
+            );
+
+            let joiner = &mut peers[joiner];
+            joiner
+                .sp
+                .open(joiner.controller.clone(), 0, open(false))
+                .await
+                .unwrap();
+            joiner
+                .sp
+                .create_player(joiner.controller.clone(), 0, local_player(joiner_id))
+                .await
+                .unwrap();
+        }
+        // Give the host time to introduce the players to each other.
+        task::sleep(Duration::from_millis(200)).await;
+    }
+
+    #[test]
+    fn finds_the_host_and_talks_to_everyone_in_the_session() {
+        task::block_on(async {
+            let mut peers = network(&[42131, 42132, 42133]);
+            start_session(&mut peers, 0).await;
+
+            // Joiners talk to each other directly.
+            let second_id = peers[2].id;
+            let expected = peers[1].send(Some(second_id), b"hi").await;
+            assert_eq!(next_message(&peers[2].receiver).await, Some(expected));
+
+            // Messages without a receiver go to everyone in the session.
+            let expected = peers[2].send(None, b"hello everyone").await;
+            assert_eq!(
+                next_message(&peers[0].receiver).await,
+                Some(expected.clone())
+            );
+            assert_eq!(next_message(&peers[1].receiver).await, Some(expected));
+
+            let expected = peers[0].send(None, b"welcome").await;
+            assert_eq!(
+                next_message(&peers[1].receiver).await,
+                Some(expected.clone())
+            );
+            assert_eq!(next_message(&peers[2].receiver).await, Some(expected));
+            // Nobody got anything else, like enumeration requests from other joiners.
+            assert!(peers.iter().all(|peer| peer.receiver.is_empty()));
+
+            for peer in &mut peers {
+                peer.sp.shutdown().await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn another_peer_takes_over_when_the_host_goes_silent() {
+        // `Peer::send` puts the sender's ID in front.
+        let mut announcement = b"play\x35\x00\x0e\x00".to_vec();
+        announcement.extend_from_slice(&[0; 16]);
+
+        task::block_on(async {
+            let mut peers = network(&[42134, 42135, 42136, 42137]);
+            let mut newcomer = peers.pop().unwrap();
+            start_session(&mut peers, DPSESSION_MIGRATEHOST).await;
+
+            // Nobody takes over while the host is still there.
+            peers[2].send(None, &announcement).await;
+            newcomer.enum_sessions().await;
+            assert!(next_message(&peers[0].receiver).await.is_some());
+            task::sleep(Duration::from_millis(200)).await;
+            assert!(peers[2].receiver.is_empty());
+
+            // Keep-alives go out every second, so the joiners notice soon after the timeout.
+            peers[0].sp.shutdown().await.unwrap();
+            task::sleep(Duration::from_secs(5)).await;
+            for joiner in &peers[1..] {
+                while let Ok(message) = joiner.receiver.try_recv() {
+                    assert!(!matches!(message, AppMessage::Close));
+                }
+            }
+
+            // The first joiner takes over, and the other one can not take over from it.
+            for joiner in &mut peers[1..] {
+                joiner.send(None, &announcement).await;
+                task::sleep(Duration::from_millis(200)).await;
+            }
+            while peers[1].receiver.try_recv().is_ok() {}
+            while peers[2].receiver.try_recv().is_ok() {}
+            newcomer.enum_sessions().await;
+            assert!(next_message(&peers[1].receiver).await.is_some());
+            task::sleep(Duration::from_millis(200)).await;
+            assert!(peers[2].receiver.is_empty());
+
+            // The remaining joiner still talks to the new host.
+            let first_id = peers[1].id;
+            let expected = peers[2].send(Some(first_id), b"still here").await;
+            assert_eq!(next_message(&peers[1].receiver).await, Some(expected));
+
+            newcomer.sp.shutdown().await.unwrap();
+            for peer in &mut peers[1..] {
+                peer.sp.shutdown().await.unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,129 @@
+//! Messages between IPX service providers, carried as the data of IPX packets.
+
+use crate::ipx::Node;
+use async_std::io;
+use dprun::{Delivery, GUID};
+use std::convert::TryInto;
+
+const MESSAGE_ENUM_REQUEST: u8 = 0;
+const MESSAGE_ENUM_REPLY: u8 = 1;
+const MESSAGE_PLAYERS: u8 = 2;
+const MESSAGE_GAME_GUARANTEED: u8 = 3;
+const MESSAGE_GAME_UNRELIABLE: u8 = 4;
+const MESSAGE_KEEPALIVE: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Message {
+    /// A game looking for sessions, broadcast to the network.
+    EnumRequest(Vec<u8>),
+    /// A hosting game describing its session.
+    EnumReply(Vec<u8>),
+    /// Players in the session and the nodes they live on.
+    Players(Vec<(GUID, Node)>),
+    /// A message for the game on the other side.
+    Game {
+        /// The player that sent the message.
+        sender: GUID,
+        /// How the sending game asked for the message to be delivered.
+        delivery: Delivery,
+        data: Vec<u8>,
+    },
+    /// Nothing to say, but still here.
+    KeepAlive,
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid message")
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Message::EnumRequest(data) => {
+                bytes.push(MESSAGE_ENUM_REQUEST);
+                bytes.extend_from_slice(data);
+            }
+            Message::EnumReply(data) => {
+                bytes.push(MESSAGE_ENUM_REPLY);
+                bytes.extend_from_slice(data);
+            }
+            Message::Players(players) => {
+                bytes.push(MESSAGE_PLAYERS);
+                for (player, node) in players {
+                    bytes.extend_from_slice(player.as_bytes());
+                    bytes.extend_from_slice(&node.0);
+                }
+            }
+            Message::Game {
+                sender,
+                delivery,
+                data,
+            } => {
+                bytes.push(match delivery {
+                    Delivery::Guaranteed => MESSAGE_GAME_GUARANTEED,
+                    Delivery::Unreliable => MESSAGE_GAME_UNRELIABLE,
+                });
+                bytes.extend_from_slice(sender.as_bytes());
+                bytes.extend_from_slice(data);
+            }
+            Message::KeepAlive => bytes.push(MESSAGE_KEEPALIVE),
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let (&kind, body) = bytes.split_first().ok_or_else(invalid)?;
+        let game = |delivery| Message::Game {
+            sender: GUID::from_slice(&body[..16]).unwrap(),
+            delivery,
+            data: body[16..].to_vec(),
+        };
+        Ok(match kind {
+            MESSAGE_ENUM_REQUEST => Message::EnumRequest(body.to_vec()),
+            MESSAGE_ENUM_REPLY => Message::EnumReply(body.to_vec()),
+            MESSAGE_PLAYERS if body.len().is_multiple_of(22) => Message::Players(
+                body.chunks(22)
+                    .map(|entry| {
+                        let player = GUID::from_slice(&entry[..16]).unwrap();
+                        (player, Node(entry[16..].try_into().unwrap()))
+                    })
+                    .collect(),
+            ),
+            MESSAGE_GAME_GUARANTEED if body.len() >= 16 => game(Delivery::Guaranteed),
+            MESSAGE_GAME_UNRELIABLE if body.len() >= 16 => game(Delivery::Unreliable),
+            MESSAGE_KEEPALIVE => Message::KeepAlive,
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_messages() {
+        let messages = vec![
+            Message::EnumRequest(b"request".to_vec()),
+            Message::EnumReply(vec![]),
+            Message::Players(vec![
+                (GUID::from_u128(1), Node([2, 0, 0, 0, 0, 1])),
+                (GUID::from_u128(2), Node([2, 0, 0, 0, 0, 2])),
+            ]),
+            Message::Game {
+                sender: GUID::from_u128(3),
+                delivery: Delivery::Unreliable,
+                data: b"data".to_vec(),
+            },
+            Message::KeepAlive,
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        assert!(Message::decode(&[]).is_err());
+        assert!(Message::decode(&[MESSAGE_PLAYERS, 1, 2, 3]).is_err());
+        assert!(Message::decode(&[MESSAGE_GAME_GUARANTEED, 1, 2, 3]).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+//! The UDP socket behind an `IpxSP`, running in its own task.
+//!
+//! Like games on a real IPX network, peers find sessions by broadcasting, and address each other
+//! by node. Where a node lives is learned from the packets it sends; packets for nodes we have not
+//! heard from yet go to the broadcast addresses, and everyone else ignores them. The host tells
+//! joining peers about every player in the session, so they can talk to each other directly.
+//!
+//! IPX has no connections, so peers in a session send each other keep-alives, and a peer that
+//! stays silent for too long counts as gone.
+
+use crate::ipx::{Address, Node, Packet};
+use crate::message::Message;
+use async_std::io;
+use async_std::net::UdpSocket;
+use async_std::task;
+use dprun::session::{Command, Outgoing, Route, Session};
+use dprun::GUID;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The IPX socket number that service providers talk to each other on, from the range for
+/// dynamically assigned sockets.
+const IPX_SOCKET: u16 = 0x5000;
+
+/// Largest datagram we can receive.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// How often to send keep-alives to the peers in the session, and check for peers that went
+/// silent.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Settings for the socket task.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// Our node address.
+    pub node: Node,
+    /// Where to send packets for every node on the network.
+    pub broadcast_addresses: Vec<SocketAddr>,
+    /// Drop peers that have been silent for this long.
+    pub peer_timeout: Duration,
+}
+
+enum Event {
+    Packet(io::Result<(usize, SocketAddr)>),
+    Command(Option<Command>),
+    Tick,
+}
+
+pub(crate) struct Network {
+    socket: UdpSocket,
+    config: Config,
+    /// The UDP addresses that nodes sent packets from.
+    addresses: HashMap<Node, SocketAddr>,
+    /// When nodes last sent us a packet.
+    last_heard: HashMap<Node, Instant>,
+    session: Session<Node>,
+}
+
+impl Network {
+    pub fn new(socket: UdpSocket, config: Config) -> Self {
+        Self {
+            socket,
+            config,
+            addresses: HashMap::new(),
+            last_heard: HashMap::new(),
+            session: Session::default(),
+        }
+    }
+
+    /// Drive the socket until the service provider goes away.
+    pub async fn run(mut self, commands: UnboundedReceiver<Command>) {
+        let mut commands = commands.fuse();
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        let mut last_tick = Instant::now();
+        loop {
+            let event = {
+                let receive = self.socket.recv_from(&mut buffer).fuse();
+                let tick = task::sleep(KEEPALIVE_INTERVAL).fuse();
+                futures::pin_mut!(receive, tick);
+                futures::select! {
+                    received = receive => Event::Packet(received),
+                    command = commands.next() => Event::Command(command),
+                    _ = tick => Event::Tick,
+                }
+            };
+            match event {
+                Event::Packet(Ok((size, from))) => {
+                    let packet = buffer[..size].to_vec();
+                    self.handle_packet(from, &packet).await;
+                }
+                Event::Packet(Err(err)) => log::debug!("[ipx] receive error: {:?}", err),
+                Event::Command(Some(command)) => self.handle_command(command).await,
+                Event::Command(None) => break,
+                Event::Tick => (),
+            }
+            if last_tick.elapsed() >= KEEPALIVE_INTERVAL {
+                last_tick = Instant::now();
+                self.tick(last_tick).await;
+            }
+        }
+        log::debug!("[ipx] service provider is gone, closing socket");
+    }
+
+    fn address(&self, node: Node) -> Address {
+        Address {
+            network: [0; 4],
+            node,
+            socket: IPX_SOCKET,
+        }
+    }
+
+    /// Send a message to a node, or to every node on the network.
+    async fn send(&self, node: Node, message: &Message) {
+        let packet = Packet {
+            destination: self.address(node),
+            source: self.address(self.config.node),
+            data: message.encode(),
+        }
+        .encode();
+        let targets = match self.addresses.get(&node) {
+            Some(&address) if node != Node::BROADCAST => vec![address],
+            _ => self.config.broadcast_addresses.clone(),
+        };
+        for target in targets {
+            if let Err(err) = self.socket.send_to(&packet, target).await {
+                log::debug!("[ipx] sending to {} failed: {:?}", target, err);
+            }
+        }
+    }
+
+    /// Keep the peers in the session from dropping us, and drop peers that went silent.
+    async fn tick(&mut self, now: Instant) {
+        for node in self.session.peers() {
+            let last_heard = *self.last_heard.entry(node).or_insert(now);
+            if now.duration_since(last_heard) >= self.config.peer_timeout {
+                log::debug!("[ipx] lost {}", node);
+                self.addresses.remove(&node);
+                self.last_heard.remove(&node);
+                self.session.peer_lost(node).await;
+            } else {
+                self.send(node, &Message::KeepAlive).await;
+            }
+        }
+    }
+
+    /// The players in the session that we know of, with their nodes.
+    fn roster(&self) -> Vec<(GUID, Node)> {
+        let local = self
+            .session
+            .local_players()
+            .iter()
+            .map(|&player| (player, self.config.node));
+        local.chain(self.session.players()).collect()
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match self.session.command(command) {
+            None => (),
+            Some(Outgoing::EnumSessions(message)) => {
+                self.send(Node::BROADCAST, &Message::EnumRequest(message))
+                    .await
+            }
+            Some(Outgoing::NewPlayer(player)) => {
+                let players = Message::Players(vec![(player, self.config.node)]);
+                for node in self.session.peers() {
+                    self.send(node, &players).await;
+                }
+            }
+            Some(Outgoing::EnumReply(node, message)) => {
+                self.send(node, &Message::EnumReply(message)).await
+            }
+            Some(Outgoing::Game {
+                route,
+                sender,
+                delivery,
+                data,
+                ..
+            }) => {
+                let nodes = match route {
+                    Route::Peer(node) => vec![node],
+                    Route::Everyone => self.session.peers(),
+                    Route::Nowhere => vec![],
+                };
+                let message = Message::Game {
+                    sender,
+                    delivery,
+                    data,
+                };
+                for node in nodes {
+                    self.send(node, &message).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, from: SocketAddr, bytes: &[u8]) {
+        let packet = match Packet::decode(bytes) {
+            Ok(packet) => packet,
+            Err(err) => return log::debug!("[ipx] bad packet from {}: {:?}", from, err),
+        };
+        let node = packet.source.node;
+        let destination = packet.destination.node;
+        // Our own broadcasts come back to us, and packets for other nodes may go to broadcast
+        // addresses.
+        if packet.destination.socket != IPX_SOCKET
+            || node == self.config.node
+            || (destination != self.config.node && destination != Node::BROADCAST)
+        {
+            return;
+        }
+        self.addresses.insert(node, from);
+        self.last_heard.insert(node, Instant::now());
+        match Message::decode(&packet.data) {
+            Ok(message) => self.handle_message(node, message).await,
+            Err(err) => log::warn!("[ipx] bad message from {}: {:?}", node, err),
+        }
+    }
+
+    async fn handle_message(&mut self, node: Node, message: Message) {
+        match message {
+            Message::EnumRequest(request) => self.session.enum_request(node, request).await,
+            Message::EnumReply(reply) => self.session.enum_reply(node, reply).await,
+            Message::Players(players) => {
+                let new_players: Vec<(GUID, Node)> = players
+                    .into_iter()
+                    .filter(|&(player, player_node)| self.session.add_player(player, player_node))
+                    .collect();
+                // The host introduces new players to everyone else, and everyone else to them.
+                if self.session.hosting() && !new_players.is_empty() {
+                    self.send(node, &Message::Players(self.roster())).await;
+                    let announcement = Message::Players(new_players);
+                    for other in self.session.peers() {
+                        if other != node {
+                            self.send(other, &announcement).await;
+                        }
+                    }
+                }
+            }
+            Message::Game {
+                sender,
+                delivery,
+                data,
+            } => {
+                self.session
+                    .game_message(node, sender, delivery, data)
+                    .await
+            }
+            Message::KeepAlive => (),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Circuit relay for players behind NAT.
+//!
+//! Usage: libp2p-relay [LISTEN_ADDRESS] [KEY_FILE] [EXTERNAL_ADDRESS...]
+//!
+//! The identity key is kept in KEY_FILE, so the relay's peer ID stays the same across restarts.
+//! EXTERNAL_ADDRESS is where peers can reach the relay, like `/ip4/203.0.113.1/tcp/4001`.
+//! Without it, the relay learns its address from the peers that connect to it.
+
+use dpsp_libp2p::{load_identity, relay, Multiaddr};
+
+fn main() -> std::io::Result<()> {
+    femme::with_level(femme::LevelFilter::Info);
+
+    let mut args = std::env::args().skip(1);
+    let address: Multiaddr = args
+        .next()
+        .unwrap_or_else(|| "/ip4/0.0.0.0/tcp/4001".into())
+        .parse()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let key_file = args.next().unwrap_or_else(|| "libp2p-relay.key".into());
+    let external_addresses = args
+        .map(|address| address.parse())
+        .collect::<Result<Vec<Multiaddr>, _>>()
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+    let key = load_identity(key_file)?;
+    log::info!("Relay peer ID: {}", key.public().to_peer_id());
+    async_std::task::block_on(relay::serve(key, address, external_addresses))
+}
@@ -0,0 +1,83 @@
+use async_std::io;
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{request_response, StreamProtocol};
+
+/// Largest message we accept from a peer. DirectPlay messages are far smaller than this.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+async fn read_message<T>(io: &mut T) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut size = [0; 4];
+    io.read_exact(&mut size).await?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {} bytes is too large", size),
+        ));
+    }
+    let mut message = vec![0; size];
+    io.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+async fn write_message<T>(io: &mut T, message: &[u8]) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    io.write_all(&(message.len() as u32).to_be_bytes()).await?;
+    io.write_all(message).await?;
+    io.flush().await
+}
+
+/// Request-response codec that sends DirectPlay messages as length-prefixed byte strings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessageCodec;
+
+#[async_trait]
+impl request_response::Codec for MessageCodec {
+    type Protocol = StreamProtocol;
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &response).await
+    }
+}
@@ -0,0 +1,213 @@
+//! Framing for the data protocol, which carries game messages between peers.
+//!
+//! Every frame is sent as its own request. Guaranteed frames are numbered per peer, so that the
+//! receiving side can put them back in order even if the requests overtake each other on the way.
+//! Unreliable frames are not numbered and are delivered as soon as they arrive.
+//!
+//! Both kinds share the same connection: every request goes over its own yamux substream on the
+//! one reliable connection to the peer, so there is no lossy path for unreliable frames, and they
+//! can still be held up behind guaranteed ones. The difference is only in how the service provider
+//! treats them: an unreliable frame is not sent again when its request fails, and is not held back
+//! to wait for frames sent before it.
+
+use async_std::io;
+use dprun::{Delivery, GUID};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+const KIND_GUARANTEED: u8 = 0;
+const KIND_UNRELIABLE: u8 = 1;
+const KIND_PLAYERS: u8 = 2;
+
+/// A message on the data protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Frame {
+    /// A message for the game on the other side.
+    Message {
+        delivery: Delivery,
+        /// The player that sent the message.
+        sender: GUID,
+        data: Vec<u8>,
+    },
+    /// The players that live on the sending peer.
+    Players(Vec<GUID>),
+}
+
+impl Frame {
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            Frame::Message { delivery, .. } => *delivery,
+            Frame::Players(_) => Delivery::Guaranteed,
+        }
+    }
+
+    /// Encode a frame with its sequence number. The sequence number of unreliable frames is
+    /// ignored.
+    pub fn encode(&self, seq: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Frame::Message {
+                delivery,
+                sender,
+                data,
+            } => {
+                bytes.push(match delivery {
+                    Delivery::Guaranteed => KIND_GUARANTEED,
+                    Delivery::Unreliable => KIND_UNRELIABLE,
+                });
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                bytes.extend_from_slice(sender.as_bytes());
+                bytes.extend_from_slice(data);
+            }
+            Frame::Players(players) => {
+                bytes.push(KIND_PLAYERS);
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                for player in players {
+                    bytes.extend_from_slice(player.as_bytes());
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Decode a frame and its sequence number.
+    pub fn decode(bytes: &[u8]) -> io::Result<(u64, Self)> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid data frame");
+        if bytes.len() < 9 {
+            return Err(invalid());
+        }
+        let seq = u64::from_be_bytes(bytes[1..9].try_into().unwrap());
+        let body = &bytes[9..];
+        let frame = match bytes[0] {
+            KIND_GUARANTEED | KIND_UNRELIABLE if body.len() >= 16 => Frame::Message {
+                delivery: if bytes[0] == KIND_GUARANTEED {
+                    Delivery::Guaranteed
+                } else {
+                    Delivery::Unreliable
+                },
+                sender: GUID::from_slice(&body[..16]).unwrap(),
+                data: body[16..].to_vec(),
+            },
+            KIND_PLAYERS if body.len().is_multiple_of(16) => Frame::Players(
+                body.chunks(16)
+                    .map(|guid| GUID::from_slice(guid).unwrap())
+                    .collect(),
+            ),
+            _ => return Err(invalid()),
+        };
+        Ok((seq, frame))
+    }
+}
+
+/// How far ahead of the next expected guaranteed frame a frame may be. Frames are only early
+/// when requests overtake each other, so a peer that gets this far ahead is misbehaving.
+const MAX_EARLY: u64 = 1024;
+
+/// Puts guaranteed frames from one peer back in order.
+#[derive(Debug, Default)]
+pub(crate) struct Inbox {
+    next_seq: u64,
+    early: BTreeMap<u64, Frame>,
+}
+
+impl Inbox {
+    /// Accept a frame, and return the frames that can be delivered now, in order. Fails if the
+    /// frame is too far ahead to wait for the frames before it.
+    pub fn receive(&mut self, seq: u64, frame: Frame) -> io::Result<Vec<Frame>> {
+        if frame.delivery() == Delivery::Unreliable {
+            return Ok(vec![frame]);
+        }
+        if seq < self.next_seq {
+            // Sent again after a failure that the first copy survived.
+            return Ok(vec![]);
+        }
+        if seq - self.next_seq >= MAX_EARLY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "guaranteed frame {} is too far ahead of {}",
+                    seq, self.next_seq
+                ),
+            ));
+        }
+        self.early.insert(seq, frame);
+        let mut ready = vec![];
+        while let Some(frame) = self.early.remove(&self.next_seq) {
+            ready.push(frame);
+            self.next_seq += 1;
+        }
+        Ok(ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(delivery: Delivery, data: &[u8]) -> Frame {
+        Frame::Message {
+            delivery,
+            sender: GUID::from_u128(1),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn encodes_and_decodes_frames() {
+        let frames = vec![
+            message(Delivery::Guaranteed, b"hello"),
+            message(Delivery::Unreliable, b""),
+            Frame::Players(vec![GUID::from_u128(2), GUID::from_u128(3)]),
+        ];
+        for (seq, frame) in frames.into_iter().enumerate() {
+            let decoded = Frame::decode(&frame.encode(seq as u64)).unwrap();
+            assert_eq!(decoded, (seq as u64, frame));
+        }
+        assert!(Frame::decode(&[KIND_PLAYERS, 0, 0, 0, 0, 0, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn delivers_guaranteed_frames_in_order() {
+        let mut inbox = Inbox::default();
+        assert_eq!(
+            inbox
+                .receive(1, message(Delivery::Guaranteed, b"b"))
+                .unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            inbox
+                .receive(9, message(Delivery::Unreliable, b"x"))
+                .unwrap(),
+            vec![message(Delivery::Unreliable, b"x")]
+        );
+        assert_eq!(
+            inbox
+                .receive(0, message(Delivery::Guaranteed, b"a"))
+                .unwrap(),
+            vec![
+                message(Delivery::Guaranteed, b"a"),
+                message(Delivery::Guaranteed, b"b")
+            ]
+        );
+        assert_eq!(
+            inbox
+                .receive(0, message(Delivery::Guaranteed, b"a"))
+                .unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn refuses_frames_too_far_ahead() {
+        let mut inbox = Inbox::default();
+        assert!(inbox
+            .receive(MAX_EARLY - 1, message(Delivery::Guaranteed, b"early"))
+            .unwrap()
+            .is_empty());
+        assert!(inbox
+            .receive(MAX_EARLY, message(Delivery::Guaranteed, b"too early"))
+            .is_err());
+        assert_eq!(inbox.early.len(), 1);
+    }
+}
@@ -1,83 +1,190 @@
-use async_std::io::{self, Read, Write};
+mod codec;
+mod data;
+mod network;
+pub mod relay;
+
+use async_std::io;
+use async_std::task::{self, JoinHandle};
 use async_trait::async_trait;
-use dprun::{structs, AppController, ServiceProvider};
-use futures::future::BoxFuture;
-use libp2p::{
-    core::UpgradeInfo, identity::Keypair, mdns::Mdns, InboundUpgrade, Multiaddr, OutboundUpgrade,
-    Swarm,
-};
-
-#[derive(Debug)]
-pub enum EnumSessionsError {
-    Any,
+use dprun::{structs, AppController, PlayerIdentity, ServiceProvider, GUID};
+use futures::channel::mpsc::{self, UnboundedSender};
+pub use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+pub use libp2p::{Multiaddr, PeerId, Swarm};
+pub use network::{build_swarm, build_transport, Behaviour, Link};
+use network::{Command, Links, Network, PeerAccess};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Player flag: the player was created by the local game.
+const DPLAYI_PLAYER_LOCAL: i32 = 8;
+
+/// Read the GUID of the sending player from the start of a network message.
+fn message_sender(message: &[u8]) -> Option<GUID> {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(message.get(0..16)?);
+    Some(GUID::from_bytes(bytes))
+}
+
+pub(crate) fn other_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::other(err)
 }
 
-impl std::fmt::Display for EnumSessionsError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            EnumSessionsError::Any => write!(f, "EnumSessionsError::Any"),
+/// Load the identity key stored at `path`, or generate one and store it there if the file does
+/// not exist yet. Reusing the key keeps the peer ID the same across games.
+pub fn load_identity(path: impl AsRef<Path>) -> io::Result<Keypair> {
+    let path = path.as_ref();
+    match std::fs::read(path) {
+        Ok(bytes) => Keypair::from_protobuf_encoding(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let key = Keypair::generate_ed25519();
+            save_identity(path, &key)?;
+            Ok(key)
         }
+        Err(err) => Err(err),
     }
 }
 
-unsafe impl Send for EnumSessionsError {}
-unsafe impl Sync for EnumSessionsError {}
+/// Store an identity key at `path`. The file is only readable by the current user.
+pub fn save_identity(path: impl AsRef<Path>, key: &Keypair) -> io::Result<()> {
+    use std::io::Write;
 
-impl std::error::Error for EnumSessionsError {}
-
-#[derive(Debug, Default, Clone, Copy)]
-struct EnumSessionsUpgrade;
-impl UpgradeInfo for EnumSessionsUpgrade {
-    type Info = &'static [u8];
-    type InfoIter = std::iter::Once<Self::Info>;
-
-    fn protocol_info(&self) -> Self::InfoIter {
-        std::iter::once(b"/dpsp-enum/0.0.0")
-    }
+    let bytes = key.to_protobuf_encoding().map_err(other_error)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&bytes)
 }
 
-impl<C> InboundUpgrade<C> for EnumSessionsUpgrade
-where
-    C: Read + Write,
-{
-    type Output = ();
-    type Error = EnumSessionsError;
-    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
-
-    fn upgrade_inbound(self, _socket: C, _: Self::Info) -> Self::Future {
-        Box::pin(async move { Ok(()) })
-    }
+/// The swarm task of a running service provider.
+struct RunningNetwork {
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
 }
 
-impl<C> OutboundUpgrade<C> for EnumSessionsUpgrade
-where
-    C: Read + Write,
-{
-    type Output = ();
-    type Error = EnumSessionsError;
-    type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+/// Kicks and bans players from the session of a `Libp2pSP`, while dprun owns the service
+/// provider.
+#[derive(Clone)]
+pub struct Moderator {
+    commands: Arc<Mutex<Option<UnboundedSender<Command>>>>,
+}
 
-    fn upgrade_outbound(self, _socket: C, _: Self::Info) -> Self::Future {
-        Box::pin(async move { Ok(()) })
+impl Moderator {
+    /// Disconnect the peer that a player is on. `identity` is the GUID of one of its players, or
+    /// the name of one of its players as shown in the game. The local game and the other peers
+    /// in the session are told that the peer's players left.
+    ///
+    /// With `ban`, the peer is blocked, and peers with players of the banned name are kicked as
+    /// soon as they create them. Only this service provider blocks the peer, so only the host,
+    /// which joining peers have to find the session through, can kick: elsewhere, kicking does
+    /// nothing.
+    pub fn kick(&self, identity: PlayerIdentity, ban: bool) -> io::Result<()> {
+        let not_running =
+            || io::Error::new(io::ErrorKind::NotConnected, "libp2p swarm is not running");
+        self.commands
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(not_running)?
+            .unbounded_send(Command::Kick { identity, ban })
+            .map_err(|_| not_running())
     }
 }
 
 pub struct Libp2pSP {
     local_key: Keypair,
+    local_peer_id: PeerId,
+    /// A swarm passed in by the application, used instead of building one. Swarms are not
+    /// `Sync`, which service providers must be.
+    swarm: Mutex<Option<Swarm<Behaviour>>>,
     address: Option<Multiaddr>,
-    // swarm: Option<Swarm>,
+    listen_address: Multiaddr,
+    relay: Option<Multiaddr>,
+    links: Links,
+    mdns: bool,
+    access: PeerAccess,
+    network: Option<RunningNetwork>,
+    /// The commands of the running swarm task, for moderators.
+    moderator: Moderator,
 }
 
 impl Default for Libp2pSP {
     fn default() -> Self {
+        let local_key = Keypair::generate_ed25519();
         Self {
-            local_key: Keypair::generate_ed25519(),
+            local_peer_id: local_key.public().to_peer_id(),
+            local_key,
+            swarm: Mutex::new(None),
             address: None,
+            relay: None,
+            links: Links::default(),
+            listen_address: "/ip4/0.0.0.0/tcp/0".parse().unwrap(),
+            mdns: true,
+            access: PeerAccess::default(),
+            network: None,
+            moderator: Moderator {
+                commands: Arc::default(),
+            },
         }
     }
 }
 
 impl Libp2pSP {
+    /// Use this identity key instead of a random one. See `load_identity`.
+    pub fn with_identity(self, local_key: Keypair) -> Self {
+        Self {
+            local_peer_id: local_key.public().to_peer_id(),
+            local_key,
+            ..self
+        }
+    }
+
+    /// Use a swarm that the application already runs, for example one that a lobby used to
+    /// connect to the other players. The game protocols run as extra substreams on its
+    /// connections, so no new connections or ports are needed.
+    ///
+    /// `build_swarm` builds a swarm with the transport and behaviour that the service provider
+    /// uses itself. The swarm is not made to listen on the listen address, and peer access lists
+    /// only apply to swarms that the service provider builds itself.
+    pub fn from_swarm(swarm: Swarm<Behaviour>) -> Self {
+        Self {
+            local_peer_id: *swarm.local_peer_id(),
+            swarm: Mutex::new(Some(swarm)),
+            ..Self::default()
+        }
+    }
+
+    /// The peer ID that other peers know this service provider by.
+    pub fn peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Only accept connections with these peers, for example the players that a lobby matched.
+    pub fn with_allowed_peers(self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            access: PeerAccess {
+                allowed: Some(peers.into_iter().collect()),
+                ..self.access
+            },
+            ..self
+        }
+    }
+
+    /// Never accept connections with these peers.
+    pub fn with_blocked_peers(self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        Self {
+            access: PeerAccess {
+                blocked: peers.into_iter().collect(),
+                ..self.access
+            },
+            ..self
+        }
+    }
+
+    /// Connect to a peer at this address.
     pub fn with_address(self, address: Multiaddr) -> Self {
         Self {
             address: Some(address),
@@ -85,84 +192,732 @@ impl Libp2pSP {
         }
     }
 
-    // TODO allow constructing a dpsp-libp2p instance from an existing (multiplex) connection
-    // pub fn from(transport: CommonTransport) {
-    // }
+    /// Listen for other peers on this address. By default, a random TCP port is used.
+    pub fn with_listen_address(self, listen_address: Multiaddr) -> Self {
+        Self {
+            listen_address,
+            ..self
+        }
+    }
+
+    /// Make this service provider reachable through a relay, for players behind NAT. The address
+    /// must end in the relay's peer ID. Other peers can then connect to `relayed_address`.
+    pub fn with_relay(self, relay: Multiaddr) -> Self {
+        Self {
+            relay: Some(relay),
+            ..self
+        }
+    }
+
+    /// The address that other peers can connect to this service provider at through its relay.
+    pub fn relayed_address(&self) -> Option<Multiaddr> {
+        self.relay.as_ref().map(|relay| {
+            relay
+                .clone()
+                .with(Protocol::P2pCircuit)
+                .with(Protocol::P2p(self.local_peer_id))
+        })
+    }
+
+    /// How this service provider is connected to each peer. Relayed links are upgraded to
+    /// direct ones when hole punching succeeds.
+    pub fn links(&self) -> HashMap<PeerId, Link> {
+        self.links.lock().unwrap().clone()
+    }
+
+    /// Get a handle to kick and ban players with. It works while the game is in a session.
+    pub fn moderator(&self) -> Moderator {
+        self.moderator.clone()
+    }
+
+    /// Whether to find other peers on the LAN through mDNS. This is on by default.
+    pub fn with_mdns(self, mdns: bool) -> Self {
+        Self { mdns, ..self }
+    }
+
+    /// Start the swarm if it is not running yet.
+    fn network(&mut self) -> io::Result<&UnboundedSender<Command>> {
+        if self.network.is_none() {
+            // A swarm from the application may never start listening, so it connects right away.
+            let (swarm, from_application) = match self.swarm.get_mut().unwrap().take() {
+                Some(swarm) => (swarm, true),
+                None => {
+                    log::debug!("--- create swarm ---");
+                    let mut swarm =
+                        network::build_swarm_with_access(&self.local_key, self.mdns, &self.access)?;
+                    swarm
+                        .listen_on(self.listen_address.clone())
+                        .map_err(other_error)?;
+                    (swarm, false)
+                }
+            };
+            let mut network = Network::new(swarm, Arc::clone(&self.links));
+            network.connect(self.relay.clone(), self.address.clone(), from_application);
+
+            let (commands, receiver) = mpsc::unbounded();
+            *self.moderator.commands.lock().unwrap() = Some(commands.clone());
+            let task = task::spawn(network.run(receiver));
+            self.network = Some(RunningNetwork { commands, task });
+        }
+        Ok(&self.network.as_ref().unwrap().commands)
+    }
+
+    /// Send a command to the swarm task, starting it if necessary.
+    fn command(&mut self, command: Command) -> io::Result<()> {
+        self.network()?
+            .unbounded_send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "libp2p swarm stopped"))
+    }
 }
 
 #[async_trait]
 impl ServiceProvider for Libp2pSP {
     async fn enum_sessions(
         &mut self,
-        _controller: AppController,
+        controller: AppController,
         _id: u32,
         data: structs::EnumSessionsData,
     ) -> io::Result<()> {
         log::debug!("[libp2p] enum sessions");
         log::debug!("{:?}", &data);
-        Ok(())
+        self.command(Command::EnumSessions {
+            controller,
+            message: data.message,
+        })
     }
 
     async fn open(
         &mut self,
-        _controller: AppController,
+        controller: AppController,
         _id: u32,
-        _data: structs::OpenData,
+        data: structs::OpenData,
     ) -> io::Result<()> {
         log::debug!("Open libp2p session");
-        log::debug!("--- create transport ---");
-        let transport = libp2p::build_development_transport(self.local_key.clone())?;
-        // how to make this work?
-        // .upgrade(EnumSessionsUpgrade);
-        log::debug!("--- create swarm ---");
-        let mut swarm = Swarm::new(
-            transport,
-            Mdns::new().unwrap(),
-            self.local_key.public().into_peer_id(),
-        );
-
-        let _addr = Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse().unwrap()).unwrap();
-        log::debug!("{:?}", _addr);
-
-        if let Some(dial_addr) = &self.address {
-            Swarm::dial_addr(&mut swarm, dial_addr.clone()).unwrap();
-            log::debug!("{:?}", dial_addr);
-        }
-
-        async_std::task::spawn(async move {
-            loop {
-                let event = swarm.next_event().await;
-                log::debug!("{:?}", event);
-            }
-        });
-
-        Ok(())
+        log::debug!("{:?}", &data);
+        self.command(Command::Open {
+            controller,
+            create: data.create,
+            migrate_host: data.migrate_host(),
+        })
     }
 
     async fn create_player(
         &mut self,
-        _controller: AppController,
+        controller: AppController,
         _id: u32,
-        _data: structs::CreatePlayerData,
+        data: structs::CreatePlayerData,
     ) -> io::Result<()> {
-        Ok(())
+        // DirectPlay also tells us about players on other peers; those are announced by the
+        // peer that created them.
+        if data.flags & DPLAYI_PLAYER_LOCAL == 0 {
+            return Ok(());
+        }
+        self.command(Command::CreatePlayer {
+            controller,
+            player: data.player_guid,
+        })
     }
 
     async fn reply(
         &mut self,
-        _controller: AppController,
+        controller: AppController,
         _id: u32,
-        _data: structs::ReplyData,
+        data: structs::ReplyData,
     ) -> io::Result<()> {
-        Ok(())
+        self.command(Command::Reply {
+            controller,
+            reply_to: data.reply_to,
+            message: data.message,
+        })
     }
 
     async fn send(
         &mut self,
-        _controller: AppController,
+        controller: AppController,
         _id: u32,
-        _data: structs::SendData,
+        data: structs::SendData,
     ) -> io::Result<()> {
+        // Guaranteed messages arrive in order; unreliable ones are not sent again when their
+        // request fails. Both take the same connection, see the `data` module.
+        self.command(Command::Send {
+            controller,
+            sender: data.sender_id,
+            receiver: data.receiver_id,
+            delivery: data.delivery(),
+            message: data.message,
+        })
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(RunningNetwork { commands, task }) = self.network.take() {
+            self.moderator.commands.lock().unwrap().take();
+            drop(commands);
+            task.await;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use dprun::structs::DPSEND_GUARANTEED;
+    use dprun::AppMessage;
+    use std::time::Duration;
+
+    fn open(create: bool) -> structs::OpenData {
+        structs::OpenData {
+            create,
+            return_status: false,
+            open_flags: 0,
+            session_flags: 0,
+        }
+    }
+
+    fn enum_request(requester: GUID) -> structs::EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        structs::EnumSessionsData { message }
+    }
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> Option<Vec<u8>> {
+        match timeout(Duration::from_secs(2), receiver.recv()).await {
+            Ok(Ok(AppMessage::Send(_, _, data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn enumerates_sessions_of_host() {
+        let address: Multiaddr = "/memory/36001".parse().unwrap();
+        let joiner_id = GUID::from_u128(1);
+
+        task::block_on(async {
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone());
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+
+            let mut joiner = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/36002".parse().unwrap())
+                .with_address(address);
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            let request = enum_request(joiner_id);
+            let expected = request.message.clone();
+            joiner
+                .enum_sessions(joiner_controller, 0, request)
+                .await
+                .unwrap();
+
+            assert_eq!(next_message(&host_receiver).await, Some(expected));
+            let reply = structs::ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session description".to_vec(),
+            };
+            host.reply(host_controller, 0, reply).await.unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver).await,
+                Some(b"session description".to_vec())
+            );
+
+            joiner.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn peers_that_do_not_host_do_not_answer() {
+        let address: Multiaddr = "/memory/36003".parse().unwrap();
+
+        task::block_on(async {
+            let mut other = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone());
+            let (other_controller, other_receiver) = AppController::create();
+            other.open(other_controller, 0, open(false)).await.unwrap();
+
+            let mut joiner = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/36004".parse().unwrap())
+                .with_address(address);
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            joiner
+                .enum_sessions(joiner_controller, 0, enum_request(GUID::from_u128(1)))
+                .await
+                .unwrap();
+
+            assert_eq!(next_message(&joiner_receiver).await, None);
+            assert!(other_receiver.is_empty());
+
+            joiner.shutdown().await.unwrap();
+            other.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn keeps_identity_in_a_file() {
+        let path = std::env::temp_dir().join(format!("dpsp-libp2p-{}.key", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let key = load_identity(&path).unwrap();
+        let loaded = load_identity(&path).unwrap();
+        assert_eq!(key.public(), loaded.public());
+
+        std::fs::write(&path, b"not a key").unwrap();
+        assert!(load_identity(&path).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn only_allowed_peers_can_connect() {
+        let address: Multiaddr = "/memory/36005".parse().unwrap();
+        let friend_key = Keypair::generate_ed25519();
+        let friend_peer = friend_key.public().to_peer_id();
+
+        task::block_on(async {
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone())
+                .with_allowed_peers(vec![friend_peer]);
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller, 0, open(true)).await.unwrap();
+
+            let mut stranger = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/36006".parse().unwrap())
+                .with_address(address.clone());
+            let (stranger_controller, _) = AppController::create();
+            stranger
+                .enum_sessions(stranger_controller, 0, enum_request(GUID::from_u128(1)))
+                .await
+                .unwrap();
+            assert_eq!(next_message(&host_receiver).await, None);
+
+            let mut friend = Libp2pSP::default()
+                .with_identity(friend_key)
+                .with_mdns(false)
+                .with_listen_address("/memory/36007".parse().unwrap())
+                .with_address(address);
+            assert_eq!(friend.peer_id(), friend_peer);
+            let (friend_controller, _) = AppController::create();
+            let request = enum_request(GUID::from_u128(2));
+            let expected = request.message.clone();
+            friend
+                .enum_sessions(friend_controller, 0, request)
+                .await
+                .unwrap();
+            assert_eq!(next_message(&host_receiver).await, Some(expected));
+
+            friend.shutdown().await.unwrap();
+            stranger.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn uses_connections_of_an_existing_swarm() {
+        use futures::StreamExt;
+        use libp2p::swarm::SwarmEvent;
+
+        let address: Multiaddr = "/memory/36008".parse().unwrap();
+
+        task::block_on(async {
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone());
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller, 0, open(true)).await.unwrap();
+
+            // The lobby connects to the host before the game starts.
+            let key = Keypair::generate_ed25519();
+            let mut swarm = build_swarm(&key, false).unwrap();
+            swarm.dial(address).unwrap();
+            loop {
+                if let SwarmEvent::ConnectionEstablished { .. } = swarm.select_next_some().await {
+                    break;
+                }
+            }
+
+            let mut joiner = Libp2pSP::from_swarm(swarm);
+            assert_eq!(joiner.peer_id(), key.public().to_peer_id());
+            let (joiner_controller, _) = AppController::create();
+            let request = enum_request(GUID::from_u128(1));
+            let expected = request.message.clone();
+            joiner
+                .enum_sessions(joiner_controller, 0, request)
+                .await
+                .unwrap();
+            assert_eq!(next_message(&host_receiver).await, Some(expected));
+
+            joiner.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn connects_through_a_relay() {
+        let relay_key = Keypair::generate_ed25519();
+        let relay_address: Multiaddr =
+            format!("/memory/36009/p2p/{}", relay_key.public().to_peer_id())
+                .parse()
+                .unwrap();
+        let joiner_id = GUID::from_u128(1);
+
+        task::block_on(async {
+            let address: Multiaddr = "/memory/36009".parse().unwrap();
+            task::spawn(relay::serve(relay_key, address.clone(), vec![address]));
+
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/36010".parse().unwrap())
+                .with_relay(relay_address);
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+            // Wait for the relay to accept the reservation.
+            task::sleep(Duration::from_millis(500)).await;
+
+            // The joiner only knows the relayed address of the host.
+            let mut joiner = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/36011".parse().unwrap())
+                .with_address(host.relayed_address().unwrap());
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            let request = enum_request(joiner_id);
+            let expected = request.message.clone();
+            joiner
+                .enum_sessions(joiner_controller, 0, request)
+                .await
+                .unwrap();
+            assert_eq!(next_message(&host_receiver).await, Some(expected));
+            let reply = structs::ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session".to_vec(),
+            };
+            host.reply(host_controller, 0, reply).await.unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver).await,
+                Some(b"session".to_vec())
+            );
+            assert_eq!(joiner.links().get(&host.peer_id()), Some(&Link::Relayed));
+
+            joiner.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    /// Network namespaces for `punches_holes_through_nat`, removed again when dropped.
+    ///
+    /// The relay namespace stands in for the internet. Each peer sits behind its own router,
+    /// which hides it behind the router's address like a home router does.
+    struct NatNetwork;
+
+    const NAT_NAMESPACES: [&str; 5] = [
+        "dpsp-relay",
+        "dpsp-router-a",
+        "dpsp-router-b",
+        "dpsp-peer-a",
+        "dpsp-peer-b",
+    ];
+
+    fn run(command: &str) {
+        let mut words = command.split_whitespace();
+        let status = std::process::Command::new(words.next().unwrap())
+            .args(words)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed", command);
+    }
+
+    impl NatNetwork {
+        fn create() -> Self {
+            for namespace in &NAT_NAMESPACES {
+                run(&format!("ip netns add {}", namespace));
+                run(&format!("ip -n {} link set lo up", namespace));
+            }
+            let network = NatNetwork;
+            for (side, subnet) in &[("a", 1), ("b", 2)] {
+                let router = format!("dpsp-router-{}", side);
+                let peer = format!("dpsp-peer-{}", side);
+                run(&format!(
+                    "ip link add wan-{} netns dpsp-relay type veth peer name wan netns {}",
+                    side, router
+                ));
+                run(&format!(
+                    "ip link add lan netns {} type veth peer name eth0 netns {}",
+                    router, peer
+                ));
+                run(&format!(
+                    "ip -n dpsp-relay addr add 10.0.{}.1/24 dev wan-{}",
+                    subnet, side
+                ));
+                run(&format!("ip -n dpsp-relay link set wan-{} up", side));
+                run(&format!(
+                    "ip -n {} addr add 10.0.{}.2/24 dev wan",
+                    router, subnet
+                ));
+                run(&format!(
+                    "ip -n {} addr add 192.168.{}.1/24 dev lan",
+                    router, subnet
+                ));
+                run(&format!("ip -n {} link set wan up", router));
+                run(&format!("ip -n {} link set lan up", router));
+                run(&format!(
+                    "ip -n {} route add default via 10.0.{}.1",
+                    router, subnet
+                ));
+                run(&format!(
+                    "ip netns exec {} sysctl -q -w net.ipv4.ip_forward=1",
+                    router
+                ));
+                run(&format!(
+                    "ip netns exec {} iptables -t nat -A POSTROUTING -o wan -j MASQUERADE",
+                    router
+                ));
+                run(&format!(
+                    "ip -n {} addr add 192.168.{}.2/24 dev eth0",
+                    peer, subnet
+                ));
+                run(&format!("ip -n {} link set eth0 up", peer));
+                run(&format!(
+                    "ip -n {} route add default via 192.168.{}.1",
+                    peer, subnet
+                ));
+            }
+            run("ip netns exec dpsp-relay sysctl -q -w net.ipv4.ip_forward=1");
+            network
+        }
+
+        /// Run a part of the test in a namespace, by running this test again in it.
+        fn spawn(&self, namespace: &str, role: &str) -> std::process::Child {
+            std::process::Command::new("ip")
+                .args(["netns", "exec", namespace])
+                .arg(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "tests::punches_holes_through_nat",
+                    "--ignored",
+                    "--nocapture",
+                ])
+                .env("DPSP_NAT_ROLE", role)
+                .spawn()
+                .unwrap()
+        }
+    }
+
+    impl Drop for NatNetwork {
+        fn drop(&mut self) {
+            for namespace in &NAT_NAMESPACES {
+                let _ = std::process::Command::new("ip")
+                    .args(["netns", "del", namespace])
+                    .status();
+            }
+        }
+    }
+
+    /// Two peers behind NAT find each other through the relay, and then connect directly.
+    ///
+    /// This needs root, `ip` and `iptables`, so it only runs when asked for:
+    /// `cargo test -p dpsp-libp2p -- --ignored punches_holes_through_nat`.
+    #[test]
+    #[ignore]
+    fn punches_holes_through_nat() {
+        let relay_key = Keypair::ed25519_from_bytes([1; 32]).unwrap();
+        let host_key = Keypair::ed25519_from_bytes([2; 32]).unwrap();
+        let relay_address: Multiaddr = "/ip4/10.0.1.1/tcp/4001".parse().unwrap();
+        let relay_peer_address = relay_address
+            .clone()
+            .with(Protocol::P2p(relay_key.public().to_peer_id()));
+        let host = Libp2pSP::default()
+            .with_identity(host_key)
+            .with_mdns(false)
+            .with_listen_address("/ip4/0.0.0.0/tcp/4002".parse().unwrap())
+            .with_relay(relay_peer_address);
+
+        match std::env::var("DPSP_NAT_ROLE").as_deref() {
+            Ok("relay") => {
+                let address = "/ip4/0.0.0.0/tcp/4001".parse().unwrap();
+                task::block_on(relay::serve(relay_key, address, vec![relay_address])).unwrap();
+            }
+            Ok("host") => task::block_on(async {
+                let mut host = host;
+                let (controller, receiver) = AppController::create();
+                host.open(controller.clone(), 0, open(true)).await.unwrap();
+                // Answer enumeration requests until the test is over.
+                while let Ok(AppMessage::Send(..)) = receiver.recv().await {
+                    let reply = structs::ReplyData {
+                        reply_to: GUID::from_u128(1),
+                        name_server_id: 0,
+                        message: b"session".to_vec(),
+                    };
+                    host.reply(controller.clone(), 0, reply).await.unwrap();
+                }
+            }),
+            Ok("joiner") => task::block_on(async {
+                let mut joiner = Libp2pSP::default()
+                    .with_mdns(false)
+                    .with_listen_address("/ip4/0.0.0.0/tcp/4003".parse().unwrap())
+                    .with_address(host.relayed_address().unwrap());
+                let (controller, receiver) = AppController::create();
+                joiner
+                    .enum_sessions(controller, 0, enum_request(GUID::from_u128(1)))
+                    .await
+                    .unwrap();
+                assert_eq!(next_message(&receiver).await, Some(b"session".to_vec()));
+                let mut link = None;
+                for _ in 0..60 {
+                    link = joiner.links().get(&host.peer_id()).copied();
+                    if link == Some(Link::Direct) {
+                        break;
+                    }
+                    task::sleep(Duration::from_millis(500)).await;
+                }
+                assert_eq!(link, Some(Link::Direct));
+                joiner.shutdown().await.unwrap();
+            }),
+            _ => {
+                let network = NatNetwork::create();
+                let mut relay = network.spawn("dpsp-relay", "relay");
+                std::thread::sleep(Duration::from_secs(1));
+                let mut host = network.spawn("dpsp-peer-a", "host");
+                // Wait for the relay to accept the host's reservation.
+                std::thread::sleep(Duration::from_secs(2));
+                let joined = network.spawn("dpsp-peer-b", "joiner").wait().unwrap();
+                let _ = host.kill();
+                let _ = relay.kill();
+                let _ = host.wait();
+                let _ = relay.wait();
+                assert!(joined.success());
+            }
+        }
+    }
+
+    fn local_player(id: GUID) -> structs::CreatePlayerData {
+        structs::CreatePlayerData {
+            player_guid: id,
+            flags: DPLAYI_PLAYER_LOCAL,
+        }
+    }
+
+    fn message(
+        flags: i32,
+        sender_id: GUID,
+        receiver_id: Option<GUID>,
+        message: &[u8],
+    ) -> structs::SendData {
+        structs::SendData {
+            flags,
+            receiver_id,
+            sender_id,
+            system_message: false,
+            message: message.to_vec(),
+        }
+    }
+
+    #[test]
+    fn routes_messages_between_players() {
+        let address: Multiaddr = "/memory/37001".parse().unwrap();
+        let host_id = GUID::from_u128(1);
+        let joiner_id = GUID::from_u128(2);
+
+        task::block_on(async {
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone());
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+            host.create_player(host_controller.clone(), 0, local_player(host_id))
+                .await
+                .unwrap();
+
+            let mut joiner = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address("/memory/37002".parse().unwrap())
+                .with_address(address);
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            joiner
+                .enum_sessions(joiner_controller.clone(), 0, enum_request(joiner_id))
+                .await
+                .unwrap();
+            assert!(next_message(&host_receiver).await.is_some());
+            let reply = structs::ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session".to_vec(),
+            };
+            host.reply(host_controller.clone(), 0, reply).await.unwrap();
+            assert!(next_message(&joiner_receiver).await.is_some());
+
+            // Joining: the joiner asks the name server for a player ID, and the host replies.
+            joiner
+                .open(joiner_controller.clone(), 0, open(false))
+                .await
+                .unwrap();
+            let mut request = joiner_id.as_bytes().to_vec();
+            request.extend_from_slice(b"join");
+            let join = message(DPSEND_GUARANTEED, joiner_id, None, &request);
+            joiner
+                .send(joiner_controller.clone(), 0, join)
+                .await
+                .unwrap();
+            assert_eq!(next_message(&host_receiver).await, Some(request));
+            let reply = structs::ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"player id".to_vec(),
+            };
+            host.reply(host_controller.clone(), 0, reply).await.unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver).await,
+                Some(b"player id".to_vec())
+            );
+
+            joiner
+                .create_player(joiner_controller.clone(), 0, local_player(joiner_id))
+                .await
+                .unwrap();
+            let hello = message(DPSEND_GUARANTEED, host_id, Some(joiner_id), b"hello");
+            host.send(host_controller.clone(), 0, hello).await.unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver).await,
+                Some(b"hello".to_vec())
+            );
+
+            // Guaranteed messages arrive in the order they were sent.
+            for i in 0..20u8 {
+                let chat = message(DPSEND_GUARANTEED, joiner_id, Some(host_id), &[i]);
+                joiner
+                    .send(joiner_controller.clone(), 0, chat)
+                    .await
+                    .unwrap();
+            }
+            for i in 0..20u8 {
+                assert_eq!(next_message(&host_receiver).await, Some(vec![i]));
+            }
+
+            // Unreliable messages are not numbered or sent again, but arrive on a good link.
+            let position = message(0, joiner_id, Some(host_id), b"position");
+            joiner
+                .send(joiner_controller.clone(), 0, position)
+                .await
+                .unwrap();
+            assert_eq!(
+                next_message(&host_receiver).await,
+                Some(b"position".to_vec())
+            );
+
+            joiner.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+}
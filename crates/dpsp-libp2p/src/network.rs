@@ -0,0 +1,1224 @@
+//! The libp2p swarm behind a `Libp2pSP`, running in its own task.
+//!
+//! The service provider talks to the swarm task through `Command`s. The task owns everything
+//! that needs the swarm: connected peers, the peers that players live on, requests waiting for an
+//! answer from the game, and the controller that incoming messages are delivered to.
+//!
+//! On a LAN, peers find each other through mDNS, like the IPX and TCP/IP service providers find
+//! sessions by broadcasting. Discovered peers are dialed straight away, so that enumerating
+//! sessions reaches them without any address configured.
+//!
+//! Which peers may connect is decided when the connection is set up, so peers that are not
+//! allowed never get to talk to the game.
+//!
+//! The peer hosting a session can kick players. Their peer is disconnected, and the other games
+//! are told that its players left. A ban also blocks the peer, so that it can not find the
+//! session again.
+//!
+//! Peers behind NAT can be reached through a circuit relay. Once two peers are connected through a
+//! relay, DCUtR tries to punch a hole for a direct connection, which is then used instead.
+
+use crate::codec::MessageCodec;
+use crate::data::{Frame, Inbox};
+use crate::{message_sender, other_error};
+use async_std::io;
+use dprun::inspect::is_name_server_announcement;
+use dprun::{AppController, Delivery, PlayerIdentity, Roster, GUID};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use libp2p::core::transport::{MemoryTransport, OptionalTransport, Transport};
+use libp2p::core::{muxing::StreamMuxerBox, transport::Boxed, upgrade, ConnectedPoint};
+use libp2p::identity::Keypair;
+use libp2p::multiaddr::Protocol;
+use libp2p::request_response::{
+    self, OutboundFailure, OutboundRequestId, ProtocolSupport, ResponseChannel,
+};
+use libp2p::swarm::{
+    behaviour::toggle::Toggle, dial_opts::DialOpts, ConnectionId, NetworkBehaviour, SwarmEvent,
+};
+use libp2p::{
+    allow_block_list, dcutr, identify, mdns, noise, relay, tcp, yamux, Multiaddr, PeerId,
+    StreamProtocol, Swarm,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Protocol for finding sessions hosted by other peers.
+const ENUM_PROTOCOL: StreamProtocol = StreamProtocol::new("/dpsp-enum/1.0.0");
+/// Protocol for game messages between peers in a session.
+const DATA_PROTOCOL: StreamProtocol = StreamProtocol::new("/dpsp-data/1.0.0");
+
+/// Protocol version that peers announce through identify.
+pub(crate) const IDENTIFY_PROTOCOL_VERSION: &str = "/dpsp/1.0.0";
+
+/// How long to keep connections to peers open while nothing is happening on them. Enumerating
+/// sessions only reaches peers we are connected to, so this is generous.
+pub(crate) const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How we are connected to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    /// Straight to the peer.
+    Direct,
+    /// Through a circuit relay, because no direct connection could be made (yet).
+    Relayed,
+}
+
+/// The links to connected peers, shared with the service provider.
+pub(crate) type Links = Arc<Mutex<HashMap<PeerId, Link>>>;
+
+/// Which peers we accept connections with.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerAccess {
+    /// If set, only these peers may connect.
+    pub allowed: Option<HashSet<PeerId>>,
+    /// These peers may never connect.
+    pub blocked: HashSet<PeerId>,
+}
+
+/// The network behaviour of a service provider's swarm.
+///
+/// Applications that already keep a swarm with connections to the other players can build it with
+/// this behaviour and pass it to `Libp2pSP::from_swarm`, so the game reuses those connections
+/// instead of making new ones. `build_swarm` builds such a swarm, and `build_transport` and
+/// `Behaviour::with_relay_client` are its parts, for applications that configure the swarm
+/// themselves.
+#[derive(NetworkBehaviour)]
+pub struct Behaviour {
+    allowed_peers: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    blocked_peers: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
+    enum_sessions: request_response::Behaviour<MessageCodec>,
+    data: request_response::Behaviour<MessageCodec>,
+    mdns: Toggle<mdns::async_io::Behaviour>,
+    relay_client: Toggle<relay::client::Behaviour>,
+    identify: identify::Behaviour,
+    dcutr: dcutr::Behaviour,
+}
+
+impl Behaviour {
+    /// Create the behaviour for a swarm with the given identity, optionally looking for peers on
+    /// the LAN.
+    pub fn new(key: &Keypair, use_mdns: bool) -> io::Result<Self> {
+        Self::with_access(key, use_mdns, &PeerAccess::default(), None)
+    }
+
+    /// Create the behaviour for a swarm that can be reached through circuit relays. The relay
+    /// client comes from `libp2p::relay::client::new`, together with the transport to pass to
+    /// `build_transport`.
+    pub fn with_relay_client(
+        key: &Keypair,
+        use_mdns: bool,
+        relay_client: relay::client::Behaviour,
+    ) -> io::Result<Self> {
+        Self::with_access(key, use_mdns, &PeerAccess::default(), Some(relay_client))
+    }
+
+    fn with_access(
+        key: &Keypair,
+        use_mdns: bool,
+        access: &PeerAccess,
+        relay_client: Option<relay::client::Behaviour>,
+    ) -> io::Result<Self> {
+        let allowed_peers = access.allowed.as_ref().map(|allowed| {
+            let mut behaviour = allow_block_list::Behaviour::default();
+            for &peer in allowed {
+                behaviour.allow_peer(peer);
+            }
+            behaviour
+        });
+        let mut blocked_peers = allow_block_list::Behaviour::default();
+        for &peer in &access.blocked {
+            blocked_peers.block_peer(peer);
+        }
+        let mdns = if use_mdns {
+            let config = mdns::Config::default();
+            Some(mdns::async_io::Behaviour::new(
+                config,
+                key.public().to_peer_id(),
+            )?)
+        } else {
+            None
+        };
+        Ok(Self {
+            allowed_peers: allowed_peers.into(),
+            blocked_peers,
+            enum_sessions: request_response::Behaviour::new(
+                [(ENUM_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            data: request_response::Behaviour::new(
+                [(DATA_PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            mdns: mdns.into(),
+            relay_client: relay_client.into(),
+            identify: identify::Behaviour::new(identify::Config::new(
+                IDENTIFY_PROTOCOL_VERSION.into(),
+                key.public(),
+            )),
+            dcutr: dcutr::Behaviour::new(key.public().to_peer_id()),
+        })
+    }
+}
+
+/// Build a transport that can dial both TCP and in-memory addresses, and relayed addresses if a
+/// relay client transport is given.
+pub fn build_transport(
+    key: &Keypair,
+    relay: Option<relay::client::Transport>,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let noise = noise::Config::new(key).map_err(other_error)?;
+    let relay = match relay {
+        Some(relay) => OptionalTransport::some(relay),
+        None => OptionalTransport::none(),
+    };
+    Ok(relay
+        .or_transport(tcp::async_io::Transport::new(
+            tcp::Config::default().nodelay(true),
+        ))
+        .or_transport(MemoryTransport::default())
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(yamux::Config::default())
+        .timeout(Duration::from_secs(20))
+        .boxed())
+}
+
+/// Build a swarm for a service provider, optionally looking for peers on the LAN. It can dial
+/// TCP, in-memory and relayed addresses.
+pub fn build_swarm(key: &Keypair, use_mdns: bool) -> io::Result<Swarm<Behaviour>> {
+    build_swarm_with_access(key, use_mdns, &PeerAccess::default())
+}
+
+/// Build a swarm for a service provider that only connects to the peers that `access` allows.
+pub(crate) fn build_swarm_with_access(
+    key: &Keypair,
+    use_mdns: bool,
+    access: &PeerAccess,
+) -> io::Result<Swarm<Behaviour>> {
+    let (relay_transport, relay_client) = relay::client::new(key.public().to_peer_id());
+    Ok(Swarm::new(
+        build_transport(key, Some(relay_transport))?,
+        Behaviour::with_access(key, use_mdns, access, Some(relay_client))?,
+        key.public().to_peer_id(),
+        libp2p::swarm::Config::with_async_std_executor()
+            .with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT),
+    ))
+}
+
+/// Messages from the service provider to the swarm task.
+///
+/// Commands from the game carry its controller, which incoming messages are delivered to from
+/// then on.
+pub(crate) enum Command {
+    /// The local game opened a session, either hosting (`create`) or joining it. Hosts answer
+    /// enumeration requests from other peers.
+    Open {
+        controller: AppController,
+        create: bool,
+        /// Whether another peer takes over as the host when the host leaves.
+        migrate_host: bool,
+    },
+    /// Ask all peers for their sessions.
+    EnumSessions {
+        controller: AppController,
+        message: Vec<u8>,
+    },
+    /// The local game created a player.
+    CreatePlayer {
+        controller: AppController,
+        player: GUID,
+    },
+    /// The local game answered a message from another game.
+    Reply {
+        controller: AppController,
+        reply_to: GUID,
+        message: Vec<u8>,
+    },
+    /// The local game sent a message to a player, or to everyone in the session if there is no
+    /// receiver.
+    Send {
+        controller: AppController,
+        sender: GUID,
+        receiver: Option<GUID>,
+        delivery: Delivery,
+        message: Vec<u8>,
+    },
+    /// Remove a player's peer from the session, and with `ban`, keep it out.
+    Kick { identity: PlayerIdentity, ban: bool },
+}
+
+impl Command {
+    fn controller(&self) -> Option<&AppController> {
+        match self {
+            Command::Open { controller, .. }
+            | Command::EnumSessions { controller, .. }
+            | Command::CreatePlayer { controller, .. }
+            | Command::Reply { controller, .. }
+            | Command::Send { controller, .. } => Some(controller),
+            Command::Kick { .. } => None,
+        }
+    }
+}
+
+pub(crate) struct Network {
+    swarm: Swarm<Behaviour>,
+    peers: HashSet<PeerId>,
+    /// How each connection to a peer is made. A relayed connection usually stays open next to
+    /// the direct one that replaces it.
+    connections: HashMap<PeerId, HashMap<ConnectionId, Link>>,
+    links: Links,
+    /// Peers found on the LAN, with the addresses they were found at. These are candidate hosts
+    /// until their mDNS records expire.
+    discovered: HashMap<PeerId, HashSet<Multiaddr>>,
+    /// The local game.
+    app: Option<AppController>,
+    /// Whether the local game is hosting a session.
+    hosting: bool,
+    /// Whether the session goes on with a new host when the host leaves.
+    migrate_host: bool,
+    /// The most recent enumeration request, sent to peers that connect later.
+    enum_request: Option<Vec<u8>>,
+    /// Enumeration requests from other peers that the local game has not answered yet, keyed
+    /// by the GUID that the requesting game sent them with.
+    pending_enums: HashMap<GUID, ResponseChannel<Vec<u8>>>,
+    /// The peer hosting the session, once it has answered an enumeration request.
+    host_peer: Option<PeerId>,
+    /// Peers that take part in the session.
+    session_peers: HashSet<PeerId>,
+    /// Players created by the local game.
+    local_players: Vec<GUID>,
+    /// The peers that remote players live on.
+    players: HashMap<GUID, PeerId>,
+    /// Player names and IDs in the session, and who is banned from it.
+    roster: Roster,
+    /// Sequence number of the next guaranteed frame to each peer.
+    next_seq: HashMap<PeerId, u64>,
+    /// Guaranteed frames from each peer that arrived early.
+    inboxes: HashMap<PeerId, Inbox>,
+    /// Guaranteed frames that the other side has not acknowledged yet. They are sent again until
+    /// they arrive or the peer disconnects, because the peer can not deliver anything after a
+    /// missing frame.
+    in_flight: HashMap<OutboundRequestId, (PeerId, Vec<u8>)>,
+    /// The relay circuit address to listen on and the peer to dial, once the swarm listens.
+    /// Connections made before that do not come from the port we listen on, and peers behind
+    /// NAT need to see that port to punch a hole to us.
+    relay_listen_address: Option<Multiaddr>,
+    dial_address: Option<Multiaddr>,
+}
+
+impl Network {
+    pub fn new(swarm: Swarm<Behaviour>, links: Links) -> Self {
+        // Peers that a swarm passed in by the application is already connected to do not show up
+        // as new connections.
+        let peers = swarm.connected_peers().copied().collect();
+        Self {
+            swarm,
+            peers,
+            connections: HashMap::new(),
+            links,
+            discovered: HashMap::new(),
+            app: None,
+            hosting: false,
+            migrate_host: false,
+            enum_request: None,
+            pending_enums: HashMap::new(),
+            host_peer: None,
+            session_peers: HashSet::new(),
+            local_players: vec![],
+            players: HashMap::new(),
+            roster: Roster::new(),
+            next_seq: HashMap::new(),
+            inboxes: HashMap::new(),
+            in_flight: HashMap::new(),
+            relay_listen_address: None,
+            dial_address: None,
+        }
+    }
+
+    /// Listen through a relay and dial a peer once the swarm listens, or right away with `now`.
+    pub fn connect(&mut self, relay: Option<Multiaddr>, dial: Option<Multiaddr>, now: bool) {
+        self.relay_listen_address = relay.map(|relay| relay.with(Protocol::P2pCircuit));
+        self.dial_address = dial;
+        if now {
+            self.connect_pending();
+        }
+    }
+
+    fn connect_pending(&mut self) {
+        if let Some(address) = self.relay_listen_address.take() {
+            log::debug!("[libp2p] listening through relay {}", address);
+            if let Err(err) = self.swarm.listen_on(address) {
+                log::warn!("[libp2p] could not listen through relay: {}", err);
+            }
+        }
+        if let Some(address) = self.dial_address.take() {
+            log::debug!("[libp2p] dialing {}", address);
+            if let Err(err) = self.swarm.dial(address) {
+                log::warn!("[libp2p] could not dial: {}", err);
+            }
+        }
+    }
+
+    /// Drive the swarm until the service provider goes away.
+    pub async fn run(mut self, commands: UnboundedReceiver<Command>) {
+        let mut commands = commands.fuse();
+        loop {
+            futures::select! {
+                event = self.swarm.select_next_some() => self.handle_event(event).await,
+                command = commands.next() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+            }
+        }
+        log::debug!("[libp2p] service provider is gone, stopping swarm");
+    }
+
+    /// Send a frame to a peer on the data protocol.
+    fn send_frame(&mut self, peer: PeerId, frame: &Frame) {
+        let seq = match frame.delivery() {
+            Delivery::Guaranteed => {
+                let next_seq = self.next_seq.entry(peer).or_default();
+                *next_seq += 1;
+                *next_seq - 1
+            }
+            Delivery::Unreliable => 0,
+        };
+        let bytes = frame.encode(seq);
+        self.send_bytes(peer, bytes, frame.delivery());
+    }
+
+    fn send_bytes(&mut self, peer: PeerId, bytes: Vec<u8>, delivery: Delivery) {
+        let request_id = self
+            .swarm
+            .behaviour_mut()
+            .data
+            .send_request(&peer, bytes.clone());
+        if delivery == Delivery::Guaranteed {
+            self.in_flight.insert(request_id, (peer, bytes));
+        }
+    }
+
+    /// Start exchanging game messages with a peer.
+    fn join_session(&mut self, peer: PeerId) {
+        if self.session_peers.insert(peer) && !self.local_players.is_empty() {
+            let players = Frame::Players(self.local_players.clone());
+            self.send_frame(peer, &players);
+        }
+    }
+
+    /// Publish the best link to a peer after its connections changed.
+    fn update_link(&mut self, peer: PeerId) {
+        let best = self.connections.get(&peer).and_then(|connections| {
+            connections
+                .values()
+                .copied()
+                .min_by_key(|link| *link == Link::Relayed)
+        });
+        let mut links = self.links.lock().unwrap();
+        match best {
+            Some(link) => {
+                links.insert(peer, link);
+            }
+            None => {
+                links.remove(&peer);
+            }
+        }
+    }
+
+    /// Dial peers found on the LAN. Once connected, they receive enumeration requests like any
+    /// other peer.
+    fn discovered(&mut self, found: Vec<(PeerId, Multiaddr)>) {
+        let mut new_peers = HashSet::new();
+        for (peer, address) in found {
+            if self
+                .discovered
+                .entry(peer)
+                .or_default()
+                .insert(address.clone())
+            {
+                log::debug!("[libp2p] discovered {} at {}", peer, address);
+                self.swarm.add_peer_address(peer, address);
+                new_peers.insert(peer);
+            }
+        }
+        for peer in new_peers {
+            if self.swarm.is_connected(&peer) {
+                continue;
+            }
+            let addresses = self.discovered[&peer].iter().cloned().collect();
+            if let Err(err) = self
+                .swarm
+                .dial(DialOpts::peer_id(peer).addresses(addresses).build())
+            {
+                log::debug!("[libp2p] could not dial {}: {:?}", peer, err);
+            }
+        }
+    }
+
+    /// Forget peers that are no longer announced on the LAN. Unless they take part in our
+    /// session, disconnect from them, so they are not asked for sessions anymore.
+    fn expired(&mut self, lost: Vec<(PeerId, Multiaddr)>) {
+        for (peer, address) in lost {
+            let addresses = match self.discovered.get_mut(&peer) {
+                Some(addresses) => addresses,
+                None => continue,
+            };
+            addresses.remove(&address);
+            if !addresses.is_empty() {
+                continue;
+            }
+            log::debug!("[libp2p] {} is gone from the LAN", peer);
+            self.discovered.remove(&peer);
+            if !self.session_peers.contains(&peer) && self.host_peer != Some(peer) {
+                let _ = self.swarm.disconnect_peer_id(peer);
+            }
+        }
+    }
+
+    /// Kick a player's peer from the session.
+    async fn kick(&mut self, identity: PlayerIdentity, ban: bool) {
+        // Only the host has a say in who takes part in the session.
+        if !self.hosting {
+            log::warn!("[libp2p] only the host can kick players");
+            return;
+        }
+        let instance = match &identity {
+            PlayerIdentity::Guid(id) => Some(*id),
+            PlayerIdentity::Name(name) => self.roster.find(name),
+        };
+        match instance.and_then(|instance| self.players.get(&instance)) {
+            Some(&peer) if self.host_peer == Some(peer) => {
+                log::warn!("[libp2p] can not kick the host");
+            }
+            Some(&peer) => {
+                if ban {
+                    self.roster.ban(&identity);
+                }
+                self.kick_peer(peer, ban).await
+            }
+            None => log::debug!("[libp2p] don't know where {:?} is", identity),
+        }
+    }
+
+    /// Disconnect a peer, and tell the local game and the other peers in the session that its
+    /// players left.
+    async fn kick_peer(&mut self, peer: PeerId, ban: bool) {
+        log::debug!("[libp2p] kicking {}", peer);
+        let instances: Vec<GUID> = self
+            .players
+            .iter()
+            .filter(|(_, other)| **other == peer)
+            .map(|(&instance, _)| instance)
+            .collect();
+        let mut deletes = vec![];
+        for instance in instances {
+            deletes.extend(self.roster.delete_messages(instance));
+            self.roster.forget(instance);
+        }
+        if ban {
+            self.swarm.behaviour_mut().blocked_peers.block_peer(peer);
+        } else {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+        self.session_peers.remove(&peer);
+        self.players.retain(|_, other| *other != peer);
+
+        for data in deletes {
+            let frame = Frame::Message {
+                delivery: Delivery::Guaranteed,
+                sender: GUID::nil(),
+                data: data.clone(),
+            };
+            for other in self.session_peers.clone() {
+                self.send_frame(other, &frame);
+            }
+            if let Some(app) = &mut self.app {
+                app.deliver(Delivery::Guaranteed, data).await;
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        if let Some(controller) = command.controller() {
+            self.app = Some(controller.clone());
+        }
+        match command {
+            Command::Open {
+                create,
+                migrate_host,
+                ..
+            } => {
+                self.hosting = create;
+                self.migrate_host = migrate_host;
+            }
+            Command::EnumSessions { message, .. } => {
+                for peer in &self.peers {
+                    self.swarm
+                        .behaviour_mut()
+                        .enum_sessions
+                        .send_request(peer, message.clone());
+                }
+                self.enum_request = Some(message);
+            }
+            Command::CreatePlayer { player, .. } => {
+                self.local_players.push(player);
+                let players = Frame::Players(vec![player]);
+                for peer in self.session_peers.clone() {
+                    self.send_frame(peer, &players);
+                }
+            }
+            Command::Reply {
+                reply_to, message, ..
+            } => {
+                if let Some(channel) = self.pending_enums.remove(&reply_to) {
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .enum_sessions
+                        .send_response(channel, message);
+                } else if let Some(&peer) = self.players.get(&reply_to) {
+                    let reply = Frame::Message {
+                        delivery: Delivery::Guaranteed,
+                        sender: GUID::nil(),
+                        data: message,
+                    };
+                    self.send_frame(peer, &reply);
+                } else {
+                    log::debug!("[libp2p] nobody is waiting for a reply to {}", reply_to);
+                }
+            }
+            Command::Send {
+                sender,
+                receiver,
+                delivery,
+                message,
+                ..
+            } => {
+                // After the host left, the local game announces that it took over.
+                if self.migrate_host
+                    && !self.hosting
+                    && self.host_peer.is_none()
+                    && is_name_server_announcement(&message)
+                {
+                    log::debug!("[libp2p] taking over as the host");
+                    self.hosting = true;
+                }
+                let peers = match receiver {
+                    Some(receiver) => match self.players.get(&receiver) {
+                        Some(&peer) => vec![peer],
+                        None => {
+                            log::debug!("[libp2p] don't know where player {} is", receiver);
+                            vec![]
+                        }
+                    },
+                    None => self.session_peers.iter().copied().collect(),
+                };
+                let frame = Frame::Message {
+                    delivery,
+                    sender,
+                    data: message,
+                };
+                for peer in peers {
+                    self.send_frame(peer, &frame);
+                }
+            }
+            Command::Kick { identity, ban } => self.kick(identity, ban).await,
+        }
+    }
+
+    async fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                log::debug!("[libp2p] listening on {}", address);
+                self.connect_pending();
+            }
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                let link = if endpoint.is_relayed() {
+                    Link::Relayed
+                } else {
+                    Link::Direct
+                };
+                log::debug!("[libp2p] connected to {} ({:?})", peer_id, link);
+                self.connections
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(connection_id, link);
+                self.update_link(peer_id);
+                if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                    if link == Link::Direct {
+                        self.swarm.add_peer_address(peer_id, address);
+                    }
+                }
+                if self.peers.insert(peer_id) {
+                    if let Some(message) = &self.enum_request {
+                        self.swarm
+                            .behaviour_mut()
+                            .enum_sessions
+                            .send_request(&peer_id, message.clone());
+                    }
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                if let Some(connections) = self.connections.get_mut(&peer_id) {
+                    connections.remove(&connection_id);
+                    if connections.is_empty() {
+                        self.connections.remove(&peer_id);
+                    }
+                }
+                self.update_link(peer_id);
+                if num_established > 0 {
+                    return;
+                }
+                log::debug!("[libp2p] disconnected from {}", peer_id);
+                self.peers.remove(&peer_id);
+                self.session_peers.remove(&peer_id);
+                self.players.retain(|_, peer| *peer != peer_id);
+                // Both sides start counting guaranteed frames again if the peer comes back.
+                self.next_seq.remove(&peer_id);
+                self.inboxes.remove(&peer_id);
+                self.in_flight.retain(|_, (peer, _)| *peer != peer_id);
+                if self.host_peer == Some(peer_id) {
+                    log::debug!("[libp2p] lost connection to the host");
+                    self.host_peer = None;
+                    if self.migrate_host {
+                        log::debug!("[libp2p] waiting for another peer to take over");
+                    } else if let Some(app) = &mut self.app {
+                        app.close().await;
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::EnumSessions(event)) => {
+                self.handle_enum_event(event).await
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Data(event)) => {
+                self.handle_data_event(event).await
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                self.discovered(found)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(lost))) => {
+                self.expired(lost)
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            })) => match result {
+                Ok(_) => log::debug!("[libp2p] direct connection to {}", remote_peer_id),
+                Err(err) => log::debug!("[libp2p] staying relayed to {}: {}", remote_peer_id, err),
+            },
+            event => log::trace!("[libp2p] {:?}", event),
+        }
+    }
+
+    async fn handle_enum_event(&mut self, event: request_response::Event<Vec<u8>, Vec<u8>>) {
+        match event {
+            request_response::Event::Message {
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => match (&mut self.app, self.hosting, message_sender(&request)) {
+                (Some(host), true, Some(requester)) => {
+                    self.pending_enums.insert(requester, channel);
+                    host.send(request).await;
+                }
+                // We are not hosting, so there are no sessions to describe.
+                _ => {
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .enum_sessions
+                        .send_response(channel, vec![]);
+                }
+            },
+            request_response::Event::Message {
+                peer,
+                message: request_response::Message::Response { response, .. },
+                ..
+            } => {
+                if let (Some(app), false) = (&mut self.app, response.is_empty()) {
+                    self.host_peer = Some(peer);
+                    app.send(response).await;
+                    self.join_session(peer);
+                }
+            }
+            event => log::debug!("[libp2p] enum_sessions: {:?}", event),
+        }
+    }
+
+    async fn handle_data_event(&mut self, event: request_response::Event<Vec<u8>, Vec<u8>>) {
+        match event {
+            request_response::Event::Message {
+                peer,
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .data
+                    .send_response(channel, vec![]);
+                let (seq, frame) = match Frame::decode(&request) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        log::warn!("[libp2p] bad frame from {}: {:?}", peer, err);
+                        return;
+                    }
+                };
+                self.join_session(peer);
+                let frames = match self.inboxes.entry(peer).or_default().receive(seq, frame) {
+                    Ok(frames) => frames,
+                    Err(err) => {
+                        // Start over with a new connection, like when a frame could not be sent.
+                        log::warn!("[libp2p] dropping {}: {:?}", peer, err);
+                        let _ = self.swarm.disconnect_peer_id(peer);
+                        return;
+                    }
+                };
+                for frame in frames {
+                    self.receive_frame(peer, frame).await;
+                }
+            }
+            request_response::Event::Message {
+                message: request_response::Message::Response { request_id, .. },
+                ..
+            } => {
+                self.in_flight.remove(&request_id);
+            }
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                log::debug!("[libp2p] sending to {} failed: {:?}", peer, error);
+                if let Some((peer, bytes)) = self.in_flight.remove(&request_id) {
+                    let retry = matches!(
+                        error,
+                        OutboundFailure::Timeout
+                            | OutboundFailure::ConnectionClosed
+                            | OutboundFailure::Io(_)
+                    );
+                    // Once the peer is gone, so are the sequence numbers.
+                    if retry && self.peers.contains(&peer) {
+                        self.send_bytes(peer, bytes, Delivery::Guaranteed);
+                    } else if self.peers.contains(&peer) {
+                        // Without this frame, the peer would wait for it forever. Start over
+                        // with a new connection instead.
+                        let _ = self.swarm.disconnect_peer_id(peer);
+                    }
+                }
+            }
+            event => log::debug!("[libp2p] data: {:?}", event),
+        }
+    }
+
+    /// Handle a frame from a peer, in order.
+    async fn receive_frame(&mut self, peer: PeerId, frame: Frame) {
+        match frame {
+            Frame::Message {
+                delivery,
+                sender,
+                data,
+            } => {
+                // Only a session whose host left can get a new one.
+                if self.migrate_host
+                    && !self.hosting
+                    && self.host_peer.is_none()
+                    && is_name_server_announcement(&data)
+                {
+                    log::debug!("[libp2p] {} took over as the host", peer);
+                    self.host_peer = Some(peer);
+                }
+                // Replies to messages go to the GUID that the message started with. Players stay
+                // on the peer they were first seen on: messages about a kicked player come from
+                // the peer that kicked it.
+                for player in std::iter::once(sender).chain(message_sender(&data)) {
+                    if !player.is_nil() && !self.local_players.contains(&player) {
+                        self.players.entry(player).or_insert(peer);
+                    }
+                }
+                if let Some(instance) = message_sender(&data) {
+                    self.roster.observe(instance, &data);
+                    if self.roster.is_banned(instance) && self.host_peer != Some(peer) {
+                        self.kick_peer(peer, true).await;
+                        return;
+                    }
+                }
+                if let Some(app) = &mut self.app {
+                    app.deliver(delivery, data).await;
+                }
+            }
+            Frame::Players(players) => {
+                for player in players {
+                    self.players.insert(player, peer);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Libp2pSP;
+    use async_std::future::timeout;
+    use async_std::task;
+    use dprun::{structs, AppMessage, ServiceProvider};
+
+    /// Handle swarm events for a while.
+    async fn drive(network: &mut Network, duration: Duration) {
+        let _ = timeout(duration, async {
+            loop {
+                let event = network.swarm.select_next_some().await;
+                network.handle_event(event).await;
+            }
+        })
+        .await;
+    }
+
+    #[test]
+    fn enumerates_discovered_peers_until_they_expire() {
+        let address: Multiaddr = "/memory/38001".parse().unwrap();
+        let joiner_id = GUID::from_u128(1);
+
+        task::block_on(async {
+            let mut host = Libp2pSP::default()
+                .with_mdns(false)
+                .with_listen_address(address.clone());
+            let host_peer = host.local_key.public().to_peer_id();
+            let (host_controller, host_receiver) = AppController::create();
+            let open = structs::OpenData {
+                create: true,
+                return_status: false,
+                open_flags: 0,
+                session_flags: 0,
+            };
+            host.open(host_controller, 0, open).await.unwrap();
+
+            let key = Keypair::generate_ed25519();
+            let mut network = Network::new(build_swarm(&key, false).unwrap(), Links::default());
+            let (controller, _receiver) = AppController::create();
+            let mut request = joiner_id.as_bytes().to_vec();
+            request.extend_from_slice(b"play");
+            network
+                .handle_command(Command::EnumSessions {
+                    controller: controller.clone(),
+                    message: request.clone(),
+                })
+                .await;
+
+            network.discovered(vec![(host_peer, address.clone())]);
+            drive(&mut network, Duration::from_secs(1)).await;
+            assert!(network.peers.contains(&host_peer));
+            match host_receiver.try_recv() {
+                Ok(AppMessage::Send(_, _, data)) => assert_eq!(data, request),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            network.expired(vec![(host_peer, address)]);
+            drive(&mut network, Duration::from_secs(1)).await;
+            assert!(!network.peers.contains(&host_peer));
+            network
+                .handle_command(Command::EnumSessions {
+                    controller,
+                    message: request,
+                })
+                .await;
+            drive(&mut network, Duration::from_millis(500)).await;
+            assert!(host_receiver.is_empty());
+
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    /// Handle swarm events of two networks for a while.
+    async fn drive_both(a: &mut Network, b: &mut Network, duration: Duration) {
+        let _ = timeout(duration, async {
+            loop {
+                futures::select! {
+                    event = a.swarm.select_next_some() => a.handle_event(event).await,
+                    event = b.swarm.select_next_some() => b.handle_event(event).await,
+                }
+            }
+        })
+        .await;
+    }
+
+    fn network() -> Network {
+        let key = Keypair::generate_ed25519();
+        Network::new(build_swarm(&key, false).unwrap(), Links::default())
+    }
+
+    /// Handle swarm events of several networks for a while.
+    async fn drive_all(networks: &mut [&mut Network], duration: Duration) {
+        let _ = timeout(duration, async {
+            loop {
+                let (event, index, _) = futures::future::select_all(
+                    networks
+                        .iter_mut()
+                        .map(|network| network.swarm.select_next_some()),
+                )
+                .await;
+                networks[index].handle_event(event).await;
+            }
+        })
+        .await;
+    }
+
+    fn guaranteed(data: &[u8]) -> Frame {
+        Frame::Message {
+            delivery: Delivery::Guaranteed,
+            sender: GUID::from_u128(1),
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn resends_guaranteed_frames_until_they_arrive() {
+        let address: Multiaddr = "/memory/38002".parse().unwrap();
+
+        task::block_on(async {
+            let mut sender = network();
+            sender.swarm.listen_on(address.clone()).unwrap();
+            let mut receiver = network();
+            let (controller, messages) = AppController::create();
+            receiver.app = Some(controller);
+            receiver.swarm.dial(address).unwrap();
+            drive_both(&mut sender, &mut receiver, Duration::from_millis(500)).await;
+            let peer = *receiver.swarm.local_peer_id();
+            assert!(sender.peers.contains(&peer));
+
+            // The first frame gets lost more often than a few retries would cover.
+            sender.send_frame(peer, &guaranteed(b"first"));
+            for _ in 0..5 {
+                let request_id = *sender.in_flight.keys().next().unwrap();
+                sender
+                    .handle_data_event(request_response::Event::OutboundFailure {
+                        peer,
+                        request_id,
+                        error: OutboundFailure::Timeout,
+                    })
+                    .await;
+                assert_eq!(sender.in_flight.len(), 1);
+            }
+            sender.send_frame(peer, &guaranteed(b"second"));
+            drive_both(&mut sender, &mut receiver, Duration::from_millis(500)).await;
+            assert!(sender.in_flight.is_empty());
+            let mut received = vec![];
+            while let Ok(AppMessage::Send(_, _, data)) = messages.try_recv() {
+                received.push(data);
+            }
+            assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+
+            // A frame that can never arrive ends the connection, so both sides start over.
+            sender.send_frame(peer, &guaranteed(b"third"));
+            let request_id = *sender.in_flight.keys().next().unwrap();
+            sender
+                .handle_data_event(request_response::Event::OutboundFailure {
+                    peer,
+                    request_id,
+                    error: OutboundFailure::UnsupportedProtocols,
+                })
+                .await;
+            drive_both(&mut sender, &mut receiver, Duration::from_millis(500)).await;
+            assert!(!sender.peers.contains(&peer));
+            assert!(sender.in_flight.is_empty());
+            assert!(!sender.next_seq.contains_key(&peer));
+        });
+    }
+
+    #[test]
+    fn nobody_takes_over_without_host_migration() {
+        let mut announcement = GUID::from_u128(2).as_bytes().to_vec();
+        announcement.extend_from_slice(b"play\x35\x00\x0e\x00");
+        announcement.extend_from_slice(&[0; 16]);
+
+        task::block_on(async {
+            let mut joiner = network();
+            let (controller, _messages) = AppController::create();
+            joiner
+                .handle_command(Command::Open {
+                    controller: controller.clone(),
+                    create: false,
+                    migrate_host: false,
+                })
+                .await;
+            joiner
+                .handle_command(Command::Send {
+                    controller,
+                    sender: GUID::from_u128(1),
+                    receiver: None,
+                    delivery: Delivery::Guaranteed,
+                    message: announcement.clone(),
+                })
+                .await;
+            assert!(!joiner.hosting);
+
+            let frame = Frame::Message {
+                delivery: Delivery::Guaranteed,
+                sender: GUID::from_u128(2),
+                data: announcement,
+            };
+            joiner.receive_frame(PeerId::random(), frame).await;
+            assert_eq!(joiner.host_peer, None);
+        });
+    }
+
+    #[test]
+    fn another_peer_takes_over_when_the_host_leaves() {
+        let host_address: Multiaddr = "/memory/38003".parse().unwrap();
+        let a_address: Multiaddr = "/memory/38004".parse().unwrap();
+        let (host_id, a_id, b_id) = (GUID::from_u128(1), GUID::from_u128(2), GUID::from_u128(3));
+        let announcement = |id: GUID| {
+            let mut message = id.as_bytes().to_vec();
+            message.extend_from_slice(b"play\x35\x00\x0e\x00");
+            message.extend_from_slice(&[0; 16]);
+            message
+        };
+        let send = |controller: &AppController, sender, receiver, message| Command::Send {
+            controller: controller.clone(),
+            sender,
+            receiver,
+            delivery: Delivery::Guaranteed,
+            message,
+        };
+
+        task::block_on(async {
+            let mut host = network();
+            let mut a = network();
+            let mut b = network();
+            let host_peer = *host.swarm.local_peer_id();
+            let a_peer = *a.swarm.local_peer_id();
+            let mut controllers = vec![];
+            for (network, id, create) in [
+                (&mut host, host_id, true),
+                (&mut a, a_id, false),
+                (&mut b, b_id, false),
+            ] {
+                let (controller, receiver) = AppController::create();
+                network
+                    .handle_command(Command::Open {
+                        controller: controller.clone(),
+                        create,
+                        migrate_host: true,
+                    })
+                    .await;
+                network
+                    .handle_command(Command::CreatePlayer {
+                        controller: controller.clone(),
+                        player: id,
+                    })
+                    .await;
+                controllers.push((controller, receiver));
+            }
+            let (_, host_messages) = controllers.remove(0);
+            let (a_controller, a_messages) = controllers.remove(0);
+            let (b_controller, b_messages) = controllers.remove(0);
+
+            // Both joiners found the session on the host, and know each other.
+            host.swarm.listen_on(host_address.clone()).unwrap();
+            a.swarm.listen_on(a_address.clone()).unwrap();
+            drive_all(&mut [&mut host, &mut a], Duration::from_millis(100)).await;
+            a.swarm.dial(host_address.clone()).unwrap();
+            b.swarm.dial(host_address).unwrap();
+            b.swarm.dial(a_address).unwrap();
+            drive_all(&mut [&mut host, &mut a, &mut b], Duration::from_millis(500)).await;
+            for joiner in [&mut a, &mut b] {
+                joiner.host_peer = Some(host_peer);
+                joiner.join_session(host_peer);
+            }
+            b.join_session(a_peer);
+            drive_all(&mut [&mut host, &mut a, &mut b], Duration::from_millis(500)).await;
+
+            // Nobody takes over while the host is still there.
+            b.handle_command(send(&b_controller, b_id, None, announcement(b_id)))
+                .await;
+            drive_all(&mut [&mut host, &mut a, &mut b], Duration::from_millis(500)).await;
+            assert!(!b.hosting);
+            assert_eq!(a.host_peer, Some(host_peer));
+            assert!(!host_messages.is_empty());
+            // Only the host can kick players.
+            b.kick(PlayerIdentity::Guid(a_id), false).await;
+            drive_all(&mut [&mut host, &mut a, &mut b], Duration::from_millis(200)).await;
+            assert!(b.session_peers.contains(&a_peer));
+            assert_eq!(b.players.get(&a_id), Some(&a_peer));
+
+            drop(host);
+            drive_all(&mut [&mut a, &mut b], Duration::from_millis(500)).await;
+            assert_eq!(a.host_peer, None);
+            assert_eq!(b.host_peer, None);
+            while let Ok(message) = a_messages.try_recv() {
+                assert!(!matches!(message, AppMessage::Close));
+            }
+            while let Ok(message) = b_messages.try_recv() {
+                assert!(!matches!(message, AppMessage::Close));
+            }
+
+            // A takes over, and B can not take over from A anymore.
+            a.handle_command(send(&a_controller, a_id, None, announcement(a_id)))
+                .await;
+            assert!(a.hosting);
+            drive_all(&mut [&mut a, &mut b], Duration::from_millis(500)).await;
+            assert_eq!(b.host_peer, Some(a_peer));
+            b.handle_command(send(&b_controller, b_id, None, announcement(b_id)))
+                .await;
+            drive_all(&mut [&mut a, &mut b], Duration::from_millis(500)).await;
+            assert!(a.hosting);
+            assert!(!b.hosting);
+            assert_eq!(a.host_peer, None);
+            assert_eq!(b.host_peer, Some(a_peer));
+            // The new host can not kick, or ban, its own player.
+            a.kick(PlayerIdentity::Guid(a_id), true).await;
+            assert!(!a.roster.is_banned(a_id));
+            while a_messages.try_recv().is_ok() {}
+
+            // The new host answers enumeration requests, and still hears from B.
+            let mut newcomer = network();
+            let (newcomer_controller, _newcomer_messages) = AppController::create();
+            newcomer
+                .swarm
+                .dial(a.swarm.listeners().next().unwrap().clone())
+                .unwrap();
+            drive_all(&mut [&mut a, &mut newcomer], Duration::from_millis(500)).await;
+            let mut request = GUID::from_u128(4).as_bytes().to_vec();
+            request.extend_from_slice(b"play");
+            newcomer
+                .handle_command(Command::EnumSessions {
+                    controller: newcomer_controller,
+                    message: request.clone(),
+                })
+                .await;
+            b.handle_command(send(&b_controller, b_id, Some(a_id), b"hello".to_vec()))
+                .await;
+            drive_all(
+                &mut [&mut a, &mut b, &mut newcomer],
+                Duration::from_millis(500),
+            )
+            .await;
+            let mut received = vec![];
+            while let Ok(AppMessage::Send(_, _, data)) = a_messages.try_recv() {
+                received.push(data);
+            }
+            assert!(received.contains(&request));
+            assert!(received.contains(&b"hello".to_vec()));
+        });
+    }
+}
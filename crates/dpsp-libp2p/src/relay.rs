@@ -0,0 +1,105 @@
+//! A circuit relay server for peers that cannot reach each other directly.
+//!
+//! Peers behind NAT ask the relay for a reservation, so that other peers can connect to them
+//! through it. The relay also tells peers which address it sees them connecting from, which they
+//! need to punch a hole for a direct connection.
+//!
+//! Reservations tell peers where the relay can be reached, so the relay only hands out addresses
+//! that are known to work: the external addresses it was started with, and addresses that
+//! several peers saw it connecting from. The addresses it listens on are often private ones.
+
+use crate::network::{build_transport, IDENTIFY_PROTOCOL_VERSION, IDLE_CONNECTION_TIMEOUT};
+use crate::other_error;
+use async_std::io;
+use futures::stream::StreamExt;
+use libp2p::identity::Keypair;
+use libp2p::swarm::{NetworkBehaviour, SwarmEvent};
+use libp2p::{identify, relay, Multiaddr, PeerId, Swarm};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How long a relayed connection may stay open. Games can take hours, and the players keep using
+/// the relay if hole punching fails.
+const MAX_CIRCUIT_DURATION: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How many different peers must see the relay at an address before it is handed out.
+const CONFIRMATIONS: usize = 2;
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    relay: relay::Behaviour,
+    identify: identify::Behaviour,
+}
+
+/// Run a relay with the given identity on `address`, reachable at `external_addresses`.
+///
+/// Returns when listening fails. Peers need both the address and the relay's peer ID to use it,
+/// for example `/ip4/203.0.113.1/tcp/4001/p2p/12D3KooW...`. Without external addresses, peers
+/// can only make reservations once the relay has learned its address from other peers.
+pub async fn serve(
+    key: Keypair,
+    address: Multiaddr,
+    external_addresses: Vec<Multiaddr>,
+) -> io::Result<()> {
+    let peer_id = key.public().to_peer_id();
+    let config = relay::Config {
+        max_circuit_duration: MAX_CIRCUIT_DURATION,
+        // Game traffic adds up; do not limit it.
+        max_circuit_bytes: 0,
+        ..Default::default()
+    };
+    let behaviour = Behaviour {
+        relay: relay::Behaviour::new(peer_id, config),
+        identify: identify::Behaviour::new(identify::Config::new(
+            IDENTIFY_PROTOCOL_VERSION.into(),
+            key.public(),
+        )),
+    };
+    let mut swarm = Swarm::new(
+        build_transport(&key, None)?,
+        behaviour,
+        peer_id,
+        libp2p::swarm::Config::with_async_std_executor()
+            .with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT),
+    );
+    swarm.listen_on(address).map_err(other_error)?;
+    for address in external_addresses {
+        swarm.add_external_address(address);
+    }
+    // The peers that saw the relay at each address that is not confirmed yet.
+    let mut observed: HashMap<Multiaddr, HashSet<PeerId>> = HashMap::new();
+
+    loop {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                log::info!("[relay] listening on {}/p2p/{}", address, peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id: observer,
+                info,
+                ..
+            })) => {
+                if swarm
+                    .external_addresses()
+                    .any(|known| *known == info.observed_addr)
+                {
+                    continue;
+                }
+                let observers = observed.entry(info.observed_addr.clone()).or_default();
+                observers.insert(observer);
+                if observers.len() >= CONFIRMATIONS {
+                    log::info!("[relay] reachable at {}", info.observed_addr);
+                    observed.remove(&info.observed_addr);
+                    swarm.add_external_address(info.observed_addr);
+                }
+            }
+            SwarmEvent::ListenerClosed { reason, .. } => {
+                return reason.map_err(|err| io::Error::new(err.kind(), err.to_string()));
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => {
+                log::debug!("[relay] {:?}", event);
+            }
+            event => log::trace!("[relay] {:?}", event),
+        }
+    }
+}
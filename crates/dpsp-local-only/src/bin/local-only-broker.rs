@@ -0,0 +1,37 @@
+//! Broker that lets game instances in separate processes play through one `LocalOnlyServer`.
+//!
+//! Usage: local-only-broker [SOCKET_PATH]
+
+#[cfg(unix)]
+fn main() -> std::io::Result<()> {
+    use async_std::sync::{Arc, Mutex};
+    use dpsp_local_only::{broker, LocalOnlyServer};
+    use std::os::unix::fs::FileTypeExt;
+
+    femme::with_level(femme::LevelFilter::Debug);
+
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "/tmp/playage-local-only.sock".into());
+    // Clean up after a previous broker that did not exit cleanly, but leave anything that is not
+    // a socket alone.
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path),
+            ));
+        }
+        std::fs::remove_file(&path)?;
+    }
+
+    log::info!("Listening on {}", path);
+    let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+    async_std::task::block_on(broker::serve(path, server))
+}
+
+#[cfg(not(unix))]
+fn main() {
+    eprintln!("local-only-broker needs Unix domain sockets");
+    std::process::exit(1);
+}
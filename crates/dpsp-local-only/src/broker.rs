@@ -0,0 +1,159 @@
+//! Sharing a `LocalOnlyServer` between processes.
+//!
+//! A `LocalOnlyServer` normally lives inside one process, and every game instance using it needs
+//! a `LocalOnlySP` with a handle to it. To connect game instances that run in separate processes,
+//! for example in separate Wine prefixes, the server can instead run in a broker that listens on
+//! a Unix domain socket. Each game instance then uses a `LocalBrokerSP`, which forwards everything
+//! to the broker. The broker runs a `LocalOnlySP` on behalf of every client, so messages are
+//! routed exactly as if all game instances shared a process.
+//!
+//! Messages on the socket are length-prefixed frames, as described in the `router` module.
+
+use crate::router::{self, length_prefixed, RouterSP};
+use crate::LocalOnlyServer;
+use async_std::io;
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::path::Path;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use dprun::GUID;
+use std::net::Shutdown;
+
+/// Service provider that routes messages through a broker in another process.
+pub type LocalBrokerSP = RouterSP;
+
+/// Listen for clients on a Unix domain socket, and route their messages through `server`.
+///
+/// Returns when accepting a client fails.
+pub async fn serve(path: impl AsRef<Path>, server: Arc<Mutex<LocalOnlyServer>>) -> io::Result<()> {
+    let listener = UnixListener::bind(path).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        task::spawn(async move {
+            let (writer, reader) = length_prefixed(stream);
+            futures::pin_mut!(writer);
+            if let Err(err) = router::route(reader, writer, server, "client").await {
+                log::warn!("[broker] client error: {:?}", err);
+            }
+        });
+    }
+}
+
+/// Connect to the broker listening at `path`, for a game instance that hosts or joins the given
+/// session.
+pub async fn connect(path: impl AsRef<Path>, session_id: GUID) -> io::Result<LocalBrokerSP> {
+    let stream = UnixStream::connect(path).await?;
+    let (writer, reader) = length_prefixed(stream.clone());
+    RouterSP::connect(reader, writer, session_id, move || {
+        stream.shutdown(Shutdown::Write)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use dprun::{structs::*, AppController, AppMessage, ServiceProvider};
+    use std::time::Duration;
+
+    const DPLAYI_PLAYER_NAMESRVR: i32 = 2;
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> AppMessage {
+        timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("no message from the broker")
+            .unwrap()
+    }
+
+    #[test]
+    fn routes_between_clients() {
+        let path = std::env::temp_dir().join(format!("dpsp-broker-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        task::block_on(async {
+            let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+            task::spawn(serve(path.clone(), server));
+            while UnixStream::connect(&path).await.is_err() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+
+            let mut host = connect(&path, session_id).await.unwrap();
+            let (host_controller, host_receiver) = AppController::create();
+            let create_host = CreatePlayerData {
+                player_guid: host_id,
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            host.create_player(host_controller.clone(), 0, create_host)
+                .await
+                .unwrap();
+
+            let mut joiner = connect(&path, session_id).await.unwrap();
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            let mut request = joiner_id.as_bytes().to_vec();
+            request.extend_from_slice(b"play");
+            joiner
+                .enum_sessions(
+                    joiner_controller.clone(),
+                    0,
+                    EnumSessionsData {
+                        message: request.clone(),
+                    },
+                )
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, request),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            let reply = ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session".to_vec(),
+            };
+            host.reply(host_controller.clone(), 0, reply).await.unwrap();
+            match next_message(&joiner_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"session"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            let create_joiner = CreatePlayerData {
+                player_guid: joiner_id,
+                flags: 0,
+            };
+            joiner
+                .create_player(joiner_controller.clone(), 0, create_joiner)
+                .await
+                .unwrap();
+            let send = SendData {
+                flags: DPSEND_GUARANTEED,
+                receiver_id: None,
+                sender_id: joiner_id,
+                system_message: false,
+                message: b"hello".to_vec(),
+            };
+            joiner
+                .send(joiner_controller.clone(), 0, send)
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"hello"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            host.disconnected(host_controller).await.unwrap();
+            assert!(matches!(
+                next_message(&joiner_receiver).await,
+                AppMessage::Close
+            ));
+            host.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+}
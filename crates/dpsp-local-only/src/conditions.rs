@@ -0,0 +1,281 @@
+//! Emulation of bad network conditions between local players.
+//!
+//! Everything passing through a `LocalOnlyServer` is normally delivered instantly and in order.
+//! With network conditions configured, each message between two players is instead delayed,
+//! dropped, duplicated or reordered as described by the `LinkConditions` for that pair of
+//! players. Links connect game instances rather than individual players, so they are identified
+//! by the system players of the game instances on either end. Every link makes its random
+//! decisions with its own generator, seeded from the configured seed and the two ends of the
+//! link, so a run with the same seed and the same traffic makes the same decisions, no matter in
+//! which order the recipients of a broadcast are handled.
+//!
+//! Guaranteed messages are only ever delayed: they are never dropped, duplicated or reordered.
+
+use async_std::task;
+use dprun::{AppController, Delivery, GUID};
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::stream::StreamExt;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Conditions on the link from one player to another.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Fixed delay added to every message.
+    pub latency: Duration,
+    /// Maximum random delay added on top of the latency.
+    pub jitter: Duration,
+    /// Chance that an unreliable message is dropped, from 0 to 1.
+    pub loss: f64,
+    /// Chance that an unreliable message is delivered twice, from 0 to 1.
+    pub duplicate: f64,
+    /// Chance that an unreliable message is held back long enough for the next messages to
+    /// overtake it, from 0 to 1.
+    pub reorder: f64,
+    /// Maximum throughput of the link in bytes per second.
+    pub bandwidth: Option<u64>,
+}
+
+/// State of a single directed link.
+struct Link {
+    /// Makes the random decisions for this link.
+    rng: StdRng,
+    /// When the link has finished transmitting everything queued so far.
+    busy_until: Option<Instant>,
+    /// When the most recent guaranteed message is delivered. Later guaranteed messages may not
+    /// arrive before this.
+    last_guaranteed: Option<Instant>,
+    /// Delivers guaranteed messages in order.
+    guaranteed_queue: Option<UnboundedSender<(Instant, AppController, Vec<u8>)>>,
+}
+
+impl Link {
+    fn new(seed: u64, from: GUID, to: GUID) -> Self {
+        let mut link_seed = [0; 32];
+        link_seed[..16].copy_from_slice(from.as_bytes());
+        link_seed[16..].copy_from_slice(to.as_bytes());
+        for (byte, seed_byte) in link_seed.iter_mut().zip(seed.to_le_bytes().iter().cycle()) {
+            *byte ^= seed_byte;
+        }
+        Self {
+            rng: StdRng::from_seed(link_seed),
+            busy_until: None,
+            last_guaranteed: None,
+            guaranteed_queue: None,
+        }
+    }
+}
+
+/// Network conditions for all links between players.
+pub struct NetworkConditions {
+    seed: u64,
+    default: LinkConditions,
+    conditions: HashMap<(GUID, GUID), LinkConditions>,
+    links: HashMap<(GUID, GUID), Link>,
+}
+
+impl NetworkConditions {
+    /// Create network conditions with a seed for the random generator. Links are perfect until
+    /// configured otherwise.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            default: LinkConditions::default(),
+            conditions: HashMap::new(),
+            links: HashMap::new(),
+        }
+    }
+
+    /// Set the conditions for links that were not configured individually.
+    pub fn with_default(self, default: LinkConditions) -> Self {
+        Self { default, ..self }
+    }
+
+    /// Set the conditions for messages from one player to another.
+    pub fn set_directed(&mut self, from: GUID, to: GUID, conditions: LinkConditions) {
+        self.conditions.insert((from, to), conditions);
+    }
+
+    /// Set the conditions for messages between two players, in both directions.
+    pub fn set_link(&mut self, a: GUID, b: GUID, conditions: LinkConditions) {
+        self.set_directed(a, b, conditions.clone());
+        self.set_directed(b, a, conditions);
+    }
+
+    fn link(&mut self, from: GUID, to: GUID) -> &mut Link {
+        let seed = self.seed;
+        self.links
+            .entry((from, to))
+            .or_insert_with(|| Link::new(seed, from, to))
+    }
+
+    /// Decide when the copies of a message should arrive. Returns no times if the message is
+    /// lost, and more than one if it is duplicated.
+    fn plan(
+        &mut self,
+        from: GUID,
+        to: GUID,
+        delivery: Delivery,
+        size: usize,
+        now: Instant,
+    ) -> Vec<Instant> {
+        let seed = self.seed;
+        let conditions = self.conditions.get(&(from, to)).unwrap_or(&self.default);
+        let link = self
+            .links
+            .entry((from, to))
+            .or_insert_with(|| Link::new(seed, from, to));
+        let rng = &mut link.rng;
+
+        // Time spent pushing the message through a capped link, behind everything before it.
+        let sent_at = match conditions.bandwidth {
+            Some(bytes_per_second) if bytes_per_second > 0 => {
+                let start = link.busy_until.map_or(now, |busy| busy.max(now));
+                let transmit = Duration::from_secs_f64(size as f64 / bytes_per_second as f64);
+                link.busy_until = Some(start + transmit);
+                start + transmit
+            }
+            _ => now,
+        };
+        let arrival = |rng: &mut StdRng| {
+            let jitter = conditions.jitter.mul_f64(rng.gen::<f64>());
+            sent_at + conditions.latency + jitter
+        };
+
+        match delivery {
+            Delivery::Guaranteed => {
+                let at = arrival(rng);
+                let at = link.last_guaranteed.map_or(at, |last| last.max(at));
+                link.last_guaranteed = Some(at);
+                vec![at]
+            }
+            Delivery::Unreliable => {
+                if rng.gen_bool(conditions.loss.clamp(0.0, 1.0)) {
+                    return vec![];
+                }
+                let copies = if rng.gen_bool(conditions.duplicate.clamp(0.0, 1.0)) {
+                    2
+                } else {
+                    1
+                };
+                (0..copies)
+                    .map(|_| {
+                        let at = arrival(rng);
+                        if rng.gen_bool(conditions.reorder.clamp(0.0, 1.0)) {
+                            at + conditions.latency + conditions.jitter
+                        } else {
+                            at
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Deliver a message from one player to another under the configured conditions.
+    pub(crate) fn deliver(
+        &mut self,
+        from: GUID,
+        to: GUID,
+        delivery: Delivery,
+        controller: &AppController,
+        data: &[u8],
+    ) {
+        let arrivals = self.plan(from, to, delivery, data.len(), Instant::now());
+        match delivery {
+            Delivery::Guaranteed => {
+                let queue = self.link(from, to).guaranteed_queue.get_or_insert_with(|| {
+                    let (sender, mut receiver) =
+                        mpsc::unbounded::<(Instant, AppController, Vec<u8>)>();
+                    task::spawn(async move {
+                        while let Some((at, mut controller, data)) = receiver.next().await {
+                            task::sleep(until(at)).await;
+                            controller.send(data).await;
+                        }
+                    });
+                    sender
+                });
+                for at in arrivals {
+                    let _ = queue.unbounded_send((at, controller.clone(), data.to_vec()));
+                }
+            }
+            Delivery::Unreliable => {
+                for at in arrivals {
+                    let mut controller = controller.clone();
+                    let data = data.to_vec();
+                    task::spawn(async move {
+                        task::sleep(until(at)).await;
+                        controller.deliver(Delivery::Unreliable, data).await;
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn until(at: Instant) -> Duration {
+    at.saturating_duration_since(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bad_link() -> LinkConditions {
+        LinkConditions {
+            latency: Duration::from_millis(50),
+            jitter: Duration::from_millis(30),
+            loss: 0.3,
+            duplicate: 0.2,
+            reorder: 0.2,
+            bandwidth: Some(10_000),
+        }
+    }
+
+    fn plan_all(conditions: &mut NetworkConditions, delivery: Delivery) -> Vec<Vec<Instant>> {
+        let (a, b) = (GUID::from_u128(1), GUID::from_u128(2));
+        let now = Instant::now();
+        (0..100)
+            .map(|i| conditions.plan(a, b, delivery, 100, now + Duration::from_millis(i)))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_makes_same_decisions() {
+        let mut first = NetworkConditions::new(1234).with_default(bad_link());
+        let mut second = NetworkConditions::new(1234).with_default(bad_link());
+        let now = Instant::now();
+        let (a, b) = (GUID::from_u128(1), GUID::from_u128(2));
+        for i in 0..100 {
+            let at = now + Duration::from_millis(i);
+            assert_eq!(
+                first.plan(a, b, Delivery::Unreliable, 100, at),
+                second.plan(a, b, Delivery::Unreliable, 100, at),
+            );
+        }
+    }
+
+    #[test]
+    fn unreliable_messages_are_dropped_and_duplicated() {
+        let mut conditions = NetworkConditions::new(1).with_default(bad_link());
+        let plans = plan_all(&mut conditions, Delivery::Unreliable);
+        assert!(plans.iter().any(|plan| plan.is_empty()));
+        assert!(plans.iter().any(|plan| plan.len() == 2));
+    }
+
+    #[test]
+    fn guaranteed_messages_are_only_delayed() {
+        let mut conditions = NetworkConditions::new(1).with_default(LinkConditions {
+            loss: 1.0,
+            duplicate: 1.0,
+            reorder: 1.0,
+            ..bad_link()
+        });
+        let plans = plan_all(&mut conditions, Delivery::Guaranteed);
+        assert!(plans.iter().all(|plan| plan.len() == 1));
+        let arrivals = plans.iter().map(|plan| plan[0]).collect::<Vec<_>>();
+        assert!(arrivals.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
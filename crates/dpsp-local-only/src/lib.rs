@@ -1,89 +1,432 @@
+#[cfg(unix)]
+pub mod broker;
+mod conditions;
+pub mod router;
+
+#[cfg(unix)]
+pub use broker::LocalBrokerSP;
+pub use conditions::{LinkConditions, NetworkConditions};
+
 use async_std::io;
 use async_std::sync::{Arc, Mutex};
 use async_trait::async_trait;
-use dprun::{structs::*, AppController, ServiceProvider, DPID, GUID};
-use std::collections::HashMap;
+use dprun::inspect::is_name_server_announcement;
+use dprun::{structs::*, AppController, Delivery, PlayerIdentity, Roster, ServiceProvider, GUID};
+use std::collections::{HashMap, HashSet};
 
-#[allow(dead_code)]
 const DPLAYI_PLAYER_SYSPLAYER: i32 = 1;
 const DPLAYI_PLAYER_NAMESRVR: i32 = 2;
 #[allow(dead_code)]
 const DPLAYI_PLAYER_LOCAL: i32 = 8;
 
+/// Read the GUID of the sending player from the start of a network message.
+fn message_sender(message: &[u8]) -> Option<GUID> {
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(message.get(0..16)?);
+    Some(GUID::from_bytes(bytes))
+}
+
+/// A player in a session.
+struct Player {
+    /// The system player of the game instance that created this player. All players of a game
+    /// instance share its connection, so this identifies where messages for the player go.
+    system_player: GUID,
+    controller: AppController,
+}
+
+/// A single hosted DirectPlay session.
+#[derive(Default)]
+struct Session {
+    name_server_id: Option<GUID>,
+    /// Whether the session goes on when the name server leaves, until another player announces
+    /// that it took over.
+    migrate_host: bool,
+    players: HashMap<GUID, Player>,
+    /// Player groups and their members.
+    groups: HashMap<GUID, HashSet<GUID>>,
+    /// Player names and IDs, and who is banned from the session.
+    roster: Roster,
+}
+
+impl Session {
+    /// The members of a group, whether the group was created on the server or by the game.
+    fn group_members(&self, group: GUID) -> Option<HashSet<GUID>> {
+        let created = self.groups.get(&group);
+        let observed = self.roster.group_members(group);
+        if created.is_none() && observed.is_none() {
+            return None;
+        }
+        Some(
+            created
+                .into_iter()
+                .flatten()
+                .copied()
+                .chain(observed.into_iter().flatten())
+                .collect(),
+        )
+    }
+
+    /// Find the game instances that should receive a message, keyed by their system players.
+    ///
+    /// A message without a receiver is broadcast to every game instance in the session, and a
+    /// message for a group goes to every game instance with a player in the group. Either way,
+    /// a game instance receives each message once, and never receives its own broadcasts.
+    fn recipients(&self, from: GUID, to: Option<GUID>) -> HashMap<GUID, AppController> {
+        let sender = self.players.get(&from).map(|player| player.system_player);
+        let others = |player: &&Player| Some(player.system_player) != sender;
+        let players: Vec<&Player> = match to {
+            None => self.players.values().filter(others).collect(),
+            Some(id) => match self.group_members(id) {
+                Some(members) => members
+                    .iter()
+                    .filter_map(|member| self.players.get(member))
+                    .filter(others)
+                    .collect(),
+                None => self.players.get(&id).into_iter().collect(),
+            },
+        };
+        players
+            .into_iter()
+            .map(|player| (player.system_player, player.controller.clone()))
+            .collect()
+    }
+}
+
 pub struct LocalOnlyServer {
-    name_server: Option<AppController>,
-    players: HashMap<GUID, AppController>,
-    enumers: HashMap<DPID, AppController>,
+    sessions: HashMap<GUID, Session>,
+    /// Clients that are looking for sessions, keyed by the GUID they send their requests with.
+    enumers: HashMap<GUID, AppController>,
+    conditions: Option<NetworkConditions>,
 }
 
 impl LocalOnlyServer {
     pub fn make() -> Self {
         Self {
-            name_server: None,
-            players: HashMap::new(),
+            sessions: HashMap::new(),
             enumers: HashMap::new(),
+            conditions: None,
+        }
+    }
+
+    /// Emulate bad network conditions between game instances.
+    pub fn with_network_conditions(self, conditions: NetworkConditions) -> Self {
+        Self {
+            conditions: Some(conditions),
+            ..self
         }
     }
 
-    pub fn set_name_server(&mut self, _id: GUID, controller: AppController) {
-        self.name_server = Some(controller);
+    /// Get the network conditions, to adjust them while the server is running.
+    pub fn network_conditions(&mut self) -> Option<&mut NetworkConditions> {
+        self.conditions.as_mut()
     }
 
-    pub fn create_player(&mut self, id: GUID, controller: AppController) {
-        self.players.insert(id, controller);
+    /// Add the name server of a session. The name server is the system player of the hosting
+    /// game instance.
+    pub fn set_name_server(&mut self, session_id: GUID, id: GUID, controller: AppController) {
+        self.create_player(session_id, id, id, controller);
+        let session = self.sessions.entry(session_id).or_default();
+        session.name_server_id = Some(id);
+    }
+
+    /// The system player of the game instance that hosts a session.
+    fn name_server(&self, session_id: GUID) -> Option<GUID> {
+        self.sessions.get(&session_id)?.name_server_id
+    }
+
+    /// Let the session go on without its name server, for sessions with `DPSESSION_MIGRATEHOST`.
+    pub fn allow_host_migration(&mut self, session_id: GUID) {
+        self.sessions.entry(session_id).or_default().migrate_host = true;
+    }
+
+    /// Remove players from a session. If the name server leaves, the session ends: the
+    /// remaining players are disconnected and the session is cleaned up. Sessions that allow
+    /// host migration go on without a name server instead, until another player takes over.
+    pub async fn leave(&mut self, session_id: GUID, players: &[GUID], name_server: bool) {
+        self.enumers.retain(|_, enumer| !enumer.is_closed());
+
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        for id in players {
+            session.players.remove(id);
+            for members in session.groups.values_mut() {
+                members.remove(id);
+            }
+        }
+        if name_server && session.migrate_host && !session.players.is_empty() {
+            log::debug!(
+                "Name server left session {}, waiting for a new one",
+                session_id
+            );
+            session.name_server_id = None;
+        } else if name_server {
+            log::debug!("Name server left session {}, closing it", session_id);
+            if let Some(session) = self.sessions.remove(&session_id) {
+                let instances = session
+                    .players
+                    .into_values()
+                    .map(|player| (player.system_player, player.controller))
+                    .collect::<HashMap<_, _>>();
+                let closes = instances
+                    .into_values()
+                    .map(|mut controller| async move { controller.close().await });
+                futures::future::join_all(closes).await;
+            }
+        }
+    }
+
+    /// Add a player to a session. `system_player` is the system player of the game instance that
+    /// created the player, or the player itself if it is a system player.
+    pub fn create_player(
+        &mut self,
+        session_id: GUID,
+        id: GUID,
+        system_player: GUID,
+        controller: AppController,
+    ) {
+        let session = self.sessions.entry(session_id).or_default();
+        session.players.insert(
+            id,
+            Player {
+                system_player,
+                controller,
+            },
+        );
         log::trace!(
-            "Current players: {:?}",
-            self.players.keys().collect::<Vec<&GUID>>()
+            "Current players in {}: {:?}",
+            session_id,
+            session.players.keys().collect::<Vec<&GUID>>()
         );
     }
 
-    pub async fn enum_sessions(&mut self, message: &[u8], requester: AppController) {
-        self.enumers.insert(0, requester);
-        match self.name_server {
-            Some(ref mut name_server) => name_server.send(message.to_vec()).await,
-            None => panic!("EnumSessions'd without a host"),
-        };
+    /// Create an empty player group. Messages sent to the group go to all of its members.
+    ///
+    /// Groups that the games create themselves are picked up from their messages. This is for
+    /// groups that only the application hosting the server knows about.
+    pub fn create_group(&mut self, session_id: GUID, group: GUID) {
+        let session = self.sessions.entry(session_id).or_default();
+        session.groups.entry(group).or_default();
+    }
+
+    /// Delete a player group.
+    pub fn delete_group(&mut self, session_id: GUID, group: GUID) {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            session.groups.remove(&group);
+        }
+    }
+
+    /// Add a player to a group. Does nothing if the group does not exist.
+    pub fn add_player_to_group(&mut self, session_id: GUID, group: GUID, player: GUID) {
+        if let Some(members) = self
+            .sessions
+            .get_mut(&session_id)
+            .and_then(|session| session.groups.get_mut(&group))
+        {
+            members.insert(player);
+        }
     }
 
-    async fn reply(&mut self, id: GUID, data: &[u8]) {
-        match self.players.get_mut(&id) {
-            Some(player) => {
-                player.send(data.to_vec()).await;
+    /// Remove a player from a group.
+    pub fn remove_player_from_group(&mut self, session_id: GUID, group: GUID, player: GUID) {
+        if let Some(members) = self
+            .sessions
+            .get_mut(&session_id)
+            .and_then(|session| session.groups.get_mut(&group))
+        {
+            members.remove(&player);
+        }
+    }
+
+    /// Check if a player or game instance is banned from a session.
+    pub fn is_banned(&self, session_id: GUID, id: GUID) -> bool {
+        match self.sessions.get(&session_id) {
+            Some(session) => session.roster.is_banned(id),
+            None => false,
+        }
+    }
+
+    /// Disconnect a game instance from a session. `identity` is the GUID of one of its players,
+    /// or the name of one of its players as shown in the game. The other game instances receive
+    /// `DeletePlayer` messages for the kicked players, as if their game had left normally.
+    ///
+    /// With `ban`, the game instance and players with the kicked name can not join the session
+    /// again. The name server can not be kicked. Returns whether a game instance was kicked.
+    pub async fn kick(&mut self, session_id: GUID, identity: &PlayerIdentity, ban: bool) -> bool {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => return false,
+        };
+        let instance = match identity {
+            PlayerIdentity::Guid(id) => session.players.get(id).map(|player| player.system_player),
+            PlayerIdentity::Name(name) => session.roster.find(name),
+        };
+        let instance = match instance {
+            Some(instance) if Some(instance) != session.name_server_id => instance,
+            _ => return false,
+        };
+        log::debug!("Kicking {} from session {}", instance, session_id);
+        if ban {
+            session.roster.ban(identity);
+            session.roster.ban(&PlayerIdentity::Guid(instance));
+        }
+
+        let deletes = session.roster.delete_messages(instance);
+        session.roster.forget(instance);
+        let kicked: Vec<GUID> = session
+            .players
+            .iter()
+            .filter(|(_, player)| player.system_player == instance)
+            .map(|(&id, _)| id)
+            .collect();
+        let mut controller = None;
+        for id in &kicked {
+            if let Some(player) = session.players.remove(id) {
+                controller = Some(player.controller);
             }
-            None => {
-                let futures = self
-                    .enumers
-                    .values_mut()
-                    .map(|player| player.send(data.to_vec()));
-                let _ = futures::future::join_all(futures).await;
+            for members in session.groups.values_mut() {
+                members.remove(id);
             }
         }
+        if let Some(mut controller) = controller {
+            controller.close().await;
+        }
+
+        let recipients = session.recipients(instance, None);
+        for message in deletes {
+            let deliveries = recipients.values().map(|receiver| {
+                let mut receiver = receiver.clone();
+                let message = message.clone();
+                async move { receiver.deliver(Delivery::Guaranteed, message).await }
+            });
+            futures::future::join_all(deliveries).await;
+        }
+        true
     }
 
-    async fn send(&mut self, to_player_id: Option<GUID>, data: &[u8]) {
-        match to_player_id {
-            Some(ref id) => {
-                if let Some(player) = self.players.get_mut(id) {
-                    player.send(data.to_vec()).await;
-                }
+    /// Ask every hosted session to describe itself to the requester. If there are no sessions,
+    /// nobody answers.
+    pub async fn enum_sessions(&mut self, message: &[u8], requester: AppController) {
+        let requester_id = message_sender(message).unwrap_or_default();
+        self.enumers.insert(requester_id, requester);
+        let name_servers = self
+            .sessions
+            .values_mut()
+            .filter_map(|session| {
+                let id = session.name_server_id?;
+                session.players.get_mut(&id)
+            })
+            .map(|name_server| name_server.controller.send(message.to_vec()));
+        futures::future::join_all(name_servers).await;
+    }
+
+    async fn reply(&mut self, session_id: GUID, id: GUID, data: &[u8]) {
+        if let Some(player) = self
+            .sessions
+            .get_mut(&session_id)
+            .and_then(|session| session.players.get_mut(&id))
+        {
+            player.controller.send(data.to_vec()).await;
+        } else if let Some(enumer) = self.enumers.get_mut(&id) {
+            enumer.send(data.to_vec()).await;
+        } else {
+            let futures = self
+                .enumers
+                .values_mut()
+                .map(|player| player.send(data.to_vec()));
+            let _ = futures::future::join_all(futures).await;
+        }
+    }
+
+    /// Send a message to a player, to a group, or to everyone in the session if there is no
+    /// receiver.
+    async fn send(
+        &mut self,
+        session_id: GUID,
+        from_player_id: GUID,
+        to_player_id: Option<GUID>,
+        delivery: Delivery,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let session = match self.sessions.get_mut(&session_id) {
+            Some(session) => session,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("session {} does not exist", session_id),
+                ))
+            }
+        };
+        // Players of kicked game instances are gone, so their games can not talk to the session
+        // while they are being disconnected.
+        let from = match session.players.get(&from_player_id) {
+            Some(player) => player.system_player,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("player {} is not in session {}", from_player_id, session_id),
+                ))
             }
-            None => match self.name_server {
-                Some(ref mut name_server) => {
-                    name_server.send(data.to_vec()).await;
+        };
+        session.roster.observe(from, data);
+        if session.roster.is_banned(from) {
+            self.kick(session_id, &PlayerIdentity::Guid(from), false)
+                .await;
+            return Ok(());
+        }
+        // Only a session whose name server left can get a new one.
+        if session.migrate_host
+            && session.name_server_id.is_none()
+            && is_name_server_announcement(data)
+        {
+            log::debug!("{} took over as the name server of {}", from, session_id);
+            session.name_server_id = Some(from);
+        }
+        if session.name_server_id.is_none() && !session.migrate_host {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("session {} has no name server", session_id),
+            ));
+        }
+        let recipients = session.recipients(from_player_id, to_player_id);
+        match self.conditions {
+            Some(ref mut conditions) => {
+                for (to, receiver) in &recipients {
+                    conditions.deliver(from, *to, delivery, receiver, data);
                 }
-                None => panic!("Tried to send message to nonexistent name server"),
-            },
+            }
+            None => {
+                let deliveries = recipients.into_values().map(|mut receiver| async move {
+                    receiver.deliver(delivery, data.to_vec()).await
+                });
+                futures::future::join_all(deliveries).await;
+            }
         }
+        Ok(())
     }
 }
 
 pub struct LocalOnlySP {
     server: Arc<Mutex<LocalOnlyServer>>,
+    session_id: GUID,
+    /// The players that this game instance created.
+    players: Vec<GUID>,
+    /// The system player of this game instance, once it has been created.
+    system_player: Option<GUID>,
+    is_name_server: bool,
 }
 
 impl LocalOnlySP {
-    pub fn new(server: Arc<Mutex<LocalOnlyServer>>) -> Self {
-        Self { server }
+    /// Create a service provider for a game instance that hosts or joins the given session.
+    pub fn new(server: Arc<Mutex<LocalOnlyServer>>, session_id: GUID) -> Self {
+        Self {
+            server,
+            session_id,
+            players: vec![],
+            system_player: None,
+            is_name_server: false,
+        }
     }
 }
 
@@ -114,6 +457,12 @@ impl ServiceProvider for LocalOnlySP {
         data: OpenData,
     ) -> io::Result<()> {
         log::trace!("[LocalOnlySP::open] Got Open message: {:?}", data);
+        if data.migrate_host() {
+            self.server
+                .lock()
+                .await
+                .allow_host_migration(self.session_id);
+        }
         Ok(())
     }
 
@@ -128,10 +477,24 @@ impl ServiceProvider for LocalOnlySP {
             data
         );
         let mut server = self.server.lock().await;
+        let system_player = self.system_player.unwrap_or(data.player_guid);
+        if server.is_banned(self.session_id, system_player) {
+            let mut controller = controller;
+            controller.close().await;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("banned from session {}", self.session_id),
+            ));
+        }
+        self.players.push(data.player_guid);
+        if data.flags & (DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR) != 0 {
+            self.system_player = Some(data.player_guid);
+        }
         if data.flags & DPLAYI_PLAYER_NAMESRVR != 0 {
-            server.set_name_server(data.player_guid, controller);
+            self.is_name_server = true;
+            server.set_name_server(self.session_id, data.player_guid, controller);
         } else {
-            server.create_player(data.player_guid, controller);
+            server.create_player(self.session_id, data.player_guid, system_player, controller);
         }
         Ok(())
     }
@@ -146,7 +509,7 @@ impl ServiceProvider for LocalOnlySP {
         self.server
             .lock()
             .await
-            .reply(data.reply_to, &data.message)
+            .reply(self.session_id, data.reply_to, &data.message)
             .await;
         Ok(())
     }
@@ -158,11 +521,652 @@ impl ServiceProvider for LocalOnlySP {
         data: SendData,
     ) -> io::Result<()> {
         // log::trace!("[LocalOnlySP::send] Got Send message: {:?}", data);
+        let mut server = self.server.lock().await;
+        let result = server
+            .send(
+                self.session_id,
+                data.sender_id,
+                data.receiver_id,
+                data.delivery(),
+                &data.message,
+            )
+            .await;
+        // The server decides whether an announcement made us the name server.
+        if is_name_server_announcement(&data.message)
+            && self.system_player.is_some()
+            && server.name_server(self.session_id) == self.system_player
+        {
+            self.is_name_server = true;
+        }
+        result
+    }
+
+    async fn disconnected(&mut self, _controller: AppController) -> io::Result<()> {
         self.server
             .lock()
             .await
-            .send(data.receiver_id, &data.message)
+            .leave(self.session_id, &self.players, self.is_name_server)
             .await;
+        self.players.clear();
+        self.system_player = None;
+        self.is_name_server = false;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::{self, block_on};
+    use dprun::AppMessage;
+    use std::time::Duration;
+
+    fn enum_request(requester: GUID) -> EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        EnumSessionsData { message }
+    }
+
+    fn player(id: GUID, flags: i32) -> CreatePlayerData {
+        CreatePlayerData {
+            player_guid: id,
+            flags,
+        }
+    }
+
+    fn message_to(sender_id: GUID, receiver_id: Option<GUID>) -> SendData {
+        SendData {
+            flags: DPSEND_GUARANTEED,
+            receiver_id,
+            sender_id,
+            system_message: false,
+            message: sender_id.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn join_before_host() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        block_on(async {
+            let mut joiner = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (joiner_controller, _joiner_receiver) = AppController::create();
+            joiner
+                .enum_sessions(joiner_controller.clone(), 0, enum_request(joiner_id))
+                .await
+                .unwrap();
+            joiner
+                .create_player(joiner_controller.clone(), 0, player(joiner_id, 0))
+                .await
+                .unwrap();
+            assert!(joiner
+                .send(joiner_controller.clone(), 0, message_to(joiner_id, None))
+                .await
+                .is_err());
+
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, host_receiver) = AppController::create();
+            host.create_player(
+                host_controller.clone(),
+                0,
+                player(host_id, DPLAYI_PLAYER_NAMESRVR),
+            )
+            .await
+            .unwrap();
+
+            joiner
+                .enum_sessions(joiner_controller.clone(), 0, enum_request(joiner_id))
+                .await
+                .unwrap();
+            assert!(matches!(host_receiver.try_recv(), Ok(AppMessage::Send(..))));
+            joiner
+                .send(joiner_controller.clone(), 0, message_to(joiner_id, None))
+                .await
+                .unwrap();
+            assert!(matches!(host_receiver.try_recv(), Ok(AppMessage::Send(..))));
+        });
+    }
+
+    #[test]
+    fn host_leaves() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, host_receiver) = AppController::create();
+            host.create_player(
+                host_controller.clone(),
+                0,
+                player(host_id, DPLAYI_PLAYER_NAMESRVR),
+            )
+            .await
+            .unwrap();
+
+            let mut joiner = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            joiner
+                .create_player(joiner_controller.clone(), 0, player(joiner_id, 0))
+                .await
+                .unwrap();
+
+            host.disconnected(host_controller).await.unwrap();
+            assert!(matches!(joiner_receiver.try_recv(), Ok(AppMessage::Close)));
+            assert!(joiner
+                .send(joiner_controller.clone(), 0, message_to(joiner_id, None))
+                .await
+                .is_err());
+            joiner
+                .enum_sessions(joiner_controller, 0, enum_request(joiner_id))
+                .await
+                .unwrap();
+            assert!(host_receiver.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn host_migrates() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let a_id = GUID::from_u128(3);
+        let b_id = GUID::from_u128(4);
+        let enumer_id = GUID::from_u128(5);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, _host_receiver) = AppController::create();
+            let open = OpenData {
+                create: true,
+                return_status: false,
+                open_flags: 0,
+                session_flags: DPSESSION_MIGRATEHOST,
+            };
+            host.open(host_controller.clone(), 0, open).await.unwrap();
+            let host_flags = DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR;
+            host.create_player(host_controller.clone(), 0, player(host_id, host_flags))
+                .await
+                .unwrap();
+
+            let mut a = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (a_controller, a_receiver) = AppController::create();
+            a.create_player(
+                a_controller.clone(),
+                0,
+                player(a_id, DPLAYI_PLAYER_SYSPLAYER),
+            )
+            .await
+            .unwrap();
+            let mut b = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (b_controller, b_receiver) = AppController::create();
+            b.create_player(
+                b_controller.clone(),
+                0,
+                player(b_id, DPLAYI_PLAYER_SYSPLAYER),
+            )
+            .await
+            .unwrap();
+
+            // The session goes on without the host.
+            host.disconnected(host_controller).await.unwrap();
+            assert_eq!(received(&a_receiver), 0);
+            assert!(b_receiver.is_empty());
+            b.send(b_controller.clone(), 0, message_to(b_id, None))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+
+            // A takes over, and answers enumeration requests from then on.
+            let announcement = |id: GUID| {
+                let mut announcement = id.as_bytes().to_vec();
+                announcement.extend_from_slice(b"play\x35\x00\x0e\x00");
+                announcement.extend_from_slice(&[0; 16]);
+                SendData {
+                    message: announcement,
+                    ..message_to(id, None)
+                }
+            };
+            a.send(a_controller.clone(), 0, announcement(a_id))
+                .await
+                .unwrap();
+            assert_eq!(received(&b_receiver), 1);
+            let (enumer_controller, _enumer_receiver) = AppController::create();
+            b.enum_sessions(enumer_controller.clone(), 0, enum_request(enumer_id))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+
+            // Once there is a new host, nobody else can take over.
+            b.send(b_controller.clone(), 0, announcement(b_id))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+            b.enum_sessions(enumer_controller, 0, enum_request(enumer_id))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+            assert!(b_receiver.is_empty());
+
+            // Without players to take over, the session ends with the last host.
+            b.disconnected(b_controller).await.unwrap();
+            a.disconnected(a_controller).await.unwrap();
+            assert!(server.lock().await.sessions.is_empty());
+        });
+    }
+
+    /// A `CreatePlayer` message for a player named `name`, from `system_player`.
+    fn create_player_message(id: DPID, system_player: GUID, name: &str) -> SendData {
+        let created = dprun::name_server::Player {
+            id,
+            flags: 0,
+            short_name: name.to_string(),
+            long_name: String::new(),
+            system_player: 1,
+            sp_data: vec![],
+            player_data: vec![],
+            address: system_player,
+        };
+        let mut message = system_player.as_bytes().to_vec();
+        message.extend_from_slice(&dprun::name_server::create_player_message(&created));
+        SendData {
+            message,
+            ..message_to(system_player, None)
+        }
+    }
+
+    #[test]
+    fn kick_and_ban() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let a_id = GUID::from_u128(3);
+        let b_id = GUID::from_u128(4);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, host_receiver) = AppController::create();
+            let host_flags = DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR;
+            host.create_player(host_controller.clone(), 0, player(host_id, host_flags))
+                .await
+                .unwrap();
+
+            let mut a = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (a_controller, a_receiver) = AppController::create();
+            a.create_player(
+                a_controller.clone(),
+                0,
+                player(a_id, DPLAYI_PLAYER_SYSPLAYER),
+            )
+            .await
+            .unwrap();
+            a.send(
+                a_controller.clone(),
+                0,
+                create_player_message(3, a_id, "Alice"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(received(&host_receiver), 1);
+
+            // Kicking by GUID lets the game instance join again.
+            let mut server_lock = server.lock().await;
+            assert!(
+                server_lock
+                    .kick(session_id, &PlayerIdentity::Guid(a_id), false)
+                    .await
+            );
+            assert!(
+                !server_lock
+                    .kick(session_id, &PlayerIdentity::Guid(host_id), true)
+                    .await
+            );
+            drop(server_lock);
+            assert!(matches!(a_receiver.try_recv(), Ok(AppMessage::Close)));
+            // Until the kicked game instance is disconnected, nobody hears from it.
+            assert!(a
+                .send(a_controller.clone(), 0, message_to(a_id, None))
+                .await
+                .is_err());
+            match host_receiver.try_recv() {
+                Ok(AppMessage::Send(_, _, data)) => {
+                    let (sender, message) = dprun::inspect::parse_network_message(&data).unwrap();
+                    assert_eq!(sender, a_id);
+                    assert!(matches!(
+                        message.body,
+                        dprun::inspect::Command::DeletePlayer(3)
+                    ));
+                }
+                message => panic!("expected a DeletePlayer message, got {:?}", message),
+            }
+            a.disconnected(a_controller).await.unwrap();
+            let (a_controller, a_receiver) = AppController::create();
+            a.create_player(
+                a_controller.clone(),
+                0,
+                player(a_id, DPLAYI_PLAYER_SYSPLAYER),
+            )
+            .await
+            .unwrap();
+            a.send(
+                a_controller.clone(),
+                0,
+                create_player_message(3, a_id, "Alice"),
+            )
+            .await
+            .unwrap();
+            assert_eq!(received(&host_receiver), 1);
+            // The refused kick did not ban the host either.
+            host.send(host_controller.clone(), 0, message_to(host_id, None))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+
+            // Banning by name also keeps other game instances with the same name out.
+            let mut server_lock = server.lock().await;
+            let alice = PlayerIdentity::Name("Alice".to_string());
+            assert!(server_lock.kick(session_id, &alice, true).await);
+            drop(server_lock);
+            assert!(matches!(a_receiver.try_recv(), Ok(AppMessage::Close)));
+            assert_eq!(received(&host_receiver), 1);
+            a.disconnected(a_controller).await.unwrap();
+            let (a_controller, _a_receiver) = AppController::create();
+            assert!(a
+                .create_player(a_controller, 0, player(a_id, DPLAYI_PLAYER_SYSPLAYER))
+                .await
+                .is_err());
+
+            let mut b = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (b_controller, b_receiver) = AppController::create();
+            b.create_player(
+                b_controller.clone(),
+                0,
+                player(b_id, DPLAYI_PLAYER_SYSPLAYER),
+            )
+            .await
+            .unwrap();
+            b.send(
+                b_controller.clone(),
+                0,
+                create_player_message(4, b_id, "Alice"),
+            )
+            .await
+            .unwrap();
+            assert!(matches!(b_receiver.try_recv(), Ok(AppMessage::Close)));
+            assert_eq!(received(&host_receiver), 1);
+        });
+    }
+
+    /// Count the messages waiting for a game instance.
+    fn received(receiver: &async_channel::Receiver<AppMessage>) -> usize {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .filter(|message| matches!(message, AppMessage::Send(..)))
+            .count()
+    }
+
+    #[test]
+    fn broadcast_and_group_sends() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let (a_system, a_player) = (GUID::from_u128(3), GUID::from_u128(4));
+        let (b_system, b_player) = (GUID::from_u128(5), GUID::from_u128(6));
+        let group_id = GUID::from_u128(7);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, host_receiver) = AppController::create();
+            let host_flags = DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR;
+            host.create_player(host_controller.clone(), 0, player(host_id, host_flags))
+                .await
+                .unwrap();
+
+            let mut a = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (a_controller, a_receiver) = AppController::create();
+            let mut b = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (b_controller, b_receiver) = AppController::create();
+            for (sp, controller, system, app) in [
+                (&mut a, &a_controller, a_system, a_player),
+                (&mut b, &b_controller, b_system, b_player),
+            ] {
+                sp.create_player(
+                    controller.clone(),
+                    0,
+                    player(system, DPLAYI_PLAYER_SYSPLAYER),
+                )
+                .await
+                .unwrap();
+                sp.create_player(controller.clone(), 0, player(app, 0))
+                    .await
+                    .unwrap();
+            }
+
+            // A broadcast reaches every other game instance once.
+            a.send(a_controller.clone(), 0, message_to(a_player, None))
+                .await
+                .unwrap();
+            assert_eq!(received(&host_receiver), 1);
+            assert_eq!(received(&a_receiver), 0);
+            assert_eq!(received(&b_receiver), 1);
+
+            // Messages to a player go to the game instance that created it.
+            host.send(
+                host_controller.clone(),
+                0,
+                message_to(host_id, Some(b_player)),
+            )
+            .await
+            .unwrap();
+            a.send(a_controller.clone(), 0, message_to(a_player, Some(host_id)))
+                .await
+                .unwrap();
+            assert_eq!(received(&host_receiver), 1);
+            assert_eq!(received(&a_receiver), 0);
+            assert_eq!(received(&b_receiver), 1);
+
+            {
+                let mut server = server.lock().await;
+                server.create_group(session_id, group_id);
+                server.add_player_to_group(session_id, group_id, a_player);
+                server.add_player_to_group(session_id, group_id, b_system);
+                server.add_player_to_group(session_id, group_id, b_player);
+            }
+
+            // Group messages reach each game instance with members once, except the sender.
+            host.send(
+                host_controller.clone(),
+                0,
+                message_to(host_id, Some(group_id)),
+            )
+            .await
+            .unwrap();
+            assert_eq!(received(&host_receiver), 0);
+            assert_eq!(received(&a_receiver), 1);
+            assert_eq!(received(&b_receiver), 1);
+            a.send(
+                a_controller.clone(),
+                0,
+                message_to(a_player, Some(group_id)),
+            )
+            .await
+            .unwrap();
+            assert_eq!(received(&host_receiver), 0);
+            assert_eq!(received(&a_receiver), 0);
+            assert_eq!(received(&b_receiver), 1);
+
+            // Players that leave are no longer part of groups.
+            b.disconnected(b_controller).await.unwrap();
+            host.send(host_controller, 0, message_to(host_id, Some(group_id)))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+            assert_eq!(received(&b_receiver), 0);
+        });
+    }
+
+    /// Broadcast unreliable messages from a host to three game instances over lossy links, and
+    /// get the messages that each game instance received.
+    fn broadcast_over_lossy_links(seed: u64) -> Vec<Vec<Vec<u8>>> {
+        let conditions = NetworkConditions::new(seed).with_default(LinkConditions {
+            loss: 0.5,
+            ..LinkConditions::default()
+        });
+        let server = Arc::new(Mutex::new(
+            LocalOnlyServer::make().with_network_conditions(conditions),
+        ));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, _host_receiver) = AppController::create();
+            let host_flags = DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR;
+            host.create_player(host_controller.clone(), 0, player(host_id, host_flags))
+                .await
+                .unwrap();
+
+            let mut receivers = vec![];
+            for id in 3..6 {
+                let mut sp = LocalOnlySP::new(Arc::clone(&server), session_id);
+                let (controller, receiver) = AppController::create();
+                sp.create_player(
+                    controller,
+                    0,
+                    player(GUID::from_u128(id), DPLAYI_PLAYER_SYSPLAYER),
+                )
+                .await
+                .unwrap();
+                receivers.push(receiver);
+            }
+
+            for i in 0..30 {
+                let message = SendData {
+                    flags: 0,
+                    ..game_message(host_id, &[i])
+                };
+                host.send(host_controller.clone(), 0, message)
+                    .await
+                    .unwrap();
+            }
+            task::sleep(Duration::from_millis(100)).await;
+
+            receivers
+                .iter()
+                .map(|receiver| {
+                    std::iter::from_fn(|| receiver.try_recv().ok())
+                        .filter_map(|message| match message {
+                            AppMessage::Send(_, _, data) => Some(data),
+                            AppMessage::Close => None,
+                        })
+                        .collect()
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn broadcasts_make_the_same_decisions_with_the_same_seed() {
+        let first = broadcast_over_lossy_links(42);
+        assert!(first.iter().all(|received| received.len() < 30));
+        assert_eq!(first, broadcast_over_lossy_links(42));
+    }
+
+    /// A DirectPlay message that `sender` broadcasts.
+    fn game_message(sender: GUID, body: &[u8]) -> SendData {
+        let mut message = sender.as_bytes().to_vec();
+        message.extend_from_slice(body);
+        SendData {
+            message,
+            ..message_to(sender, None)
+        }
+    }
+
+    /// A `CreatePlayer` or `CreateGroup` message for a player or group with the given GUID.
+    fn create_message(command: u8, id: DPID, guid: GUID) -> Vec<u8> {
+        let mut message = dprun::name_server::create_player_message(&dprun::name_server::Player {
+            id,
+            flags: 0,
+            short_name: String::new(),
+            long_name: String::new(),
+            system_player: 1,
+            sp_data: guid.as_bytes().to_vec(),
+            player_data: vec![],
+            address: GUID::nil(),
+        });
+        message[4] = command;
+        message
+    }
+
+    #[test]
+    fn groups_created_by_games() {
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let session_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let (a_id, b_id) = (GUID::from_u128(3), GUID::from_u128(4));
+        let group_id = GUID::from_u128(7);
+
+        block_on(async {
+            let mut host = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (host_controller, host_receiver) = AppController::create();
+            let host_flags = DPLAYI_PLAYER_SYSPLAYER | DPLAYI_PLAYER_NAMESRVR;
+            host.create_player(host_controller.clone(), 0, player(host_id, host_flags))
+                .await
+                .unwrap();
+            let mut a = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (a_controller, a_receiver) = AppController::create();
+            let mut b = LocalOnlySP::new(Arc::clone(&server), session_id);
+            let (b_controller, b_receiver) = AppController::create();
+            for (sp, controller, id, dpid) in [
+                (&mut a, &a_controller, a_id, 3),
+                (&mut b, &b_controller, b_id, 4),
+            ] {
+                sp.create_player(controller.clone(), 0, player(id, 0))
+                    .await
+                    .unwrap();
+                sp.send(
+                    controller.clone(),
+                    0,
+                    game_message(id, &create_message(0x08, dpid, id)),
+                )
+                .await
+                .unwrap();
+            }
+            received(&host_receiver);
+            received(&a_receiver);
+            received(&b_receiver);
+
+            // The host's game creates a group with the first player in it.
+            host.send(
+                host_controller.clone(),
+                0,
+                game_message(host_id, &create_message(0x09, 7, group_id)),
+            )
+            .await
+            .unwrap();
+            let mut add_player = create_message(0x0d, 0, GUID::nil());
+            add_player.truncate(8);
+            for value in &[0u32, 3, 7, 0, 0] {
+                add_player.extend_from_slice(&value.to_le_bytes());
+            }
+            host.send(
+                host_controller.clone(),
+                0,
+                game_message(host_id, &add_player),
+            )
+            .await
+            .unwrap();
+            assert_eq!(received(&a_receiver), 2);
+            assert_eq!(received(&b_receiver), 2);
+
+            host.send(host_controller, 0, message_to(host_id, Some(group_id)))
+                .await
+                .unwrap();
+            assert_eq!(received(&a_receiver), 1);
+            assert_eq!(received(&b_receiver), 0);
+        });
+    }
+}
@@ -0,0 +1,295 @@
+//! Routing game instances that connect over a stream, like a Unix domain socket, a TCP
+//! connection or a WebSocket, through a `LocalOnlyServer`.
+//!
+//! The router runs a `LocalOnlySP` on behalf of every client, so messages are routed exactly as
+//! if all game instances shared a process. `RouterSP` is the other end, in the game's process.
+//!
+//! Every message is a frame starting with a four byte method name. Clients send:
+//!
+//!  - `helo`, followed by the session GUID, once after connecting;
+//!  - `enum`, `open`, `crpl`, `repl` and `send`, followed by the data that dprun passed to the
+//!    service provider;
+//!  - `disc` when the game instance disconnected.
+//!
+//! The router sends:
+//!
+//!  - `send`, followed by a message for the game;
+//!  - `clos` when the game's connection should be closed.
+
+use crate::{LocalOnlySP, LocalOnlyServer};
+use async_std::io;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use async_trait::async_trait;
+use dprun::{structs::*, AppController, AppMessage, ServiceProvider, GUID};
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use futures_codec::{Bytes, Framed, LengthCodec};
+use std::fmt::Display;
+use std::pin::Pin;
+
+/// Build a frame for a method.
+pub fn frame(method: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + data.len());
+    bytes.extend_from_slice(method);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// Split a frame into its method name and data.
+pub fn split_frame(bytes: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    if bytes.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame is too short",
+        ));
+    }
+    Ok(bytes.split_at(4))
+}
+
+/// Send and receive frames on a byte stream, each prefixed by its length.
+pub fn length_prefixed<S>(
+    stream: S,
+) -> (
+    impl Sink<Vec<u8>, Error = io::Error>,
+    impl Stream<Item = io::Result<Vec<u8>>>,
+)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (writer, reader) = Framed::new(stream, LengthCodec).split();
+    let writer = writer.with(|data: Vec<u8>| future::ready(Ok::<_, io::Error>(Bytes::from(data))));
+    let reader = reader.map(|bytes| bytes.map(|bytes| bytes.to_vec()));
+    (writer, reader)
+}
+
+/// Forward messages for a game instance to its client.
+async fn write_messages(
+    mut writer: impl Sink<Vec<u8>, Error = io::Error> + Unpin,
+    receiver: async_channel::Receiver<AppMessage>,
+) -> io::Result<()> {
+    while let Ok(message) = receiver.recv().await {
+        match message {
+            AppMessage::Send(_, _, data) => writer.send(frame(b"send", &data)).await?,
+            AppMessage::Close => writer.send(frame(b"clos", &[])).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Route the messages of a game instance through `server`, until either side goes away.
+/// `client` names the game instance in logs.
+pub async fn route<R, W>(
+    mut reader: R,
+    writer: W,
+    server: Arc<Mutex<LocalOnlyServer>>,
+    client: impl Display,
+) -> io::Result<()>
+where
+    R: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+    W: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    let hello = match reader.next().await {
+        Some(hello) => hello?,
+        None => return Ok(()),
+    };
+    let session_id = match split_frame(&hello)? {
+        (b"helo", guid) if guid.len() == 16 => GUID::from_slice(guid).unwrap(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a helo message",
+            ))
+        }
+    };
+    log::debug!("[router] {} joined session {}", client, session_id);
+
+    let mut sp = LocalOnlySP::new(server, session_id);
+    let (controller, receiver) = AppController::create();
+
+    let read_messages = async {
+        while let Some(bytes) = reader.next().await {
+            let bytes = bytes?;
+            let (method, data) = split_frame(&bytes)?;
+            let controller = controller.clone();
+            let result = match method {
+                b"enum" => {
+                    let data = EnumSessionsData {
+                        message: data.to_vec(),
+                    };
+                    sp.enum_sessions(controller, 0, data).await
+                }
+                b"open" => sp.open(controller, 0, OpenData::try_parse(data)?).await,
+                b"crpl" => {
+                    sp.create_player(controller, 0, CreatePlayerData::try_parse(data)?)
+                        .await
+                }
+                b"repl" => sp.reply(controller, 0, ReplyData::try_parse(data)?).await,
+                b"send" => sp.send(controller, 0, SendData::try_parse(data)?).await,
+                b"disc" => sp.disconnected(controller).await,
+                _ => {
+                    log::warn!("[router] {}: unknown method {:?}", client, method);
+                    Ok(())
+                }
+            };
+            if let Err(err) = result {
+                log::warn!("[router] {}: {:?}", client, err);
+            }
+        }
+        Ok(())
+    };
+    // Stop reading when the client can no longer be written to, and vice versa.
+    let result = {
+        futures::pin_mut!(read_messages);
+        let write_messages = write_messages(writer, receiver);
+        futures::pin_mut!(write_messages);
+        match future::select(read_messages, write_messages).await {
+            Either::Left((result, _)) => result,
+            Either::Right((result, _)) => result,
+        }
+    };
+    sp.disconnected(controller).await?;
+    log::debug!("[router] {} left session {}", client, session_id);
+    result
+}
+
+type Writer = Pin<Box<dyn Sink<Vec<u8>, Error = io::Error> + Send + Sync>>;
+type Shutdown = Box<dyn FnOnce() -> io::Result<()> + Send + Sync>;
+
+/// Service provider that routes messages through a router in another process.
+pub struct RouterSP {
+    writer: Writer,
+    /// Called after closing the writer, for connections where that only flushes it: the router
+    /// notices that we left once the connection is shut down.
+    shutdown: Option<Shutdown>,
+    /// Controller for the current connection from the game, which messages from the router are
+    /// delivered to.
+    controller: Arc<Mutex<Option<AppController>>>,
+    reader: Option<task::JoinHandle<()>>,
+}
+
+impl RouterSP {
+    /// Join a session through the router on the other end of a connection, which `reader` and
+    /// `writer` are the two halves of. `shutdown` ends the connection when the game is done.
+    pub async fn connect<R, W>(
+        mut reader: R,
+        writer: W,
+        session_id: GUID,
+        shutdown: impl FnOnce() -> io::Result<()> + Send + Sync + 'static,
+    ) -> io::Result<Self>
+    where
+        R: Stream<Item = io::Result<Vec<u8>>> + Unpin + Send + 'static,
+        W: Sink<Vec<u8>, Error = io::Error> + Send + Sync + 'static,
+    {
+        let mut writer: Writer = Box::pin(writer);
+        writer.send(frame(b"helo", session_id.as_bytes())).await?;
+
+        let controller = Arc::new(Mutex::new(None::<AppController>));
+        let reader = task::spawn({
+            let controller = Arc::clone(&controller);
+            async move {
+                while let Some(Ok(bytes)) = reader.next().await {
+                    let mut current = match controller.lock().await.clone() {
+                        Some(current) => current,
+                        None => continue,
+                    };
+                    match split_frame(&bytes) {
+                        Ok((b"send", data)) => current.send(data.to_vec()).await,
+                        Ok((b"clos", _)) => current.close().await,
+                        _ => log::warn!("[RouterSP] unexpected message from router"),
+                    }
+                }
+                // Without the router, the session is gone.
+                log::debug!("[RouterSP] router connection closed");
+                if let Some(mut current) = controller.lock().await.take() {
+                    current.close().await;
+                }
+            }
+        });
+
+        Ok(Self {
+            writer,
+            shutdown: Some(Box::new(shutdown)),
+            controller,
+            reader: Some(reader),
+        })
+    }
+
+    async fn forward(
+        &mut self,
+        controller: AppController,
+        method: &[u8; 4],
+        data: &[u8],
+    ) -> io::Result<()> {
+        *self.controller.lock().await = Some(controller);
+        self.writer.send(frame(method, data)).await
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for RouterSP {
+    async fn enum_sessions(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: EnumSessionsData,
+    ) -> io::Result<()> {
+        self.forward(controller, b"enum", &data.message).await
+    }
+
+    async fn open(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: OpenData,
+    ) -> io::Result<()> {
+        log::trace!("[RouterSP::open] Got Open message: {:?}", data);
+        // The router needs to know whether the session may outlive its host.
+        self.forward(controller, b"open", &data.to_bytes()).await
+    }
+
+    async fn create_player(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: CreatePlayerData,
+    ) -> io::Result<()> {
+        self.forward(controller, b"crpl", &data.to_bytes()).await
+    }
+
+    async fn reply(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: ReplyData,
+    ) -> io::Result<()> {
+        self.forward(controller, b"repl", &data.to_bytes()).await
+    }
+
+    async fn send(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: SendData,
+    ) -> io::Result<()> {
+        self.forward(controller, b"send", &data.to_bytes()).await
+    }
+
+    async fn disconnected(&mut self, _controller: AppController) -> io::Result<()> {
+        self.controller.lock().await.take();
+        self.writer.send(frame(b"disc", &[])).await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.close().await?;
+        if let Some(shutdown) = self.shutdown.take() {
+            shutdown()?;
+        }
+        if let Some(reader) = self.reader.take() {
+            reader.await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,636 @@
+//! Service provider that plays DirectPlay sessions over QUIC.
+//!
+//! Guaranteed messages go over a QUIC stream, so they arrive in order without a reliability
+//! layer of our own. Unreliable messages go out as QUIC datagrams, which are never sent again
+//! and don't hold up anything else. All traffic is encrypted with TLS 1.3, and connections follow
+//! players whose address changes mid-game.
+//!
+//! The host listens for connections from joining players, who need its address and a way to
+//! trust its certificate: either one from a regular certificate authority, a pinned certificate,
+//! or for ad-hoc games, whatever certificate the host presents. By default, a host presents a
+//! freshly generated self-signed certificate.
+//!
+//! The host relays traffic between joining players, who only ever connect to it. Sessions end
+//! when the host leaves, even with `DPSESSION_MIGRATEHOST`: nobody else is connected to everyone.
+
+mod message;
+mod network;
+
+use async_std::io;
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use dprun::session::Command;
+use dprun::{structs, AppController, ServiceProvider};
+use futures::channel::mpsc::{self, UnboundedSender};
+use network::Network;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Endpoint, IdleTimeout, TransportConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+pub use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::pki_types::{ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Player flag: the player was created by the local game.
+const DPLAYI_PLAYER_LOCAL: i32 = 8;
+
+/// The application protocol that peers agree on during the TLS handshake.
+const ALPN: &[u8] = b"dpsp";
+
+fn tls_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+/// Generate a self-signed certificate for the given names, and its private key.
+pub fn self_signed_certificate(
+    names: Vec<String>,
+) -> io::Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    Ok((certified.cert.der().clone(), key.into()))
+}
+
+/// Accepts every server certificate, for ad-hoc games against self-signed hosts. The
+/// connection is still encrypted, but the host is not authenticated.
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// The endpoint task and the channel to send it commands.
+struct RunningNetwork {
+    endpoint: Endpoint,
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+pub struct QuicSP {
+    bind_address: SocketAddr,
+    /// The hosting peer to connect to, and the name on its certificate.
+    host: Option<(SocketAddr, String)>,
+    certificate_chain: Vec<CertificateDer<'static>>,
+    private_key: PrivateKeyDer<'static>,
+    /// Certificates to trust in addition to the usual certificate authorities.
+    trusted_certificates: Vec<CertificateDer<'static>>,
+    any_host_certificate: bool,
+    idle_timeout: Duration,
+    network: Option<RunningNetwork>,
+}
+
+/// # Panics
+///
+/// Panics if no self-signed certificate can be generated. Use `QuicSP::new` to handle that.
+impl Default for QuicSP {
+    fn default() -> Self {
+        Self::new().expect("could not generate a certificate")
+    }
+}
+
+impl QuicSP {
+    /// Create a service provider that presents a freshly generated self-signed certificate for
+    /// "localhost" when hosting.
+    pub fn new() -> io::Result<Self> {
+        let (certificate, private_key) = self_signed_certificate(vec!["localhost".to_string()])?;
+        Ok(Self {
+            bind_address: ([0, 0, 0, 0], 0).into(),
+            host: None,
+            certificate_chain: vec![certificate],
+            private_key,
+            trusted_certificates: vec![],
+            any_host_certificate: false,
+            idle_timeout: Duration::from_secs(10),
+            network: None,
+        })
+    }
+
+    /// Set the address to receive packets on. Defaults to a random port on all interfaces.
+    pub fn with_bind_address(self, bind_address: SocketAddr) -> Self {
+        Self {
+            bind_address,
+            ..self
+        }
+    }
+
+    /// Set the address of the hosting peer to connect to, and the name its certificate must be
+    /// valid for.
+    pub fn with_host(self, address: SocketAddr, server_name: &str) -> Self {
+        Self {
+            host: Some((address, server_name.to_string())),
+            ..self
+        }
+    }
+
+    /// Present this certificate chain to joining players when hosting, instead of a self-signed
+    /// certificate for "localhost".
+    pub fn with_certificate(
+        self,
+        certificate_chain: Vec<CertificateDer<'static>>,
+        private_key: PrivateKeyDer<'static>,
+    ) -> Self {
+        Self {
+            certificate_chain,
+            private_key,
+            ..self
+        }
+    }
+
+    /// Trust a host that presents this certificate, such as the self-signed certificate of a
+    /// host that a lobby passed on to us.
+    pub fn with_trusted_certificate(mut self, certificate: CertificateDer<'static>) -> Self {
+        self.trusted_certificates.push(certificate);
+        self
+    }
+
+    /// Accept whatever certificate the host presents, for ad-hoc games. Traffic is still
+    /// encrypted, but anyone who can intercept it could pose as the host.
+    pub fn with_any_host_certificate(self) -> Self {
+        Self {
+            any_host_certificate: true,
+            ..self
+        }
+    }
+
+    /// Set how long a peer may stay silent before its connection is closed. If the host goes
+    /// silent, the game's connection is closed too.
+    pub fn with_idle_timeout(self, idle_timeout: Duration) -> Self {
+        Self {
+            idle_timeout,
+            ..self
+        }
+    }
+
+    /// The certificate that joining players see when we host.
+    pub fn certificate(&self) -> &CertificateDer<'static> {
+        &self.certificate_chain[0]
+    }
+
+    /// The address that packets are received on, for other peers to connect to. Binds the
+    /// socket if that did not happen yet.
+    pub fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.network()?.endpoint.local_addr()
+    }
+
+    /// Move to a new local address, for example after switching networks. Connections carry on
+    /// from the new address.
+    pub fn rebind(&mut self, bind_address: SocketAddr) -> io::Result<()> {
+        let socket = std::net::UdpSocket::bind(bind_address)?;
+        self.network()?.endpoint.rebind(socket)
+    }
+
+    fn transport_config(&self) -> io::Result<Arc<TransportConfig>> {
+        let mut transport = TransportConfig::default();
+        transport
+            .max_idle_timeout(Some(
+                IdleTimeout::try_from(self.idle_timeout).map_err(tls_error)?,
+            ))
+            .keep_alive_interval(Some(self.idle_timeout / 3));
+        Ok(Arc::new(transport))
+    }
+
+    fn server_config(&self, provider: Arc<CryptoProvider>) -> io::Result<quinn::ServerConfig> {
+        let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(self.certificate_chain.clone(), self.private_key.clone_key())
+            .map_err(tls_error)?;
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicServerConfig::try_from(tls).map_err(tls_error)?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(self.transport_config()?);
+        Ok(config)
+    }
+
+    fn client_config(&self, provider: Arc<CryptoProvider>) -> io::Result<quinn::ClientConfig> {
+        let builder = rustls::ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?;
+        let mut tls = if self.any_host_certificate {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+                .with_no_client_auth()
+        } else {
+            let mut roots = rustls::RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            for certificate in &self.trusted_certificates {
+                roots.add(certificate.clone()).map_err(tls_error)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(tls).map_err(tls_error)?;
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(self.transport_config()?);
+        Ok(config)
+    }
+
+    fn network(&mut self) -> io::Result<&RunningNetwork> {
+        if self.network.is_none() {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut endpoint = Endpoint::server(
+                self.server_config(Arc::clone(&provider))?,
+                self.bind_address,
+            )?;
+            endpoint.set_default_client_config(self.client_config(provider)?);
+            log::debug!("[quic] listening on {}", endpoint.local_addr()?);
+
+            let (commands, receiver) = mpsc::unbounded();
+            let network = Network::new(endpoint.clone(), self.host.clone());
+            let task = task::spawn(network.run(receiver));
+            self.network = Some(RunningNetwork {
+                endpoint,
+                commands,
+                task,
+            });
+        }
+        Ok(self.network.as_ref().unwrap())
+    }
+
+    /// Send a command to the endpoint task, starting it if necessary.
+    fn command(&mut self, command: Command) -> io::Result<()> {
+        self.network()?
+            .commands
+            .unbounded_send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "QUIC endpoint stopped"))
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for QuicSP {
+    async fn enum_sessions(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::EnumSessionsData,
+    ) -> io::Result<()> {
+        self.command(Command::EnumSessions {
+            controller,
+            message: data.message,
+        })
+    }
+
+    async fn open(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::OpenData,
+    ) -> io::Result<()> {
+        // Joining peers only connect to the host, so nobody can take over when it leaves.
+        self.command(Command::Open {
+            controller,
+            create: data.create,
+            migrate_host: false,
+        })
+    }
+
+    async fn create_player(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::CreatePlayerData,
+    ) -> io::Result<()> {
+        // DirectPlay also tells us about players on other peers; those are announced by the
+        // peer that created them.
+        if data.flags & DPLAYI_PLAYER_LOCAL == 0 {
+            return Ok(());
+        }
+        self.command(Command::CreatePlayer {
+            controller,
+            player: data.player_guid,
+        })
+    }
+
+    async fn reply(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::ReplyData,
+    ) -> io::Result<()> {
+        self.command(Command::Reply {
+            controller,
+            reply_to: data.reply_to,
+            message: data.message,
+        })
+    }
+
+    async fn send(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::SendData,
+    ) -> io::Result<()> {
+        self.command(Command::Send {
+            controller,
+            sender: data.sender_id,
+            receiver: data.receiver_id,
+            delivery: data.delivery(),
+            message: data.message,
+        })
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(RunningNetwork { commands, task, .. }) = self.network.take() {
+            drop(commands);
+            task.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use dprun::structs::DPSEND_GUARANTEED;
+    use dprun::AppMessage;
+    use dprun::GUID;
+
+    fn loopback() -> SocketAddr {
+        ([127, 0, 0, 1], 0).into()
+    }
+
+    fn open(create: bool) -> structs::OpenData {
+        structs::OpenData {
+            create,
+            return_status: false,
+            open_flags: 0,
+            session_flags: 0,
+        }
+    }
+
+    fn enum_request(requester: GUID) -> structs::EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        structs::EnumSessionsData { message }
+    }
+
+    fn local_player(player: GUID) -> structs::CreatePlayerData {
+        structs::CreatePlayerData {
+            player_guid: player,
+            flags: DPLAYI_PLAYER_LOCAL,
+        }
+    }
+
+    fn message(flags: i32, sender: GUID, receiver: Option<GUID>, data: &[u8]) -> structs::SendData {
+        let mut message = sender.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        structs::SendData {
+            flags,
+            receiver_id: receiver,
+            sender_id: sender,
+            system_message: false,
+            message,
+        }
+    }
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> Option<Vec<u8>> {
+        match timeout(Duration::from_secs(5), receiver.recv()).await {
+            Ok(Ok(AppMessage::Send(_, _, data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    struct Peer {
+        sp: QuicSP,
+        controller: AppController,
+        receiver: async_channel::Receiver<AppMessage>,
+    }
+
+    impl Peer {
+        fn new(sp: QuicSP) -> Self {
+            let (controller, receiver) = AppController::create();
+            Self {
+                sp,
+                controller,
+                receiver,
+            }
+        }
+
+        async fn send(&mut self, data: structs::SendData) {
+            self.sp
+                .send(self.controller.clone(), 0, data)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Host a session with one player, and join it with another.
+    async fn start_session(
+        host: QuicSP,
+        host_id: GUID,
+        joiner: QuicSP,
+        joiner_id: GUID,
+    ) -> (Peer, Peer) {
+        let mut host = Peer::new(host.with_bind_address(loopback()));
+        host.sp
+            .open(host.controller.clone(), 0, open(true))
+            .await
+            .unwrap();
+        host.sp
+            .create_player(host.controller.clone(), 0, local_player(host_id))
+            .await
+            .unwrap();
+
+        let host_address = host.sp.local_addr().unwrap();
+        let mut joiner = Peer::new(
+            joiner
+                .with_bind_address(loopback())
+                .with_host(host_address, "localhost"),
+        );
+        let request = enum_request(joiner_id);
+        let expected = request.message.clone();
+        joiner
+            .sp
+            .enum_sessions(joiner.controller.clone(), 0, request)
+            .await
+            .unwrap();
+        assert_eq!(next_message(&host.receiver).await, Some(expected));
+
+        let reply = structs::ReplyData {
+            reply_to: joiner_id,
+            name_server_id: 0,
+            message: b"session description".to_vec(),
+        };
+        host.sp
+            .reply(host.controller.clone(), 0, reply)
+            .await
+            .unwrap();
+        assert_eq!(
+            next_message(&joiner.receiver).await,
+            Some(b"session description".to_vec())
+        );
+
+        joiner
+            .sp
+            .open(joiner.controller.clone(), 0, open(false))
+            .await
+            .unwrap();
+        joiner
+            .sp
+            .create_player(joiner.controller.clone(), 0, local_player(joiner_id))
+            .await
+            .unwrap();
+        // Messages without a receiver go to everyone in the session.
+        let hello = message(DPSEND_GUARANTEED, joiner_id, None, b"hello");
+        let expected = hello.message.clone();
+        joiner.send(hello).await;
+        assert_eq!(next_message(&host.receiver).await, Some(expected));
+
+        (host, joiner)
+    }
+
+    #[test]
+    fn sends_guaranteed_and_unreliable_messages() {
+        let host_id = GUID::from_u128(1);
+        let joiner_id = GUID::from_u128(2);
+
+        task::block_on(async {
+            let host = QuicSP::new().unwrap();
+            let joiner = QuicSP::new()
+                .unwrap()
+                .with_trusted_certificate(host.certificate().clone());
+            let (mut host, mut joiner) = start_session(host, host_id, joiner, joiner_id).await;
+
+            for i in 0..20u8 {
+                host.send(message(DPSEND_GUARANTEED, host_id, Some(joiner_id), &[i]))
+                    .await;
+            }
+            for i in 0..20u8 {
+                let turn = next_message(&joiner.receiver).await;
+                assert_eq!(turn.as_deref().and_then(|m| m.get(16)), Some(&i));
+            }
+
+            let ping = message(0, joiner_id, Some(host_id), b"ping");
+            let expected = ping.message.clone();
+            joiner.send(ping).await;
+            assert_eq!(next_message(&host.receiver).await, Some(expected));
+
+            joiner.sp.shutdown().await.unwrap();
+            host.sp.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn does_not_join_untrusted_hosts() {
+        task::block_on(async {
+            let mut host = Peer::new(QuicSP::default().with_bind_address(loopback()));
+            host.sp
+                .open(host.controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+
+            let mut joiner = Peer::new(
+                QuicSP::default()
+                    .with_bind_address(loopback())
+                    .with_host(host.sp.local_addr().unwrap(), "localhost"),
+            );
+            joiner
+                .sp
+                .enum_sessions(
+                    joiner.controller.clone(),
+                    0,
+                    enum_request(GUID::from_u128(2)),
+                )
+                .await
+                .unwrap();
+            let received = timeout(Duration::from_secs(1), host.receiver.recv()).await;
+            assert!(received.is_err());
+
+            joiner.sp.shutdown().await.unwrap();
+            host.sp.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn follows_players_to_a_new_address() {
+        let host_id = GUID::from_u128(1);
+        let joiner_id = GUID::from_u128(2);
+
+        task::block_on(async {
+            let host = QuicSP::default();
+            let joiner = QuicSP::default().with_any_host_certificate();
+            let (mut host, mut joiner) = start_session(host, host_id, joiner, joiner_id).await;
+
+            let old_address = joiner.sp.local_addr().unwrap();
+            joiner.sp.rebind(loopback()).unwrap();
+            assert_ne!(joiner.sp.local_addr().unwrap(), old_address);
+
+            let moved = message(DPSEND_GUARANTEED, joiner_id, Some(host_id), b"moved");
+            let expected = moved.message.clone();
+            joiner.send(moved).await;
+            assert_eq!(next_message(&host.receiver).await, Some(expected));
+            let welcome = message(DPSEND_GUARANTEED, host_id, Some(joiner_id), b"welcome");
+            let expected = welcome.message.clone();
+            host.send(welcome).await;
+            assert_eq!(next_message(&joiner.receiver).await, Some(expected));
+
+            joiner.sp.shutdown().await.unwrap();
+            host.sp.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn closes_the_game_when_the_host_leaves() {
+        task::block_on(async {
+            let host = QuicSP::default();
+            let joiner = QuicSP::default().with_any_host_certificate();
+            let (mut host, mut joiner) =
+                start_session(host, GUID::from_u128(1), joiner, GUID::from_u128(2)).await;
+
+            host.sp.shutdown().await.unwrap();
+            let closed = timeout(Duration::from_secs(5), joiner.receiver.recv()).await;
+            assert!(matches!(closed, Ok(Ok(AppMessage::Close))));
+
+            joiner.sp.shutdown().await.unwrap();
+        });
+    }
+}
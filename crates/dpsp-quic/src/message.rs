@@ -0,0 +1,133 @@
+//! The wire format of the QUIC service provider.
+//!
+//! Guaranteed messages go over one unidirectional stream in each direction, each prefixed with
+//! its length as a big endian u32. Unreliable messages are QUIC datagrams, one message each.
+//! Messages start with a kind byte.
+
+use async_std::io;
+use dprun::GUID;
+
+const MESSAGE_ENUM_REQUEST: u8 = 0;
+const MESSAGE_ENUM_REPLY: u8 = 1;
+const MESSAGE_PLAYERS: u8 = 2;
+const MESSAGE_GAME: u8 = 3;
+
+/// Largest message we accept on a stream.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// A message between service providers.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Message {
+    /// A game looking for sessions.
+    EnumRequest(Vec<u8>),
+    /// A hosting game describing its session.
+    EnumReply(Vec<u8>),
+    /// Players that live on the sending peer.
+    Players(Vec<GUID>),
+    /// A message for a game.
+    Game {
+        /// The player that sent the message.
+        sender: GUID,
+        /// The player to deliver the message to, or everyone if there is none.
+        receiver: Option<GUID>,
+        data: Vec<u8>,
+    },
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid message")
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Message::EnumRequest(data) => {
+                bytes.push(MESSAGE_ENUM_REQUEST);
+                bytes.extend_from_slice(data);
+            }
+            Message::EnumReply(data) => {
+                bytes.push(MESSAGE_ENUM_REPLY);
+                bytes.extend_from_slice(data);
+            }
+            Message::Players(players) => {
+                bytes.push(MESSAGE_PLAYERS);
+                for player in players {
+                    bytes.extend_from_slice(player.as_bytes());
+                }
+            }
+            Message::Game {
+                sender,
+                receiver,
+                data,
+            } => {
+                bytes.push(MESSAGE_GAME);
+                bytes.extend_from_slice(sender.as_bytes());
+                bytes.extend_from_slice(receiver.unwrap_or_else(GUID::nil).as_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let (&kind, body) = bytes.split_first().ok_or_else(invalid)?;
+        Ok(match kind {
+            MESSAGE_ENUM_REQUEST => Message::EnumRequest(body.to_vec()),
+            MESSAGE_ENUM_REPLY => Message::EnumReply(body.to_vec()),
+            MESSAGE_PLAYERS if body.len().is_multiple_of(16) => Message::Players(
+                body.chunks(16)
+                    .map(|guid| GUID::from_slice(guid).unwrap())
+                    .collect(),
+            ),
+            MESSAGE_GAME if body.len() >= 32 => {
+                let receiver = GUID::from_slice(&body[16..32]).unwrap();
+                Message::Game {
+                    sender: GUID::from_slice(&body[..16]).unwrap(),
+                    receiver: Some(receiver).filter(|receiver| !receiver.is_nil()),
+                    data: body[32..].to_vec(),
+                }
+            }
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// Prefix an encoded message with its length, for sending on a stream.
+pub(crate) fn frame(message: &[u8]) -> Vec<u8> {
+    let mut bytes = (message.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(message);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_messages() {
+        let messages = vec![
+            Message::EnumRequest(b"request".to_vec()),
+            Message::EnumReply(vec![]),
+            Message::Players(vec![GUID::from_u128(1), GUID::from_u128(2)]),
+            Message::Game {
+                sender: GUID::from_u128(3),
+                receiver: None,
+                data: b"to everyone".to_vec(),
+            },
+            Message::Game {
+                sender: GUID::from_u128(3),
+                receiver: Some(GUID::from_u128(4)),
+                data: b"to one player".to_vec(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+
+        assert!(Message::decode(&[]).is_err());
+        assert!(Message::decode(&[MESSAGE_PLAYERS, 1, 2, 3]).is_err());
+        assert!(Message::decode(&[MESSAGE_GAME, 1, 2, 3]).is_err());
+        assert_eq!(frame(b"abc"), vec![0, 0, 0, 3, b'a', b'b', b'c']);
+    }
+}
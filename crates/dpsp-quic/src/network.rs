@@ -0,0 +1,382 @@
+//! The QUIC endpoint behind a `QuicSP`, running in its own task.
+//!
+//! Sessions are a star: joining peers connect to the host, and the host passes on messages
+//! between joining players. The host keeps track of the connection that every remote player lives
+//! on; joining peers send everything to the host.
+
+use crate::message::{frame, Message, MAX_MESSAGE_SIZE};
+use async_std::io;
+use async_std::task;
+use dprun::session::{Command, Outgoing, Session};
+use dprun::Delivery;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::stream::StreamExt;
+use quinn::{Connection, Endpoint};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Things that happen on connections, reported by their tasks.
+enum Event {
+    /// The connection to the host was established, or failed.
+    Connected(io::Result<Connection>),
+    /// Another peer connected to us.
+    Accepted(Connection),
+    Message(usize, Delivery, Message),
+    Closed(usize),
+}
+
+/// A connection to another peer.
+struct Peer {
+    connection: Connection,
+    /// Frames for the stream that carries guaranteed messages.
+    stream: UnboundedSender<Vec<u8>>,
+}
+
+fn connection_error(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, err)
+}
+
+/// Accept connections until the endpoint is closed.
+async fn accept(endpoint: Endpoint, events: UnboundedSender<Event>) {
+    while let Some(incoming) = endpoint.accept().await {
+        let events = events.clone();
+        task::spawn(async move {
+            match incoming.await {
+                Ok(connection) => {
+                    let _ = events.unbounded_send(Event::Accepted(connection));
+                }
+                Err(err) => log::debug!("[quic] incoming connection failed: {:?}", err),
+            }
+        });
+    }
+}
+
+/// Send guaranteed messages on a stream of their own, in order.
+async fn write_stream(connection: Connection, mut frames: UnboundedReceiver<Vec<u8>>) {
+    let mut stream = match connection.open_uni().await {
+        Ok(stream) => stream,
+        Err(err) => return log::debug!("[quic] could not open stream: {:?}", err),
+    };
+    while let Some(frame) = frames.next().await {
+        if let Err(err) = stream.write_all(&frame).await {
+            return log::debug!("[quic] could not write to stream: {:?}", err);
+        }
+    }
+    let _ = stream.finish();
+}
+
+/// Read guaranteed messages from the streams that the peer opens.
+async fn read_streams(id: usize, connection: Connection, events: UnboundedSender<Event>) {
+    while let Ok(mut stream) = connection.accept_uni().await {
+        loop {
+            let mut length = [0; 4];
+            if stream.read_exact(&mut length).await.is_err() {
+                break;
+            }
+            let length = u32::from_be_bytes(length) as usize;
+            if length > MAX_MESSAGE_SIZE {
+                log::warn!("[quic] message of {} bytes is too large", length);
+                connection.close(0u32.into(), b"message too large");
+                return;
+            }
+            let mut bytes = vec![0; length];
+            if stream.read_exact(&mut bytes).await.is_err() {
+                break;
+            }
+            match Message::decode(&bytes) {
+                Ok(message) => {
+                    let _ =
+                        events.unbounded_send(Event::Message(id, Delivery::Guaranteed, message));
+                }
+                Err(err) => log::warn!("[quic] bad message: {:?}", err),
+            }
+        }
+    }
+}
+
+/// Read unreliable messages until the connection closes.
+async fn read_datagrams(id: usize, connection: Connection, events: UnboundedSender<Event>) {
+    loop {
+        match connection.read_datagram().await {
+            Ok(bytes) => match Message::decode(&bytes) {
+                Ok(message) => {
+                    let _ =
+                        events.unbounded_send(Event::Message(id, Delivery::Unreliable, message));
+                }
+                Err(err) => log::warn!("[quic] bad datagram: {:?}", err),
+            },
+            Err(err) => {
+                log::debug!("[quic] connection {} closed: {:?}", id, err);
+                break;
+            }
+        }
+    }
+    let _ = events.unbounded_send(Event::Closed(id));
+}
+
+pub(crate) struct Network {
+    endpoint: Endpoint,
+    /// The hosting peer to connect to, and the name on its certificate.
+    host: Option<(SocketAddr, String)>,
+    events: UnboundedSender<Event>,
+    next_peer_id: usize,
+    peers: HashMap<usize, Peer>,
+    /// The connection to the host, once it is established.
+    host_connection: Option<usize>,
+    /// Whether we are connecting to the host.
+    connecting: bool,
+    /// Messages for the host that wait for the connection.
+    host_queue: Vec<(Delivery, Message)>,
+    /// The session, with the connections that remote players live on.
+    session: Session<usize>,
+    /// Events from the connection tasks, taken by `run`.
+    receiver: Option<UnboundedReceiver<Event>>,
+}
+
+impl Network {
+    pub fn new(endpoint: Endpoint, host: Option<(SocketAddr, String)>) -> Self {
+        let (events, receiver) = mpsc::unbounded();
+        task::spawn(accept(endpoint.clone(), events.clone()));
+        Self {
+            endpoint,
+            host,
+            events,
+            next_peer_id: 0,
+            peers: HashMap::new(),
+            host_connection: None,
+            connecting: false,
+            host_queue: vec![],
+            session: Session::default(),
+            receiver: Some(receiver),
+        }
+    }
+
+    /// Drive the endpoint until the service provider goes away.
+    pub async fn run(mut self, commands: UnboundedReceiver<Command>) {
+        let mut events = self.receiver.take().unwrap().fuse();
+        let mut commands = commands.fuse();
+        loop {
+            futures::select! {
+                command = commands.next() => match command {
+                    Some(command) => self.handle_command(command).await,
+                    None => break,
+                },
+                event = events.next() => {
+                    // We hold a sender ourselves, so this never ends.
+                    if let Some(event) = event {
+                        self.handle_event(event).await;
+                    }
+                }
+            }
+        }
+        log::debug!("[quic] service provider is gone, closing endpoint");
+        self.endpoint.close(0u32.into(), b"shutdown");
+        self.endpoint.wait_idle().await;
+    }
+
+    fn add_peer(&mut self, connection: Connection) -> usize {
+        let id = self.next_peer_id;
+        self.next_peer_id += 1;
+        log::debug!(
+            "[quic] connection {} with {}",
+            id,
+            connection.remote_address()
+        );
+        let (stream, frames) = mpsc::unbounded();
+        task::spawn(write_stream(connection.clone(), frames));
+        task::spawn(read_streams(id, connection.clone(), self.events.clone()));
+        task::spawn(read_datagrams(id, connection.clone(), self.events.clone()));
+        self.peers.insert(id, Peer { connection, stream });
+        id
+    }
+
+    /// Send a message to a peer: guaranteed messages on the stream, others as datagrams if they
+    /// fit.
+    fn send(&self, id: usize, delivery: Delivery, message: &Message) {
+        let peer = match self.peers.get(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let bytes = message.encode();
+        if delivery == Delivery::Unreliable {
+            let max_size = peer.connection.max_datagram_size().unwrap_or(0);
+            if bytes.len() <= max_size {
+                if let Err(err) = peer.connection.send_datagram(bytes.into()) {
+                    log::debug!("[quic] could not send datagram: {:?}", err);
+                }
+                return;
+            }
+        }
+        let _ = peer.stream.unbounded_send(frame(&bytes));
+    }
+
+    /// Send a message to the host, connecting to it first if necessary.
+    fn send_to_host(&mut self, delivery: Delivery, message: Message) {
+        if let Some(id) = self.host_connection {
+            return self.send(id, delivery, &message);
+        }
+        self.host_queue.push((delivery, message));
+        if self.connecting {
+            return;
+        }
+        let (address, server_name) = match &self.host {
+            Some(host) => host.clone(),
+            None => {
+                log::debug!("[quic] no host address to connect to");
+                self.host_queue.clear();
+                return;
+            }
+        };
+        self.connecting = true;
+        let connecting = self
+            .endpoint
+            .connect(address, &server_name)
+            .map_err(connection_error);
+        let events = self.events.clone();
+        task::spawn(async move {
+            let connection = match connecting {
+                Ok(connecting) => connecting.await.map_err(connection_error),
+                Err(err) => Err(err),
+            };
+            let _ = events.unbounded_send(Event::Connected(connection));
+        });
+    }
+
+    /// Pass a game message on to the peers that should get it. `from` is the connection that it
+    /// came in on, if it came from another peer.
+    fn forward(&mut self, from: Option<usize>, delivery: Delivery, message: Message) {
+        if !self.session.hosting() {
+            return self.send_to_host(delivery, message);
+        }
+        let receiver = match &message {
+            Message::Game { receiver, .. } => *receiver,
+            _ => None,
+        };
+        match receiver {
+            Some(receiver) => match self.session.player_peer(receiver) {
+                Some(id) if Some(id) != from => self.send(id, delivery, &message),
+                Some(_) => (),
+                None => log::debug!("[quic] don't know where player {} is", receiver),
+            },
+            None => {
+                for &id in self.peers.keys() {
+                    if Some(id) != from {
+                        self.send(id, delivery, &message);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match self.session.command(command) {
+            Some(Outgoing::EnumSessions(message)) => {
+                self.send_to_host(Delivery::Guaranteed, Message::EnumRequest(message))
+            }
+            // The host tells nobody: joining peers learn about its players from its messages.
+            Some(Outgoing::NewPlayer(player)) if !self.session.hosting() => {
+                self.send_to_host(Delivery::Guaranteed, Message::Players(vec![player]))
+            }
+            None | Some(Outgoing::NewPlayer(_)) => (),
+            Some(Outgoing::EnumReply(id, message)) => {
+                self.send(id, Delivery::Guaranteed, &Message::EnumReply(message))
+            }
+            // The host knows where every player is, so `forward` finds the way itself.
+            Some(Outgoing::Game {
+                sender,
+                receiver,
+                delivery,
+                data,
+                ..
+            }) => {
+                let message = Message::Game {
+                    sender,
+                    receiver,
+                    data,
+                };
+                self.forward(None, delivery, message);
+            }
+        }
+    }
+
+    async fn handle_event(&mut self, event: Event) {
+        match event {
+            Event::Connected(Ok(connection)) => {
+                self.connecting = false;
+                let id = self.add_peer(connection);
+                self.host_connection = Some(id);
+                for (delivery, message) in std::mem::take(&mut self.host_queue) {
+                    self.send(id, delivery, &message);
+                }
+            }
+            Event::Connected(Err(err)) => {
+                log::warn!("[quic] could not connect to host: {:?}", err);
+                self.connecting = false;
+                // DirectPlay enumerates again, which tries another connection.
+                self.host_queue.clear();
+            }
+            Event::Accepted(connection) => {
+                self.add_peer(connection);
+            }
+            Event::Message(id, delivery, message) => {
+                self.handle_message(id, delivery, message).await
+            }
+            Event::Closed(id) => {
+                self.peers.remove(&id);
+                if self.host_connection == Some(id) {
+                    self.host_connection = None;
+                }
+                self.session.peer_lost(id).await;
+            }
+        }
+    }
+
+    /// Handle a message from a peer, in order if it was guaranteed.
+    async fn handle_message(&mut self, id: usize, delivery: Delivery, message: Message) {
+        match message {
+            Message::EnumRequest(request) => self.session.enum_request(id, request).await,
+            Message::EnumReply(reply) => self.session.enum_reply(id, reply).await,
+            Message::Players(players) => {
+                for player in players {
+                    self.session.add_player(player, id);
+                }
+            }
+            Message::Game {
+                sender,
+                receiver,
+                data,
+            } => {
+                self.session.learn_players(id, sender, &data);
+                let hosting = self.session.hosting();
+                let target = match receiver {
+                    Some(receiver) if hosting => self.session.player_peer(receiver),
+                    _ => None,
+                };
+                match target {
+                    // The host passes on messages between joining players.
+                    Some(target) => {
+                        if target != id {
+                            let message = Message::Game {
+                                sender,
+                                receiver,
+                                data,
+                            };
+                            self.send(target, delivery, &message);
+                        }
+                    }
+                    None => {
+                        if hosting && receiver.is_none() {
+                            let message = Message::Game {
+                                sender,
+                                receiver,
+                                data: data.clone(),
+                            };
+                            self.forward(Some(id), delivery, message);
+                        }
+                        self.session.game_message(id, sender, delivery, data).await;
+                    }
+                }
+            }
+        }
+    }
+}
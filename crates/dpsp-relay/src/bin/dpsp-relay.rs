@@ -0,0 +1,34 @@
+//! Relay server for players that cannot connect to each other directly.
+//!
+//! Usage: dpsp-relay [LISTEN_ADDRESS]
+//!
+//! Players can be kicked and banned by typing `kick ROOM PLAYER` or `ban ROOM PLAYER`; see
+//! `server::moderate`.
+
+use async_std::io::BufReader;
+use async_std::net::TcpListener;
+use async_std::sync::{Arc, Mutex};
+use dpsp_local_only::LocalOnlyServer;
+use dpsp_relay::server;
+
+fn main() -> std::io::Result<()> {
+    femme::with_level(femme::LevelFilter::Info);
+
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "0.0.0.0:22198".into());
+
+    async_std::task::block_on(async {
+        let listener = TcpListener::bind(&address).await?;
+        log::info!("Listening on {}", listener.local_addr()?);
+        let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+        let stdin = BufReader::new(async_std::io::stdin());
+        let moderated = Arc::clone(&server);
+        async_std::task::spawn(async move {
+            if let Err(err) = server::moderate(stdin, moderated).await {
+                log::warn!("Could not read commands: {:?}", err);
+            }
+        });
+        server::serve(listener, server).await
+    })
+}
@@ -0,0 +1,258 @@
+//! A lightweight relay for playing over the internet.
+//!
+//! The relay server is a small program on a public address. Game instances connect to it over
+//! plain TCP with a `RelaySP`, and join a room by its GUID; the lobby that matched the players
+//! hands out the room GUID. The server routes `send`, `reply` and `enum_sessions` traffic between
+//! the game instances in a room by player GUID, so players behind NAT only ever need to make an
+//! outgoing connection.
+//!
+//! Messages on the connection are length-prefixed frames, the same as between
+//! `dpsp_local_only::broker` and its clients. A room ends when its host leaves, unless the
+//! session migrates hosts.
+
+pub mod server;
+
+use async_std::io;
+use async_std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use dprun::GUID;
+use dpsp_local_only::router::{length_prefixed, RouterSP};
+
+/// Service provider that plays through a relay server.
+pub type RelaySP = RouterSP;
+
+/// Connect to the relay server at `address`, for a game instance that hosts or joins the given
+/// room.
+pub async fn connect(address: impl ToSocketAddrs, room_id: GUID) -> io::Result<RelaySP> {
+    let stream = TcpStream::connect(address).await?;
+    // Game messages are small and latency matters more than throughput.
+    stream.set_nodelay(true)?;
+    let (writer, reader) = length_prefixed(stream.clone());
+    RouterSP::connect(reader, writer, room_id, move || {
+        stream.shutdown(Shutdown::Write)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::net::TcpListener;
+    use async_std::sync::{Arc, Mutex};
+    use async_std::task;
+    use dprun::{structs::*, AppController, AppMessage, ServiceProvider};
+    use dpsp_local_only::LocalOnlyServer;
+    use std::time::Duration;
+
+    const DPLAYI_PLAYER_NAMESRVR: i32 = 2;
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> AppMessage {
+        timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("no message from the relay")
+            .unwrap()
+    }
+
+    fn enum_request(requester: GUID) -> EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        EnumSessionsData { message }
+    }
+
+    #[test]
+    fn routes_between_players_in_a_room() {
+        let room_id = GUID::from_u128(1);
+        let other_room_id = GUID::from_u128(2);
+        let host_id = GUID::from_u128(3);
+        let joiner_id = GUID::from_u128(4);
+
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+            task::spawn(server::serve(listener, server));
+
+            let mut host = connect(address, room_id).await.unwrap();
+            let (host_controller, host_receiver) = AppController::create();
+            let create_host = CreatePlayerData {
+                player_guid: host_id,
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            host.create_player(host_controller.clone(), 0, create_host)
+                .await
+                .unwrap();
+
+            let mut joiner = connect(address, room_id).await.unwrap();
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            let request = enum_request(joiner_id);
+            let expected = request.message.clone();
+            joiner
+                .enum_sessions(joiner_controller.clone(), 0, request)
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, expected),
+                message => panic!("unexpected {:?}", message),
+            }
+            let reply = ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session".to_vec(),
+            };
+            host.reply(host_controller.clone(), 0, reply).await.unwrap();
+            match next_message(&joiner_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"session"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            let create_joiner = CreatePlayerData {
+                player_guid: joiner_id,
+                flags: 0,
+            };
+            joiner
+                .create_player(joiner_controller.clone(), 0, create_joiner)
+                .await
+                .unwrap();
+            let send = SendData {
+                flags: DPSEND_GUARANTEED,
+                receiver_id: Some(host_id),
+                sender_id: joiner_id,
+                system_message: false,
+                message: b"hello".to_vec(),
+            };
+            joiner
+                .send(joiner_controller.clone(), 0, send)
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"hello"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            // Broadcasts stay inside the room.
+            let mut stranger = connect(address, other_room_id).await.unwrap();
+            let (stranger_controller, stranger_receiver) = AppController::create();
+            let create_stranger = CreatePlayerData {
+                player_guid: GUID::from_u128(5),
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            stranger
+                .create_player(stranger_controller, 0, create_stranger)
+                .await
+                .unwrap();
+            let broadcast = SendData {
+                flags: DPSEND_GUARANTEED,
+                receiver_id: None,
+                sender_id: host_id,
+                system_message: false,
+                message: b"everyone".to_vec(),
+            };
+            host.send(host_controller.clone(), 0, broadcast)
+                .await
+                .unwrap();
+            match next_message(&joiner_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"everyone"),
+                message => panic!("unexpected {:?}", message),
+            }
+            assert!(stranger_receiver.is_empty());
+
+            host.disconnected(host_controller).await.unwrap();
+            assert!(matches!(
+                next_message(&joiner_receiver).await,
+                AppMessage::Close
+            ));
+            host.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+            stranger.shutdown().await.unwrap();
+        });
+    }
+
+    /// Create a player that joins the room.
+    async fn join(
+        joiner: &mut RelaySP,
+        player_guid: GUID,
+    ) -> (AppController, async_channel::Receiver<AppMessage>) {
+        let (controller, receiver) = AppController::create();
+        let create_joiner = CreatePlayerData {
+            player_guid,
+            flags: 0,
+        };
+        joiner
+            .create_player(controller.clone(), 0, create_joiner)
+            .await
+            .unwrap();
+        (controller, receiver)
+    }
+
+    /// Send a message from a joined player to the host, and wait until it arrives, so the relay
+    /// knows where the player is.
+    async fn say_hello(
+        joiner: &mut RelaySP,
+        controller: &AppController,
+        sender_id: GUID,
+        host_receiver: &async_channel::Receiver<AppMessage>,
+    ) {
+        let send = SendData {
+            flags: DPSEND_GUARANTEED,
+            receiver_id: None,
+            sender_id,
+            system_message: false,
+            message: b"hello".to_vec(),
+        };
+        joiner.send(controller.clone(), 0, send).await.unwrap();
+        assert!(matches!(
+            next_message(host_receiver).await,
+            AppMessage::Send(..)
+        ));
+    }
+
+    #[test]
+    fn kicks_and_bans_players_on_command() {
+        let room_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+            task::spawn(server::serve(listener, Arc::clone(&server)));
+
+            let mut host = connect(address, room_id).await.unwrap();
+            let (host_controller, host_receiver) = AppController::create();
+            let create_host = CreatePlayerData {
+                player_guid: host_id,
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            host.create_player(host_controller.clone(), 0, create_host)
+                .await
+                .unwrap();
+            let mut joiner = connect(address, room_id).await.unwrap();
+
+            // A kicked game instance can join again, a banned one can not.
+            let (controller, receiver) = join(&mut joiner, joiner_id).await;
+            say_hello(&mut joiner, &controller, joiner_id, &host_receiver).await;
+            let commands = format!("hello\nkick {} {}\n", room_id, joiner_id);
+            server::moderate(commands.as_bytes(), Arc::clone(&server))
+                .await
+                .unwrap();
+            assert!(matches!(next_message(&receiver).await, AppMessage::Close));
+            joiner.disconnected(controller).await.unwrap();
+
+            let (controller, receiver) = join(&mut joiner, joiner_id).await;
+            say_hello(&mut joiner, &controller, joiner_id, &host_receiver).await;
+            let commands = format!("ban {} {}\n", room_id, joiner_id);
+            server::moderate(commands.as_bytes(), Arc::clone(&server))
+                .await
+                .unwrap();
+            assert!(matches!(next_message(&receiver).await, AppMessage::Close));
+            joiner.disconnected(controller).await.unwrap();
+
+            let (_controller, receiver) = join(&mut joiner, joiner_id).await;
+            assert!(matches!(next_message(&receiver).await, AppMessage::Close));
+
+            host.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+        });
+    }
+}
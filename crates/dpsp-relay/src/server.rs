@@ -0,0 +1,97 @@
+//! The relay server.
+//!
+//! Every room is a session on a `LocalOnlyServer`. The relay runs a `LocalOnlySP` on behalf of
+//! every connected game instance, so messages are routed exactly as if all game instances shared
+//! a process.
+//!
+//! Rooms are moderated through the same `LocalOnlyServer`: `LocalOnlyServer::kick` closes the
+//! kicked game instance's connection to the relay, and a ban keeps it out of the room. `moderate`
+//! does the same for commands typed by whoever runs the relay.
+
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use dprun::{PlayerIdentity, GUID};
+use dpsp_local_only::router::{self, length_prefixed};
+use dpsp_local_only::LocalOnlyServer;
+use futures::io::{AsyncBufRead, AsyncBufReadExt};
+use futures::stream::StreamExt;
+use std::net::SocketAddr;
+
+/// Accept game instances on `listener`, and route their messages through `server`.
+///
+/// Returns when accepting a connection fails.
+pub async fn serve(listener: TcpListener, server: Arc<Mutex<LocalOnlyServer>>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        task::spawn(async move {
+            if let Err(err) = handle_client(stream, peer, server).await {
+                log::warn!("[relay] {}: {:?}", peer, err);
+            }
+        });
+    }
+}
+
+async fn handle_client(
+    stream: TcpStream,
+    peer: SocketAddr,
+    server: Arc<Mutex<LocalOnlyServer>>,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    log::info!("[relay] {} connected", peer);
+    let (writer, reader) = length_prefixed(stream);
+    futures::pin_mut!(writer);
+    let result = router::route(reader, writer, server, peer).await;
+    log::info!("[relay] {} disconnected", peer);
+    result
+}
+
+/// Kick and ban players with commands from `input`, one per line:
+///
+///  - `kick ROOM PLAYER` disconnects the game instance that a player is on;
+///  - `ban ROOM PLAYER` also keeps it, and players with the same name, out of the room.
+///
+/// `ROOM` is the room GUID, and `PLAYER` the GUID of a player or of its game instance, or the
+/// name of a player as shown in the game. Returns when `input` ends.
+pub async fn moderate(
+    input: impl AsyncBufRead + Unpin,
+    server: Arc<Mutex<LocalOnlyServer>>,
+) -> io::Result<()> {
+    let mut lines = input.lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        let (room_id, identity, ban) = match parse_command(&line) {
+            Some(command) => command,
+            None => {
+                log::warn!("[relay] expected `kick ROOM PLAYER` or `ban ROOM PLAYER`");
+                continue;
+            }
+        };
+        let kicked = server.lock().await.kick(room_id, &identity, ban).await;
+        match (kicked, ban) {
+            (true, _) => log::info!("[relay] kicked {:?} from {}", identity, room_id),
+            (false, true) => log::info!("[relay] banned {:?} from {}", identity, room_id),
+            (false, false) => log::warn!("[relay] {:?} is not in {}", identity, room_id),
+        }
+    }
+    Ok(())
+}
+
+fn parse_command(line: &str) -> Option<(GUID, PlayerIdentity, bool)> {
+    let mut words = line.trim().splitn(3, ' ');
+    let ban = match words.next()? {
+        "kick" => false,
+        "ban" => true,
+        _ => return None,
+    };
+    let room_id = GUID::parse_str(words.next()?).ok()?;
+    let player = words.next()?.trim();
+    let identity = match GUID::parse_str(player) {
+        Ok(id) => PlayerIdentity::Guid(id),
+        Err(_) if !player.is_empty() => PlayerIdentity::Name(player.to_string()),
+        Err(_) => return None,
+    };
+    Some((room_id, identity, ban))
+}
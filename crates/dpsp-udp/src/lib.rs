@@ -0,0 +1,538 @@
+//! Service provider that sends DirectPlay messages directly between peers over UDP.
+//!
+//! TCP holds back every message behind a lost packet, which stalls lockstep games like AoC when
+//! the connection drops packets. Here, unreliable messages go out as plain datagrams, and only
+//! guaranteed messages go through a small reliability layer with acknowledgements, retransmits
+//! and ordering. Peers send keep-alives while they are quiet, so a peer that goes away is
+//! noticed even when the game has nothing to say.
+//!
+//! Joining players need the address of the host to enumerate its sessions. After that, peers
+//! learn each other's addresses from the messages they exchange.
+
+mod link;
+mod network;
+mod packet;
+
+use async_std::io;
+use async_std::net::UdpSocket;
+use async_std::task::{self, JoinHandle};
+use async_trait::async_trait;
+use dprun::session::Command;
+use dprun::{structs, AppController, ServiceProvider};
+use futures::channel::mpsc::{self, UnboundedSender};
+use network::{Config, Network};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Player flag: the player was created by the local game.
+const DPLAYI_PLAYER_LOCAL: i32 = 8;
+
+/// The socket task and the channel to send it commands.
+struct RunningNetwork {
+    local_addr: SocketAddr,
+    commands: UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+pub struct UdpSP {
+    bind_address: SocketAddr,
+    config: Config,
+    network: Option<RunningNetwork>,
+}
+
+impl Default for UdpSP {
+    fn default() -> Self {
+        Self {
+            bind_address: ([0, 0, 0, 0], 0).into(),
+            config: Config {
+                host: None,
+                packet_loss: 0.0,
+                peer_timeout: Duration::from_secs(10),
+            },
+            network: None,
+        }
+    }
+}
+
+impl UdpSP {
+    /// Set the address to receive packets on. Defaults to a random port on all interfaces.
+    pub fn with_bind_address(self, bind_address: SocketAddr) -> Self {
+        Self {
+            bind_address,
+            ..self
+        }
+    }
+
+    /// Set the address of the hosting peer, to enumerate sessions at.
+    pub fn with_host(self, host: SocketAddr) -> Self {
+        Self {
+            config: Config {
+                host: Some(host),
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Drop a fraction of outgoing packets, between 0 and 1, to test games on a bad connection.
+    pub fn with_packet_loss(self, packet_loss: f64) -> Self {
+        Self {
+            config: Config {
+                packet_loss,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// Set how long a peer may stay silent before it counts as disconnected. If the host goes
+    /// silent, the game's connection is closed.
+    pub fn with_peer_timeout(self, peer_timeout: Duration) -> Self {
+        Self {
+            config: Config {
+                peer_timeout,
+                ..self.config
+            },
+            ..self
+        }
+    }
+
+    /// The address that packets are received on, for other peers to connect to. Binds the
+    /// socket if that did not happen yet.
+    pub async fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.network().await?.local_addr)
+    }
+
+    async fn network(&mut self) -> io::Result<&RunningNetwork> {
+        if self.network.is_none() {
+            let socket = UdpSocket::bind(self.bind_address).await?;
+            let local_addr = socket.local_addr()?;
+            log::debug!("[udp] listening on {}", local_addr);
+            let (commands, receiver) = mpsc::unbounded();
+            let task = task::spawn(Network::new(socket, self.config.clone()).run(receiver));
+            self.network = Some(RunningNetwork {
+                local_addr,
+                commands,
+                task,
+            });
+        }
+        Ok(self.network.as_ref().unwrap())
+    }
+
+    /// Send a command to the socket task, starting it if necessary.
+    async fn command(&mut self, command: Command) -> io::Result<()> {
+        self.network()
+            .await?
+            .commands
+            .unbounded_send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "UDP socket stopped"))
+    }
+}
+
+#[async_trait]
+impl ServiceProvider for UdpSP {
+    async fn enum_sessions(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::EnumSessionsData,
+    ) -> io::Result<()> {
+        self.command(Command::EnumSessions {
+            controller,
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn open(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::OpenData,
+    ) -> io::Result<()> {
+        self.command(Command::Open {
+            controller,
+            create: data.create,
+            migrate_host: data.migrate_host(),
+        })
+        .await
+    }
+
+    async fn create_player(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::CreatePlayerData,
+    ) -> io::Result<()> {
+        // DirectPlay also tells us about players on other peers; those are announced by the
+        // peer that created them.
+        if data.flags & DPLAYI_PLAYER_LOCAL == 0 {
+            return Ok(());
+        }
+        self.command(Command::CreatePlayer {
+            controller,
+            player: data.player_guid,
+        })
+        .await
+    }
+
+    async fn reply(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::ReplyData,
+    ) -> io::Result<()> {
+        self.command(Command::Reply {
+            controller,
+            reply_to: data.reply_to,
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn send(
+        &mut self,
+        controller: AppController,
+        _id: u32,
+        data: structs::SendData,
+    ) -> io::Result<()> {
+        self.command(Command::Send {
+            controller,
+            sender: data.sender_id,
+            receiver: data.receiver_id,
+            delivery: data.delivery(),
+            message: data.message,
+        })
+        .await
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(RunningNetwork { commands, task, .. }) = self.network.take() {
+            drop(commands);
+            task.await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use dprun::structs::{DPSEND_GUARANTEED, DPSESSION_MIGRATEHOST};
+    use dprun::AppMessage;
+    use dprun::GUID;
+    use std::time::Instant;
+
+    fn loopback() -> SocketAddr {
+        ([127, 0, 0, 1], 0).into()
+    }
+
+    fn open(create: bool) -> structs::OpenData {
+        structs::OpenData {
+            create,
+            return_status: false,
+            open_flags: 0,
+            session_flags: 0,
+        }
+    }
+
+    fn enum_request(requester: GUID) -> structs::EnumSessionsData {
+        let mut message = requester.as_bytes().to_vec();
+        message.extend_from_slice(b"play");
+        structs::EnumSessionsData { message }
+    }
+
+    fn local_player(player: GUID) -> structs::CreatePlayerData {
+        structs::CreatePlayerData {
+            player_guid: player,
+            flags: DPLAYI_PLAYER_LOCAL,
+        }
+    }
+
+    fn message(sender: GUID, receiver: Option<GUID>, data: &[u8]) -> structs::SendData {
+        let mut message = sender.as_bytes().to_vec();
+        message.extend_from_slice(data);
+        structs::SendData {
+            flags: DPSEND_GUARANTEED,
+            receiver_id: receiver,
+            sender_id: sender,
+            system_message: false,
+            message,
+        }
+    }
+
+    async fn next_message(
+        receiver: &async_channel::Receiver<AppMessage>,
+        wait: Duration,
+    ) -> Option<Vec<u8>> {
+        match timeout(wait, receiver.recv()).await {
+            Ok(Ok(AppMessage::Send(_, _, data))) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Enumerate the host's sessions until it answers, and join its session.
+    async fn join(
+        host: &mut UdpSP,
+        host_controller: &AppController,
+        host_receiver: &async_channel::Receiver<AppMessage>,
+        joiner: &mut UdpSP,
+        joiner_controller: &AppController,
+        joiner_receiver: &async_channel::Receiver<AppMessage>,
+        joiner_id: GUID,
+    ) {
+        // Enumeration requests are unreliable; the game asks again if nobody answers.
+        let request = enum_request(joiner_id);
+        loop {
+            joiner
+                .enum_sessions(joiner_controller.clone(), 0, enum_request(joiner_id))
+                .await
+                .unwrap();
+            if let Some(received) = next_message(host_receiver, Duration::from_millis(300)).await {
+                assert_eq!(received, request.message);
+                break;
+            }
+        }
+
+        let reply = structs::ReplyData {
+            reply_to: joiner_id,
+            name_server_id: 0,
+            message: b"session description".to_vec(),
+        };
+        host.reply(host_controller.clone(), 0, reply).await.unwrap();
+        assert_eq!(
+            next_message(joiner_receiver, Duration::from_secs(5)).await,
+            Some(b"session description".to_vec())
+        );
+    }
+
+    #[test]
+    fn delivers_guaranteed_messages_in_order_despite_loss() {
+        let host_id = GUID::from_u128(1);
+        let joiner_id = GUID::from_u128(2);
+
+        task::block_on(async {
+            let mut host = UdpSP::default()
+                .with_bind_address(loopback())
+                .with_packet_loss(0.3);
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+            host.create_player(host_controller.clone(), 0, local_player(host_id))
+                .await
+                .unwrap();
+
+            let mut joiner = UdpSP::default()
+                .with_bind_address(loopback())
+                .with_host(host.local_addr().await.unwrap())
+                .with_packet_loss(0.3);
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            join(
+                &mut host,
+                &host_controller,
+                &host_receiver,
+                &mut joiner,
+                &joiner_controller,
+                &joiner_receiver,
+                joiner_id,
+            )
+            .await;
+
+            joiner
+                .create_player(joiner_controller.clone(), 0, local_player(joiner_id))
+                .await
+                .unwrap();
+            // Messages without a receiver go to everyone in the session.
+            let hello = message(joiner_id, None, b"hello");
+            let expected = hello.message.clone();
+            joiner
+                .send(joiner_controller.clone(), 0, hello)
+                .await
+                .unwrap();
+            assert_eq!(
+                next_message(&host_receiver, Duration::from_secs(10)).await,
+                Some(expected)
+            );
+            // The host announced its players before this, on the same reliable link.
+            let hello = message(host_id, Some(joiner_id), b"hello");
+            let expected = hello.message.clone();
+            host.send(host_controller.clone(), 0, hello).await.unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver, Duration::from_secs(10)).await,
+                Some(expected)
+            );
+
+            for i in 0..30u8 {
+                let turn = message(host_id, Some(joiner_id), &[i]);
+                host.send(host_controller.clone(), 0, turn).await.unwrap();
+            }
+            for i in 0..30u8 {
+                let turn = next_message(&joiner_receiver, Duration::from_secs(10)).await;
+                assert_eq!(turn.as_deref().and_then(|m| m.get(16)), Some(&i));
+            }
+            for i in 0..30u8 {
+                let turn = message(joiner_id, Some(host_id), &[i]);
+                joiner
+                    .send(joiner_controller.clone(), 0, turn)
+                    .await
+                    .unwrap();
+            }
+            for i in 0..30u8 {
+                let turn = next_message(&host_receiver, Duration::from_secs(10)).await;
+                assert_eq!(turn.as_deref().and_then(|m| m.get(16)), Some(&i));
+            }
+
+            joiner.shutdown().await.unwrap();
+            host.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn closes_the_game_when_the_host_goes_silent() {
+        let joiner_id = GUID::from_u128(3);
+
+        task::block_on(async {
+            let mut host = UdpSP::default().with_bind_address(loopback());
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+
+            let mut joiner = UdpSP::default()
+                .with_bind_address(loopback())
+                .with_host(host.local_addr().await.unwrap())
+                .with_peer_timeout(Duration::from_millis(500));
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            join(
+                &mut host,
+                &host_controller,
+                &host_receiver,
+                &mut joiner,
+                &joiner_controller,
+                &joiner_receiver,
+                joiner_id,
+            )
+            .await;
+
+            host.shutdown().await.unwrap();
+            let closed = timeout(Duration::from_secs(2), joiner_receiver.recv()).await;
+            assert!(matches!(closed, Ok(Ok(AppMessage::Close))));
+
+            joiner.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn another_peer_takes_over_when_the_host_goes_silent() {
+        let joiner_id = GUID::from_u128(4);
+        let newcomer_id = GUID::from_u128(5);
+        let mut announcement = b"play\x35\x00\x0e\x00".to_vec();
+        announcement.extend_from_slice(&[0; 16]);
+
+        task::block_on(async {
+            let mut host = UdpSP::default().with_bind_address(loopback());
+            let (host_controller, host_receiver) = AppController::create();
+            host.open(host_controller.clone(), 0, open(true))
+                .await
+                .unwrap();
+
+            let mut joiner = UdpSP::default()
+                .with_bind_address(loopback())
+                .with_host(host.local_addr().await.unwrap())
+                .with_peer_timeout(Duration::from_secs(3));
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            join(
+                &mut host,
+                &host_controller,
+                &host_receiver,
+                &mut joiner,
+                &joiner_controller,
+                &joiner_receiver,
+                joiner_id,
+            )
+            .await;
+            let mut migrate = open(false);
+            migrate.session_flags = DPSESSION_MIGRATEHOST;
+            joiner
+                .open(joiner_controller.clone(), 0, migrate)
+                .await
+                .unwrap();
+            joiner
+                .create_player(joiner_controller.clone(), 0, local_player(joiner_id))
+                .await
+                .unwrap();
+
+            // Nobody takes over while the host is still there.
+            let mut newcomer = UdpSP::default()
+                .with_bind_address(loopback())
+                .with_host(joiner.local_addr().await.unwrap());
+            let (newcomer_controller, newcomer_receiver) = AppController::create();
+            let early = message(joiner_id, None, &announcement);
+            joiner
+                .send(joiner_controller.clone(), 0, early)
+                .await
+                .unwrap();
+            assert!(next_message(&host_receiver, Duration::from_secs(2))
+                .await
+                .is_some());
+            newcomer
+                .enum_sessions(newcomer_controller.clone(), 0, enum_request(newcomer_id))
+                .await
+                .unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver, Duration::from_millis(500)).await,
+                None
+            );
+
+            // The joiner takes over once it notices that the host is gone, and answers the
+            // newcomer from then on.
+            host.shutdown().await.unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                assert!(Instant::now() < deadline, "the joiner did not take over");
+                let takeover = message(joiner_id, None, &announcement);
+                joiner
+                    .send(joiner_controller.clone(), 0, takeover)
+                    .await
+                    .unwrap();
+                newcomer
+                    .enum_sessions(newcomer_controller.clone(), 0, enum_request(newcomer_id))
+                    .await
+                    .unwrap();
+                if next_message(&joiner_receiver, Duration::from_millis(300))
+                    .await
+                    .is_some()
+                {
+                    break;
+                }
+            }
+
+            // Newcomers can join the joiner's session.
+            join(
+                &mut joiner,
+                &joiner_controller,
+                &joiner_receiver,
+                &mut newcomer,
+                &newcomer_controller,
+                &newcomer_receiver,
+                newcomer_id,
+            )
+            .await;
+            let hello = message(newcomer_id, Some(joiner_id), b"hello");
+            let expected = hello.message.clone();
+            newcomer
+                .send(newcomer_controller.clone(), 0, hello)
+                .await
+                .unwrap();
+            assert_eq!(
+                next_message(&joiner_receiver, Duration::from_secs(5)).await,
+                Some(expected)
+            );
+
+            newcomer.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+        });
+    }
+}
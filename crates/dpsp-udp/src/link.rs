@@ -0,0 +1,208 @@
+//! The reliability layer for guaranteed messages to one peer.
+//!
+//! Every reliable packet gets the next sequence number for its peer, and is sent again until the
+//! peer acknowledges it. The receiving side acknowledges every reliable packet, including copies
+//! it has already seen, and hands messages on in sequence order. Unreliable messages bypass all
+//! of this.
+//!
+//! A link also keeps track of when we last heard from the peer, so silent peers can be dropped.
+
+use crate::packet::{Message, Packet};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// How long to wait for an acknowledgement before sending a packet again. Doubles with every
+/// attempt, up to `MAX_RETRANSMIT_TIMEOUT`.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to stay quiet before telling the peer that we are still there.
+pub(crate) const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A reliable packet that the peer has not acknowledged yet.
+#[derive(Debug)]
+struct Unacked {
+    bytes: Vec<u8>,
+    sent_at: Instant,
+    attempts: u32,
+}
+
+impl Unacked {
+    fn retransmit_at(&self) -> Instant {
+        let timeout = RETRANSMIT_TIMEOUT * 2u32.saturating_pow(self.attempts - 1);
+        self.sent_at + timeout.min(MAX_RETRANSMIT_TIMEOUT)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Link {
+    next_seq: u32,
+    unacked: BTreeMap<u32, Unacked>,
+    next_expected: u32,
+    early: BTreeMap<u32, Message>,
+    last_heard: Instant,
+    last_sent: Instant,
+}
+
+impl Link {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            next_seq: 0,
+            unacked: BTreeMap::new(),
+            next_expected: 0,
+            early: BTreeMap::new(),
+            last_heard: now,
+            last_sent: now,
+        }
+    }
+
+    /// Number a message for reliable delivery, and return the packet to send.
+    pub fn send_reliable(&mut self, message: Message, now: Instant) -> Vec<u8> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let bytes = Packet::Reliable { seq, message }.encode();
+        self.unacked.insert(
+            seq,
+            Unacked {
+                bytes: bytes.clone(),
+                sent_at: now,
+                attempts: 1,
+            },
+        );
+        self.last_sent = now;
+        bytes
+    }
+
+    /// Note that something was sent to the peer outside of the reliability layer.
+    pub fn sent(&mut self, now: Instant) {
+        self.last_sent = now;
+    }
+
+    /// Note that the peer sent something.
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = now;
+    }
+
+    pub fn acked(&mut self, seq: u32) {
+        self.unacked.remove(&seq);
+    }
+
+    /// Accept a reliable message, and return the messages that can be handed on now, in order.
+    pub fn receive(&mut self, seq: u32, message: Message) -> Vec<Message> {
+        // Sequence numbers before the expected one were delivered already; their
+        // acknowledgement must have been lost.
+        if seq.wrapping_sub(self.next_expected) > u32::MAX / 2 {
+            return vec![];
+        }
+        self.early.insert(seq, message);
+        let mut ready = vec![];
+        while let Some(message) = self.early.remove(&self.next_expected) {
+            ready.push(message);
+            self.next_expected = self.next_expected.wrapping_add(1);
+        }
+        ready
+    }
+
+    /// Packets that were not acknowledged in time, to send again.
+    pub fn retransmits(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut packets = vec![];
+        for unacked in self.unacked.values_mut() {
+            if unacked.retransmit_at() <= now {
+                unacked.sent_at = now;
+                unacked.attempts += 1;
+                packets.push(unacked.bytes.clone());
+            }
+        }
+        if !packets.is_empty() {
+            self.last_sent = now;
+        }
+        packets
+    }
+
+    pub fn needs_keepalive(&self, now: Instant) -> bool {
+        now.duration_since(self.last_sent) >= KEEPALIVE_INTERVAL
+    }
+
+    /// Whether the peer has been silent for longer than `timeout`.
+    pub fn timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now.duration_since(self.last_heard) >= timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dprun::GUID;
+
+    fn message(i: u8) -> Message {
+        Message::Game {
+            sender: GUID::nil(),
+            data: vec![i],
+        }
+    }
+
+    #[test]
+    fn delivers_in_order_despite_loss() {
+        let start = Instant::now();
+        let mut sender = Link::new(start);
+        let mut receiver = Link::new(start);
+        // Drop every third packet in either direction.
+        let mut sent = 0;
+        let mut lose = move || {
+            sent += 1;
+            sent % 3 == 0
+        };
+
+        let mut in_flight = vec![];
+        for i in 0..20 {
+            in_flight.push(sender.send_reliable(message(i), start));
+        }
+        let mut delivered = vec![];
+        let mut now = start;
+        for _ in 0..100 {
+            for bytes in in_flight.drain(..) {
+                if lose() {
+                    continue;
+                }
+                if let Packet::Reliable { seq, message } = Packet::decode(&bytes).unwrap() {
+                    delivered.extend(receiver.receive(seq, message));
+                    if !lose() {
+                        sender.acked(seq);
+                    }
+                }
+            }
+            now += Duration::from_millis(100);
+            in_flight = sender.retransmits(now);
+        }
+
+        assert_eq!(delivered, (0..20).map(message).collect::<Vec<_>>());
+        assert!(sender.unacked.is_empty());
+    }
+
+    #[test]
+    fn backs_off_and_times_out() {
+        let start = Instant::now();
+        let mut link = Link::new(start);
+        link.send_reliable(message(0), start);
+        assert!(link
+            .retransmits(start + Duration::from_millis(100))
+            .is_empty());
+        assert_eq!(
+            link.retransmits(start + Duration::from_millis(200)).len(),
+            1
+        );
+        // The second retransmit waits twice as long.
+        assert!(link
+            .retransmits(start + Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(
+            link.retransmits(start + Duration::from_millis(600)).len(),
+            1
+        );
+
+        assert!(!link.timed_out(start + Duration::from_secs(1), Duration::from_secs(5)));
+        assert!(link.timed_out(start + Duration::from_secs(5), Duration::from_secs(5)));
+        link.heard(start + Duration::from_secs(5));
+        assert!(!link.timed_out(start + Duration::from_secs(5), Duration::from_secs(5)));
+    }
+}
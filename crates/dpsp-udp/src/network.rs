@@ -0,0 +1,271 @@
+//! The UDP socket behind a `UdpSP`, running in its own task.
+//!
+//! The service provider talks to the socket task through `Command`s. The task owns everything
+//! that needs the socket: a link with reliability state for every peer we exchange packets with,
+//! and the session, which keeps track of the peers that players live on.
+
+use crate::link::Link;
+use crate::packet::{Message, Packet};
+use async_std::io;
+use async_std::net::UdpSocket;
+use async_std::task;
+use dprun::session::{Command, Outgoing, Route, Session};
+use dprun::Delivery;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::stream::StreamExt;
+use futures::FutureExt;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often to check for packets to send again, keep-alives to send and peers that went silent.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Largest datagram we can receive.
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+
+/// Settings for the socket task.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// The hosting peer to send enumeration requests to.
+    pub host: Option<SocketAddr>,
+    /// Drop this fraction of outgoing packets, to emulate a bad connection.
+    pub packet_loss: f64,
+    /// Drop peers that have been silent for this long.
+    pub peer_timeout: Duration,
+}
+
+enum Event {
+    Packet(io::Result<(usize, SocketAddr)>),
+    Command(Option<Command>),
+    Tick,
+}
+
+pub(crate) struct Network {
+    socket: UdpSocket,
+    config: Config,
+    /// Every peer we exchange packets with.
+    links: HashMap<SocketAddr, Link>,
+    /// Peers that take part in the session.
+    session_peers: HashSet<SocketAddr>,
+    session: Session<SocketAddr>,
+}
+
+impl Network {
+    pub fn new(socket: UdpSocket, config: Config) -> Self {
+        Self {
+            socket,
+            config,
+            links: HashMap::new(),
+            session_peers: HashSet::new(),
+            session: Session::default(),
+        }
+    }
+
+    /// Drive the socket until the service provider goes away.
+    pub async fn run(mut self, commands: UnboundedReceiver<Command>) {
+        let mut commands = commands.fuse();
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        let mut last_tick = Instant::now();
+        loop {
+            let event = {
+                let receive = self.socket.recv_from(&mut buffer).fuse();
+                let tick = task::sleep(TICK).fuse();
+                futures::pin_mut!(receive, tick);
+                futures::select! {
+                    received = receive => Event::Packet(received),
+                    command = commands.next() => Event::Command(command),
+                    _ = tick => Event::Tick,
+                }
+            };
+            match event {
+                Event::Packet(Ok((size, from))) => {
+                    let packet = buffer[..size].to_vec();
+                    self.handle_packet(from, &packet).await;
+                }
+                // Some platforms report ICMP errors for earlier packets here.
+                Event::Packet(Err(err)) => log::debug!("[udp] receive error: {:?}", err),
+                Event::Command(Some(command)) => self.handle_command(command).await,
+                Event::Command(None) => break,
+                Event::Tick => (),
+            }
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.tick(last_tick).await;
+            }
+        }
+        log::debug!("[udp] service provider is gone, closing socket");
+    }
+
+    fn link(&mut self, peer: SocketAddr) -> &mut Link {
+        self.links
+            .entry(peer)
+            .or_insert_with(|| Link::new(Instant::now()))
+    }
+
+    async fn send_packet(&self, peer: SocketAddr, bytes: &[u8]) {
+        if self.config.packet_loss > 0.0 && rand::random::<f64>() < self.config.packet_loss {
+            return;
+        }
+        if let Err(err) = self.socket.send_to(bytes, peer).await {
+            log::debug!("[udp] sending to {} failed: {:?}", peer, err);
+        }
+    }
+
+    /// Send a message to a peer, through the reliability layer if it is guaranteed.
+    async fn send_message(&mut self, peer: SocketAddr, delivery: Delivery, message: Message) {
+        let now = Instant::now();
+        let bytes = match delivery {
+            Delivery::Guaranteed => self.link(peer).send_reliable(message, now),
+            Delivery::Unreliable => {
+                self.link(peer).sent(now);
+                Packet::Unreliable(message).encode()
+            }
+        };
+        self.send_packet(peer, &bytes).await;
+    }
+
+    /// Start exchanging game messages with a peer.
+    async fn join_session(&mut self, peer: SocketAddr) {
+        let local_players = self.session.local_players();
+        if self.session_peers.insert(peer) && !local_players.is_empty() {
+            let players = Message::Players(local_players.to_vec());
+            self.send_message(peer, Delivery::Guaranteed, players).await;
+        }
+    }
+
+    /// Forget a peer that went silent.
+    async fn remove_peer(&mut self, peer: SocketAddr) {
+        log::debug!("[udp] lost {}", peer);
+        self.links.remove(&peer);
+        self.session_peers.remove(&peer);
+        self.session.peer_lost(peer).await;
+    }
+
+    /// Send packets again, keep links alive, and drop silent peers.
+    async fn tick(&mut self, now: Instant) {
+        let mut packets = vec![];
+        let mut silent = vec![];
+        for (&peer, link) in &mut self.links {
+            if link.timed_out(now, self.config.peer_timeout) {
+                silent.push(peer);
+                continue;
+            }
+            for bytes in link.retransmits(now) {
+                packets.push((peer, bytes));
+            }
+            if self.session_peers.contains(&peer) && link.needs_keepalive(now) {
+                link.sent(now);
+                packets.push((peer, Packet::KeepAlive.encode()));
+            }
+        }
+        for (peer, bytes) in packets {
+            self.send_packet(peer, &bytes).await;
+        }
+        for peer in silent {
+            self.remove_peer(peer).await;
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match self.session.command(command) {
+            None => (),
+            Some(Outgoing::EnumSessions(message)) => match self.config.host {
+                // DirectPlay asks again if nobody answers, so this need not be reliable.
+                Some(host) => {
+                    let request = Message::EnumRequest(message);
+                    self.send_message(host, Delivery::Unreliable, request).await;
+                }
+                None => log::debug!("[udp] no host address to enumerate sessions at"),
+            },
+            Some(Outgoing::NewPlayer(player)) => {
+                for peer in self.session_peers.clone() {
+                    let players = Message::Players(vec![player]);
+                    self.send_message(peer, Delivery::Guaranteed, players).await;
+                }
+            }
+            Some(Outgoing::EnumReply(peer, message)) => {
+                // The peer will likely join, and needs keep-alives from us until it does. Our
+                // players go out first, so the peer knows where they are by the time its game
+                // sees the reply and starts talking to them.
+                self.join_session(peer).await;
+                let reply = Message::EnumReply(message);
+                self.send_message(peer, Delivery::Guaranteed, reply).await;
+            }
+            Some(Outgoing::Game {
+                route,
+                sender,
+                delivery,
+                data,
+                ..
+            }) => {
+                let peers = match route {
+                    Route::Peer(peer) => vec![peer],
+                    Route::Everyone => self.session_peers.iter().copied().collect(),
+                    Route::Nowhere => vec![],
+                };
+                for peer in peers {
+                    let message = Message::Game {
+                        sender,
+                        data: data.clone(),
+                    };
+                    self.send_message(peer, delivery, message).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, peer: SocketAddr, bytes: &[u8]) {
+        let packet = match Packet::decode(bytes) {
+            Ok(packet) => packet,
+            Err(err) => {
+                log::warn!("[udp] bad packet from {}: {:?}", peer, err);
+                return;
+            }
+        };
+        let now = Instant::now();
+        self.link(peer).heard(now);
+        match packet {
+            Packet::Reliable { seq, message } => {
+                // Acknowledge copies too: the acknowledgement for the first one may have been
+                // lost.
+                self.link(peer).sent(now);
+                self.send_packet(peer, &Packet::Ack(seq).encode()).await;
+                let messages = self.link(peer).receive(seq, message);
+                for message in messages {
+                    self.handle_message(peer, Delivery::Guaranteed, message)
+                        .await;
+                }
+            }
+            Packet::Unreliable(message) => {
+                self.handle_message(peer, Delivery::Unreliable, message)
+                    .await
+            }
+            Packet::Ack(seq) => self.link(peer).acked(seq),
+            Packet::KeepAlive => (),
+        }
+    }
+
+    /// Handle a message from a peer, in order if it was reliable.
+    async fn handle_message(&mut self, peer: SocketAddr, delivery: Delivery, message: Message) {
+        match message {
+            Message::EnumRequest(request) => self.session.enum_request(peer, request).await,
+            Message::EnumReply(reply) => {
+                self.session.enum_reply(peer, reply).await;
+                self.join_session(peer).await;
+            }
+            Message::Players(players) => {
+                self.join_session(peer).await;
+                for player in players {
+                    self.session.add_player(player, peer);
+                }
+            }
+            Message::Game { sender, data } => {
+                self.join_session(peer).await;
+                self.session
+                    .game_message(peer, sender, delivery, data)
+                    .await;
+            }
+        }
+    }
+}
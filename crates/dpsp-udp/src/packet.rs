@@ -0,0 +1,169 @@
+//! The wire format of the UDP service provider.
+//!
+//! Every datagram is one packet, starting with a kind byte. Reliable packets carry a sequence
+//! number that the other side acknowledges; unreliable packets go out as they are. Both carry one
+//! message for the service provider on the other side.
+
+use async_std::io;
+use dprun::GUID;
+use std::convert::TryInto;
+
+const PACKET_RELIABLE: u8 = 0;
+const PACKET_UNRELIABLE: u8 = 1;
+const PACKET_ACK: u8 = 2;
+const PACKET_KEEPALIVE: u8 = 3;
+
+const MESSAGE_ENUM_REQUEST: u8 = 0;
+const MESSAGE_ENUM_REPLY: u8 = 1;
+const MESSAGE_PLAYERS: u8 = 2;
+const MESSAGE_GAME: u8 = 3;
+
+/// A message between service providers.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Message {
+    /// A game looking for sessions.
+    EnumRequest(Vec<u8>),
+    /// A hosting game describing its session.
+    EnumReply(Vec<u8>),
+    /// The players that live on the sending peer.
+    Players(Vec<GUID>),
+    /// A message for the game on the other side.
+    Game {
+        /// The player that sent the message.
+        sender: GUID,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Packet {
+    Reliable {
+        seq: u32,
+        message: Message,
+    },
+    Unreliable(Message),
+    Ack(u32),
+    /// Sent when nothing else was, so the other side knows we are still there.
+    KeepAlive,
+}
+
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid packet")
+}
+
+impl Message {
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Message::EnumRequest(data) => {
+                bytes.push(MESSAGE_ENUM_REQUEST);
+                bytes.extend_from_slice(data);
+            }
+            Message::EnumReply(data) => {
+                bytes.push(MESSAGE_ENUM_REPLY);
+                bytes.extend_from_slice(data);
+            }
+            Message::Players(players) => {
+                bytes.push(MESSAGE_PLAYERS);
+                for player in players {
+                    bytes.extend_from_slice(player.as_bytes());
+                }
+            }
+            Message::Game { sender, data } => {
+                bytes.push(MESSAGE_GAME);
+                bytes.extend_from_slice(sender.as_bytes());
+                bytes.extend_from_slice(data);
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let (&kind, body) = bytes.split_first().ok_or_else(invalid)?;
+        Ok(match kind {
+            MESSAGE_ENUM_REQUEST => Message::EnumRequest(body.to_vec()),
+            MESSAGE_ENUM_REPLY => Message::EnumReply(body.to_vec()),
+            MESSAGE_PLAYERS if body.len().is_multiple_of(16) => Message::Players(
+                body.chunks(16)
+                    .map(|guid| GUID::from_slice(guid).unwrap())
+                    .collect(),
+            ),
+            MESSAGE_GAME if body.len() >= 16 => Message::Game {
+                sender: GUID::from_slice(&body[..16]).unwrap(),
+                data: body[16..].to_vec(),
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Packet::Reliable { seq, message } => {
+                bytes.push(PACKET_RELIABLE);
+                bytes.extend_from_slice(&seq.to_be_bytes());
+                message.encode_into(&mut bytes);
+            }
+            Packet::Unreliable(message) => {
+                bytes.push(PACKET_UNRELIABLE);
+                message.encode_into(&mut bytes);
+            }
+            Packet::Ack(seq) => {
+                bytes.push(PACKET_ACK);
+                bytes.extend_from_slice(&seq.to_be_bytes());
+            }
+            Packet::KeepAlive => bytes.push(PACKET_KEEPALIVE),
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let (&kind, body) = bytes.split_first().ok_or_else(invalid)?;
+        let seq = || -> io::Result<u32> {
+            let seq = body.get(0..4).ok_or_else(invalid)?;
+            Ok(u32::from_be_bytes(seq.try_into().unwrap()))
+        };
+        Ok(match kind {
+            PACKET_RELIABLE => Packet::Reliable {
+                seq: seq()?,
+                message: Message::decode(&body[4..])?,
+            },
+            PACKET_UNRELIABLE => Packet::Unreliable(Message::decode(body)?),
+            PACKET_ACK => Packet::Ack(seq()?),
+            PACKET_KEEPALIVE => Packet::KeepAlive,
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_packets() {
+        let packets = vec![
+            Packet::Reliable {
+                seq: 7,
+                message: Message::Game {
+                    sender: GUID::from_u128(1),
+                    data: b"hello".to_vec(),
+                },
+            },
+            Packet::Unreliable(Message::EnumRequest(b"play".to_vec())),
+            Packet::Reliable {
+                seq: 0,
+                message: Message::Players(vec![GUID::from_u128(2), GUID::from_u128(3)]),
+            },
+            Packet::Unreliable(Message::EnumReply(vec![])),
+            Packet::Ack(7),
+            Packet::KeepAlive,
+        ];
+        for packet in packets {
+            assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+        }
+        assert!(Packet::decode(&[]).is_err());
+        assert!(Packet::decode(&[PACKET_ACK, 0, 0]).is_err());
+        assert!(Packet::decode(&[PACKET_UNRELIABLE, MESSAGE_GAME, 1]).is_err());
+    }
+}
@@ -0,0 +1,345 @@
+//! Service provider that tunnels DirectPlay traffic over a WebSocket.
+//!
+//! WebSockets get through proxies and firewalls that only let web traffic out, and a web-based
+//! matchmaking frontend can route them next to its own connections. Every message is a binary
+//! frame with a four byte method name followed by its data, as described in
+//! `dpsp_local_only::router`: the WebSocket router routes game instances in the same way as the
+//! local broker and the relay, with a room GUID as the session ID.
+//!
+//! The other end is a `router`, which can run on its own or behind an existing HTTP server.
+//! Networks that only let web traffic out through an HTTP proxy can use
+//! `connect_through_proxy`.
+
+pub mod router;
+
+use async_std::io::{self, prelude::*};
+use async_std::net::TcpStream;
+use async_tungstenite::async_std::client_async_tls_with_connector_and_config;
+use async_tungstenite::tungstenite::{self, client::IntoClientRequest};
+use dprun::GUID;
+use dpsp_local_only::router::RouterSP;
+
+/// Service provider that plays through a WebSocket router.
+pub type WebSocketSP = RouterSP;
+
+/// Longest response to a CONNECT request that we accept from a proxy.
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+/// Convert a WebSocket error, keeping I/O errors as they are.
+pub(crate) fn ws_error(err: tungstenite::Error) -> io::Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        err => io::Error::other(err),
+    }
+}
+
+/// Connect to the router at `url`, for example `wss://example.com/play`, for a game instance
+/// that hosts or joins the given room.
+pub async fn connect(url: &str, room_id: GUID) -> io::Result<WebSocketSP> {
+    connect_with(url, room_id, None).await
+}
+
+/// Connect to the router at `url` through the HTTP proxy at `proxy`, for example
+/// `proxy.example.com:3128`, which must support CONNECT tunnels.
+pub async fn connect_through_proxy(
+    url: &str,
+    room_id: GUID,
+    proxy: &str,
+) -> io::Result<WebSocketSP> {
+    connect_with(url, room_id, Some(proxy)).await
+}
+
+async fn connect_with(url: &str, room_id: GUID, proxy: Option<&str>) -> io::Result<WebSocketSP> {
+    let request = url.into_client_request().map_err(ws_error)?;
+    let invalid_url = || io::Error::new(io::ErrorKind::InvalidInput, "invalid WebSocket URL");
+    let host = request.uri().host().ok_or_else(invalid_url)?.to_string();
+    let port = match (request.uri().port_u16(), request.uri().scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("wss")) => 443,
+        (None, Some("ws")) => 80,
+        _ => return Err(invalid_url()),
+    };
+    let stream = match proxy {
+        Some(proxy) => {
+            let mut stream = TcpStream::connect(proxy).await?;
+            open_tunnel(&mut stream, &host, port).await?;
+            stream
+        }
+        None => TcpStream::connect((host.as_str(), port)).await?,
+    };
+    // Game messages are small and latency matters more than throughput.
+    stream.set_nodelay(true)?;
+    let (socket, _) = client_async_tls_with_connector_and_config(request, stream, None, None)
+        .await
+        .map_err(ws_error)?;
+    let (writer, reader) = router::binary_messages(socket);
+    // Closing the WebSocket tells the router that we left, so there is nothing else to shut down.
+    RouterSP::connect(reader, writer, room_id, || Ok(())).await
+}
+
+/// Ask an HTTP proxy to open a tunnel to `host` and `port` on `stream`.
+async fn open_tunnel(stream: &mut TcpStream, host: &str, port: u16) -> io::Result<()> {
+    let request = format!(
+        "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n",
+        host, port
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response a byte at a time, so that nothing after it is taken from the WebSocket.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_PROXY_RESPONSE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "proxy response is too long",
+            ));
+        }
+        let mut byte = [0];
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "proxy closed the connection",
+            ));
+        }
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    let mut parts = status.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some("200")) if version.starts_with("HTTP/1.") => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("proxy refused the tunnel: {}", status),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::future::timeout;
+    use async_std::net::TcpListener;
+    use async_std::sync::{Arc, Mutex};
+    use async_std::task;
+    use dprun::{structs::*, AppController, AppMessage, ServiceProvider};
+    use dpsp_local_only::LocalOnlyServer;
+    use std::time::Duration;
+
+    const DPLAYI_PLAYER_NAMESRVR: i32 = 2;
+
+    async fn next_message(receiver: &async_channel::Receiver<AppMessage>) -> AppMessage {
+        timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("no message from the router")
+            .unwrap()
+    }
+
+    #[test]
+    fn routes_through_a_local_router() {
+        let room_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}/play", listener.local_addr().unwrap());
+            let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+            task::spawn(router::serve(listener, server));
+
+            let mut host = connect(&url, room_id).await.unwrap();
+            let (host_controller, host_receiver) = AppController::create();
+            let create_host = CreatePlayerData {
+                player_guid: host_id,
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            host.create_player(host_controller.clone(), 1, create_host)
+                .await
+                .unwrap();
+
+            let mut joiner = connect(&url, room_id).await.unwrap();
+            let (joiner_controller, joiner_receiver) = AppController::create();
+            let mut request = joiner_id.as_bytes().to_vec();
+            request.extend_from_slice(b"play");
+            let enum_sessions = EnumSessionsData {
+                message: request.clone(),
+            };
+            joiner
+                .enum_sessions(joiner_controller.clone(), 1, enum_sessions)
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, request),
+                message => panic!("unexpected {:?}", message),
+            }
+            let reply = ReplyData {
+                reply_to: joiner_id,
+                name_server_id: 0,
+                message: b"session".to_vec(),
+            };
+            host.reply(host_controller.clone(), 2, reply).await.unwrap();
+            match next_message(&joiner_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"session"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            let open = OpenData {
+                create: false,
+                return_status: false,
+                open_flags: 0,
+                session_flags: 0,
+            };
+            joiner
+                .open(joiner_controller.clone(), 2, open)
+                .await
+                .unwrap();
+            let create_joiner = CreatePlayerData {
+                player_guid: joiner_id,
+                flags: 0,
+            };
+            joiner
+                .create_player(joiner_controller.clone(), 3, create_joiner)
+                .await
+                .unwrap();
+            let send = SendData {
+                flags: DPSEND_GUARANTEED,
+                receiver_id: Some(host_id),
+                sender_id: joiner_id,
+                system_message: false,
+                message: b"hello".to_vec(),
+            };
+            joiner
+                .send(joiner_controller.clone(), 4, send)
+                .await
+                .unwrap();
+            match next_message(&host_receiver).await {
+                AppMessage::Send(_, _, data) => assert_eq!(data, b"hello"),
+                message => panic!("unexpected {:?}", message),
+            }
+
+            host.disconnected(host_controller).await.unwrap();
+            assert!(matches!(
+                next_message(&joiner_receiver).await,
+                AppMessage::Close
+            ));
+            host.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn rejects_bad_urls() {
+        task::block_on(async {
+            let err = connect("http://example.com/", GUID::nil())
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        });
+    }
+
+    /// Run an HTTP proxy that opens a tunnel for every CONNECT request, and refuses requests to
+    /// other ports than `allowed_port`.
+    async fn run_proxy(listener: TcpListener, allowed_port: u16) {
+        while let Ok((mut client, _)) = listener.accept().await {
+            task::spawn(async move {
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    if client.read(&mut byte).await.unwrap() == 0 {
+                        return;
+                    }
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let target = request.split_whitespace().nth(1).unwrap().to_string();
+                if !target.ends_with(&format!(":{}", allowed_port)) {
+                    client
+                        .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                        .await
+                        .unwrap();
+                    return;
+                }
+                let server = TcpStream::connect(target).await.unwrap();
+                client
+                    .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                    .await
+                    .unwrap();
+                let (mut client_reader, mut server_reader) = (client.clone(), server.clone());
+                let (mut client_writer, mut server_writer) = (client, server);
+                let upstream = io::copy(&mut client_reader, &mut server_writer);
+                let downstream = io::copy(&mut server_reader, &mut client_writer);
+                futures::future::select(Box::pin(upstream), Box::pin(downstream)).await;
+            });
+        }
+    }
+
+    #[test]
+    fn connects_through_a_proxy() {
+        let room_id = GUID::from_u128(1);
+        let host_id = GUID::from_u128(2);
+        let joiner_id = GUID::from_u128(3);
+
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let router_address = listener.local_addr().unwrap();
+            let url = format!("ws://{}/play", router_address);
+            let server = Arc::new(Mutex::new(LocalOnlyServer::make()));
+            task::spawn(router::serve(listener, server));
+            let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_address = proxy.local_addr().unwrap().to_string();
+            task::spawn(run_proxy(proxy, router_address.port()));
+
+            let mut host = connect_through_proxy(&url, room_id, &proxy_address)
+                .await
+                .unwrap();
+            let (host_controller, host_receiver) = AppController::create();
+            let create_host = CreatePlayerData {
+                player_guid: host_id,
+                flags: DPLAYI_PLAYER_NAMESRVR,
+            };
+            host.create_player(host_controller.clone(), 1, create_host)
+                .await
+                .unwrap();
+
+            let mut joiner = connect(&url, room_id).await.unwrap();
+            let (joiner_controller, _joiner_receiver) = AppController::create();
+            let mut request = joiner_id.as_bytes().to_vec();
+            request.extend_from_slice(b"play");
+            // The host's player may still be on its way through the proxy, in which case nobody
+            // answers. The game asks again, like DirectPlay does.
+            let mut attempts = 0;
+            let received = loop {
+                attempts += 1;
+                assert!(
+                    attempts <= 20,
+                    "the host never heard the enumeration request"
+                );
+                let enum_sessions = EnumSessionsData {
+                    message: request.clone(),
+                };
+                joiner
+                    .enum_sessions(joiner_controller.clone(), 1, enum_sessions)
+                    .await
+                    .unwrap();
+                if let Ok(message) = timeout(Duration::from_millis(250), host_receiver.recv()).await
+                {
+                    break message.unwrap();
+                }
+            };
+            match received {
+                AppMessage::Send(_, _, data) => assert_eq!(data, request),
+                message => panic!("unexpected {:?}", message),
+            }
+            host.shutdown().await.unwrap();
+            joiner.shutdown().await.unwrap();
+
+            let elsewhere = "ws://127.0.0.1:1/play";
+            let err = connect_through_proxy(elsewhere, room_id, &proxy_address)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        });
+    }
+}
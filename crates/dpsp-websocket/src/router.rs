@@ -0,0 +1,86 @@
+//! The server side of `WebSocketSP`: routes messages between the game instances in a room.
+//!
+//! Every room is a session on a `LocalOnlyServer`, and game instances are routed by
+//! `dpsp_local_only::router`, the same way the local broker and the relay do it. `serve` accepts
+//! WebSockets on its own; applications that already run an HTTP server can upgrade the
+//! connection themselves and pass the WebSocket to `handle_connection`.
+
+use crate::ws_error;
+use async_std::io;
+use async_std::net::TcpListener;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use dpsp_local_only::{router, LocalOnlyServer};
+use futures::future;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::sink::{Sink, SinkExt};
+use futures::stream::{Stream, StreamExt};
+use std::fmt::Display;
+
+/// Accept WebSocket connections on `listener`, and route their messages through `server`.
+///
+/// Returns when accepting a connection fails.
+pub async fn serve(listener: TcpListener, server: Arc<Mutex<LocalOnlyServer>>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        stream.set_nodelay(true)?;
+        let server = Arc::clone(&server);
+        task::spawn(async move {
+            let result = match async_tungstenite::accept_async(stream).await {
+                Ok(socket) => handle_connection(socket, server, peer).await,
+                Err(err) => Err(ws_error(err)),
+            };
+            if let Err(err) = result {
+                log::warn!("[router] {}: {:?}", peer, err);
+            }
+        });
+    }
+}
+
+/// Send and receive frames as binary WebSocket messages.
+///
+/// The stream ends when the other side closes the WebSocket; other kinds of messages are skipped,
+/// the WebSocket library answers pings by itself.
+pub(crate) fn binary_messages<S>(
+    socket: S,
+) -> (
+    impl Sink<Vec<u8>, Error = io::Error>,
+    impl Stream<Item = io::Result<Vec<u8>>>,
+)
+where
+    S: Sink<Message, Error = async_tungstenite::tungstenite::Error>
+        + Stream<Item = Result<Message, async_tungstenite::tungstenite::Error>>,
+{
+    let (writer, reader) = socket.split();
+    let writer = writer
+        .sink_map_err(ws_error)
+        .with(|data: Vec<u8>| future::ready(Ok::<_, io::Error>(Message::Binary(data))));
+    let reader = reader
+        .take_while(|message| future::ready(!matches!(message, Ok(Message::Close(_)))))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Binary(bytes)) => Some(Ok(bytes)),
+                Ok(_) => None,
+                Err(err) => Some(Err(ws_error(err))),
+            })
+        });
+    (writer, reader)
+}
+
+/// Route messages for a game instance that connected over `socket`, until it disconnects.
+/// `client` names the game instance in logs.
+pub async fn handle_connection<S>(
+    socket: WebSocketStream<S>,
+    server: Arc<Mutex<LocalOnlyServer>>,
+    client: impl Display,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (writer, reader) = binary_messages(socket);
+    futures::pin_mut!(writer);
+    futures::pin_mut!(reader);
+    router::route(reader, writer, server, client).await
+}
@@ -1,8 +1,11 @@
 use async_std::prelude::*;
 use async_std::sync::{Arc, Mutex};
 use dprun::{run, DPRunOptions, GUID};
-use dpsp_libp2p::Libp2pSP;
+use dpsp_gateway::GatewaySP;
+use dpsp_ipx::IpxSP;
+use dpsp_libp2p::{Libp2pSP, Multiaddr};
 use dpsp_local_only::{LocalOnlySP, LocalOnlyServer};
+use std::net::SocketAddr;
 use std::str::FromStr;
 use std::time::Duration;
 use structopt::StructOpt;
@@ -14,6 +17,10 @@ enum SPType {
     Local,
     #[allow(dead_code)]
     P2P,
+    #[allow(dead_code)]
+    Ipx,
+    #[allow(dead_code)]
+    Gateway,
 }
 
 impl FromStr for SPType {
@@ -23,7 +30,9 @@ impl FromStr for SPType {
             "tcp" => Ok(SPType::TCPIP),
             "local" => Ok(SPType::Local),
             "p2p" => Ok(SPType::P2P),
-            _ => Err("unknown sp-type, must be tcp, local, p2p"),
+            "ipx" => Ok(SPType::Ipx),
+            "gateway" => Ok(SPType::Gateway),
+            _ => Err("unknown sp-type, must be tcp, local, p2p, ipx, gateway"),
         }
     }
 }
@@ -66,28 +75,67 @@ async fn main() -> anyhow::Result<()> {
             let local_server = Arc::new(Mutex::new(LocalOnlyServer::make()));
 
             host_options = host_options
-                .service_provider_handler(Box::new(LocalOnlySP::new(Arc::clone(&local_server))))
+                .service_provider_handler(Box::new(LocalOnlySP::new(
+                    Arc::clone(&local_server),
+                    test_session_id,
+                )))
                 .named_address_part("INet", "127.0.0.1")
                 .named_address_part("INetPort", 2197)
                 .named_address_part("SelfID", host_guid.as_bytes().to_vec());
             join_options = join_options
-                .service_provider_handler(Box::new(LocalOnlySP::new(Arc::clone(&local_server))))
+                .service_provider_handler(Box::new(LocalOnlySP::new(
+                    Arc::clone(&local_server),
+                    test_session_id,
+                )))
                 .named_address_part("INet", "127.0.0.1")
                 .named_address_part("INetPort", 2198)
                 .named_address_part("SelfID", join_guid.as_bytes().to_vec());
         }
         SPType::P2P => {
+            let host_address: Multiaddr = "/ip4/127.0.0.1/tcp/22197".parse()?;
             host_options = host_options
-                .service_provider_handler(Box::new(Libp2pSP::default()))
+                .service_provider_handler(Box::new(
+                    Libp2pSP::default().with_listen_address(host_address.clone()),
+                ))
                 .named_address_part("INet", "127.0.0.1")
                 .named_address_part("INetPort", 2197)
                 .named_address_part("SelfID", host_guid.as_bytes().to_vec());
             join_options = join_options
-                .service_provider_handler(Box::new(Libp2pSP::default()))
+                .service_provider_handler(Box::new(Libp2pSP::default().with_address(host_address)))
                 .named_address_part("INet", "127.0.0.1")
                 .named_address_part("INetPort", 2198)
                 .named_address_part("SelfID", join_guid.as_bytes().to_vec());
         }
+        SPType::Ipx => {
+            // Both instances run on this machine, so they need their own ports, and broadcasts
+            // go to both.
+            let broadcast: Vec<SocketAddr> =
+                vec!["127.0.0.1:2213".parse()?, "127.0.0.1:2214".parse()?];
+            host_options = host_options
+                .service_provider_handler(Box::new(
+                    IpxSP::default()
+                        .with_port(2213)
+                        .with_broadcast_addresses(broadcast.clone()),
+                ))
+                .named_address_part("SelfID", host_guid.as_bytes().to_vec());
+            join_options = join_options
+                .service_provider_handler(Box::new(
+                    IpxSP::default()
+                        .with_port(2214)
+                        .with_broadcast_addresses(broadcast),
+                ))
+                .named_address_part("SelfID", join_guid.as_bytes().to_vec());
+        }
+        SPType::Gateway => {
+            // The host runs through DPRUN, and the joiner uses the stock TCP/IP service
+            // provider.
+            host_options = host_options
+                .service_provider_handler(Box::new(GatewaySP::default()))
+                .named_address_part("SelfID", host_guid.as_bytes().to_vec());
+            join_options = join_options
+                .named_service_provider("TCPIP")
+                .named_address_part("INet", "127.0.0.1");
+        }
         SPType::TCPIP => {
             host_options = host_options
                 .named_service_provider("TCPIP")